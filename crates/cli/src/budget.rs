@@ -0,0 +1,198 @@
+//! `simulate-budget`: token limits on an existing dataset or raw sessions.
+
+use std::path::PathBuf;
+
+use clap::Parser;
+
+use crowd_pilot_serializer_core::{
+    pipeline::PipelineConfig, process_all_sessions, simulate_budget, BudgetLimits,
+    BudgetSimulation, CharApproxTokenizer, RecordVersion, Tokenizer,
+};
+
+use crate::RustTokenizer;
+
+/// Report how many conversations hypothetical token limits would split or
+/// truncate, without writing a dataset.
+#[derive(Parser, Debug)]
+#[command(name = "crowd-pilot-serialize simulate-budget")]
+pub struct SimulateBudgetArgs {
+    /// JSONL dataset written by a previous run (repeatable). Splits and
+    /// truncations of that run cannot be undone, so only limits at most as
+    /// large as its own are meaningful
+    #[arg(long = "dataset", value_name = "FILE", required_unless_present = "csv_root")]
+    datasets: Vec<PathBuf>,
+
+    /// Root directory of raw CSV sessions, serialized without limits
+    #[arg(long, conflicts_with = "datasets")]
+    csv_root: Option<PathBuf>,
+
+    /// HuggingFace tokenizer model name or path (default: ~4 characters per
+    /// token)
+    #[arg(long)]
+    tokenizer: Option<String>,
+
+    /// Conversation limits to simulate (comma-separated)
+    #[arg(long, value_delimiter = ',', default_value = "8192")]
+    max_tokens_per_conversation: Vec<usize>,
+
+    /// Message limits to simulate (comma-separated)
+    #[arg(long, value_delimiter = ',', default_value = "2048")]
+    max_tokens_per_message: Vec<usize>,
+
+    /// Write the simulations as JSON to this file
+    #[arg(long, value_name = "FILE")]
+    output: Option<PathBuf>,
+}
+
+/// Message values of each conversation in a JSONL dataset. Packed records
+/// are split back into their conversations.
+///
+/// Every record must have the version of the first record read, `version`.
+fn read_dataset(
+    path: &PathBuf,
+    version: &mut Option<RecordVersion>,
+) -> Result<Vec<Vec<String>>, Box<dyn std::error::Error>> {
+    let mut conversations = Vec::new();
+    for (line_no, line) in std::fs::read_to_string(path)?.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record: serde_json::Value = serde_json::from_str(line)
+            .map_err(|e| format!("{}:{}: {}", path.display(), line_no + 1, e))?;
+        let record_version: RecordVersion = serde_json::from_str(line)?;
+        let first = version.get_or_insert_with(|| record_version.clone());
+        if let Some(message) = record_version.incompatibility(first) {
+            return Err(format!("{}:{}: {}", path.display(), line_no + 1, message).into());
+        }
+        let values: Vec<String> = match record["conversations"].as_array() {
+            Some(messages) => messages
+                .iter()
+                .map(|m| m["value"].as_str().unwrap_or_default().to_string())
+                .collect(),
+            // Prompt-completion records: the prompt messages, then the completion
+            None => record["prompt"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .map(|m| m["value"].as_str().unwrap_or_default().to_string())
+                .chain([record["completion"].as_str().unwrap_or_default().to_string()])
+                .collect(),
+        };
+        match record["packing"]["sources"].as_array() {
+            Some(sources) => {
+                for source in sources {
+                    let first = source["first_message"].as_u64().unwrap_or(0) as usize;
+                    let count = source["messages"].as_u64().unwrap_or(0) as usize;
+                    conversations.push(values.iter().skip(first).take(count).cloned().collect());
+                }
+            }
+            None => conversations.push(values),
+        }
+    }
+    Ok(conversations)
+}
+
+/// Per-message token counts of the input conversations.
+fn message_tokens<T: Tokenizer + Sync + Send>(
+    args: &SimulateBudgetArgs,
+    tokenizer: &T,
+) -> Result<Vec<Vec<usize>>, Box<dyn std::error::Error>> {
+    if let Some(csv_root) = &args.csv_root {
+        let config = PipelineConfig {
+            max_tokens_per_conversation: usize::MAX,
+            max_tokens_per_message: usize::MAX,
+            deferred_tokenization: true,
+            ..PipelineConfig::default()
+        };
+        println!("Processing CSV files from {:?}...", csv_root);
+        let sessions = process_all_sessions(csv_root, tokenizer, &config)?;
+        return Ok(sessions
+            .into_iter()
+            .flat_map(|s| s.conversations)
+            .map(|c| c.message_token_counts)
+            .collect());
+    }
+
+    let mut tokens = Vec::new();
+    let mut version = None;
+    for path in &args.datasets {
+        println!("Reading {:?}...", path);
+        tokens.extend(read_dataset(path, &mut version)?.into_iter().map(|values| {
+            values.iter().map(|v| tokenizer.count_tokens(v)).collect::<Vec<usize>>()
+        }));
+    }
+    Ok(tokens)
+}
+
+pub fn simulate(args: SimulateBudgetArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let tokens = match &args.tokenizer {
+        Some(name) => {
+            println!("Loading tokenizer from {}...", name);
+            message_tokens(&args, &RustTokenizer::load(name, "main", None, false)?)?
+        }
+        None => message_tokens(&args, &CharApproxTokenizer)?,
+    };
+
+    let mut simulations: Vec<BudgetSimulation> = Vec::new();
+    for &max_tokens_per_conversation in &args.max_tokens_per_conversation {
+        for &max_tokens_per_message in &args.max_tokens_per_message {
+            let limits = BudgetLimits {
+                max_tokens_per_conversation,
+                max_tokens_per_message,
+            };
+            simulations.push(simulate_budget(tokens.iter().map(Vec::as_slice), limits));
+        }
+    }
+
+    println!(
+        "{:>10} {:>10} {:>10} {:>10} {:>10} {:>10} {:>12}",
+        "conv_max", "msg_max", "convs", "split", "output", "truncated", "tokens_cut"
+    );
+    for sim in &simulations {
+        println!(
+            "{:>10} {:>10} {:>10} {:>10} {:>10} {:>10} {:>12}",
+            sim.max_tokens_per_conversation,
+            sim.max_tokens_per_message,
+            sim.conversations,
+            sim.split_conversations,
+            sim.output_conversations,
+            sim.truncated_messages,
+            sim.truncated_tokens
+        );
+    }
+    if let Some(output) = &args.output {
+        std::fs::write(output, serde_json::to_string_pretty(&simulations)?)?;
+        println!("Simulations: {:?}", output);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crowd_pilot_serializer_core::{prompt_completion_records, ConversationMessage, FinalizedConversation};
+
+    #[test]
+    fn test_read_prompt_completion_dataset() {
+        let conversation = FinalizedConversation {
+            messages: vec![
+                ConversationMessage::assistant("```bash\ncat -n /a.rs\n```\n"),
+                ConversationMessage::user("<stdout>\n     1\tfn a() {}\n</stdout>"),
+                ConversationMessage::assistant("```bash\nls\n```\n"),
+            ],
+            message_token_counts: vec![4, 5, 3],
+            ..Default::default()
+        };
+        let records = prompt_completion_records(&conversation, "system", 100, &[]);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("train.jsonl");
+        let lines: Vec<String> = records.iter().map(|r| serde_json::to_string(r).unwrap()).collect();
+        std::fs::write(&path, lines.join("\n")).unwrap();
+
+        let conversations = read_dataset(&path, &mut None).unwrap();
+        assert_eq!(
+            conversations,
+            vec![conversation.messages.iter().map(|m| m.value.clone()).collect::<Vec<_>>()]
+        );
+    }
+}
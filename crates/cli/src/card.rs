@@ -0,0 +1,283 @@
+//! `DATASET_CARD.md`: standard documentation written next to every dataset.
+//!
+//! The card is rendered from a Markdown template with `{{name}}`
+//! placeholders. A name is either a generated section (`splits`, `files`,
+//! `token_distribution`, `redaction`) or a dotted path into `metadata.json`,
+//! e.g. `{{counts.total_conversations}}` or `{{config.tokenizer}}`.
+
+use std::fmt::Write as _;
+use std::path::Path;
+
+use serde_json::Value;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+/// File name of the card in the output directory.
+pub const DATASET_CARD: &str = "DATASET_CARD.md";
+
+/// Template used without `--dataset-card-template`.
+pub const DEFAULT_TEMPLATE: &str = "# crowd-pilot dataset
+
+Conversations serialized from crowd-pilot IDE interaction sessions by
+`crowd-pilot-serialize` {{serializer_version}} (record schema {{schema_version}}),
+tokenized with `{{config.tokenizer}}` at revision `{{config.tokenizer_revision}}`.
+
+## Splits
+
+{{splits}}
+
+## Files
+
+{{files}}
+
+## Token distribution
+
+{{token_distribution}}
+
+## Redaction
+
+{{redaction}}
+
+## Reproduction
+
+```bash
+crowd-pilot-serialize reproduce metadata.json --output-dir <DIR>
+```
+
+The full configuration and statistics are in `metadata.json`.
+";
+
+/// `value` as card text: strings unquoted, `null` as `-`, anything else as JSON.
+fn display(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        Value::Null => "-".to_string(),
+        other => other.to_string(),
+    }
+}
+
+/// Table of the train/validation split.
+fn splits(metadata: &Value) -> String {
+    let counts = &metadata["counts"];
+    let mut table = String::from("| Split | Conversations |\n|---|---|\n");
+    let _ = writeln!(table, "| train | {} |", display(&counts["train_conversations"]));
+    let _ = writeln!(table, "| validation | {} |", display(&counts["val_conversations"]));
+    let _ = write!(
+        table,
+        "\n{} sessions, {} records, {} messages, {} tokens.",
+        display(&counts["total_sessions"]),
+        display(&counts["total_records"]),
+        display(&metadata["stats"]["total_messages"]),
+        display(&metadata["stats"]["total_tokens"])
+    );
+    table
+}
+
+/// Number and total size of the files under `dir`.
+fn dir_size(dir: &Path) -> Result<(usize, u64)> {
+    let (mut count, mut bytes) = (0, 0);
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            let (sub_count, sub_bytes) = dir_size(&entry.path())?;
+            count += sub_count;
+            bytes += sub_bytes;
+        } else if file_type.is_file() {
+            count += 1;
+            bytes += entry.metadata()?.len();
+        }
+    }
+    Ok((count, bytes))
+}
+
+/// Table of the top-level files in `output_dir` and their sizes, excluding
+/// the card; each subdirectory is one row with its file count and total size.
+fn files(output_dir: &Path) -> Result<String> {
+    let mut entries = std::fs::read_dir(output_dir)?.collect::<std::io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    let mut table = String::from("| File | Size (bytes) |\n|---|---|\n");
+    for entry in entries {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            let (count, bytes) = dir_size(&entry.path())?;
+            let _ = writeln!(table, "| `{}/` ({} files) | {} |", name, count, bytes);
+        } else if file_type.is_file() && name != DATASET_CARD {
+            let _ = writeln!(table, "| `{}` | {} |", name, entry.metadata()?.len());
+        }
+    }
+    Ok(table.trim_end().to_string())
+}
+
+/// Table of the token and message histograms of `dataset_stats`.
+fn token_distribution(metadata: &Value) -> String {
+    let mut table = String::from("| Distribution | Min | Mean | p50 | p90 | p99 | Max |\n|---|---|---|---|---|---|---|\n");
+    for (title, key) in [
+        ("Tokens per conversation", "tokens_per_conversation"),
+        ("Messages per conversation", "messages_per_conversation"),
+    ] {
+        let hist = &metadata["dataset_stats"][key];
+        let _ = writeln!(
+            table,
+            "| {} | {} | {:.1} | {} | {} | {} | {} |",
+            title,
+            display(&hist["min"]),
+            hist["mean"].as_f64().unwrap_or(0.0),
+            display(&hist["p50"]),
+            display(&hist["p90"]),
+            display(&hist["p99"]),
+            display(&hist["max"])
+        );
+    }
+    if let Some(shares) = metadata["dataset_stats"]["role_token_share"].as_object() {
+        let shares: Vec<String> = shares
+            .iter()
+            .map(|(role, share)| format!("{} {:.1}%", role, share.as_f64().unwrap_or(0.0) * 100.0))
+            .collect();
+        let _ = write!(table, "\nToken share by role: {}.", shares.join(", "));
+    }
+    table.trim_end().to_string()
+}
+
+/// List of the anonymization and filtering applied to the data.
+fn redaction(metadata: &Value) -> String {
+    let config = &metadata["config"];
+    let drops = &metadata["dataset_stats"]["drops"];
+    let count = |value: &Value| value.as_array().map_or(0, Vec::len);
+    let mut items = vec![
+        if config["hash_identity"].as_bool().unwrap_or(false) {
+            "Session ids and source paths are salted hashes; the salt is not recorded.".to_string()
+        } else {
+            "Session ids and source paths are not anonymized.".to_string()
+        },
+        format!("Path mappings applied: {}.", count(&config["path_mappings"])),
+        format!(
+            "Ignored path patterns: {}{} ({} events dropped).",
+            count(&config["ignored_paths"]),
+            if config["ignore_vendored_paths"].as_bool().unwrap_or(false) {
+                " plus vendored directories"
+            } else {
+                ""
+            },
+            display(&drops["ignored_path_events"])
+        ),
+        format!(
+            "Destructive commands dropped: {}.",
+            if config["drop_destructive_commands"].as_bool().unwrap_or(false) { "yes" } else { "no" }
+        ),
+    ];
+    if let Some(contamination) = metadata["contamination"].as_object() {
+        items.push(format!(
+            "Conversations overlapping evaluation corpora: {} ({}).",
+            display(&contamination["flagged_conversations"]),
+            if contamination["dropped"].as_bool().unwrap_or(false) { "dropped" } else { "flagged only" }
+        ));
+    }
+    items.iter().map(|item| format!("- {}", item)).collect::<Vec<_>>().join("\n")
+}
+
+/// Value of the placeholder `name`.
+fn placeholder(name: &str, metadata: &Value, output_dir: &Path) -> Result<String> {
+    Ok(match name {
+        "splits" => splits(metadata),
+        "files" => files(output_dir)?,
+        "token_distribution" => token_distribution(metadata),
+        "redaction" => redaction(metadata),
+        path => {
+            let value = path
+                .split('.')
+                .try_fold(metadata, |value, key| value.get(key))
+                .ok_or_else(|| format!("unknown dataset card placeholder '{{{{{}}}}}'", path))?;
+            display(value)
+        }
+    })
+}
+
+/// Render `template` for the run described by `metadata`.
+pub fn render(template: &str, metadata: &Value, output_dir: &Path) -> Result<String> {
+    let mut card = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let Some(len) = rest[start..].find("}}") else {
+            break;
+        };
+        card.push_str(&rest[..start]);
+        card.push_str(&placeholder(rest[start + 2..start + len].trim(), metadata, output_dir)?);
+        rest = &rest[start + len + 2..];
+    }
+    card.push_str(rest);
+    Ok(card)
+}
+
+/// Render the card with `template` (default: `DEFAULT_TEMPLATE`) and write it
+/// into `output_dir`.
+pub fn write_dataset_card(template: Option<&Path>, metadata: &Value, output_dir: &Path) -> Result<()> {
+    let template = match template {
+        Some(path) => std::fs::read_to_string(path)
+            .map_err(|e| format!("cannot read dataset card template {}: {}", path.display(), e))?,
+        None => DEFAULT_TEMPLATE.to_string(),
+    };
+    let card = render(&template, metadata, output_dir)?;
+    std::fs::write(output_dir.join(DATASET_CARD), card)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn metadata() -> Value {
+        json!({
+            "serializer_version": "1.2.3",
+            "schema_version": 2,
+            "config": { "tokenizer": "tok", "tokenizer_revision": "main", "hash_identity": true, "path_mappings": ["/a=/b"] },
+            "counts": {
+                "train_conversations": 9,
+                "val_conversations": 1,
+                "total_sessions": 4,
+                "total_records": 10,
+            },
+            "stats": { "total_messages": 50, "total_tokens": 1000 },
+            "dataset_stats": {
+                "tokens_per_conversation": { "min": 10, "mean": 100.0, "p50": 90, "p90": 150, "p99": 200, "max": 210 },
+                "drops": { "ignored_path_events": 3 },
+            },
+        })
+    }
+
+    #[test]
+    fn test_render() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("training.jsonl"), "12345").unwrap();
+        std::fs::write(dir.path().join(DATASET_CARD), "old card").unwrap();
+        std::fs::create_dir_all(dir.path().join("shards/nested")).unwrap();
+        std::fs::write(dir.path().join("shards/a.jsonl"), "123").unwrap();
+        std::fs::write(dir.path().join("shards/nested/b.jsonl"), "4567").unwrap();
+
+        let card = render(
+            "{{ serializer_version }} {{config.tokenizer}} {{counts.total_sessions}} {{config.missing_ok}}",
+            &json!({ "serializer_version": "1.2.3", "config": { "tokenizer": "tok", "missing_ok": null }, "counts": { "total_sessions": 4 } }),
+            dir.path(),
+        )
+        .unwrap();
+        assert_eq!(card, "1.2.3 tok 4 -");
+
+        let card = render(DEFAULT_TEMPLATE, &metadata(), dir.path()).unwrap();
+        assert!(!card.contains("{{"));
+        assert!(card.contains("| train | 9 |\n| validation | 1 |"));
+        assert!(card.contains("4 sessions, 10 records, 50 messages, 1000 tokens."));
+        assert!(card.contains("| `training.jsonl` | 5 |"));
+        assert!(card.contains("| `shards/` (2 files) | 7 |"));
+        assert!(!card.contains("a.jsonl"));
+        assert!(!card.contains(&format!("`{}`", DATASET_CARD)));
+        assert!(card.contains("| Tokens per conversation | 10 | 100.0 | 90 | 150 | 200 | 210 |"));
+        assert!(card.contains("- Session ids and source paths are salted hashes"));
+        assert!(card.contains("- Path mappings applied: 1."));
+        assert!(card.contains("(3 events dropped)"));
+
+        assert!(render("{{counts.unknown}}", &metadata(), dir.path()).is_err());
+        assert_eq!(render("a {{b", &metadata(), dir.path()).unwrap(), "a {{b");
+    }
+}
@@ -0,0 +1,203 @@
+//! `compare-serializers`: side-by-side message diff of two serializations of
+//! a session.
+
+use std::path::{Path, PathBuf};
+
+use clap::Parser;
+
+use crowd_pilot_serializer_core::{
+    diff_messages, process_session, read_jsonl_input, tokenize_conversations, CharApproxTokenizer,
+    ConversationMessage, OpcodeTag, Tokenizer,
+};
+
+use crate::{pipeline_config, Args, RustTokenizer};
+
+/// Serialize one session with two sets of flags (or compare against a
+/// recorded output) and print a side-by-side message-level diff.
+#[derive(Parser, Debug)]
+#[command(name = "crowd-pilot-serialize compare-serializers")]
+pub struct CompareArgs {
+    /// Session CSV file
+    session: PathBuf,
+
+    /// Serializer flags of the left side, whitespace-separated, e.g.
+    /// "--viewport-radius 10" (default: the defaults)
+    #[arg(long, value_name = "FLAGS", allow_hyphen_values = true, conflicts_with = "golden")]
+    flags_a: Option<String>,
+
+    /// Serializer flags of the right side (default: the defaults)
+    #[arg(long, value_name = "FLAGS", allow_hyphen_values = true)]
+    flags_b: Option<String>,
+
+    /// Recorded JSONL output of this session (conversation format, e.g. its
+    /// `sessions/<id>.jsonl`) to use as the left side
+    #[arg(long, value_name = "FILE")]
+    golden: Option<PathBuf>,
+
+    /// HuggingFace tokenizer model name or path (default: ~4 characters per
+    /// token)
+    #[arg(long)]
+    tokenizer: Option<String>,
+
+    /// Width of each side in characters
+    #[arg(long, default_value = "80")]
+    width: usize,
+
+    /// Print unchanged messages instead of collapsing them
+    #[arg(long)]
+    full: bool,
+}
+
+/// A serialized conversation: its id and messages.
+type Conversation = (String, Vec<ConversationMessage>);
+
+/// Serialize `session` with the serializer `flags`.
+fn serialize<T: Tokenizer + Sync>(
+    session: &Path,
+    flags: Option<&str>,
+    tokenizer_name: &str,
+    tokenizer: &T,
+) -> Result<Vec<Conversation>, Box<dyn std::error::Error>> {
+    let csv_root = session.parent().unwrap_or(Path::new(".")).to_string_lossy().into_owned();
+    let args = Args::try_parse_from(
+        ["crowd-pilot-serialize", "--csv-root", &csv_root, "--output-dir", "-", "--tokenizer", tokenizer_name]
+            .into_iter()
+            .chain(flags.unwrap_or_default().split_whitespace()),
+    )?;
+    let (config, _) = pipeline_config(&args, tokenizer)?;
+    let mut conversations = process_session(session, tokenizer, &config)?.conversations;
+    if config.deferred_tokenization {
+        tokenize_conversations(
+            conversations.iter_mut(),
+            tokenizer,
+            config.max_tokens_per_message,
+            config.tokenization_batch_size,
+        );
+    }
+    Ok(conversations.into_iter().map(|c| (c.id, c.messages)).collect())
+}
+
+/// Conversations of a recorded JSONL output.
+fn read_golden(path: &Path) -> Result<Vec<Conversation>, Box<dyn std::error::Error>> {
+    Ok(read_jsonl_input(path)?
+        .into_iter()
+        .map(|record| {
+            let messages = record.messages();
+            (record.id, messages)
+        })
+        .collect())
+}
+
+/// Lines of a message, as shown in one column.
+fn message_lines(message: &ConversationMessage) -> Vec<String> {
+    std::iter::once(format!("[{}]", message.from))
+        .chain(message.value.lines().map(|line| line.replace('\t', "    ")))
+        .collect()
+}
+
+/// `text` cut or padded to exactly `width` characters.
+fn fit(text: &str, width: usize) -> String {
+    let mut fitted: String = text.chars().take(width).collect();
+    let len = fitted.chars().count();
+    fitted.extend(std::iter::repeat_n(' ', width - len));
+    fitted
+}
+
+/// Print a message pair side by side; `None` leaves a column empty.
+fn print_side_by_side(
+    gutter: char,
+    left: Option<&ConversationMessage>,
+    right: Option<&ConversationMessage>,
+    width: usize,
+) {
+    let left = left.map(message_lines).unwrap_or_default();
+    let right = right.map(message_lines).unwrap_or_default();
+    for i in 0..left.len().max(right.len()) {
+        let l = left.get(i).map(String::as_str).unwrap_or_default();
+        let r = right.get(i).map(String::as_str).unwrap_or_default();
+        println!("{} {} {}", fit(l, width), gutter, fit(r, width).trim_end());
+    }
+}
+
+/// Print the diff of two conversations; returns whether they differ.
+fn print_conversation_diff(
+    before: &[ConversationMessage],
+    after: &[ConversationMessage],
+    args: &CompareArgs,
+) -> bool {
+    let mut differs = false;
+    for hunk in diff_messages(before, after) {
+        let left = &before[hunk.before.clone()];
+        let right = &after[hunk.after.clone()];
+        match hunk.tag {
+            OpcodeTag::Equal if !args.full => {
+                let note = format!("... {} identical messages ...", left.len());
+                println!("{}", format!("{:^width$}", note, width = 2 * args.width + 3).trim_end());
+            }
+            OpcodeTag::Equal => {
+                for (l, r) in left.iter().zip(right) {
+                    print_side_by_side(' ', Some(l), Some(r), args.width);
+                }
+            }
+            tag => {
+                differs = true;
+                let gutter = match tag {
+                    OpcodeTag::Delete => '<',
+                    OpcodeTag::Insert => '>',
+                    _ => '|',
+                };
+                for i in 0..left.len().max(right.len()) {
+                    print_side_by_side(gutter, left.get(i), right.get(i), args.width);
+                }
+            }
+        }
+    }
+    differs
+}
+
+pub fn compare(args: CompareArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let (left, right) = match &args.tokenizer {
+        Some(name) => {
+            println!("Loading tokenizer from {}...", name);
+            let tokenizer = RustTokenizer::load(name, "main", None, false)?;
+            let left = match &args.golden {
+                Some(path) => read_golden(path)?,
+                None => serialize(&args.session, args.flags_a.as_deref(), name, &tokenizer)?,
+            };
+            (left, serialize(&args.session, args.flags_b.as_deref(), name, &tokenizer)?)
+        }
+        None => {
+            let left = match &args.golden {
+                Some(path) => read_golden(path)?,
+                None => serialize(&args.session, args.flags_a.as_deref(), "chars", &CharApproxTokenizer)?,
+            };
+            (left, serialize(&args.session, args.flags_b.as_deref(), "chars", &CharApproxTokenizer)?)
+        }
+    };
+    let left_label = match (&args.golden, &args.flags_a) {
+        (Some(path), _) => path.display().to_string(),
+        (None, flags) => flags.clone().unwrap_or_else(|| "defaults".to_string()),
+    };
+    let right_label = args.flags_b.clone().unwrap_or_else(|| "defaults".to_string());
+
+    let mut differing = 0;
+    for i in 0..left.len().max(right.len()) {
+        let (left_id, left_messages) = left.get(i).map(|(id, m)| (id.as_str(), m.as_slice())).unwrap_or_default();
+        let (right_id, right_messages) = right.get(i).map(|(id, m)| (id.as_str(), m.as_slice())).unwrap_or_default();
+        println!();
+        println!(
+            "{} {}",
+            fit(&format!("=== conversation {} {}", i, left_id), args.width),
+            format!("=== conversation {} {}", i, right_id).trim_end()
+        );
+        if print_conversation_diff(left_messages, right_messages, &args) {
+            differing += 1;
+        }
+    }
+
+    println!();
+    println!("Left:  {} ({} conversations)", left_label, left.len());
+    println!("Right: {} ({} conversations)", right_label, right.len());
+    println!("{} of {} conversations differ", differing, left.len().max(right.len()));
+    Ok(())
+}
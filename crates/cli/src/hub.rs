@@ -0,0 +1,413 @@
+//! Publishing serialized datasets to the HuggingFace Hub.
+//!
+//! Uploads the data shards of the output directory in a single commit, with
+//! the `DATASET_CARD.md` written by `card` as the repository's `README.md`, using the Hub's HTTP commit API. Files
+//! the Hub wants in LFS go through the git-lfs batch API first.
+
+use std::cmp::Reverse;
+use std::error::Error;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use base64::Engine;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use crowd_pilot_serializer_core::BLOBS_DIR;
+
+use crate::card::DATASET_CARD;
+
+type Result<T> = std::result::Result<T, Box<dyn Error>>;
+
+/// Bytes of each file sent to the preupload endpoint to pick the upload mode.
+const PREUPLOAD_SAMPLE_BYTES: usize = 512;
+
+/// Files and directories of the output directory that are uploaded, per
+/// layout. Everything else (`metadata.json`, reports, event dumps) can hold
+/// local paths or raw session data and stays local.
+const MONOLITHIC_SHARDS: [&str; 3] = ["training.jsonl", "validation.jsonl", BLOBS_DIR];
+const PER_SESSION_SHARDS: [&str; 3] = ["index.jsonl", "sessions", BLOBS_DIR];
+
+/// `config` keys of `metadata.json` holding local paths, which are replaced
+/// by `<key>` in the uploaded card.
+const LOCAL_PATH_KEYS: [&str; 10] = [
+    "csv_root",
+    "output_dir",
+    "hf_cache_dir",
+    "dump_events",
+    "warnings_report",
+    "session_manifest",
+    "exclude_ids",
+    "augment_system_prompts",
+    "dataset_card_template",
+    "contamination_corpora",
+];
+
+/// `config` keys that name either a Hub repository or a local file; only
+/// local files are replaced.
+const MAYBE_LOCAL_PATH_KEYS: [&str; 2] = ["tokenizer", "compare_tokenizer"];
+
+/// A file to upload, relative to the repository root.
+struct UploadFile {
+    path_in_repo: String,
+    local_path: PathBuf,
+    size: u64,
+}
+
+/// Read the Hub token from `HF_TOKEN` or the huggingface-cli token file.
+fn hub_token() -> Result<String> {
+    if let Ok(token) = std::env::var("HF_TOKEN") {
+        if !token.trim().is_empty() {
+            return Ok(token.trim().to_string());
+        }
+    }
+    let token_path = match std::env::var_os("HF_HOME") {
+        Some(home) => PathBuf::from(home).join("token"),
+        None => std::env::var_os("HOME")
+            .map(|home| PathBuf::from(home).join(".cache/huggingface/token"))
+            .ok_or("cannot locate the HuggingFace token: HF_TOKEN and HOME are unset")?,
+    };
+    let token = std::fs::read_to_string(&token_path).map_err(|e| {
+        format!(
+            "no HuggingFace token: set HF_TOKEN or run `huggingface-cli login` ({}: {})",
+            token_path.display(),
+            e
+        )
+    })?;
+    Ok(token.trim().to_string())
+}
+
+fn hub_endpoint() -> String {
+    std::env::var("HF_ENDPOINT")
+        .map(|e| e.trim_end_matches('/').to_string())
+        .unwrap_or_else(|_| "https://huggingface.co".to_string())
+}
+
+fn is_per_session(metadata: &Value) -> bool {
+    metadata["files"].get("sessions_dir").is_some()
+}
+
+/// Hub metadata header of the `README.md`: the data files of each split, so
+/// the dataset viewer and `datasets.load_dataset` find them.
+fn front_matter(metadata: &Value) -> String {
+    let configs = if is_per_session(metadata) {
+        "configs:\n- config_name: default\n  data_files:\n  - split: train\n    path: sessions/*.jsonl\n"
+    } else {
+        "configs:\n- config_name: default\n  data_files:\n  - split: train\n    path: training.jsonl\n  - split: validation\n    path: validation.jsonl\n"
+    };
+    format!("---\n{}tags:\n- crowd-pilot\n- code\n---\n\n", configs)
+}
+
+/// `card` with the local paths of the run's config replaced by `<key>`.
+fn strip_local_paths(card: &str, metadata: &Value) -> String {
+    let config = &metadata["config"];
+    let mut paths: Vec<(&str, &str)> = Vec::new();
+    for key in LOCAL_PATH_KEYS {
+        let values = match &config[key] {
+            Value::Array(values) => values.iter().collect(),
+            value => vec![value],
+        };
+        paths.extend(values.into_iter().filter_map(Value::as_str).map(|path| (key, path)));
+    }
+    for key in MAYBE_LOCAL_PATH_KEYS {
+        if let Some(path) = config[key].as_str().filter(|path| Path::new(path).exists()) {
+            paths.push((key, path));
+        }
+    }
+    // Longest first, so a path is not left half-replaced by its parent's key.
+    paths.sort_by_key(|(_, path)| Reverse(path.len()));
+    let mut card = card.to_string();
+    for (key, path) in paths {
+        if !path.is_empty() {
+            card = card.replace(path, &format!("<{}>", key));
+        }
+    }
+    card
+}
+
+/// `README.md` of the Hub dataset: the front matter and the stripped card.
+fn readme(card: &str, metadata: &Value) -> String {
+    front_matter(metadata) + &strip_local_paths(card, metadata)
+}
+
+/// The shards of the run's layout found in `output_dir`.
+fn dataset_files(output_dir: &Path, metadata: &Value) -> Result<Vec<UploadFile>> {
+    let shards = if is_per_session(metadata) { PER_SESSION_SHARDS } else { MONOLITHIC_SHARDS };
+    let mut files = Vec::new();
+    for name in shards {
+        let path = output_dir.join(name);
+        if path.is_dir() {
+            collect_files(&path, &format!("{}/", name), &mut files)?;
+        } else if path.is_file() {
+            files.push(UploadFile {
+                path_in_repo: name.to_string(),
+                size: path.metadata()?.len(),
+                local_path: path,
+            });
+        }
+    }
+    Ok(files)
+}
+
+/// Collect every file under `dir`, with `/`-separated repository paths.
+fn collect_files(dir: &Path, prefix: &str, files: &mut Vec<UploadFile>) -> Result<()> {
+    let mut entries = std::fs::read_dir(dir)?.collect::<std::io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let path_in_repo = format!("{}{}", prefix, name);
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            collect_files(&entry.path(), &format!("{}/", path_in_repo), files)?;
+        } else if file_type.is_file() {
+            files.push(UploadFile {
+                path_in_repo,
+                size: entry.metadata()?.len(),
+                local_path: entry.path(),
+            });
+        }
+    }
+    Ok(())
+}
+
+fn read_sample(path: &Path) -> Result<Vec<u8>> {
+    let mut sample = Vec::with_capacity(PREUPLOAD_SAMPLE_BYTES);
+    File::open(path)?
+        .take(PREUPLOAD_SAMPLE_BYTES as u64)
+        .read_to_end(&mut sample)?;
+    Ok(sample)
+}
+
+fn sha256_file(path: &Path) -> Result<String> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect())
+}
+
+struct HubClient {
+    endpoint: String,
+    repo: String,
+    auth: String,
+}
+
+impl HubClient {
+    fn post(&self, url: &str) -> ureq::Request {
+        ureq::post(url).set("Authorization", &self.auth)
+    }
+
+    /// Create the dataset repository (private); an existing repository is fine.
+    fn create_repo(&self) -> Result<()> {
+        let (organization, name) = match self.repo.split_once('/') {
+            Some((org, name)) => (Some(org), name),
+            None => (None, self.repo.as_str()),
+        };
+        let body = json!({
+            "type": "dataset",
+            "name": name,
+            "organization": organization,
+            "private": true,
+        });
+        match self
+            .post(&format!("{}/api/repos/create", self.endpoint))
+            .send_json(body)
+        {
+            Ok(_) | Err(ureq::Error::Status(409, _)) => Ok(()),
+            Err(e) => Err(format!("failed to create dataset repo {}: {}", self.repo, e).into()),
+        }
+    }
+
+    /// Ask the Hub which files must be uploaded through LFS.
+    fn lfs_paths(&self, files: &[UploadFile]) -> Result<Vec<bool>> {
+        let mut payload = Vec::with_capacity(files.len());
+        for file in files {
+            payload.push(json!({
+                "path": file.path_in_repo,
+                "size": file.size,
+                "sample": base64::engine::general_purpose::STANDARD
+                    .encode(read_sample(&file.local_path)?),
+            }));
+        }
+        let response: Value = self
+            .post(&format!(
+                "{}/api/datasets/{}/preupload/main",
+                self.endpoint, self.repo
+            ))
+            .send_json(json!({ "files": payload }))?
+            .into_json()?;
+
+        let modes = response["files"].as_array().cloned().unwrap_or_default();
+        Ok(files
+            .iter()
+            .map(|file| {
+                modes.iter().any(|mode| {
+                    mode["path"] == file.path_in_repo.as_str() && mode["uploadMode"] == "lfs"
+                })
+            })
+            .collect())
+    }
+
+    /// Upload one file's content to LFS storage, unless the Hub already has it.
+    fn upload_lfs(&self, file: &UploadFile, oid: &str) -> Result<()> {
+        let response: Value = self
+            .post(&format!(
+                "{}/datasets/{}.git/info/lfs/objects/batch",
+                self.endpoint, self.repo
+            ))
+            .set("Accept", "application/vnd.git-lfs+json")
+            .set("Content-Type", "application/vnd.git-lfs+json")
+            .send_json(json!({
+                "operation": "upload",
+                "transfers": ["basic"],
+                "objects": [{ "oid": oid, "size": file.size }],
+                "hash_algo": "sha256",
+            }))?
+            .into_json()?;
+
+        let object = &response["objects"][0];
+        if let Some(error) = object.get("error") {
+            return Err(format!("LFS upload of {} rejected: {}", file.path_in_repo, error).into());
+        }
+        let Some(upload) = object["actions"].get("upload") else {
+            // Already stored.
+            return Ok(());
+        };
+
+        let href = upload["href"].as_str().ok_or("LFS upload action without href")?;
+        let mut request = ureq::put(href).set("Content-Length", &file.size.to_string());
+        if let Some(headers) = upload["header"].as_object() {
+            for (name, value) in headers {
+                request = request.set(name, value.as_str().unwrap_or_default());
+            }
+        }
+        request.send(File::open(&file.local_path)?)?;
+
+        if let Some(verify) = object["actions"].get("verify") {
+            let href = verify["href"].as_str().ok_or("LFS verify action without href")?;
+            self.post(href)
+                .set("Content-Type", "application/vnd.git-lfs+json")
+                .send_json(json!({ "oid": oid, "size": file.size }))?;
+        }
+        Ok(())
+    }
+
+    fn commit(&self, summary: &str, operations: &[Value]) -> Result<Value> {
+        let mut body = json!({ "key": "header", "value": { "summary": summary } }).to_string();
+        for operation in operations {
+            body.push('\n');
+            body.push_str(&operation.to_string());
+        }
+        Ok(self
+            .post(&format!(
+                "{}/api/datasets/{}/commit/main",
+                self.endpoint, self.repo
+            ))
+            .set("Content-Type", "application/x-ndjson")
+            .send_string(&body)?
+            .into_json()?)
+    }
+}
+
+/// Upload the shards of `output_dir` to the `repo` dataset (`org/name`), with
+/// its `DATASET_CARD.md`, stripped of local paths, as the `README.md`.
+///
+/// Returns the dataset URL.
+pub fn push_to_hub(repo: &str, output_dir: &Path, metadata: &Value) -> Result<String> {
+    let client = HubClient {
+        endpoint: hub_endpoint(),
+        repo: repo.to_string(),
+        auth: format!("Bearer {}", hub_token()?),
+    };
+    client.create_repo()?;
+
+    let card_path = output_dir.join(DATASET_CARD);
+    let card = std::fs::read_to_string(&card_path)
+        .map_err(|e| format!("cannot read the dataset card {}: {}", card_path.display(), e))?;
+    let files = dataset_files(output_dir, metadata)?;
+    let lfs = client.lfs_paths(&files)?;
+
+    let engine = base64::engine::general_purpose::STANDARD;
+    let mut operations = vec![json!({
+        "key": "file",
+        "value": {
+            "path": "README.md",
+            "encoding": "base64",
+            "content": engine.encode(readme(&card, metadata)),
+        },
+    })];
+    for (file, is_lfs) in files.iter().zip(lfs) {
+        if is_lfs {
+            let oid = sha256_file(&file.local_path)?;
+            println!("  Uploading {} ({} bytes)", file.path_in_repo, file.size);
+            client.upload_lfs(file, &oid)?;
+            operations.push(json!({
+                "key": "lfsFile",
+                "value": { "path": file.path_in_repo, "algo": "sha256", "oid": oid },
+            }));
+        } else {
+            operations.push(json!({
+                "key": "file",
+                "value": {
+                    "path": file.path_in_repo,
+                    "encoding": "base64",
+                    "content": engine.encode(std::fs::read(&file.local_path)?),
+                },
+            }));
+        }
+    }
+
+    let summary = format!(
+        "Upload {} conversations from crowd-pilot-serialize",
+        metadata["counts"]["total_conversations"]
+    );
+    client.commit(&summary, &operations)?;
+    Ok(format!("{}/datasets/{}", client.endpoint, repo))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_readme_and_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let output_dir = dir.path().join("out");
+        std::fs::create_dir_all(output_dir.join(BLOBS_DIR)).unwrap();
+        for name in ["training.jsonl", "validation.jsonl", "metadata.json", DATASET_CARD, "blobs/ab.txt"] {
+            std::fs::write(output_dir.join(name), "x").unwrap();
+        }
+        let tokenizer = dir.path().join("tokenizer.json");
+        std::fs::write(&tokenizer, "{}").unwrap();
+        let metadata = json!({
+            "config": {
+                "csv_root": "/data/sessions",
+                "output_dir": output_dir.to_string_lossy(),
+                "hf_cache_dir": null,
+                "contamination_corpora": ["/data/sessions/evals"],
+                "tokenizer": tokenizer.to_string_lossy(),
+                "compare_tokenizer": "org/model",
+            },
+            "files": { "train_path": output_dir.join("training.jsonl").to_string_lossy() },
+        });
+
+        let card = format!(
+            "from /data/sessions, /data/sessions/evals; train {}; tokenized with {}, compared with org/model",
+            output_dir.join("training.jsonl").display(),
+            tokenizer.display()
+        );
+        let readme = readme(&card, &metadata);
+        assert!(readme.starts_with("---\nconfigs:"));
+        assert!(readme.ends_with(
+            "from <csv_root>, <contamination_corpora>; train <output_dir>/training.jsonl; \
+             tokenized with <tokenizer>, compared with org/model"
+        ));
+
+        let files: Vec<String> =
+            dataset_files(&output_dir, &metadata).unwrap().into_iter().map(|f| f.path_in_repo).collect();
+        assert_eq!(files, ["training.jsonl", "validation.jsonl", "blobs/ab.txt"]);
+    }
+}
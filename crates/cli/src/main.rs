@@ -4,20 +4,40 @@
 //! NeMo SFT training. It uses the HuggingFace tokenizers Rust library for
 //! accurate token counting.
 
-use std::path::PathBuf;
+// The `metadata.json` literal nests deeper than the default limit allows
+#![recursion_limit = "512"]
 
-use clap::Parser;
+mod budget;
+mod card;
+mod compare;
+mod hub;
+mod materialize;
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use clap::{CommandFactory, Parser};
+use hf_hub::{api::sync::ApiBuilder, Cache, Repo, RepoType};
 use tokenizers::Tokenizer as HfTokenizer;
 
 use crowd_pilot_serializer_core::{
     pipeline::{PipelineConfig, PipelineResult},
-    process_all_sessions, write_jsonl_output, Tokenizer,
+    load_session_manifest, load_system_prompts, process_all_sessions, sample_sessions, verify_sessions,
+    write_jsonl_output, AugmentationConfig, CommandClass, ContaminationConfig, ContaminationFilter, DatasetStats,
+    ClassifierChain, CompanionPattern, DropCommandClasses, ExcludeIds, HeuristicClassifier, MultilineCommandPolicy, FilterChain, IgnorePattern, OutputFormat, OutputLayout, PackingConfig, PackingSystemPrompt, PathCanonicalization, PathMapping,
+    RecordVersion, RequireTags, SessionPlatform, StitchConfig, StitchKey, StructureValidation, TagRequirement,
+    TaskBoundary, TaskBoundaryConfig, TimingBlur, Tokenizer, UntrackedFilePolicy,
+    ViewCommandMix, WarningAggregator, WhitespaceEditPolicy, WindowsSessionMode, DEFAULT_WARNING_SAMPLES, SCHEMA_VERSION,
+    VENDORED_PATH_PATTERNS,
 };
 
 /// Serialize crowd-pilot CSV sessions to NeMo JSONL format.
 #[derive(Parser, Debug)]
 #[command(name = "crowd-pilot-serialize")]
 #[command(author, version, about, long_about = None)]
+#[command(
+    after_help = "To re-run a previous run and check its counts:\n  crowd-pilot-serialize reproduce <METADATA_JSON> --output-dir <DIR>\n\nTo see how token limits would split or truncate a dataset:\n  crowd-pilot-serialize simulate-budget --dataset <JSONL> --max-tokens-per-conversation 4096,8192\n\nTo diff a session's serialization under two sets of flags:\n  crowd-pilot-serialize compare-serializers <CSV> --flags-b \"--compact\"\n\nTo expand the blob references of a dataset written with --blob-min-bytes:\n  crowd-pilot-serialize materialize <DIR> --output-dir <DIR>"
+)]
 struct Args {
     /// Root directory containing CSV session files
     #[arg(long)]
@@ -31,10 +51,35 @@ struct Args {
     #[arg(long)]
     tokenizer: String,
 
+    /// Tokenizer revision (branch, tag or commit) on the HuggingFace Hub
+    #[arg(long, default_value = "main")]
+    tokenizer_revision: String,
+
+    /// Second HuggingFace tokenizer (name or path) to count every conversation
+    /// with, e.g. of another model family; reported in the statistics
+    #[arg(long)]
+    compare_tokenizer: Option<String>,
+
+    /// Never download the tokenizer; fail unless it is in the local cache
+    /// (also enabled by `HF_HUB_OFFLINE=1`)
+    #[arg(long)]
+    tokenizer_local_only: bool,
+
+    /// HuggingFace Hub cache directory (defaults to `$HF_HOME/hub` or
+    /// `~/.cache/huggingface/hub`)
+    #[arg(long)]
+    hf_cache_dir: Option<PathBuf>,
+
     /// Maximum tokens per conversation chunk
     #[arg(long, default_value = "8192")]
     max_tokens_per_conversation: usize,
 
+    /// Choose each session's conversation budget between this and
+    /// --max-tokens-per-conversation, from a quick pass with ~4 characters
+    /// per token, to avoid splitting conversations unevenly
+    #[arg(long, value_name = "TOKENS")]
+    auto_chunk_min_tokens: Option<usize>,
+
     /// Maximum tokens per message
     #[arg(long, default_value = "2048")]
     max_tokens_per_message: usize,
@@ -47,6 +92,31 @@ struct Args {
     #[arg(long, default_value = "10")]
     viewport_radius: usize,
 
+    /// Lines shown above the cursor (defaults to --viewport-radius)
+    #[arg(long)]
+    viewport_above: Option<usize>,
+
+    /// Lines shown below the cursor (defaults to --viewport-radius)
+    #[arg(long)]
+    viewport_below: Option<usize>,
+
+    /// Re-emit the viewport once the cursor is this many lines from its edge
+    #[arg(long, default_value = "0")]
+    viewport_margin: usize,
+
+    /// Recenter the viewport on every k-th cursor movement
+    #[arg(long, value_name = "K")]
+    viewport_recenter_every: Option<usize>,
+
+    /// Keep a file's viewport until the cursor moved N times since it was
+    /// emitted (suppresses re-emissions while scrolling)
+    #[arg(long, default_value = "0", value_name = "N")]
+    viewport_min_movements: usize,
+
+    /// Keep a file's viewport unless the new one starts at least N lines away
+    #[arg(long, default_value = "0", value_name = "N")]
+    viewport_min_shift: usize,
+
     /// Coalesce radius for grouping nearby edits
     #[arg(long, default_value = "5")]
     coalesce_radius: usize,
@@ -55,6 +125,397 @@ struct Args {
     #[arg(long, default_value = "0.1")]
     val_ratio: f64,
 
+    /// Count tokens after serialization in parallel batches (disables chunking)
+    #[arg(long)]
+    deferred_tokenization: bool,
+
+    /// Give up on a session after this many seconds and report it as timed out
+    #[arg(long)]
+    session_timeout_secs: Option<u64>,
+
+    /// Handling of edits to files never captured: assume-empty, drop, placeholder, buffer
+    #[arg(long, default_value = "assume-empty")]
+    untracked_file_policy: UntrackedFilePolicy,
+
+    /// Drop edits after an offset drift until the file's next tab snapshot
+    #[arg(long)]
+    resync_on_offset_drift: bool,
+
+    /// Fraction of conversations to replay-verify (0.0 disables)
+    #[arg(long, default_value = "0.0")]
+    verify_fraction: f64,
+
+    /// Check structural invariants of produced conversations (one output per
+    /// command, no consecutive commands, no empty code blocks): off, report, drop
+    #[arg(long, default_value = "off")]
+    validate_structure: StructureValidation,
+
+    /// Process rows in file order instead of sorting by the Sequence column
+    #[arg(long)]
+    no_sort_by_sequence: bool,
+
+    /// Drop rows that exactly duplicate the preceding row
+    #[arg(long)]
+    drop_duplicate_rows: bool,
+
+    /// Skip every event of this type, e.g. `selection` (all `selection_*`
+    /// types) or `terminal_focus` (repeatable)
+    #[arg(long = "ignore-event-type", value_name = "TYPE")]
+    ignored_event_types: Vec<String>,
+
+    /// Print at most this many warnings of each category (unknown event
+    /// type, missing text, offset drift, ...); the rest are only counted
+    #[arg(long, default_value_t = DEFAULT_WARNING_SAMPLES)]
+    warning_samples: usize,
+
+    /// Write every warning as a JSON line to this file
+    #[arg(long, value_name = "FILE")]
+    warnings_report: Option<PathBuf>,
+
+    /// Write stats.json and stats.md into the output directory
+    #[arg(long)]
+    stats_report: bool,
+
+    /// Gaps between events longer than this many seconds count as idle time
+    #[arg(long, default_value = "300")]
+    idle_gap_secs: u64,
+
+    /// Output record layout: conversation or prompt-completion
+    #[arg(long, default_value = "conversation")]
+    output_format: OutputFormat,
+
+    /// Output file layout: monolithic or per-session
+    #[arg(long, default_value = "monolithic")]
+    output_layout: OutputLayout,
+
+    /// Context token budget per prompt in prompt-completion mode
+    #[arg(long, default_value = "4096")]
+    prompt_context_tokens: usize,
+
+    /// Roles excluded from the loss (comma-separated)
+    #[arg(long, value_delimiter = ',', default_value = "User")]
+    mask_roles: Vec<String>,
+
+    /// Mask file re-captures repeated after a conversation chunk split
+    #[arg(long)]
+    mask_recaptures: bool,
+
+    /// Show files unchanged since their capture in an earlier chunk as only
+    /// their first N lines instead of a full re-capture
+    #[arg(long, value_name = "N")]
+    max_recapture_lines: Option<usize>,
+
+    /// Rewrite an absolute root in paths and commands, as FROM=TO (repeatable)
+    #[arg(long = "path-map")]
+    path_mappings: Vec<PathMapping>,
+
+    /// Treat "a/./b.rs", "a//b.rs" and "a/x/../b.rs" as the same file as "a/b.rs"
+    #[arg(long)]
+    normalize_paths: bool,
+
+    /// Treat paths differing only in case as the same file (case-insensitive
+    /// file systems); the first spelling seen is kept
+    #[arg(long)]
+    case_insensitive_paths: bool,
+
+    /// Treat paths under a symlink as paths under its target, as LINK=TARGET (repeatable)
+    #[arg(long = "symlink", value_name = "LINK=TARGET")]
+    symlinks: Vec<PathMapping>,
+
+    /// After an edit, also show the file's companion if it is open, as
+    /// PRIMARY=COMPANION globs with one "*" each, e.g. "*.rs=*_test.rs" (repeatable)
+    #[arg(long = "companion", value_name = "PRIMARY=COMPANION")]
+    companion_files: Vec<CompanionPattern>,
+
+    /// Skip editor events of files matching this glob, e.g. "node_modules/**"
+    /// or "*.min.js" (repeatable)
+    #[arg(long = "ignore-path", value_name = "GLOB")]
+    ignored_paths: Vec<IgnorePattern>,
+
+    /// Also skip common generated and vendored files (dependency directories,
+    /// build outputs, minified bundles, lockfiles)
+    #[arg(long)]
+    ignore_vendored_paths: bool,
+
+    /// Which sessions were recorded on Windows: detect (from file paths), posix, windows
+    #[arg(long, default_value = "detect")]
+    platform: SessionPlatform,
+
+    /// Serialization of Windows sessions: keep, posix-paths, or powershell
+    #[arg(long, default_value = "keep")]
+    windows_sessions: WindowsSessionMode,
+
+    /// Whitespace-only edits: emit, skip, or formatter
+    #[arg(long, default_value = "emit")]
+    whitespace_edit_policy: WhitespaceEditPolicy,
+
+    /// Multi-line terminal commands: verbatim, wrap (`bash -lc '...'`), or
+    /// split into one turn per line
+    #[arg(long, default_value = "verbatim", value_name = "POLICY")]
+    multiline_commands: MultilineCommandPolicy,
+
+    /// Merge consecutive terminal commands run within this many seconds
+    #[arg(long)]
+    merge_commands_within_secs: Option<f64>,
+
+    /// Attach terminal output that arrives after the next command back to
+    /// the preceding command, by timestamps and shell-integration markers
+    #[arg(long)]
+    reassociate_late_output: bool,
+
+    /// Assistant message appended at recorded `session_end` events, e.g.
+    /// "<task_complete>"
+    #[arg(long, value_name = "TEXT")]
+    end_of_task_marker: Option<String>,
+
+    /// Every N edit commands, record a content hash of each file shown in
+    /// the conversation as `checkpoints` in its record
+    #[arg(long, value_name = "N")]
+    checkpoint_every_edits: Option<usize>,
+
+    /// Also end conversations at task boundaries (comma-separated or
+    /// repeatable): tests-passed (a test run passes after a failing one),
+    /// git-commit or idle-gap; the reason is recorded as `boundary`
+    #[arg(long = "split-at", value_name = "BOUNDARY", value_delimiter = ',')]
+    split_at: Vec<TaskBoundary>,
+
+    /// Idle gap for `--split-at idle-gap`, in seconds
+    #[arg(long, default_value = "900")]
+    split_idle_gap_secs: u64,
+
+    /// Flush buffered terminal output once it reaches this many bytes
+    #[arg(long)]
+    max_terminal_buffer_bytes: Option<usize>,
+
+    /// Flush buffered terminal output once it is this many seconds old
+    #[arg(long)]
+    max_terminal_buffer_secs: Option<f64>,
+
+    /// Show files with more lines than this only as viewport slices
+    #[arg(long)]
+    large_file_line_threshold: Option<usize>,
+
+    /// Track at most this many files, forgetting the least recently used
+    #[arg(long)]
+    max_tracked_files: Option<usize>,
+
+    /// Drop redundant captures, superseded viewport reads and empty outputs
+    #[arg(long)]
+    compact: bool,
+
+    /// Add per-conversation serialization metrics to each output record
+    #[arg(long)]
+    emit_metrics: bool,
+
+    /// Record keystroke and pause timing of edits and commands as metadata
+    #[arg(long)]
+    emit_timing: bool,
+
+    /// Round exported timing values (message timing, session times) to
+    /// multiples of this many milliseconds
+    #[arg(long)]
+    timing_granularity_ms: Option<u64>,
+
+    /// Add deterministic noise of up to this many milliseconds to exported
+    /// timing values, seeded by --hash-identity-salt (required)
+    #[arg(long)]
+    timing_noise_ms: Option<u64>,
+
+    /// Add each message's kind (file_capture, viewport, edit_command,
+    /// terminal_command, terminal_output, git_action, ...) to the records
+    #[arg(long)]
+    emit_message_kinds: bool,
+
+    /// Add character spans of the decision-relevant parts (the command, the
+    /// sed payload) to assistant messages, for span-level loss masks
+    #[arg(long)]
+    emit_loss_spans: bool,
+
+    /// Write each session's normalized event stream as JSONL into this directory
+    #[arg(long)]
+    dump_events: Option<PathBuf>,
+
+    /// Augmentation variants per session, in addition to the original
+    /// (0 disables augmentation)
+    #[arg(long, default_value = "0")]
+    augment_variants: usize,
+
+    /// Seed of the augmentation choices
+    #[arg(long, default_value = "0")]
+    augment_seed: u64,
+
+    /// Move each variant's project root to a generated path
+    #[arg(long)]
+    augment_rename_paths: bool,
+
+    /// Change each variant's viewport by up to this many lines above and below
+    #[arg(long, default_value = "0")]
+    augment_viewport_jitter: usize,
+
+    /// JSON array of system prompt paraphrases to sample for variants
+    #[arg(long, value_name = "FILE")]
+    augment_system_prompts: Option<PathBuf>,
+
+    /// Show multi-line selections as viewport reads of the selected lines
+    #[arg(long)]
+    emit_selection_ranges: bool,
+
+    /// Show multi-line selections as viewport reads centered on the selected
+    /// lines, with the usual context
+    #[arg(long)]
+    center_selections: bool,
+
+    /// Flag the selected lines with ">" in viewport reads shown for
+    /// multi-line selections
+    #[arg(long)]
+    annotate_selections: bool,
+
+    /// Serialize tab snapshots that differ from the tracked content of an
+    /// already shown file as edits instead of full re-captures
+    #[arg(long)]
+    diff_stale_snapshots: bool,
+
+    /// Skip the view of a tab event for the file that already has focus,
+    /// unless the terminal had focus in between or the content changed
+    #[arg(long)]
+    skip_refocus_views: bool,
+
+    /// Flush the pending edits of other files when an edit arrives for a
+    /// different file, keeping interleaved edits in their real order
+    #[arg(long)]
+    flush_on_cross_file_edit: bool,
+
+    /// Don't serialize file changes written by the preceding terminal command
+    /// (`echo >> file`, `sed -i`; not terminal editors) again as edits
+    #[arg(long)]
+    dedupe_command_writes: bool,
+
+    /// Read the saved viewport again after a `file_save` event flushed an edit
+    #[arg(long)]
+    verify_on_save: bool,
+
+    /// Commands showing files, with weights: `cat`, `nl` and `sed-nl`
+    /// (e.g. `cat=3,nl=1,sed-nl=1`; default: always `cat -n`)
+    #[arg(long, value_name = "SPEC")]
+    view_commands: Option<ViewCommandMix>,
+
+    /// Seed of the --view-commands choice
+    #[arg(long, default_value = "0")]
+    view_command_seed: u64,
+
+    /// JSON manifest mapping session paths (or directories) to tags
+    #[arg(long, value_name = "FILE")]
+    session_manifest: Option<PathBuf>,
+
+    /// Process CSV fragments of a session split by recorder restarts as one
+    /// session, if they share these keys (comma-separated): directory,
+    /// workspace, tag:<name>
+    #[arg(long = "stitch-by", value_name = "KEY", value_delimiter = ',')]
+    stitch_by: Vec<StitchKey>,
+
+    /// Longest gap between stitched fragments, in seconds
+    #[arg(long, default_value = "600")]
+    stitch_max_gap_secs: u64,
+
+    /// Session tag holding a recording's start in milliseconds since the
+    /// epoch (required with --stitch-by; fragments without it are not
+    /// stitched)
+    #[arg(long, value_name = "TAG")]
+    stitch_start_tag: Option<String>,
+
+    /// Replace user/device directory names in source paths and ids with
+    /// hashes salted with this secret (not recorded in metadata.json)
+    #[arg(long, value_name = "SALT")]
+    hash_identity_salt: Option<String>,
+
+    /// Keep only sessions carrying this tag, as KEY=VALUE (repeatable)
+    #[arg(long = "require-tag", value_name = "KEY=VALUE")]
+    required_tags: Vec<TagRequirement>,
+
+    /// File of conversation ids to drop, one per line (`#` starts a comment)
+    #[arg(long, value_name = "FILE")]
+    exclude_ids: Option<PathBuf>,
+
+    /// Drop conversations in which the assistant runs a destructive command
+    /// (`rm`, `git reset --hard`, ...)
+    #[arg(long)]
+    drop_destructive_commands: bool,
+
+    /// Drop conversations in which the assistant runs a command of this
+    /// class: read, edit, build, network or destructive (repeatable)
+    #[arg(long = "drop-command-class", value_name = "CLASS")]
+    drop_command_classes: Vec<CommandClass>,
+
+    /// Label conversations with their primary language and a guess of the
+    /// task type, from file extensions and commands
+    #[arg(long)]
+    classify: bool,
+
+    /// Benchmark file or directory to check conversations against for
+    /// contamination (repeatable); writes `contamination.json`
+    #[arg(long = "contamination-corpus", value_name = "PATH")]
+    contamination_corpora: Vec<PathBuf>,
+
+    /// Words per shingle of the contamination check
+    #[arg(long, default_value = "13")]
+    contamination_ngram: usize,
+
+    /// Shingles a conversation must share with the corpora to be flagged
+    #[arg(long, default_value = "1")]
+    contamination_min_matches: usize,
+
+    /// Drop flagged conversations instead of only reporting them
+    #[arg(long)]
+    drop_contaminated: bool,
+
+    /// Pack short conversations into records of up to this many tokens
+    /// (conversation format, monolithic layout)
+    #[arg(long, value_name = "TOKENS")]
+    pack_to_tokens: Option<usize>,
+
+    /// Write user messages (file captures, command output) of at least this
+    /// many bytes once to a content-addressed `blobs/` store and reference
+    /// them from the records (conversation format, no packing); expand them
+    /// with `materialize`
+    #[arg(long, value_name = "BYTES")]
+    blob_min_bytes: Option<usize>,
+
+    /// Text inserted before each packed conversation after the first
+    #[arg(long, default_value = "")]
+    pack_separator: String,
+
+    /// System prompt in packed records: once (record `system` only) or
+    /// repeat (also after each separator)
+    #[arg(long, default_value = "once")]
+    pack_system_prompt: PackingSystemPrompt,
+
+    /// Upload the outputs and a generated dataset card to this HuggingFace
+    /// Hub dataset (`org/name`, created private if missing; needs `HF_TOKEN`)
+    #[arg(long, value_name = "REPO")]
+    push_to_hub: Option<String>,
+
+    /// Markdown template of the generated DATASET_CARD.md, with `{{name}}`
+    /// placeholders: `splits`, `files`, `token_distribution`, `redaction` or
+    /// a dotted `metadata.json` path such as `counts.total_conversations`
+    #[arg(long, value_name = "FILE")]
+    dataset_card_template: Option<PathBuf>,
+
+    /// Fraction of conversations to keep (seeded sampling)
+    #[arg(long, default_value = "1.0")]
+    sample_rate: f64,
+
+    /// Seed for --sample-rate and the size caps
+    #[arg(long, default_value = "0")]
+    sample_seed: u64,
+
+    /// Cap on the number of conversations in the dataset
+    #[arg(long)]
+    max_conversations: Option<usize>,
+
+    /// Cap on the total tokens of the dataset
+    #[arg(long)]
+    max_total_tokens: Option<usize>,
+
     /// Custom system prompt (optional)
     #[arg(long)]
     system_prompt: Option<String>,
@@ -83,9 +544,56 @@ struct RustTokenizer {
 
 impl RustTokenizer {
     /// Load a HuggingFace tokenizer from a model name or path.
-    fn load(model_name: &str) -> Result<Self, Box<dyn std::error::Error>> {
-        let inner = HfTokenizer::from_pretrained(model_name, None)
-            .map_err(|e| e as Box<dyn std::error::Error>)?;
+    ///
+    /// A local `tokenizer.json` (or a directory containing one) is used as is.
+    /// Hub models are looked up in the cache first and only downloaded when
+    /// `local_only` is false.
+    fn load(
+        model_name: &str,
+        revision: &str,
+        cache_dir: Option<&PathBuf>,
+        local_only: bool,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let local = PathBuf::from(model_name);
+        let file = if local.is_file() {
+            local
+        } else if local.join("tokenizer.json").is_file() {
+            local.join("tokenizer.json")
+        } else {
+            let cache = cache_dir.map_or_else(Cache::from_env, |dir| Cache::new(dir.clone()));
+            let repo = Repo::with_revision(
+                model_name.to_string(),
+                RepoType::Model,
+                revision.to_string(),
+            );
+            match cache.repo(repo.clone()).get("tokenizer.json") {
+                Some(cached) => cached,
+                None if local_only => {
+                    return Err(format!(
+                        "tokenizer {} (revision {}) is not in the HuggingFace cache at {}; \
+                         download it once with network access or pass --hf-cache-dir",
+                        model_name,
+                        revision,
+                        cache.path().display()
+                    )
+                    .into())
+                }
+                None => {
+                    let mut builder = ApiBuilder::from_env().with_progress(false);
+                    if let Some(dir) = cache_dir {
+                        builder = builder.with_cache_dir(dir.clone());
+                    }
+                    builder.build()?.repo(repo).get("tokenizer.json").map_err(|e| {
+                        format!(
+                            "failed to download tokenizer {} (revision {}): {}",
+                            model_name, revision, e
+                        )
+                    })?
+                }
+            }
+        };
+        let inner = HfTokenizer::from_file(&file)
+            .map_err(|e| format!("failed to load tokenizer from {}: {}", file.display(), e))?;
         Ok(Self { inner })
     }
 }
@@ -116,23 +624,394 @@ impl Tokenizer for RustTokenizer {
     }
 }
 
+/// Re-run a previous run from its `metadata.json` and check that it produces
+/// the same counts.
+#[derive(Parser, Debug)]
+#[command(name = "crowd-pilot-serialize reproduce")]
+struct ReproduceArgs {
+    /// `metadata.json` of the run to reproduce
+    metadata: PathBuf,
+
+    /// Output directory for the reproduced run
+    #[arg(long)]
+    output_dir: PathBuf,
+
+    /// Salt of a run that used `--hash-identity-salt`, which is not recorded
+    #[arg(long, value_name = "SALT")]
+    hash_identity_salt: Option<String>,
+}
+
+/// Stands in for the identity salt in the recorded command line.
+const REDACTED_SALT: &str = "<redacted>";
+
+/// `command_line` with the value of `--hash-identity-salt` redacted.
+fn redact_command_line(command_line: &[String]) -> Vec<String> {
+    let mut redacted = Vec::with_capacity(command_line.len());
+    let mut redact_next = false;
+    for arg in command_line {
+        if redact_next {
+            redacted.push(REDACTED_SALT.to_string());
+            redact_next = false;
+        } else if arg == "--hash-identity-salt" {
+            redacted.push(arg.clone());
+            redact_next = true;
+        } else if arg.starts_with("--hash-identity-salt=") {
+            redacted.push(format!("--hash-identity-salt={}", REDACTED_SALT));
+        } else {
+            redacted.push(arg.clone());
+        }
+    }
+    redacted
+}
+
+/// Sections of `metadata.json` that must match for a run to count as reproduced.
+const REPRODUCED_SECTIONS: [&str; 2] = ["counts", "stats"];
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args = Args::parse();
+    let command_line: Vec<String> = std::env::args().collect();
+    if command_line.get(1).map(String::as_str) == Some("reproduce") {
+        return reproduce(ReproduceArgs::parse_from(&command_line[1..]));
+    }
+    if command_line.get(1).map(String::as_str) == Some("simulate-budget") {
+        return budget::simulate(budget::SimulateBudgetArgs::parse_from(&command_line[1..]));
+    }
+    if command_line.get(1).map(String::as_str) == Some("compare-serializers") {
+        return compare::compare(compare::CompareArgs::parse_from(&command_line[1..]));
+    }
+    if command_line.get(1).map(String::as_str) == Some("materialize") {
+        return materialize::materialize(materialize::MaterializeArgs::parse_from(&command_line[1..]));
+    }
+    run(&Args::parse(), &command_line[1..], None)?;
+    Ok(())
+}
 
-    println!("Loading tokenizer from {}...", args.tokenizer);
-    let tokenizer = RustTokenizer::load(&args.tokenizer)?;
+/// Command line (without the binary name) equivalent to the `config` section
+/// of a `metadata.json`. The identity salt and `--push-to-hub` are left out.
+fn config_command_line(config: &serde_json::Value) -> Option<Vec<String>> {
+    let fields = config.as_object()?;
+    let mut command_line = Vec::new();
+    for arg in Args::command().get_arguments() {
+        let Some(long) = arg.get_long() else {
+            continue;
+        };
+        let value = match arg.get_id().as_str() {
+            "hash_identity_salt" | "push_to_hub" => continue,
+            "no_sort_by_sequence" => serde_json::Value::Bool(fields.get("sort_by_sequence") == Some(&false.into())),
+            id => match fields.get(id) {
+                Some(value) => value.clone(),
+                None => continue,
+            },
+        };
+        let arg_value = |value: &serde_json::Value| match value {
+            serde_json::Value::String(text) => text.clone(),
+            other => other.to_string(),
+        };
+        match value {
+            serde_json::Value::Null | serde_json::Value::Bool(false) => {}
+            serde_json::Value::Bool(true) => command_line.push(format!("--{}", long)),
+            serde_json::Value::Array(items) if matches!(arg.get_action(), clap::ArgAction::Append) => {
+                for item in &items {
+                    command_line.push(format!("--{}", long));
+                    command_line.push(arg_value(item));
+                }
+            }
+            serde_json::Value::Array(items) if items.is_empty() => {}
+            serde_json::Value::Array(items) => {
+                command_line.push(format!("--{}", long));
+                command_line.push(items.iter().map(arg_value).collect::<Vec<_>>().join(","));
+            }
+            other => {
+                command_line.push(format!("--{}", long));
+                command_line.push(arg_value(&other));
+            }
+        }
+    }
+    Some(command_line)
+}
+
+fn reproduce(reproduce_args: ReproduceArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let original: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&reproduce_args.metadata)?)?;
+    let command_line = config_command_line(&original["config"]).ok_or_else(|| {
+        format!("{} has no config section", reproduce_args.metadata.display())
+    })?;
+
+    let mut args = Args::try_parse_from(
+        std::iter::once("crowd-pilot-serialize").chain(command_line.iter().map(String::as_str)),
+    )?;
+    // Every output goes under the new output directory, so the original run is left untouched.
+    let output_dir = reproduce_args.output_dir;
+    std::fs::create_dir_all(&output_dir)?;
+    for path in [&mut args.dump_events, &mut args.warnings_report].into_iter().flatten() {
+        *path = match path.file_name() {
+            Some(name) => output_dir.join(name),
+            None => output_dir.clone(),
+        };
+    }
+    args.output_dir = output_dir;
+    args.push_to_hub = None;
+    if original["config"]["hash_identity"] == true {
+        args.hash_identity_salt = Some(reproduce_args.hash_identity_salt.ok_or(
+            "the run used --hash-identity-salt; pass the same salt to reproduce it",
+        )?);
+    }
+
+    println!("Reproducing run from {:?}...", reproduce_args.metadata);
+    let original_version = original["serializer_version"].as_str().unwrap_or("an unversioned serializer");
+    let current_version = RecordVersion::current().serializer_version;
+    if original_version != current_version {
+        println!("  Note: the run was written by {}, this is {}", original_version, current_version);
+    }
+    let reproduce_command_line: Vec<String> = std::env::args().skip(1).collect();
+    let reproduced = run(&args, &reproduce_command_line, Some(&reproduce_args.metadata))?;
+
+    let mut mismatches = Vec::new();
+    for section in REPRODUCED_SECTIONS {
+        let Some(fields) = original[section].as_object() else {
+            continue;
+        };
+        for (key, expected) in fields {
+            let actual = &reproduced[section][key];
+            if actual != expected {
+                mismatches.push(format!("{}.{}: expected {}, got {}", section, key, expected, actual));
+            }
+        }
+    }
+    if !mismatches.is_empty() {
+        for mismatch in &mismatches {
+            eprintln!("  mismatch {}", mismatch);
+        }
+        return Err(format!("run is not reproducible: {} mismatches", mismatches.len()).into());
+    }
+    println!("  Reproduced: counts and stats match {:?}", reproduce_args.metadata);
+    Ok(())
+}
+
+/// Pipeline configuration plus the contamination filter, if any, whose
+/// report is read after the run.
+type PipelineSetup = (PipelineConfig, Option<Arc<ContaminationFilter>>);
+
+/// Pipeline configuration for `args`.
+fn pipeline_config<T: Tokenizer>(
+    args: &Args,
+    tokenizer: &T,
+) -> Result<PipelineSetup, Box<dyn std::error::Error>> {
+    if args.timing_noise_ms.is_some_and(|noise| noise > 0) && args.hash_identity_salt.is_none() {
+        return Err("--timing-noise-ms needs --hash-identity-salt, which seeds the noise; \
+                    without a secret seed anyone can recompute and remove it"
+            .into());
+    }
+    let stitch = match (args.stitch_by.is_empty(), &args.stitch_start_tag) {
+        (true, _) => None,
+        (false, Some(start_tag)) => Some(StitchConfig {
+            keys: args.stitch_by.clone(),
+            max_gap_ms: args.stitch_max_gap_secs * 1000,
+            start_tag: start_tag.clone(),
+        }),
+        (false, None) => {
+            return Err("--stitch-by needs --stitch-start-tag, the session tag holding each recording's start".into())
+        }
+    };
+    let system_prompt = args.system_prompt.as_deref().unwrap_or(DEFAULT_SYSTEM_PROMPT);
+    let packing = args.pack_to_tokens.map(|target_tokens| {
+        let mut packing = PackingConfig {
+            target_tokens,
+            separator: args.pack_separator.clone(),
+            system_prompt: args.pack_system_prompt,
+            boundary_tokens: 0,
+        };
+        packing.boundary_tokens = tokenizer.count_tokens(&packing.boundary_text(system_prompt));
+        packing
+    });
+
+    let mut filters = FilterChain::new();
+    if !args.required_tags.is_empty() {
+        filters = filters.with(RequireTags::new(args.required_tags.clone()));
+    }
+    let session_manifest = match &args.session_manifest {
+        Some(path) => load_session_manifest(path)?,
+        None => Default::default(),
+    };
+    if let Some(path) = &args.exclude_ids {
+        let exclude_ids = ExcludeIds::from_file(path)?;
+        println!("Excluding {} conversation ids listed in {:?}", exclude_ids.len(), path);
+        filters = filters.with(exclude_ids);
+    }
+    let mut warnings = WarningAggregator::new(args.warning_samples);
+    if let Some(path) = &args.warnings_report {
+        warnings = warnings.with_report(path)?;
+    }
+    let mut ignored_paths = args.ignored_paths.clone();
+    if args.ignore_vendored_paths {
+        for pattern in VENDORED_PATH_PATTERNS {
+            ignored_paths.push(pattern.parse()?);
+        }
+    }
+    let mut drop_command_classes = args.drop_command_classes.clone();
+    if args.drop_destructive_commands {
+        drop_command_classes.push(CommandClass::Destructive);
+    }
+    if !drop_command_classes.is_empty() {
+        filters = filters.with(DropCommandClasses::new(drop_command_classes));
+    }
+    let augmentation = AugmentationConfig {
+        variants: args.augment_variants,
+        seed: args.augment_seed,
+        rename_paths: args.augment_rename_paths,
+        viewport_jitter: args.augment_viewport_jitter,
+        system_prompts: match &args.augment_system_prompts {
+            Some(path) => load_system_prompts(path)?,
+            None => Vec::new(),
+        },
+    };
+    let contamination = if args.contamination_corpora.is_empty() {
+        None
+    } else {
+        println!("Fingerprinting benchmark corpora...");
+        let filter = Arc::new(ContaminationFilter::build(
+            &args.contamination_corpora,
+            ContaminationConfig {
+                shingle_size: args.contamination_ngram,
+                min_matches: args.contamination_min_matches,
+                drop: args.drop_contaminated,
+            },
+        )?);
+        println!("  {} distinct shingles", filter.corpus_shingles());
+        filters.push(filter.clone());
+        Some(filter)
+    };
 
     let config = PipelineConfig {
         max_tokens_per_conversation: args.max_tokens_per_conversation,
+        auto_chunk_min_tokens: args.auto_chunk_min_tokens,
         max_tokens_per_message: args.max_tokens_per_message,
         min_conversation_messages: args.min_conversation_messages,
         viewport_radius: args.viewport_radius,
+        viewport_lines_above: args.viewport_above,
+        viewport_lines_below: args.viewport_below,
+        viewport_margin: args.viewport_margin,
+        viewport_recenter_every: args.viewport_recenter_every,
+        viewport_min_movements: args.viewport_min_movements,
+        viewport_min_shift: args.viewport_min_shift,
         coalesce_radius: args.coalesce_radius,
         val_ratio: args.val_ratio,
+        deferred_tokenization: args.deferred_tokenization,
+        session_timeout_secs: args.session_timeout_secs,
+        untracked_file_policy: args.untracked_file_policy,
+        resync_on_offset_drift: args.resync_on_offset_drift,
+        verify_fraction: args.verify_fraction,
+        structure_validation: args.validate_structure,
+        sort_by_sequence: !args.no_sort_by_sequence,
+        drop_duplicate_rows: args.drop_duplicate_rows,
+        ignored_event_types: args.ignored_event_types.clone(),
+        idle_gap_ms: args.idle_gap_secs * 1000,
+        output_format: args.output_format,
+        output_layout: args.output_layout,
+        prompt_context_tokens: args.prompt_context_tokens,
+        masked_roles: args.mask_roles.clone(),
+        mask_recaptures: args.mask_recaptures,
+        max_recapture_lines: args.max_recapture_lines,
+        path_mappings: args.path_mappings.clone(),
+        path_canonicalization: PathCanonicalization {
+            lexical: args.normalize_paths,
+            case_fold: args.case_insensitive_paths,
+            symlinks: args.symlinks.clone(),
+        },
+        companion_files: args.companion_files.clone(),
+        ignored_paths,
+        session_platform: args.platform,
+        windows_sessions: args.windows_sessions,
+        whitespace_edit_policy: args.whitespace_edit_policy,
+        multiline_commands: args.multiline_commands,
+        merge_commands_window_ms: args.merge_commands_within_secs.map(|s| (s * 1000.0) as u64),
+        reassociate_late_output: args.reassociate_late_output,
+        end_of_task_marker: args.end_of_task_marker.clone(),
+        checkpoint_every_edits: args.checkpoint_every_edits,
+        task_boundaries: (!args.split_at.is_empty()).then(|| TaskBoundaryConfig {
+            tests_passed: args.split_at.contains(&TaskBoundary::TestsPassed),
+            git_commit: args.split_at.contains(&TaskBoundary::GitCommit),
+            idle_gap_ms: args
+                .split_at
+                .contains(&TaskBoundary::IdleGap)
+                .then_some(args.split_idle_gap_secs * 1000),
+        }),
+        large_file_line_threshold: args.large_file_line_threshold,
+        max_tracked_files: args.max_tracked_files,
+        max_terminal_buffer_bytes: args.max_terminal_buffer_bytes,
+        max_terminal_buffer_age_ms: args.max_terminal_buffer_secs.map(|s| (s * 1000.0) as u64),
+        compact_conversations: args.compact,
+        emit_selection_ranges: args.emit_selection_ranges,
+        center_selections: args.center_selections,
+        annotate_selections: args.annotate_selections,
+        diff_stale_snapshots: args.diff_stale_snapshots,
+        skip_refocus_views: args.skip_refocus_views,
+        flush_on_cross_file_edit: args.flush_on_cross_file_edit,
+        dedupe_command_writes: args.dedupe_command_writes,
+        verify_on_save: args.verify_on_save,
+        view_commands: ViewCommandMix {
+            seed: args.view_command_seed,
+            ..args.view_commands.clone().unwrap_or_default()
+        },
+        sample_rate: args.sample_rate,
+        dump_events_dir: args.dump_events.clone(),
+        augmentation: (args.augment_variants > 0).then_some(augmentation),
+        emit_metrics: args.emit_metrics,
+        emit_timing: args.emit_timing,
+        timing_blur: (args.timing_granularity_ms.is_some() || args.timing_noise_ms.is_some()).then(|| TimingBlur {
+            granularity_ms: args.timing_granularity_ms.unwrap_or(0),
+            noise_ms: args.timing_noise_ms.unwrap_or(0),
+            seed: args.hash_identity_salt.clone().unwrap_or_default(),
+        }),
+        emit_message_kinds: args.emit_message_kinds,
+        emit_loss_spans: args.emit_loss_spans,
+        identity_salt: args.hash_identity_salt.clone(),
+        warnings: Arc::new(warnings),
+        blob_min_bytes: args.blob_min_bytes,
+        packing,
+        filters,
+        classifiers: if args.classify {
+            ClassifierChain::new().with(HeuristicClassifier)
+        } else {
+            ClassifierChain::default()
+        },
+        session_manifest,
+        stitch,
+        sample_seed: args.sample_seed,
+        max_conversations: args.max_conversations,
+        max_total_tokens: args.max_total_tokens,
+        ..PipelineConfig::default()
     };
+    Ok((config, contamination))
+}
+
+/// Run the pipeline and return the `metadata.json` it wrote.
+///
+/// `command_line` (without the binary name) is recorded for reference;
+/// `reproduce` rebuilds the run from the recorded config.
+fn run(
+    args: &Args,
+    command_line: &[String],
+    reproduced_from: Option<&Path>,
+) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
+
+    println!("Loading tokenizer from {}...", args.tokenizer);
+    let tokenizer_local_only = args.tokenizer_local_only
+        || std::env::var("HF_HUB_OFFLINE").is_ok_and(|v| v == "1" || v == "true");
+    let tokenizer = RustTokenizer::load(
+        &args.tokenizer,
+        &args.tokenizer_revision,
+        args.hf_cache_dir.as_ref(),
+        tokenizer_local_only,
+    )?;
+
+    let system_prompt = args.system_prompt.as_deref().unwrap_or(DEFAULT_SYSTEM_PROMPT);
+    let (config, contamination) = pipeline_config(args, &tokenizer)?;
 
     println!("Processing CSV files from {:?}...", args.csv_root);
-    let session_results = process_all_sessions(
+    if args.augment_variants > 0 {
+        println!("  Augmenting with {} variants per session", args.augment_variants);
+    }
+    let mut session_results = process_all_sessions(
         &args.csv_root,
         &tokenizer,
         &config,
@@ -141,38 +1020,195 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let total_sessions = session_results.len();
     println!("Processed {} sessions", total_sessions);
 
-    let system_prompt = args.system_prompt.as_deref().unwrap_or(DEFAULT_SYSTEM_PROMPT);
+    sample_sessions(&mut session_results, &config)?;
+
+    let verification = (config.verify_fraction > 0.0).then(|| {
+        println!("Replay-verifying {:.1}% of conversations...", config.verify_fraction * 100.0);
+        verify_sessions(&session_results, &config)
+    });
+
+    let augmented_conversations: usize = session_results
+        .iter()
+        .flat_map(|s| &s.conversations)
+        .filter(|c| c.augmentation.is_some())
+        .count();
+    let mut dataset_stats = DatasetStats::compute(&session_results);
+    if let Some(name) = &args.compare_tokenizer {
+        println!("Counting tokens with {} for comparison...", name);
+        let compare_tokenizer =
+            RustTokenizer::load(name, "main", args.hf_cache_dir.as_ref(), tokenizer_local_only)?;
+        dataset_stats.compare_tokenizer(
+            &session_results,
+            name,
+            &compare_tokenizer,
+            args.max_tokens_per_conversation,
+            args.max_tokens_per_message,
+        );
+    }
+    if args.stats_report {
+        dataset_stats.write_report(&args.output_dir)?;
+    }
 
     println!("Writing output to {:?}...", args.output_dir);
     let result: PipelineResult = write_jsonl_output(
         session_results,
         &args.output_dir,
-        args.val_ratio,
+        &config,
         system_prompt,
     )?;
 
+    let contamination_report = contamination.map(|filter| filter.report());
+    if let Some(report) = &contamination_report {
+        std::fs::write(
+            args.output_dir.join("contamination.json"),
+            serde_json::to_string_pretty(report)?,
+        )?;
+    }
+
     let metadata_path = args.output_dir.join("metadata.json");
     let metadata = serde_json::json!({
         "config": {
             "csv_root": args.csv_root.to_string_lossy(),
             "output_dir": args.output_dir.to_string_lossy(),
             "tokenizer": args.tokenizer,
+            "tokenizer_revision": args.tokenizer_revision,
+            "tokenizer_local_only": tokenizer_local_only,
+            "compare_tokenizer": args.compare_tokenizer,
+            "hf_cache_dir": args.hf_cache_dir.as_ref().map(|p| p.to_string_lossy()),
             "max_tokens_per_conversation": args.max_tokens_per_conversation,
+            "auto_chunk_min_tokens": args.auto_chunk_min_tokens,
             "max_tokens_per_message": args.max_tokens_per_message,
             "min_conversation_messages": args.min_conversation_messages,
             "viewport_radius": args.viewport_radius,
+            "viewport_above": args.viewport_above,
+            "viewport_below": args.viewport_below,
+            "viewport_margin": args.viewport_margin,
+            "viewport_recenter_every": args.viewport_recenter_every,
+            "viewport_min_movements": args.viewport_min_movements,
+            "viewport_min_shift": args.viewport_min_shift,
             "coalesce_radius": args.coalesce_radius,
             "val_ratio": args.val_ratio,
+            "deferred_tokenization": args.deferred_tokenization,
+            "session_timeout_secs": args.session_timeout_secs,
+            "untracked_file_policy": args.untracked_file_policy.as_str(),
+            "resync_on_offset_drift": args.resync_on_offset_drift,
+            "verify_fraction": args.verify_fraction,
+            "validate_structure": args.validate_structure.as_str(),
+            "sort_by_sequence": !args.no_sort_by_sequence,
+            "drop_duplicate_rows": args.drop_duplicate_rows,
+            "ignored_event_types": args.ignored_event_types,
+            "stats_report": args.stats_report,
+            "warning_samples": args.warning_samples,
+            "warnings_report": args.warnings_report.as_ref().map(|p| p.to_string_lossy()),
+            "idle_gap_secs": args.idle_gap_secs,
+            "output_format": args.output_format.as_str(),
+            "output_layout": args.output_layout.as_str(),
+            "prompt_context_tokens": args.prompt_context_tokens,
+            "mask_roles": args.mask_roles,
+            "mask_recaptures": args.mask_recaptures,
+            "max_recapture_lines": args.max_recapture_lines,
+            "path_mappings": args
+                .path_mappings
+                .iter()
+                .map(|m| format!("{}={}", m.from, m.to))
+                .collect::<Vec<_>>(),
+            "normalize_paths": args.normalize_paths,
+            "case_insensitive_paths": args.case_insensitive_paths,
+            "symlinks": args.symlinks.iter().map(|m| format!("{}={}", m.from, m.to)).collect::<Vec<_>>(),
+            "companion_files": args.companion_files.iter().map(CompanionPattern::as_str).collect::<Vec<_>>(),
+            "ignored_paths": args.ignored_paths.iter().map(IgnorePattern::as_str).collect::<Vec<_>>(),
+            "ignore_vendored_paths": args.ignore_vendored_paths,
+            "platform": args.platform.as_str(),
+            "windows_sessions": args.windows_sessions.as_str(),
+            "whitespace_edit_policy": args.whitespace_edit_policy.as_str(),
+            "multiline_commands": args.multiline_commands.as_str(),
+            "merge_commands_within_secs": args.merge_commands_within_secs,
+            "reassociate_late_output": args.reassociate_late_output,
+            "end_of_task_marker": args.end_of_task_marker,
+            "checkpoint_every_edits": args.checkpoint_every_edits,
+            "split_at": args.split_at.iter().map(|b| b.as_str().replace('_', "-")).collect::<Vec<_>>(),
+            "split_idle_gap_secs": args.split_idle_gap_secs,
+            "max_terminal_buffer_bytes": args.max_terminal_buffer_bytes,
+            "max_terminal_buffer_secs": args.max_terminal_buffer_secs,
+            "large_file_line_threshold": args.large_file_line_threshold,
+            "max_tracked_files": args.max_tracked_files,
+            "compact": args.compact,
+            "emit_selection_ranges": args.emit_selection_ranges,
+            "center_selections": args.center_selections,
+            "annotate_selections": args.annotate_selections,
+            "diff_stale_snapshots": args.diff_stale_snapshots,
+            "skip_refocus_views": args.skip_refocus_views,
+            "flush_on_cross_file_edit": args.flush_on_cross_file_edit,
+            "dedupe_command_writes": args.dedupe_command_writes,
+            "verify_on_save": args.verify_on_save,
+            "view_commands": args.view_commands.as_ref().map(|mix| {
+                mix.weights
+                    .iter()
+                    .map(|(command, weight)| format!("{}={}", command.as_str(), weight))
+                    .collect::<Vec<_>>()
+            }),
+            "view_command_seed": args.view_command_seed,
+            "session_manifest": args.session_manifest.as_ref().map(|p| p.to_string_lossy()),
+            "stitch_by": args.stitch_by.iter().map(StitchKey::to_string).collect::<Vec<_>>(),
+            "stitch_max_gap_secs": args.stitch_max_gap_secs,
+            "stitch_start_tag": args.stitch_start_tag,
+            "hash_identity": args.hash_identity_salt.is_some(),
+            "required_tags": args
+                .required_tags
+                .iter()
+                .map(|t| format!("{}={}", t.key, t.value))
+                .collect::<Vec<_>>(),
+            "contamination_corpora": args
+                .contamination_corpora
+                .iter()
+                .map(|p| p.to_string_lossy())
+                .collect::<Vec<_>>(),
+            "contamination_ngram": args.contamination_ngram,
+            "contamination_min_matches": args.contamination_min_matches,
+            "drop_contaminated": args.drop_contaminated,
+            "exclude_ids": args.exclude_ids.as_ref().map(|p| p.to_string_lossy()),
+            "drop_destructive_commands": args.drop_destructive_commands,
+            "drop_command_classes": args.drop_command_classes,
+            "classify": args.classify,
+            "pack_to_tokens": args.pack_to_tokens,
+            "blob_min_bytes": args.blob_min_bytes,
+            "pack_separator": args.pack_separator,
+            "pack_system_prompt": args.pack_system_prompt.as_str(),
+            "emit_metrics": args.emit_metrics,
+            "emit_timing": args.emit_timing,
+            "timing_granularity_ms": args.timing_granularity_ms,
+            "timing_noise_ms": args.timing_noise_ms,
+            "emit_message_kinds": args.emit_message_kinds,
+            "emit_loss_spans": args.emit_loss_spans,
+            "dump_events": args.dump_events.as_ref().map(|p| p.to_string_lossy()),
+            "augment_variants": args.augment_variants,
+            "augment_seed": args.augment_seed,
+            "augment_rename_paths": args.augment_rename_paths,
+            "augment_viewport_jitter": args.augment_viewport_jitter,
+            "augment_system_prompts": args.augment_system_prompts.as_ref().map(|p| p.to_string_lossy()),
+            "sample_rate": args.sample_rate,
+            "sample_seed": args.sample_seed,
+            "max_conversations": args.max_conversations,
+            "max_total_tokens": args.max_total_tokens,
+            "push_to_hub": args.push_to_hub,
+            "dataset_card_template": args.dataset_card_template.as_ref().map(|p| p.to_string_lossy()),
+            "system_prompt": args.system_prompt,
         },
         "counts": {
             "total_sessions": result.total_sessions,
             "total_conversations": result.total_conversations,
             "train_conversations": result.train_conversations,
             "val_conversations": result.val_conversations,
+            "total_records": result.total_records,
+            "augmented_conversations": augmented_conversations,
         },
         "stats": {
             "total_messages": result.total_messages,
             "total_tokens": result.total_tokens,
+            "truncated_messages": result.truncated_messages,
+            "reordered_rows": result.reordered_rows,
+            "duplicate_rows": result.duplicate_rows,
+            "ignored_events": result.ignored_events,
             "avg_messages_per_conversation": if result.total_conversations > 0 {
                 result.total_messages as f64 / result.total_conversations as f64
             } else {
@@ -184,21 +1220,165 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 0.0
             },
         },
-        "files": {
-            "train_path": args.output_dir.join("training.jsonl").to_string_lossy(),
-            "val_path": args.output_dir.join("validation.jsonl").to_string_lossy(),
+        "dataset_stats": dataset_stats,
+        "packing": result.packing,
+        "blobs": result.blobs,
+        "contamination": contamination_report.as_ref().map(|report| serde_json::json!({
+            "checked_conversations": report.checked_conversations,
+            "flagged_conversations": report.flagged.len(),
+            "dropped": report.dropped,
+            "report_path": args.output_dir.join("contamination.json").to_string_lossy(),
+        })),
+        "verification": verification,
+        "warnings": config.warnings.counts(),
+        "command_line": redact_command_line(command_line),
+        "schema_version": SCHEMA_VERSION,
+        "serializer_version": RecordVersion::current().serializer_version,
+        "reproduced_from": reproduced_from.map(|p| p.to_string_lossy()),
+        "files": match args.output_layout {
+            OutputLayout::Monolithic => serde_json::json!({
+                "train_path": args.output_dir.join("training.jsonl").to_string_lossy(),
+                "val_path": args.output_dir.join("validation.jsonl").to_string_lossy(),
+            }),
+            OutputLayout::PerSession => serde_json::json!({
+                "sessions_dir": args.output_dir.join("sessions").to_string_lossy(),
+                "index_path": args.output_dir.join("index.jsonl").to_string_lossy(),
+            }),
         },
     });
     std::fs::write(&metadata_path, serde_json::to_string_pretty(&metadata)?)?;
+    card::write_dataset_card(args.dataset_card_template.as_deref(), &metadata, &args.output_dir)?;
+
+    let hub_url = match &args.push_to_hub {
+        Some(repo) => {
+            println!("Pushing to HuggingFace Hub dataset {}...", repo);
+            Some(hub::push_to_hub(repo, &args.output_dir, &metadata)?)
+        }
+        None => None,
+    };
 
     println!("\n[summary]");
     println!("  Total sessions processed: {}", result.total_sessions);
+    if dataset_stats.windows_sessions > 0 {
+        println!(
+            "  Windows sessions: {} ({:?})",
+            dataset_stats.windows_sessions, args.windows_sessions
+        );
+    }
     println!("  Train conversations: {}", result.train_conversations);
     println!("  Val conversations: {}", result.val_conversations);
+    if args.output_format == OutputFormat::PromptCompletion {
+        println!("  Prompt/completion pairs: {}", result.total_records);
+    }
+    if let Some(report) = &contamination_report {
+        println!(
+            "  Contaminated conversations: {} of {} ({})",
+            report.flagged.len(),
+            report.checked_conversations,
+            if report.dropped { "dropped" } else { "flagged only" }
+        );
+    }
+    if augmented_conversations > 0 {
+        println!("  Augmented conversations: {}", augmented_conversations);
+    }
+    if let Some(packing) = &result.packing {
+        println!(
+            "  Packed records: {} ({} conversations, {:.1}% filled, {} over target)",
+            packing.packed_records,
+            packing.packed_conversations,
+            packing.fill_ratio * 100.0,
+            packing.oversized_conversations
+        );
+    }
+    if let Some(blobs) = &result.blobs {
+        println!(
+            "  Blob references: {} to {} blobs ({} of {} bytes stored)",
+            blobs.references, blobs.blobs, blobs.stored_bytes, blobs.referenced_bytes
+        );
+    }
     println!("  Total messages: {}", result.total_messages);
     println!("  Total tokens: {}", result.total_tokens);
-    println!("  Output: {:?}/{{training,validation}}.jsonl", args.output_dir);
+    let drops = &dataset_stats.drops;
+    println!(
+        "  Discarded conversations: {} (too short: {}, missing role: {}; {} messages)",
+        drops.discarded_conversations(),
+        drops.too_short_conversations,
+        drops.missing_role_conversations,
+        drops.discarded_messages
+    );
+    println!(
+        "  Truncated messages: {} ({:.2}%)",
+        dataset_stats.truncated_messages,
+        dataset_stats.truncation_rate * 100.0
+    );
+    if let Some(comparison) = &dataset_stats.tokenizer_comparison {
+        println!(
+            "  Tokens with {}: {} ({:.3}x; {} conversations, {} messages over budget)",
+            comparison.tokenizer,
+            comparison.tokens,
+            comparison.token_ratio,
+            comparison.conversations_over_budget,
+            comparison.messages_over_budget
+        );
+    }
+    if args.compact {
+        println!(
+            "  Compacted messages: {} ({} tokens, {:.2}% saved)",
+            dataset_stats.compacted_messages,
+            dataset_stats.compacted_tokens,
+            dataset_stats.compaction_savings * 100.0
+        );
+    }
+    for (reason, count) in &drops.filtered_conversations {
+        println!("  Filtered conversations ({}): {}", reason, count);
+    }
+    if drops.sampled_out_conversations > 0 {
+        println!("  Sampled out conversations: {}", drops.sampled_out_conversations);
+    }
+    if drops.unsupported_vim_commands > 0 {
+        println!("  Unsupported Vim commands: {}", drops.unsupported_vim_commands);
+    }
+    if drops.ignored_path_events > 0 {
+        println!("  Events of ignored paths: {}", drops.ignored_path_events);
+    }
+    if drops.untracked_edits + drops.resync_edits > 0 {
+        println!(
+            "  Dropped edits: {} untracked, {} awaiting resync",
+            drops.untracked_edits, drops.resync_edits
+        );
+    }
+    for (language, lang_stats) in &dataset_stats.languages {
+        println!(
+            "  Language {}: {} conversations ({:.1}%), {} tokens",
+            language,
+            lang_stats.conversations,
+            lang_stats.conversation_share * 100.0,
+            lang_stats.tokens
+        );
+    }
+    if !dataset_stats.structure_violations.is_empty() {
+        let violations: usize = dataset_stats.structure_violations.values().sum();
+        println!("  Structural violations: {} ({:?})", violations, dataset_stats.structure_violations);
+    }
+    if let Some(report) = &verification {
+        println!(
+            "  Replay fidelity: {:.3} ({}/{} verified conversations)",
+            report.fidelity, report.passed_conversations, report.verified_conversations
+        );
+    }
+    match args.output_layout {
+        OutputLayout::Monolithic => {
+            println!("  Output: {:?}/{{training,validation}}.jsonl", args.output_dir)
+        }
+        OutputLayout::PerSession => {
+            println!("  Output: {:?}/{{sessions/,index.jsonl}}", args.output_dir)
+        }
+    }
     println!("  Metadata: {:?}", metadata_path);
+    println!("  Dataset card: {:?}", args.output_dir.join(card::DATASET_CARD));
+    if let Some(url) = hub_url {
+        println!("  Hub dataset: {}", url);
+    }
 
-    Ok(())
+    Ok(metadata)
 }
@@ -0,0 +1,30 @@
+//! `materialize`: expand the blob references of a dataset.
+
+use std::path::PathBuf;
+
+use clap::Parser;
+
+use crowd_pilot_serializer_core::{materialize_dataset, BLOBS_DIR};
+
+/// Copy a dataset written with `--blob-min-bytes`, replacing every blob
+/// reference with the blob's content.
+#[derive(Parser, Debug)]
+#[command(name = "crowd-pilot-serialize materialize")]
+pub struct MaterializeArgs {
+    /// Output directory of the run that wrote the blob store
+    dataset: PathBuf,
+
+    /// Directory to write the expanded dataset to
+    #[arg(long)]
+    output_dir: PathBuf,
+}
+
+pub fn materialize(args: MaterializeArgs) -> Result<(), Box<dyn std::error::Error>> {
+    if !args.dataset.join(BLOBS_DIR).is_dir() {
+        println!("Note: {:?} has no {} directory; copying it unchanged", args.dataset, BLOBS_DIR);
+    }
+    println!("Materializing {:?} into {:?}...", args.dataset, args.output_dir);
+    let replaced = materialize_dataset(&args.dataset, &args.output_dir)?;
+    println!("  Expanded {} blob references", replaced);
+    Ok(())
+}
@@ -0,0 +1,181 @@
+//! Seeded augmentation: extra variants of each session's conversations.
+//!
+//! A variant re-serializes the session with the project root moved to a
+//! generated path, the viewport radius jittered, and a system prompt sampled
+//! from a list of paraphrases. Each choice is a hash of the seed, the session
+//! path and the variant number, so reruns produce the same variants.
+
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::SerializerError;
+use crate::helpers::{stable_hash, unit_interval_hash};
+use crate::paths::{is_windows_path, PathMapping};
+
+/// Configuration of the augmentation stage.
+#[derive(Debug, Clone, Default)]
+pub struct AugmentationConfig {
+    /// Variants per session, in addition to the original.
+    pub variants: usize,
+    pub seed: u64,
+    /// Move the session's project root to a generated path.
+    pub rename_paths: bool,
+    /// Change the viewport lines above and below the cursor by up to this
+    /// many lines.
+    pub viewport_jitter: usize,
+    /// System prompt paraphrases; each variant uses one of them.
+    pub system_prompts: Vec<String>,
+}
+
+/// How a variant conversation was produced.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AugmentationInfo {
+    /// 1-based variant number.
+    pub variant: usize,
+    /// Generated project root replacing the recorded one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub renamed_root: Option<String>,
+    pub viewport_lines_above: usize,
+    pub viewport_lines_below: usize,
+    /// Index into `AugmentationConfig::system_prompts`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system_prompt_index: Option<usize>,
+    /// The sampled system prompt, written as the record's `system`.
+    #[serde(skip)]
+    pub system_prompt: Option<String>,
+}
+
+const USERS: &[&str] = &["alex", "sam", "kim", "maria", "chen", "jo", "priya", "dev", "lena", "omar"];
+const PARENTS: &[&str] = &["/home/{user}", "/home/{user}/src", "/Users/{user}/code", "/workspace", "/srv/{user}"];
+
+/// Load system prompt paraphrases from a JSON array of strings.
+pub fn load_system_prompts(path: &Path) -> Result<Vec<String>, SerializerError> {
+    let prompts: Vec<String> = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+    if prompts.is_empty() {
+        return Err(SerializerError::InvalidConfig(format!(
+            "system prompt file {:?} contains no prompts",
+            path
+        )));
+    }
+    Ok(prompts)
+}
+
+/// Deepest directory containing every absolute, non-Windows `File` value of
+/// a session, if it is below the filesystem root.
+pub fn session_root(csv_path: &Path) -> Result<Option<String>, SerializerError> {
+    let mut reader = csv::Reader::from_path(csv_path)?;
+    let Some(file_idx) = reader.headers()?.iter().position(|h| h.trim_start_matches('\u{feff}') == "File") else {
+        return Ok(None);
+    };
+    let mut root: Option<Vec<String>> = None;
+    for record in reader.records() {
+        let record = record?;
+        let file = record.get(file_idx).unwrap_or("");
+        if !file.starts_with('/') || is_windows_path(file) {
+            continue;
+        }
+        let mut dirs: Vec<&str> = file.split('/').filter(|c| !c.is_empty()).collect();
+        dirs.pop();
+        root = Some(match root {
+            None => dirs.iter().map(|d| d.to_string()).collect(),
+            Some(mut root) => {
+                let common = root.iter().zip(&dirs).take_while(|(a, b)| a == *b).count();
+                root.truncate(common);
+                root
+            }
+        });
+    }
+    Ok(root.filter(|r| !r.is_empty()).map(|r| format!("/{}", r.join("/"))))
+}
+
+impl AugmentationConfig {
+    /// Seeded value in `[0, 1)` for one choice of a variant.
+    fn draw(&self, session: &str, variant: usize, choice: &str) -> f64 {
+        unit_interval_hash(&format!("{}#{}#{}#{}", self.seed, session, variant, choice))
+    }
+
+    fn pick<'a>(&self, items: &'a [&'a str], session: &str, variant: usize, choice: &str) -> &'a str {
+        items[(self.draw(session, variant, choice) * items.len() as f64) as usize]
+    }
+
+    /// Generated replacement for `root`: a new parent directory, keeping the
+    /// root's last component with a short suffix.
+    fn renamed_root(&self, root: &str, session: &str, variant: usize) -> String {
+        let user = self.pick(USERS, session, variant, "user");
+        let parent = self.pick(PARENTS, session, variant, "parent").replace("{user}", user);
+        let name = root.rsplit('/').next().unwrap_or("project");
+        let suffix = stable_hash(format!("{}#{}#{}", self.seed, session, variant).as_bytes()) % 100;
+        format!("{}/{}-{}", parent, name, suffix)
+    }
+
+    /// Describe variant `variant` of a session. `lines_above`/`lines_below`
+    /// are the configured viewport; the returned mapping, if any, goes first
+    /// in the path mappings.
+    pub fn plan(
+        &self,
+        session: &str,
+        root: Option<&str>,
+        lines_above: usize,
+        lines_below: usize,
+        variant: usize,
+    ) -> (AugmentationInfo, Option<PathMapping>) {
+        let jitter = |lines: usize, choice: &str| {
+            let span = 2 * self.viewport_jitter + 1;
+            let offset = (self.draw(session, variant, choice) * span as f64) as usize;
+            (lines + offset).saturating_sub(self.viewport_jitter).max(1)
+        };
+        let mapping = root.filter(|_| self.rename_paths).map(|root| PathMapping {
+            from: root.to_string(),
+            to: self.renamed_root(root, session, variant),
+        });
+        let system_prompt_index = (!self.system_prompts.is_empty()).then(|| {
+            (self.draw(session, variant, "system") * self.system_prompts.len() as f64) as usize
+        });
+        let info = AugmentationInfo {
+            variant,
+            renamed_root: mapping.as_ref().map(|m| m.to.clone()),
+            viewport_lines_above: jitter(lines_above, "above"),
+            viewport_lines_below: jitter(lines_below, "below"),
+            system_prompt_index,
+            system_prompt: system_prompt_index.map(|i| self.system_prompts[i].clone()),
+        };
+        (info, mapping)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plan_variant() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let csv_path = dir.path().join("s.csv");
+        std::fs::write(
+            &csv_path,
+            "Sequence,Time,File,RangeOffset,RangeLength,Text,Language,Type\n\
+             1,0,/home/u/proj/src/a.rs,0,0,x,rust,tab\n\
+             2,0,/home/u/proj/README.md,0,0,y,markdown,tab\n\
+             3,0,TERMINAL,0,0,ls,,terminal_command\n",
+        )
+        .unwrap();
+        let root = session_root(&csv_path).unwrap();
+        assert_eq!(root.as_deref(), Some("/home/u/proj"));
+
+        let config = AugmentationConfig {
+            variants: 2,
+            seed: 7,
+            rename_paths: true,
+            viewport_jitter: 3,
+            system_prompts: vec!["a".to_string(), "b".to_string()],
+        };
+        let (info, mapping) = config.plan("s.csv", root.as_deref(), 10, 10, 1);
+        assert_eq!(config.plan("s.csv", root.as_deref(), 10, 10, 1), (info.clone(), mapping.clone()));
+        let mapping = mapping.unwrap();
+        assert_eq!(mapping.from, "/home/u/proj");
+        assert!(mapping.to.contains("/proj-"));
+        assert!((7..=13).contains(&info.viewport_lines_above));
+        assert!(info.system_prompt.is_some());
+    }
+}
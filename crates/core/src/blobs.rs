@@ -0,0 +1,189 @@
+//! Content-addressed storage of large message values.
+//!
+//! Identical files (licenses, lockfiles, generated code) are captured over
+//! and over across a corpus. With a blob store, every user message of at
+//! least `min_bytes` is written once to `blobs/<aa>/<sha256>` in the output
+//! directory, and the record holds only a `crowd-pilot-blob:<sha256>`
+//! reference. `materialize_dataset` expands the references again.
+
+use std::collections::BTreeSet;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::conversation::ConversationMessage;
+use crate::error::SerializerError;
+
+/// Directory of the blobs, inside the output directory.
+pub const BLOBS_DIR: &str = "blobs";
+
+/// Prefix of a message value referencing a blob.
+const BLOB_REF_PREFIX: &str = "crowd-pilot-blob:";
+
+/// Counts of a run writing to a blob store.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct BlobStats {
+    /// Messages replaced by a reference.
+    pub references: usize,
+    /// Distinct blobs written.
+    pub blobs: usize,
+    /// Bytes of the replaced message values.
+    pub referenced_bytes: usize,
+    /// Bytes of the distinct blobs.
+    pub stored_bytes: usize,
+}
+
+/// Writer of the blobs of one output directory.
+#[derive(Debug)]
+pub struct BlobStore {
+    dir: PathBuf,
+    min_bytes: usize,
+    written: BTreeSet<String>,
+    stats: BlobStats,
+}
+
+/// Path of the blob with `hash` under `dir`.
+fn blob_path(dir: &Path, hash: &str) -> PathBuf {
+    dir.join(&hash[..2]).join(hash)
+}
+
+impl BlobStore {
+    /// Store user messages of at least `min_bytes` under `output_dir/blobs`.
+    pub fn new(output_dir: &Path, min_bytes: usize) -> Self {
+        Self {
+            dir: output_dir.join(BLOBS_DIR),
+            min_bytes,
+            written: BTreeSet::new(),
+            stats: BlobStats::default(),
+        }
+    }
+
+    /// Replace the large user messages of a conversation by references,
+    /// writing blobs not stored yet.
+    pub fn externalize(&mut self, messages: &mut [ConversationMessage]) -> Result<(), SerializerError> {
+        for message in messages {
+            if message.from != "User" || message.value.len() < self.min_bytes {
+                continue;
+            }
+            let hash = format!("{:x}", Sha256::digest(message.value.as_bytes()));
+            if self.written.insert(hash.clone()) {
+                let path = blob_path(&self.dir, &hash);
+                std::fs::create_dir_all(path.parent().expect("blob paths have a parent"))?;
+                std::fs::write(&path, &message.value)?;
+                self.stats.blobs += 1;
+                self.stats.stored_bytes += message.value.len();
+            }
+            self.stats.references += 1;
+            self.stats.referenced_bytes += message.value.len();
+            message.value = format!("{}{}", BLOB_REF_PREFIX, hash);
+        }
+        Ok(())
+    }
+
+    pub fn stats(&self) -> &BlobStats {
+        &self.stats
+    }
+}
+
+/// Replace the blob references in a JSONL line of a dataset with the JSON
+/// strings of the blobs in `blobs_dir`, keeping the rest of the line byte for
+/// byte. Returns the line and the number of references replaced.
+pub fn materialize_line(line: &str, blobs_dir: &Path) -> Result<(String, usize), SerializerError> {
+    let quoted_prefix = format!("\"{}", BLOB_REF_PREFIX);
+    let mut out = String::with_capacity(line.len());
+    let mut replaced = 0;
+    let mut rest = line;
+    while let Some(start) = rest.find(&quoted_prefix) {
+        let after = &rest[start + quoted_prefix.len()..];
+        let hash = after.get(..64).filter(|h| h.bytes().all(|b| b.is_ascii_hexdigit()));
+        let Some(hash) = hash.filter(|_| after[64..].starts_with('"')) else {
+            out.push_str(&rest[..start + quoted_prefix.len()]);
+            rest = after;
+            continue;
+        };
+        let path = blob_path(blobs_dir, hash);
+        let value = std::fs::read_to_string(&path).map_err(|e| {
+            SerializerError::InvalidConfig(format!("cannot read blob {:?}: {}", path, e))
+        })?;
+        out.push_str(&rest[..start]);
+        out.push_str(&serde_json::to_string(&value)?);
+        replaced += 1;
+        rest = &after[65..];
+    }
+    out.push_str(rest);
+    Ok((out, replaced))
+}
+
+/// Copy the dataset in `input_dir` to `output_dir` with every blob reference
+/// of its JSONL files expanded, leaving out the blob store. Returns the
+/// number of references expanded.
+pub fn materialize_dataset(input_dir: &Path, output_dir: &Path) -> Result<usize, SerializerError> {
+    let blobs_dir = input_dir.join(BLOBS_DIR);
+    let mut replaced = 0;
+    for entry in walkdir::WalkDir::new(input_dir).into_iter().filter_entry(|e| e.path() != blobs_dir) {
+        let entry = entry.map_err(|e| SerializerError::Io(e.into()))?;
+        let relative = entry.path().strip_prefix(input_dir).expect("walked paths are under the root");
+        let target = output_dir.join(relative);
+        if entry.file_type().is_dir() {
+            std::fs::create_dir_all(&target)?;
+            continue;
+        }
+        if entry.path().extension().is_none_or(|ext| ext != "jsonl") {
+            std::fs::copy(entry.path(), &target)?;
+            continue;
+        }
+        let reader = BufReader::new(std::fs::File::open(entry.path())?);
+        let mut writer = BufWriter::new(std::fs::File::create(&target)?);
+        for line in reader.lines() {
+            let (line, count) = materialize_line(&line?, &blobs_dir)?;
+            replaced += count;
+            writeln!(writer, "{}", line)?;
+        }
+        writer.flush()?;
+    }
+    Ok(replaced)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_blob_store_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let dataset = dir.path().join("dataset");
+        let license = "Permission is hereby granted, free of charge, ...\n".repeat(10);
+        let mut messages = vec![
+            ConversationMessage::assistant("```bash\ncat -n LICENSE\n```"),
+            ConversationMessage::user(license.clone()),
+            ConversationMessage::user("short"),
+        ];
+        let mut store = BlobStore::new(&dataset, 100);
+        store.externalize(&mut messages).unwrap();
+        // Captured again in another conversation
+        store.externalize(&mut [ConversationMessage::user(license.clone())]).unwrap();
+        assert_eq!(store.stats().references, 2);
+        assert_eq!(store.stats().blobs, 1);
+        assert!(messages[1].value.starts_with(BLOB_REF_PREFIX));
+        assert_eq!(messages[2].value, "short");
+
+        let record = serde_json::json!({
+            "id": "a",
+            "conversations": messages
+                .iter()
+                .map(|m| serde_json::json!({ "from": m.from, "value": m.value }))
+                .collect::<Vec<_>>(),
+        });
+        std::fs::write(dataset.join("training.jsonl"), format!("{}\n", record)).unwrap();
+
+        let output = dir.path().join("materialized");
+        assert_eq!(materialize_dataset(&dataset, &output).unwrap(), 1);
+        assert!(!output.join(BLOBS_DIR).exists());
+        let line = std::fs::read_to_string(output.join("training.jsonl")).unwrap();
+        let record: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(record["conversations"][1]["value"], license.as_str());
+        assert_eq!(record["conversations"][2]["value"], "short");
+    }
+}
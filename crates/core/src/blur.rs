@@ -0,0 +1,155 @@
+//! Blurring of timing metadata before a dataset is released.
+//!
+//! Exact timestamps and keystroke timings can identify contributors. With a
+//! `TimingBlur`, every exported time value of a session (message timing,
+//! session start, end and active time) gets deterministic noise and is
+//! rounded to a granularity, before any output format sees it.
+
+use crate::error::SerializerError;
+use crate::helpers::unit_interval_hash;
+use crate::pipeline::SessionResult;
+
+/// Noise and rounding applied to the timing values of a session.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct TimingBlur {
+    /// Values are rounded to multiples of this; 0 keeps milliseconds.
+    pub granularity_ms: u64,
+    /// Uniform noise in `[-noise_ms, noise_ms]`, added before rounding.
+    pub noise_ms: u64,
+    /// Secret mixed into the noise; the same seed reproduces the same values.
+    /// `Pipeline::run` rejects noise without a seed.
+    pub seed: String,
+}
+
+impl TimingBlur {
+    /// Reject noise without a seed, which anyone could recompute.
+    pub fn validate(&self) -> Result<(), SerializerError> {
+        if self.noise_ms > 0 && self.seed.is_empty() {
+            return Err(SerializerError::InvalidConfig(
+                "timing noise needs a secret seed; without one it can be recomputed".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Blur `value_ms`, with noise drawn from `key`.
+    fn blur(&self, value_ms: i64, key: &str) -> i64 {
+        let mut value = value_ms;
+        if self.noise_ms > 0 {
+            let unit = unit_interval_hash(&format!("{}\0{}", self.seed, key));
+            value += ((unit * 2.0 - 1.0) * self.noise_ms as f64).round() as i64;
+        }
+        if self.granularity_ms > 1 {
+            let granularity = self.granularity_ms as i64;
+            value = (value + granularity / 2).div_euclid(granularity) * granularity;
+        }
+        value
+    }
+
+    fn blur_duration(&self, value_ms: u64, key: &str) -> u64 {
+        self.blur(value_ms as i64, key).max(0) as u64
+    }
+
+    /// Blur the timing values of `session` in place.
+    pub fn apply(&self, session: &mut SessionResult) {
+        let source = session.source_path.clone();
+        let stats = &mut session.stats;
+        stats.start_time_ms = stats.start_time_ms.map(|t| self.blur(t, &format!("{}\0start", source)));
+        stats.end_time_ms = stats.end_time_ms.map(|t| self.blur(t, &format!("{}\0end", source)));
+        if let (Some(start), Some(end)) = (stats.start_time_ms, stats.end_time_ms) {
+            stats.end_time_ms = Some(end.max(start));
+        }
+        stats.active_time_ms = self.blur_duration(stats.active_time_ms, &format!("{}\0active", source));
+        if let Some(duration) = stats.duration_ms() {
+            stats.active_time_ms = stats.active_time_ms.min(duration);
+        }
+
+        for conv in &mut session.conversations {
+            for (i, message) in conv.messages.iter_mut().enumerate() {
+                let Some(timing) = message.timing.as_mut() else {
+                    continue;
+                };
+                let key = |field: &str| format!("{}\0{}\0{}\0{}", source, conv.id, i, field);
+                for (field, value) in [
+                    ("typing", &mut timing.typing_ms),
+                    ("max_gap", &mut timing.max_keystroke_gap_ms),
+                    ("pause", &mut timing.pause_before_ms),
+                ] {
+                    *value = value.map(|v| self.blur_duration(v, &key(field)));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conversation::{ConversationMessage, FinalizedConversation, MessageTiming};
+    use crate::pipeline::SessionStats;
+
+    #[test]
+    fn test_timing_blur() {
+        let blur = TimingBlur {
+            granularity_ms: 1000,
+            noise_ms: 400,
+            seed: "secret".to_string(),
+        };
+        let mut message = ConversationMessage::assistant("```bash\nls\n```");
+        message.timing = Some(MessageTiming {
+            typing_ms: Some(12_345),
+            keystrokes: Some(7),
+            max_keystroke_gap_ms: None,
+            pause_before_ms: Some(50),
+        });
+        let mut session = SessionResult {
+            conversations: vec![FinalizedConversation {
+                messages: vec![message],
+                ..Default::default()
+            }],
+            source_path: "a.csv".to_string(),
+            tags: Default::default(),
+            stats: SessionStats::default(),
+        };
+        session.stats.start_time_ms = Some(1_234);
+        session.stats.end_time_ms = Some(61_234);
+        session.stats.active_time_ms = 30_500;
+        blur.apply(&mut session);
+
+        let stats = &session.stats;
+        for value in [stats.start_time_ms.unwrap(), stats.end_time_ms.unwrap(), stats.active_time_ms as i64] {
+            assert_eq!(value % 1000, 0);
+        }
+        assert!((59_000..=61_000).contains(&stats.duration_ms().unwrap()));
+        let timing = session.conversations[0].messages[0].timing.unwrap();
+        assert!(matches!(timing.typing_ms, Some(12_000) | Some(13_000)));
+        assert_eq!(timing.pause_before_ms, Some(0));
+        assert_eq!(timing.keystrokes, Some(7));
+        assert_eq!(timing.max_keystroke_gap_ms, None);
+    }
+
+    #[test]
+    fn test_timing_blur_seeds() {
+        let blurred = |seed: &str| {
+            let blur = TimingBlur {
+                granularity_ms: 0,
+                noise_ms: 10_000,
+                seed: seed.to_string(),
+            };
+            (0..8).map(|i| blur.blur(100_000, &format!("a.csv\0{}", i))).collect::<Vec<_>>()
+        };
+        assert_eq!(blurred("secret"), blurred("secret"));
+        assert_ne!(blurred("secret"), blurred("other secret"));
+
+        let unseeded = TimingBlur {
+            noise_ms: 1000,
+            ..Default::default()
+        };
+        assert!(matches!(unseeded.validate(), Err(SerializerError::InvalidConfig(_))));
+        let rounded_only = TimingBlur {
+            granularity_ms: 1000,
+            ..Default::default()
+        };
+        assert!(rounded_only.validate().is_ok());
+    }
+}
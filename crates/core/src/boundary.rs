@@ -0,0 +1,202 @@
+//! Heuristic task boundaries for splitting conversations.
+//!
+//! Instead of only splitting at token limits, a conversation can end where a
+//! task most likely ended: when a test suite passes after failing, after a
+//! `git commit`, or before a long idle gap. `TaskBoundaryDetector` watches
+//! the events and tells the state manager where to split.
+
+use std::sync::LazyLock;
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::error::SerializerError;
+use crate::stats::command_template;
+
+static TESTS_FAILED_RE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?m)test result: FAILED|^FAILED\b|^FAIL\b|\b[1-9]\d* (?:failed|failing|failures?|errors?)\b")
+        .unwrap()
+});
+static TESTS_PASSED_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?m)test result: ok\b|\b\d+ passed\b|\b\d+ passing\b|^OK\b|^PASS\b|^ok\s").unwrap());
+
+/// Programs and subcommands that run a test suite.
+const TEST_WORDS: &[&str] = &["test", "tests", "pytest", "jest", "vitest", "rspec", "ctest", "nextest", "unittest"];
+
+/// Why a conversation ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskBoundary {
+    /// A test run passed after a failing run in the same conversation.
+    TestsPassed,
+    /// A `git commit` command ran.
+    GitCommit,
+    /// No event arrived for longer than the idle gap.
+    IdleGap,
+    /// The conversation reached `max_tokens_per_conversation`.
+    TokenLimit,
+}
+
+impl TaskBoundary {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::TestsPassed => "tests_passed",
+            Self::GitCommit => "git_commit",
+            Self::IdleGap => "idle_gap",
+            Self::TokenLimit => "token_limit",
+        }
+    }
+}
+
+impl std::str::FromStr for TaskBoundary {
+    type Err = SerializerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tests-passed" => Ok(Self::TestsPassed),
+            "git-commit" => Ok(Self::GitCommit),
+            "idle-gap" => Ok(Self::IdleGap),
+            other => Err(SerializerError::InvalidConfig(format!(
+                "unknown task boundary '{}' (known: tests-passed, git-commit, idle-gap)",
+                other
+            ))),
+        }
+    }
+}
+
+/// Which task boundaries split conversations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct TaskBoundaryConfig {
+    pub tests_passed: bool,
+    pub git_commit: bool,
+    /// Split before an event arriving more than this long after the previous
+    /// one (see `set_event_time`).
+    pub idle_gap_ms: Option<u64>,
+}
+
+/// Whether `command` runs a test suite.
+pub(crate) fn is_test_command(command: &str) -> bool {
+    command
+        .lines()
+        .next()
+        .unwrap_or("")
+        .split_whitespace()
+        .any(|word| TEST_WORDS.contains(&word))
+}
+
+/// Whether test output reports a pass (`Some(true)`) or a failure.
+fn test_outcome(output: &str) -> Option<bool> {
+    if TESTS_FAILED_RE.is_match(output) {
+        Some(false)
+    } else if TESTS_PASSED_RE.is_match(output) {
+        Some(true)
+    } else {
+        None
+    }
+}
+
+/// State of the boundary heuristics within the current conversation.
+#[derive(Debug, Clone, Default)]
+pub struct TaskBoundaryDetector {
+    config: TaskBoundaryConfig,
+    last_time_ms: Option<i64>,
+    running_tests: bool,
+    tests_failed: bool,
+    /// Boundary found after a command, applied before the next event that
+    /// is not its output.
+    pending: Option<TaskBoundary>,
+}
+
+impl TaskBoundaryDetector {
+    pub fn new(config: TaskBoundaryConfig) -> Self {
+        Self {
+            config,
+            ..Default::default()
+        }
+    }
+
+    /// Boundary to split at before an event of `kind` (`IdeEvent::kind`)
+    /// happening at `time_ms`.
+    pub fn before_event(&mut self, kind: &str, time_ms: Option<i64>) -> Option<TaskBoundary> {
+        let idle = match (self.config.idle_gap_ms, self.last_time_ms, time_ms) {
+            (Some(gap), Some(last), Some(now)) => now - last > gap as i64,
+            _ => false,
+        };
+        if time_ms.is_some() {
+            self.last_time_ms = time_ms;
+        }
+        if idle {
+            self.pending = None;
+            return Some(TaskBoundary::IdleGap);
+        }
+        if kind == "terminal_output" {
+            return None;
+        }
+        self.pending.take()
+    }
+
+    /// Note a terminal command.
+    pub fn command(&mut self, command: &str) {
+        self.running_tests = is_test_command(command);
+        if self.config.git_commit && command_template(command) == "git commit" {
+            self.pending = Some(TaskBoundary::GitCommit);
+        }
+    }
+
+    /// Note terminal output of the last command.
+    pub fn output(&mut self, output: &str) {
+        if !self.running_tests || !self.config.tests_passed {
+            return;
+        }
+        match test_outcome(output) {
+            Some(false) => self.tests_failed = true,
+            Some(true) if self.tests_failed => {
+                self.tests_failed = false;
+                self.pending = Some(TaskBoundary::TestsPassed);
+            }
+            _ => {}
+        }
+    }
+
+    /// Forget the test history of a finished conversation.
+    pub fn conversation_finished(&mut self) {
+        self.tests_failed = false;
+        self.pending = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_task_boundary_detector() {
+        assert_eq!(test_outcome("test result: FAILED. 3 passed; 1 failed"), Some(false));
+        assert_eq!(test_outcome("test result: ok. 4 passed; 0 failed"), Some(true));
+        assert_eq!(test_outcome("===== 2 failed, 5 passed in 0.1s ====="), Some(false));
+        assert_eq!(test_outcome("Compiling foo v0.1.0"), None);
+
+        let mut detector = TaskBoundaryDetector::new(TaskBoundaryConfig {
+            tests_passed: true,
+            git_commit: true,
+            idle_gap_ms: Some(1000),
+        });
+        assert_eq!(detector.before_event("terminal_command", Some(0)), None);
+        detector.command("cargo test");
+        detector.output("test result: ok. 1 passed");
+        // A pass without an earlier failure is no boundary
+        assert_eq!(detector.before_event("terminal_command", Some(10)), None);
+        detector.command("cargo test");
+        detector.output("test result: FAILED. 0 passed; 1 failed");
+        assert_eq!(detector.before_event("terminal_command", Some(20)), None);
+        detector.command("cargo test");
+        assert_eq!(detector.before_event("terminal_output", Some(30)), None);
+        detector.output("test result: ok. 1 passed");
+        assert_eq!(detector.before_event("tab", Some(40)), Some(TaskBoundary::TestsPassed));
+
+        detector.command("git commit -m 'fix'");
+        assert_eq!(detector.before_event("terminal_output", Some(50)), None);
+        assert_eq!(detector.before_event("tab", Some(60)), Some(TaskBoundary::GitCommit));
+        assert_eq!(detector.before_event("tab", Some(5000)), Some(TaskBoundary::IdleGap));
+    }
+}
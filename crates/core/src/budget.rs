@@ -0,0 +1,159 @@
+//! Simulation of token limits on already tokenized conversations.
+//!
+//! Replays the state manager's limits on per-message token counts: a message
+//! over `max_tokens_per_message` is truncated to it, and a conversation is
+//! split before the message that would push it over
+//! `max_tokens_per_conversation`. Re-captures of files after a real split are
+//! not simulated, so split counts are a lower bound.
+
+use serde::Serialize;
+
+use crate::Tokenizer;
+
+/// Share added to a budget chosen from approximate token counts, since the
+/// exact tokenizer counts differently.
+const AUTO_CHUNK_HEADROOM: f64 = 0.1;
+
+/// Hypothetical token limits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BudgetLimits {
+    pub max_tokens_per_conversation: usize,
+    pub max_tokens_per_message: usize,
+}
+
+/// Outcome of a set of conversations under some limits.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct BudgetSimulation {
+    pub max_tokens_per_conversation: usize,
+    pub max_tokens_per_message: usize,
+    pub conversations: usize,
+    pub messages: usize,
+    pub truncated_messages: usize,
+    /// Tokens cut by message truncation.
+    pub truncated_tokens: usize,
+    /// Input conversations that would be split.
+    pub split_conversations: usize,
+    /// Conversations after splitting.
+    pub output_conversations: usize,
+    /// Tokens after truncation.
+    pub total_tokens: usize,
+}
+
+/// Simulate `limits` on conversations given as per-message token counts.
+pub fn simulate_budget<'a, I>(conversations: I, limits: BudgetLimits) -> BudgetSimulation
+where
+    I: IntoIterator<Item = &'a [usize]>,
+{
+    let mut sim = BudgetSimulation {
+        max_tokens_per_conversation: limits.max_tokens_per_conversation,
+        max_tokens_per_message: limits.max_tokens_per_message,
+        ..Default::default()
+    };
+    for message_tokens in conversations {
+        if message_tokens.is_empty() {
+            continue;
+        }
+        sim.conversations += 1;
+        sim.messages += message_tokens.len();
+        let mut chunks = 1;
+        let mut current = 0;
+        for (i, &tokens) in message_tokens.iter().enumerate() {
+            let tokens = if tokens > limits.max_tokens_per_message {
+                sim.truncated_messages += 1;
+                sim.truncated_tokens += tokens - limits.max_tokens_per_message;
+                limits.max_tokens_per_message
+            } else {
+                tokens
+            };
+            if current + tokens > limits.max_tokens_per_conversation && i > 0 {
+                chunks += 1;
+                current = 0;
+            }
+            current += tokens;
+            sim.total_tokens += tokens;
+        }
+        sim.output_conversations += chunks;
+        sim.split_conversations += (chunks > 1) as usize;
+    }
+    sim
+}
+
+/// Character-based token approximation (~4 bytes per token), as used by
+/// the extension.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CharApproxTokenizer;
+
+impl Tokenizer for CharApproxTokenizer {
+    fn count_tokens(&self, text: &str) -> usize {
+        text.len() / 4
+    }
+
+    fn truncate_to_max_tokens(&self, text: &str, max_tokens: usize) -> String {
+        text.chars().take(max_tokens * 4).collect()
+    }
+}
+
+/// Conversation budget for a session whose unsplit conversations have the
+/// given per-message token counts: the smallest budget in `[min_tokens,
+/// max_tokens]` splitting them no more often than `max_tokens` does, plus
+/// headroom, up to `max_tokens`. Spreads a session's tokens evenly over as
+/// few chunks as its bounds allow instead of leaving a short last chunk.
+pub fn auto_chunk_budget(
+    conversations: &[Vec<usize>],
+    min_tokens: usize,
+    max_tokens: usize,
+    max_tokens_per_message: usize,
+) -> usize {
+    let chunks = |budget: usize| {
+        let limits = BudgetLimits {
+            max_tokens_per_conversation: budget,
+            max_tokens_per_message,
+        };
+        simulate_budget(conversations.iter().map(Vec::as_slice), limits).output_conversations
+    };
+    let fewest = chunks(max_tokens);
+    let (mut low, mut high) = (min_tokens.min(max_tokens), max_tokens);
+    while low < high {
+        let middle = low + (high - low) / 2;
+        if chunks(middle) <= fewest {
+            high = middle;
+        } else {
+            low = middle + 1;
+        }
+    }
+    let headroom = (low as f64 * AUTO_CHUNK_HEADROOM).ceil() as usize;
+    (low + headroom).min(max_tokens)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_simulate_budget() {
+        let conversations: Vec<Vec<usize>> = vec![vec![10, 50, 10], vec![40, 40, 40], vec![]];
+        let limits = BudgetLimits {
+            max_tokens_per_conversation: 80,
+            max_tokens_per_message: 30,
+        };
+        let sim = simulate_budget(conversations.iter().map(Vec::as_slice), limits);
+        assert_eq!(sim.conversations, 2);
+        assert_eq!(sim.messages, 6);
+        assert_eq!(sim.truncated_messages, 4);
+        assert_eq!(sim.truncated_tokens, 50);
+        // [10, 30, 10] fits; [30, 30 | 30] splits once
+        assert_eq!(sim.split_conversations, 1);
+        assert_eq!(sim.output_conversations, 3);
+        assert_eq!(sim.total_tokens, 140);
+    }
+
+    #[test]
+    fn test_auto_chunk_budget() {
+        // 120 tokens need two chunks under 100; 60 per chunk is enough
+        let conversations = vec![vec![20; 6]];
+        assert_eq!(auto_chunk_budget(&conversations, 10, 100, 50), 66);
+        // Already one chunk at the lower bound
+        assert_eq!(auto_chunk_budget(&[vec![10, 10]], 50, 100, 50), 55);
+        assert_eq!(auto_chunk_budget(&conversations, 100, 100, 50), 100);
+    }
+}
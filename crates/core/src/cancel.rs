@@ -0,0 +1,78 @@
+//! Cooperative cancellation and per-session timeouts.
+//!
+//! Processing checks a `SessionInterrupt` between events (of the session and
+//! of its augmented variants), while diffing a flushed edit and between
+//! tokenization batches, so a cancelled run or a
+//! pathological session stops at the next check instead of wedging a worker.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::error::SerializerError;
+
+/// Shared flag to stop a running pipeline. Sessions in progress stop at
+/// their next check; the run then returns `SerializerError::Cancelled`.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// When the processing of one session has to stop. The default never fires.
+#[derive(Debug, Clone, Default)]
+pub struct SessionInterrupt {
+    cancellation: Option<CancellationToken>,
+    deadline: Option<(Instant, Duration)>,
+}
+
+impl SessionInterrupt {
+    /// Stop when `cancellation` is cancelled or `timeout` has passed from now.
+    pub fn new(cancellation: Option<CancellationToken>, timeout: Option<Duration>) -> Self {
+        Self {
+            cancellation,
+            deadline: timeout.map(|timeout| (Instant::now() + timeout, timeout)),
+        }
+    }
+
+    /// `Cancelled` or `TimedOut` once processing has to stop.
+    pub fn check(&self) -> Result<(), SerializerError> {
+        if self.cancellation.as_ref().is_some_and(|c| c.is_cancelled()) {
+            return Err(SerializerError::Cancelled);
+        }
+        match self.deadline {
+            Some((deadline, timeout)) if Instant::now() >= deadline => Err(SerializerError::TimedOut(timeout)),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_session_interrupt() {
+        assert!(SessionInterrupt::default().check().is_ok());
+
+        let token = CancellationToken::new();
+        let interrupt = SessionInterrupt::new(Some(token.clone()), Some(Duration::from_secs(3600)));
+        assert!(interrupt.check().is_ok());
+        token.cancel();
+        assert!(matches!(interrupt.check(), Err(SerializerError::Cancelled)));
+
+        let expired = SessionInterrupt::new(None, Some(Duration::ZERO));
+        assert!(matches!(expired.check(), Err(SerializerError::TimedOut(d)) if d == Duration::ZERO));
+    }
+}
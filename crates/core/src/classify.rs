@@ -0,0 +1,436 @@
+//! Classification of shell commands for safety filtering.
+//!
+//! A lightweight parser splits a command line into simple commands (on `;`,
+//! `&&`, `||`, `|`, `&` and newlines outside quotes), strips wrappers like
+//! `sudo` or `env` and `VAR=value` assignments, and classifies each by its
+//! program, subcommand and flags. A command line gets the union of the
+//! classes of its parts.
+
+use std::collections::BTreeSet;
+
+use serde::Serialize;
+
+use crate::conversation::{ConversationMessage, FinalizedConversation};
+use crate::error::SerializerError;
+use crate::filter::{ConversationFilter, FilterDecision, SessionContext};
+
+/// What a command does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CommandClass {
+    /// Reads or searches files (`cat`, `grep`, `sed -n`, `Get-Content`).
+    Read,
+    /// Modifies files (`sed -i`, `> file`, `mv`, `Set-Content`, formatters).
+    Edit,
+    /// Builds, tests or runs code (`cargo test`, `make`, `pytest`).
+    Build,
+    /// Talks to the network (`curl`, `git push`, `pip install`).
+    Network,
+    /// Deletes data or discards work (`rm`, `git reset --hard`, `kill`).
+    Destructive,
+}
+
+impl CommandClass {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Read => "read",
+            Self::Edit => "edit",
+            Self::Build => "build",
+            Self::Network => "network",
+            Self::Destructive => "destructive",
+        }
+    }
+}
+
+impl std::str::FromStr for CommandClass {
+    type Err = SerializerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read" => Ok(Self::Read),
+            "edit" => Ok(Self::Edit),
+            "build" => Ok(Self::Build),
+            "network" => Ok(Self::Network),
+            "destructive" => Ok(Self::Destructive),
+            other => Err(SerializerError::InvalidConfig(format!(
+                "unknown command class '{}'",
+                other
+            ))),
+        }
+    }
+}
+
+/// Programs run with their arguments by a wrapper.
+const WRAPPERS: &[&str] = &["sudo", "env", "time", "nohup", "nice", "command", "exec", "xargs"];
+
+/// Split a command line into simple commands of unquoted words. Redirection
+/// operators become their own `>`, `>>` or `<` words.
+fn simple_commands(command: &str) -> Vec<Vec<String>> {
+    let mut commands = Vec::new();
+    let mut words: Vec<String> = Vec::new();
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut chars = command.chars().peekable();
+    let mut quote: Option<char> = None;
+
+    fn end_word(words: &mut Vec<String>, word: &mut String, in_word: &mut bool) {
+        if *in_word {
+            words.push(std::mem::take(word));
+            *in_word = false;
+        }
+    }
+
+    while let Some(c) = chars.next() {
+        if let Some(q) = quote {
+            match c {
+                _ if c == q => quote = None,
+                '\\' if q == '"' => word.extend(chars.next()),
+                _ => word.push(c),
+            }
+            continue;
+        }
+        match c {
+            '\'' | '"' => {
+                quote = Some(c);
+                in_word = true;
+            }
+            '\\' => match chars.next() {
+                // Line continuation
+                Some('\n') => {}
+                Some(next) => {
+                    word.push(next);
+                    in_word = true;
+                }
+                None => {}
+            },
+            ';' | '&' | '|' | '\n' => {
+                end_word(&mut words, &mut word, &mut in_word);
+                if !words.is_empty() {
+                    commands.push(std::mem::take(&mut words));
+                }
+            }
+            '>' | '<' => {
+                // `2>` redirects a descriptor, not a word
+                if word.chars().all(|d| d.is_ascii_digit()) {
+                    word.clear();
+                    in_word = false;
+                }
+                end_word(&mut words, &mut word, &mut in_word);
+                let mut op = c.to_string();
+                if c == '>' && chars.peek() == Some(&'>') {
+                    chars.next();
+                    op.push('>');
+                }
+                if chars.peek() == Some(&'&') {
+                    // `>&2` duplicates a descriptor
+                    chars.next();
+                    while chars.peek().is_some_and(|d| d.is_ascii_digit()) {
+                        chars.next();
+                    }
+                    continue;
+                }
+                words.push(op);
+            }
+            c if c.is_whitespace() => end_word(&mut words, &mut word, &mut in_word),
+            c => {
+                word.push(c);
+                in_word = true;
+            }
+        }
+    }
+    end_word(&mut words, &mut word, &mut in_word);
+    if !words.is_empty() {
+        commands.push(words);
+    }
+    commands
+}
+
+fn has_flag(args: &[String], flags: &[&str]) -> bool {
+    args.iter().any(|arg| flags.contains(&arg.as_str()))
+}
+
+/// First argument that is not a flag.
+fn subcommand(args: &[String]) -> &str {
+    args.iter()
+        .find(|arg| !arg.starts_with('-'))
+        .map(String::as_str)
+        .unwrap_or("")
+}
+
+/// Classes of a simple command given as words.
+fn classify_words(words: &[String], classes: &mut BTreeSet<CommandClass>) {
+    use CommandClass::*;
+
+    // Redirections: writing to a file is an edit
+    let mut args = Vec::with_capacity(words.len());
+    let mut iter = words.iter();
+    while let Some(word) = iter.next() {
+        match word.as_str() {
+            ">" | ">>" => {
+                if iter.next().is_some_and(|target| target != "/dev/null") {
+                    classes.insert(Edit);
+                }
+            }
+            "<" => {
+                iter.next();
+            }
+            _ => args.push(word.clone()),
+        }
+    }
+
+    let mut rest = args.as_slice();
+    loop {
+        match rest.first().map(String::as_str) {
+            Some(word) if word.contains('=') && !word.starts_with('-') && !word.starts_with('=') => {
+                rest = &rest[1..];
+            }
+            Some(word) if WRAPPERS.contains(&word) => {
+                rest = &rest[1..];
+                // Options of the wrapper (`sudo -u root`, `xargs -0`)
+                while rest.first().is_some_and(|w| w.starts_with('-')) {
+                    rest = &rest[1..];
+                }
+            }
+            _ => break,
+        }
+    }
+    let Some((program, args)) = rest.split_first() else {
+        return;
+    };
+    let program = program.rsplit(['/', '\\']).next().unwrap_or(program).to_lowercase();
+    let program = program.strip_suffix(".exe").unwrap_or(&program);
+    let sub = subcommand(args);
+
+    match program {
+        "cat" | "less" | "more" | "head" | "tail" | "bat" | "nl" | "wc" | "grep" | "egrep"
+        | "rg" | "ag" | "ls" | "tree" | "stat" | "file" | "diff" | "awk" | "get-content" | "gc"
+        | "type" | "select-string" | "get-childitem" | "dir" | "gci" => {
+            classes.insert(Read);
+        }
+        "sed" | "perl" => {
+            let in_place = args.iter().any(|a| {
+                a == "--in-place" || (a.starts_with('-') && !a.starts_with("--") && a.contains('i'))
+            });
+            classes.insert(if in_place { Edit } else { Read });
+        }
+        "find" => {
+            classes.insert(Read);
+            if has_flag(args, &["-delete"])
+                || args
+                    .windows(2)
+                    .any(|w| w[0] == "-exec" && matches!(w[1].as_str(), "rm" | "shred"))
+            {
+                classes.insert(Destructive);
+            }
+        }
+        "tee" | "touch" | "mkdir" | "cp" | "mv" | "ln" | "patch" | "vim" | "vi" | "nano"
+        | "emacs" | "code" | "rustfmt" | "black" | "prettier" | "gofmt" | "clang-format"
+        | "set-content" | "add-content" | "out-file" | "new-item" | "rename-item" | "copy-item"
+        | "move-item" | "ni" | "sc" | "ac" => {
+            classes.insert(Edit);
+        }
+        "rm" | "rmdir" | "shred" | "dd" | "truncate" | "kill" | "pkill" | "killall"
+        | "remove-item" | "ri" | "del" | "erase" | "rd" | "stop-process" => {
+            classes.insert(Destructive);
+        }
+        p if p.starts_with("mkfs") => {
+            classes.insert(Destructive);
+        }
+        "chmod" | "chown" => {
+            classes.insert(if has_flag(args, &["-R", "--recursive"]) { Destructive } else { Edit });
+        }
+        "curl" | "wget" | "ssh" | "scp" | "sftp" | "rsync" | "nc" | "netcat" | "telnet" | "ftp"
+        | "gh" | "invoke-webrequest" | "iwr" | "invoke-restmethod" | "irm" => {
+            classes.insert(Network);
+        }
+        "git" => match sub {
+            "clone" | "fetch" | "pull" | "ls-remote" => {
+                classes.insert(Network);
+            }
+            "push" => {
+                classes.insert(Network);
+                if has_flag(args, &["-f", "--force", "--force-with-lease", "--mirror", "--delete"]) {
+                    classes.insert(Destructive);
+                }
+            }
+            "reset" if has_flag(args, &["--hard"]) => {
+                classes.insert(Destructive);
+            }
+            "clean" => {
+                classes.insert(Destructive);
+            }
+            "branch" if has_flag(args, &["-D", "--delete"]) => {
+                classes.insert(Destructive);
+            }
+            "stash" if matches!(args.get(1).map(String::as_str), Some("drop" | "clear")) => {
+                classes.insert(Destructive);
+            }
+            "checkout" | "restore" if args.iter().any(|a| a == "." || a == "--") => {
+                classes.insert(Destructive);
+            }
+            "apply" | "commit" | "add" | "mv" | "merge" | "rebase" | "cherry-pick" => {
+                classes.insert(Edit);
+            }
+            "log" | "diff" | "show" | "status" | "blame" | "grep" => {
+                classes.insert(Read);
+            }
+            _ => {}
+        },
+        "cargo" => match sub {
+            "install" | "fetch" | "update" | "publish" | "search" => {
+                classes.insert(Network);
+            }
+            "fmt" => {
+                classes.insert(Edit);
+            }
+            "clean" => {
+                classes.insert(Destructive);
+            }
+            _ => {
+                classes.insert(Build);
+            }
+        },
+        "npm" | "yarn" | "pnpm" | "bun" => match sub {
+            "install" | "i" | "ci" | "add" | "update" | "upgrade" | "publish" => {
+                classes.insert(Network);
+            }
+            "" => {
+                // Bare `yarn` installs
+                classes.insert(Network);
+            }
+            _ => {
+                classes.insert(Build);
+            }
+        },
+        "pip" | "pip3" | "uv" | "poetry" | "conda" | "apt" | "apt-get" | "brew" | "dnf" | "yum"
+        | "pacman" | "choco" | "winget" => {
+            if matches!(sub, "install" | "add" | "download" | "update" | "upgrade" | "sync" | "-S") {
+                classes.insert(Network);
+            } else if matches!(sub, "uninstall" | "remove" | "purge") {
+                classes.insert(Destructive);
+            }
+        }
+        "go" => {
+            classes.insert(if matches!(sub, "get" | "install") { Network } else { Build });
+        }
+        "docker" | "podman" => match sub {
+            "pull" | "push" | "login" => {
+                classes.insert(Network);
+            }
+            "rm" | "rmi" | "prune" | "kill" => {
+                classes.insert(Destructive);
+            }
+            "system" | "volume" | "image" | "container" if has_flag(args, &["prune", "rm"]) => {
+                classes.insert(Destructive);
+            }
+            _ => {
+                classes.insert(Build);
+            }
+        },
+        "make" | "cmake" | "ninja" | "gcc" | "g++" | "cc" | "clang" | "clang++" | "rustc"
+        | "javac" | "java" | "tsc" | "pytest" | "mvn" | "gradle" | "gradlew" | "dotnet" | "bazel"
+        | "msbuild" | "node" | "deno" | "ruby" | "jest" | "vitest" => {
+            classes.insert(Build);
+        }
+        "python" | "python3" | "py" => {
+            classes.insert(Build);
+            if args.windows(2).any(|w| w[0] == "-m" && w[1] == "pip")
+                && args.iter().any(|a| a == "install")
+            {
+                classes.insert(Network);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Classes of a command line, sorted and deduplicated. Unknown programs add
+/// nothing, so an empty result means "unclassified", not "safe".
+pub fn classify_command(command: &str) -> Vec<CommandClass> {
+    let mut classes = BTreeSet::new();
+    for words in simple_commands(command) {
+        classify_words(&words, &mut classes);
+    }
+    classes.into_iter().collect()
+}
+
+impl ConversationMessage {
+    /// The command of an assistant message (the body of its code fence, if
+    /// any); None for other roles.
+    pub fn command(&self) -> Option<&str> {
+        if self.from != "Assistant" {
+            return None;
+        }
+        let value = self.value.trim();
+        Some(match value.strip_prefix("```") {
+            Some(fenced) => {
+                let body = fenced.split_once('\n').map_or("", |(_, body)| body);
+                body.trim_end().strip_suffix("```").unwrap_or(body)
+            }
+            None => value,
+        })
+    }
+
+    /// Classes of the command in an assistant message. Empty for other roles.
+    pub fn command_classes(&self) -> Vec<CommandClass> {
+        self.command().map(classify_command).unwrap_or_default()
+    }
+}
+
+/// Drops conversations in which the assistant runs a command of any of the
+/// given classes (e.g. `Destructive`).
+#[derive(Debug, Clone)]
+pub struct DropCommandClasses {
+    classes: Vec<CommandClass>,
+}
+
+impl DropCommandClasses {
+    pub fn new(classes: Vec<CommandClass>) -> Self {
+        Self { classes }
+    }
+}
+
+impl ConversationFilter for DropCommandClasses {
+    fn name(&self) -> &str {
+        "drop-command-classes"
+    }
+
+    fn accept(&self, conv: &FinalizedConversation, _ctx: &SessionContext<'_>) -> FilterDecision {
+        for message in &conv.messages {
+            if let Some(class) = message
+                .command_classes()
+                .into_iter()
+                .find(|class| self.classes.contains(class))
+            {
+                return FilterDecision::Reject {
+                    reason: format!("{}_command", class.as_str()),
+                };
+            }
+        }
+        FilterDecision::Accept
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use CommandClass::*;
+
+    #[test]
+    fn test_classify_command() {
+        assert_eq!(classify_command("cat -n src/main.rs | sed -n '1,20p'"), vec![Read]);
+        assert_eq!(classify_command("sed -i '3d' a.rs && cat -n a.rs"), vec![Read, Edit]);
+        assert_eq!(classify_command("sudo rm -rf /tmp/x"), vec![Destructive]);
+        assert_eq!(classify_command("RUST_LOG=debug cargo test 2>&1 | tail"), vec![Read, Build]);
+        assert_eq!(classify_command("curl -sSL https://x.sh | sh"), vec![Network]);
+        assert_eq!(classify_command("git push --force origin main"), vec![Network, Destructive]);
+        assert_eq!(classify_command("echo 'rm -rf /; git push' > notes.txt"), vec![Edit]);
+        assert_eq!(classify_command("ls > /dev/null"), vec![Read]);
+        assert_eq!(classify_command("Remove-Item -Recurse C:\\tmp"), vec![Destructive]);
+        assert!(classify_command("echo hello").is_empty());
+
+        let message = ConversationMessage::assistant("```bash\ngit reset --hard HEAD~1\n```\n");
+        assert_eq!(message.command_classes(), vec![Destructive]);
+        assert!(ConversationMessage::user("rm -rf /").command_classes().is_empty());
+    }
+}
@@ -0,0 +1,304 @@
+//! Contamination check of conversations against benchmark corpora.
+//!
+//! Benchmark files (HumanEval, SWE-bench, ...) are fingerprinted as hashed
+//! word n-grams ("shingles"). A conversation sharing at least `min_matches`
+//! shingles with the corpus is flagged and, optionally, dropped. Words are
+//! runs of alphanumerics and `_`, lowercased, so formatting differences do
+//! not hide overlap; `cat -n` line number gutters are stripped first.
+
+use std::collections::{BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use serde::Serialize;
+use walkdir::WalkDir;
+
+use crate::conversation::FinalizedConversation;
+use crate::error::SerializerError;
+use crate::filter::{ConversationFilter, FilterDecision, SessionContext};
+use crate::helpers::stable_hash;
+
+/// Configuration of the contamination check.
+#[derive(Debug, Clone)]
+pub struct ContaminationConfig {
+    /// Words per shingle.
+    pub shingle_size: usize,
+    /// Shared shingles needed to flag a conversation.
+    pub min_matches: usize,
+    /// Drop flagged conversations instead of only reporting them.
+    pub drop: bool,
+}
+
+impl Default for ContaminationConfig {
+    fn default() -> Self {
+        Self {
+            shingle_size: 13,
+            min_matches: 1,
+            drop: false,
+        }
+    }
+}
+
+/// A conversation overlapping the benchmark corpora.
+#[derive(Debug, Clone, Serialize)]
+pub struct ContaminationMatch {
+    pub conversation_id: String,
+    pub source_path: String,
+    pub chunk_index: usize,
+    pub matched_shingles: usize,
+    pub total_shingles: usize,
+    /// Benchmark files sharing shingles with the conversation.
+    pub benchmark_files: Vec<String>,
+}
+
+/// Summary of a contamination check, written as `contamination.json`.
+#[derive(Debug, Clone, Serialize)]
+pub struct ContaminationReport {
+    pub corpus_files: Vec<String>,
+    pub corpus_shingles: usize,
+    pub shingle_size: usize,
+    pub min_matches: usize,
+    pub dropped: bool,
+    pub checked_conversations: usize,
+    pub flagged: Vec<ContaminationMatch>,
+}
+
+/// Lowercased words of `text`, without `cat -n` line number gutters.
+fn words(text: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    for line in text.lines() {
+        // Selected lines are flagged with `>` (see `annotate_selections`)
+        let trimmed = line.trim_start_matches([' ', '>']);
+        let digits = trimmed.len() - trimmed.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+        let line = match trimmed[digits..].strip_prefix('\t') {
+            Some(rest) if digits > 0 => rest,
+            _ => line,
+        };
+        words.extend(
+            line.split(|c: char| !(c.is_alphanumeric() || c == '_'))
+                .filter(|w| !w.is_empty())
+                .map(str::to_lowercase),
+        );
+    }
+    words
+}
+
+/// Hashes of every `size`-word window of `text`.
+fn shingles(text: &str, size: usize) -> impl Iterator<Item = u64> {
+    let words = words(text);
+    let windows = (words.len() + 1).saturating_sub(size);
+    (0..windows).map(move |start| stable_hash(words[start..start + size].join(" ").as_bytes()))
+}
+
+/// Collect the string values of a JSON document, so escaped code in
+/// `.json`/`.jsonl` corpora is fingerprinted as code.
+fn json_strings(value: &serde_json::Value, out: &mut String) {
+    match value {
+        serde_json::Value::String(s) => {
+            out.push_str(s);
+            out.push('\n');
+        }
+        serde_json::Value::Array(items) => items.iter().for_each(|v| json_strings(v, out)),
+        serde_json::Value::Object(fields) => fields.values().for_each(|v| json_strings(v, out)),
+        _ => {}
+    }
+}
+
+fn read_corpus_text(path: &Path) -> Result<Option<String>, SerializerError> {
+    let bytes = std::fs::read(path)?;
+    let Ok(text) = String::from_utf8(bytes) else {
+        // Binary file
+        return Ok(None);
+    };
+    let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+    if !matches!(extension, "json" | "jsonl") {
+        return Ok(Some(text));
+    }
+    let mut out = String::new();
+    let documents: Vec<serde_json::Value> = if extension == "json" {
+        serde_json::from_str(&text).into_iter().collect()
+    } else {
+        text.lines().filter_map(|line| serde_json::from_str(line).ok()).collect()
+    };
+    if documents.is_empty() {
+        return Ok(Some(text));
+    }
+    documents.iter().for_each(|doc| json_strings(doc, &mut out));
+    Ok(Some(out))
+}
+
+/// Filter flagging (and with `drop`, rejecting) conversations that share
+/// shingles with the benchmark corpora. Read the findings with `report`.
+#[derive(Debug)]
+pub struct ContaminationFilter {
+    config: ContaminationConfig,
+    corpus_files: Vec<String>,
+    /// Shingle hash to the index of the first corpus file containing it.
+    index: HashMap<u64, usize>,
+    checked: AtomicUsize,
+    flagged: Mutex<Vec<ContaminationMatch>>,
+}
+
+impl ContaminationFilter {
+    /// Fingerprint every file under `corpora` (files or directories).
+    pub fn build(corpora: &[PathBuf], config: ContaminationConfig) -> Result<Self, SerializerError> {
+        if config.shingle_size == 0 || config.min_matches == 0 {
+            return Err(SerializerError::InvalidConfig(
+                "contamination shingle size and minimum matches must be positive".to_string(),
+            ));
+        }
+        let mut files = Vec::new();
+        for root in corpora {
+            if !root.exists() {
+                return Err(SerializerError::InvalidConfig(format!(
+                    "benchmark corpus {:?} does not exist",
+                    root
+                )));
+            }
+            let mut found: Vec<PathBuf> = WalkDir::new(root)
+                .into_iter()
+                .filter_map(|e| e.ok())
+                .filter(|e| e.file_type().is_file())
+                .map(|e| e.into_path())
+                .collect();
+            found.sort();
+            files.extend(found);
+        }
+
+        let mut filter = Self {
+            config,
+            corpus_files: Vec::new(),
+            index: HashMap::new(),
+            checked: AtomicUsize::new(0),
+            flagged: Mutex::new(Vec::new()),
+        };
+        for path in files {
+            let Some(text) = read_corpus_text(&path)? else {
+                continue;
+            };
+            let file_index = filter.corpus_files.len();
+            filter.corpus_files.push(path.to_string_lossy().into_owned());
+            for shingle in shingles(&text, filter.config.shingle_size) {
+                filter.index.entry(shingle).or_insert(file_index);
+            }
+        }
+        Ok(filter)
+    }
+
+    /// Distinct shingles in the corpus index.
+    pub fn corpus_shingles(&self) -> usize {
+        self.index.len()
+    }
+
+    /// Findings so far, sorted by source path and chunk index.
+    pub fn report(&self) -> ContaminationReport {
+        let mut flagged = self.flagged.lock().expect("contamination report lock").clone();
+        flagged.sort_by(|a, b| {
+            (a.source_path.as_str(), a.chunk_index).cmp(&(b.source_path.as_str(), b.chunk_index))
+        });
+        ContaminationReport {
+            corpus_files: self.corpus_files.clone(),
+            corpus_shingles: self.index.len(),
+            shingle_size: self.config.shingle_size,
+            min_matches: self.config.min_matches,
+            dropped: self.config.drop,
+            checked_conversations: self.checked.load(Ordering::Relaxed),
+            flagged,
+        }
+    }
+}
+
+impl ConversationFilter for ContaminationFilter {
+    fn name(&self) -> &str {
+        "contamination"
+    }
+
+    fn accept(&self, conv: &FinalizedConversation, ctx: &SessionContext<'_>) -> FilterDecision {
+        self.checked.fetch_add(1, Ordering::Relaxed);
+        let mut total = 0;
+        let mut matched = 0;
+        let mut files = BTreeSet::new();
+        for message in &conv.messages {
+            for shingle in shingles(&message.value, self.config.shingle_size) {
+                total += 1;
+                if let Some(&file) = self.index.get(&shingle) {
+                    matched += 1;
+                    files.insert(file);
+                }
+            }
+        }
+        if matched < self.config.min_matches {
+            return FilterDecision::Accept;
+        }
+
+        self.flagged
+            .lock()
+            .expect("contamination report lock")
+            .push(ContaminationMatch {
+                conversation_id: conv.id.clone(),
+                source_path: ctx.source_path.to_string(),
+                chunk_index: ctx.chunk_index,
+                matched_shingles: matched,
+                total_shingles: total,
+                benchmark_files: files.into_iter().map(|i| self.corpus_files[i].clone()).collect(),
+            });
+        if self.config.drop {
+            FilterDecision::Reject {
+                reason: "contaminated".to_string(),
+            }
+        } else {
+            FilterDecision::Accept
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conversation::ConversationMessage;
+    use crate::pipeline::SessionStats;
+
+    #[test]
+    fn test_contamination_filter() {
+        let dir = tempfile::TempDir::new().unwrap();
+        std::fs::write(
+            dir.path().join("humaneval.jsonl"),
+            "{\"task_id\": \"HumanEval/0\", \"prompt\": \"def has_close_elements(numbers, threshold):\\n    for idx, elem in enumerate(numbers):\\n        pass\\n\"}\n",
+        )
+        .unwrap();
+        let config = ContaminationConfig {
+            shingle_size: 5,
+            drop: true,
+            ..Default::default()
+        };
+        let filter = ContaminationFilter::build(&[dir.path().to_path_buf()], config).unwrap();
+        assert_eq!(filter.corpus_files.len(), 1);
+
+        let stats = SessionStats::default();
+        let ctx = SessionContext {
+            source_path: "s.csv",
+            chunk_index: 3,
+            stats: &stats,
+            tags: &Default::default(),
+        };
+        let conversation = |text: &str| FinalizedConversation {
+            id: "c".to_string(),
+            messages: vec![ConversationMessage::user(text)],
+            ..Default::default()
+        };
+        // Same code behind a `cat -n` gutter
+        let shown = conversation(
+            "<stdout>\n     1\tdef has_close_elements(numbers, threshold):\n     2\t    for idx, elem in enumerate(numbers):\n</stdout>",
+        );
+        assert!(matches!(filter.accept(&shown, &ctx), FilterDecision::Reject { .. }));
+        let unrelated = conversation("def parse_args(argv):\n    return argv[1:]\n");
+        assert_eq!(filter.accept(&unrelated, &ctx), FilterDecision::Accept);
+
+        let report = filter.report();
+        assert_eq!(report.checked_conversations, 2);
+        assert_eq!(report.flagged.len(), 1);
+        assert_eq!(report.flagged[0].chunk_index, 3);
+        assert!(report.flagged[0].matched_shingles >= 1);
+    }
+}
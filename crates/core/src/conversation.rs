@@ -1,20 +1,106 @@
 //! Conversation state manager for serializing IDE events into conversation format.
 
-use std::collections::{HashMap, HashSet};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::ops::Range;
+use std::sync::Arc;
 
-use crate::diff::compute_changed_block_lines;
+use serde::{Deserialize, Serialize};
+
+use crate::augment::AugmentationInfo;
+use crate::boundary::{TaskBoundary, TaskBoundaryConfig, TaskBoundaryDetector};
+use crate::cancel::SessionInterrupt;
+use crate::diff::{compute_changed_block_lines_until, whitespace_only_hunks};
+use crate::error::SerializerError;
+use crate::event::IdeEvent;
 use crate::helpers::{
-    clean_text, escape_single_quotes_for_sed, fenced_block, floor_char_boundary,
-    line_numbered_output, normalize_terminal_output, serialize_compute_viewport, Viewport,
+    clean_text, command_written_files, compute_viewport, escape_single_quotes_for_sed, floor_char_boundary,
+    normalize_terminal_output, stable_hash, strip_bom, truncate_with_marker, Viewport,
 };
+use crate::labels::ConversationLabels;
+use crate::observer::{notify_all, ConversationObserver, Notification};
+use crate::paths::{CompanionPattern, IgnorePattern, PathCanonicalization, PathCanonicalizer, PathMapper, PathMapping};
+use crate::render::{Action, BashRenderer, FileEdit, Observation, Renderer, ViewCommandMix};
+use crate::reorder::{ReorderBuffer, ReorderKey};
+use crate::vim::{parse_vim_command, LineAddress, VimCommand};
+use crate::warnings::WarningAggregator;
 use crate::Tokenizer;
-use crate::{COALESCE_RADIUS, MAX_TOKENS_PER_MESSAGE, MAX_TOKENS_PER_TERMINAL_OUTPUT, VIEWPORT_RADIUS};
+use crate::{
+    COALESCE_RADIUS, ELISION_MARKER, MAX_TOKENS_PER_MESSAGE, MAX_TOKENS_PER_TERMINAL_OUTPUT,
+    VIEWPORT_RADIUS,
+};
+
+/// Shell-integration sequences (VS Code and FinalTerm) marking the start of
+/// a command's output and the end of the command.
+const COMMAND_STARTED_MARKERS: [&str; 2] = ["\x1b]633;C", "\x1b]133;C"];
+const COMMAND_FINISHED_MARKERS: [&str; 2] = ["\x1b]633;D", "\x1b]133;D"];
 
 /// A single message in the conversation.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct ConversationMessage {
     pub from: String,
     pub value: String,
+    /// Per-message loss-mask override. `None` follows the writer's role policy.
+    pub mask: Option<bool>,
+    /// Typing and pause timing, with `record_timing`.
+    pub timing: Option<MessageTiming>,
+    /// What the message shows, set by the state manager.
+    pub kind: Option<MessageKind>,
+    /// Byte range of the decision-relevant part of a rendered action, from
+    /// `Renderer::render_action_with_span` (see `loss_spans`).
+    pub loss_span: Option<Range<usize>>,
+}
+
+/// What a message shows. Observations have the kind of the action they
+/// answer, except that command output is `TerminalOutput`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageKind {
+    /// A full capture or line count of a file.
+    FileCapture,
+    /// A read of some lines of a file.
+    Viewport,
+    /// An edit of a file and the view of its result.
+    EditCommand,
+    TerminalCommand,
+    TerminalOutput,
+    /// A branch checkout.
+    GitAction,
+    DirectoryListing,
+    /// Markers and injected messages.
+    Other,
+}
+
+impl MessageKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::FileCapture => "file_capture",
+            Self::Viewport => "viewport",
+            Self::EditCommand => "edit_command",
+            Self::TerminalCommand => "terminal_command",
+            Self::TerminalOutput => "terminal_output",
+            Self::GitAction => "git_action",
+            Self::DirectoryListing => "directory_listing",
+            Self::Other => "other",
+        }
+    }
+}
+
+/// Timing of the events behind an assistant message, in milliseconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct MessageTiming {
+    /// From the first to the last keystroke of a coalesced edit.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub typing_ms: Option<u64>,
+    /// Content events coalesced into the edit.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keystrokes: Option<usize>,
+    /// Longest gap between two keystrokes of the edit.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_keystroke_gap_ms: Option<u64>,
+    /// From the preceding event to the first keystroke or the command.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pause_before_ms: Option<u64>,
 }
 
 impl ConversationMessage {
@@ -22,6 +108,10 @@ impl ConversationMessage {
         Self {
             from: "User".to_string(),
             value: value.into(),
+            mask: None,
+            timing: None,
+            kind: None,
+            loss_span: None,
         }
     }
 
@@ -29,14 +119,223 @@ impl ConversationMessage {
         Self {
             from: "Assistant".to_string(),
             value: value.into(),
+            mask: None,
+            timing: None,
+            kind: None,
+            loss_span: None,
+        }
+    }
+
+    /// Set the loss-mask override.
+    pub fn with_mask(mut self, mask: Option<bool>) -> Self {
+        self.mask = mask;
+        self
+    }
+
+    /// Set the timing metadata.
+    pub fn with_timing(mut self, timing: Option<MessageTiming>) -> Self {
+        self.timing = timing;
+        self
+    }
+
+    pub fn with_kind(mut self, kind: MessageKind) -> Self {
+        self.kind = Some(kind);
+        self
+    }
+
+    pub fn with_loss_span(mut self, span: Option<Range<usize>>) -> Self {
+        self.loss_span = span;
+        self
+    }
+}
+
+/// How to handle content events for files without tracked state
+/// (no tab snapshot has been seen yet).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UntrackedFilePolicy {
+    /// Apply edits to an empty file.
+    #[default]
+    AssumeEmpty,
+    /// Ignore the event.
+    Drop,
+    /// Emit a `[file content unknown]` capture before serializing the edit.
+    Placeholder,
+    /// Buffer edits until the first tab snapshot, then replay them on top of
+    /// it, unless the snapshot already contains them.
+    Buffer,
+}
+
+impl UntrackedFilePolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::AssumeEmpty => "assume-empty",
+            Self::Drop => "drop",
+            Self::Placeholder => "placeholder",
+            Self::Buffer => "buffer",
+        }
+    }
+}
+
+impl std::str::FromStr for UntrackedFilePolicy {
+    type Err = SerializerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "assume-empty" => Ok(Self::AssumeEmpty),
+            "drop" => Ok(Self::Drop),
+            "placeholder" => Ok(Self::Placeholder),
+            "buffer" => Ok(Self::Buffer),
+            other => Err(SerializerError::InvalidConfig(format!(
+                "unknown untracked file policy '{}'",
+                other
+            ))),
+        }
+    }
+}
+
+/// How to serialize edits that only change whitespace (e.g. format-on-save).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WhitespaceEditPolicy {
+    /// Emit the `sed` edit like any other.
+    #[default]
+    Emit,
+    /// Apply the edit to the tracked state without emitting anything, if it
+    /// keeps the line count; otherwise emit it.
+    Skip,
+    /// Emit a formatter invocation for the file's language instead of a `sed`.
+    /// Falls back to `Emit` for files without a known formatter.
+    Formatter,
+}
+
+impl WhitespaceEditPolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Emit => "emit",
+            Self::Skip => "skip",
+            Self::Formatter => "formatter",
+        }
+    }
+}
+
+impl std::str::FromStr for WhitespaceEditPolicy {
+    type Err = SerializerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "emit" => Ok(Self::Emit),
+            "skip" => Ok(Self::Skip),
+            "formatter" => Ok(Self::Formatter),
+            other => Err(SerializerError::InvalidConfig(format!(
+                "unknown whitespace edit policy '{}'",
+                other
+            ))),
+        }
+    }
+}
+
+/// How to serialize terminal commands spanning several lines, which break
+/// the one-command-per-turn contract of the system prompt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MultilineCommandPolicy {
+    /// Emit the script as captured.
+    #[default]
+    Verbatim,
+    /// Emit `bash -lc '<script>'`.
+    Wrap,
+    /// Emit each line as a turn of its own. Scripts with heredocs or quotes
+    /// spanning lines are wrapped instead.
+    Split,
+}
+
+impl MultilineCommandPolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Verbatim => "verbatim",
+            Self::Wrap => "wrap",
+            Self::Split => "split",
+        }
+    }
+}
+
+impl std::str::FromStr for MultilineCommandPolicy {
+    type Err = SerializerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "verbatim" => Ok(Self::Verbatim),
+            "wrap" => Ok(Self::Wrap),
+            "split" => Ok(Self::Split),
+            other => Err(SerializerError::InvalidConfig(format!(
+                "unknown multi-line command policy '{}'",
+                other
+            ))),
+        }
+    }
+}
+
+/// `script` as a single `bash -lc` command.
+fn wrap_script(script: &str) -> String {
+    format!("bash -lc '{}'", escape_single_quotes_for_sed(script))
+}
+
+/// The commands to emit for the terminal command `command` under `policy`.
+fn normalize_multiline_command(command: String, policy: MultilineCommandPolicy) -> Vec<String> {
+    if policy == MultilineCommandPolicy::Verbatim || !command.contains('\n') {
+        return vec![command];
+    }
+    // Backslash continuations belong to the line they continue
+    let joined = command.split("\\\n").map(str::trim).collect::<Vec<_>>().join(" ");
+    let lines: Vec<&str> = joined.lines().map(str::trim).filter(|line| !line.is_empty()).collect();
+    let splittable = !joined.contains("<<")
+        && lines
+            .iter()
+            .all(|line| line.matches('\'').count() % 2 == 0 && line.matches('"').count() % 2 == 0);
+    match lines.as_slice() {
+        [] => vec![command],
+        [line] => vec![line.to_string()],
+        _ if policy == MultilineCommandPolicy::Split && splittable => {
+            lines.iter().map(|line| line.to_string()).collect()
         }
+        _ => vec![wrap_script(&command)],
     }
 }
 
+/// Formatter for a file, chosen by extension.
+fn formatter_for(file_path: &str) -> Option<&'static str> {
+    let extension = file_path.rsplit_once('.')?.1;
+    let formatter = match extension {
+        "rs" => "rustfmt",
+        "py" => "black -q",
+        "go" => "gofmt -w",
+        "ts" | "tsx" | "js" | "jsx" | "json" | "css" | "scss" | "html" | "md" | "yaml" | "yml" => {
+            "npx prettier --write"
+        }
+        "c" | "h" | "cc" | "cpp" | "hpp" => "clang-format -i",
+        _ => return None,
+    };
+    Some(formatter)
+}
+
 /// Configuration for the ConversationStateManager.
 #[derive(Debug, Clone)]
 pub struct ConversationStateManagerConfig {
     pub viewport_radius: usize,
+    /// Lines shown above the cursor line. None uses `viewport_radius`.
+    pub viewport_lines_above: Option<usize>,
+    /// Lines shown below the cursor line. None uses `viewport_radius`.
+    pub viewport_lines_below: Option<usize>,
+    /// Re-emit the viewport once the cursor comes within this many lines of
+    /// its edge (0: only when the cursor leaves it).
+    pub viewport_margin: usize,
+    /// Recenter the viewport on every k-th cursor movement, even within it.
+    /// None disables recentering.
+    pub viewport_recenter_every: Option<usize>,
+    /// Keep the viewport of a file until the cursor moved this many times
+    /// since it was emitted, suppressing re-emissions while scrolling fast.
+    pub viewport_min_movements: usize,
+    /// Keep the viewport of a file unless the new one would start at least
+    /// this many lines away from it.
+    pub viewport_min_shift: usize,
     pub coalesce_radius: usize,
     pub max_tokens_per_message: usize,
     pub max_tokens_per_terminal_output: usize,
@@ -44,26 +343,481 @@ pub struct ConversationStateManagerConfig {
     pub max_tokens_per_conversation: Option<usize>,
     /// Minimum messages required to keep a conversation chunk.
     pub min_conversation_messages: usize,
+    /// Skip token counting and per-message truncation in `append_message`.
+    /// Only takes effect when chunking is disabled; counts are then filled in
+    /// after finalization (see `pipeline::tokenize_conversations`).
+    pub defer_token_counting: bool,
+    /// Handling of content events for files that were never captured.
+    pub untracked_file_policy: UntrackedFilePolicy,
+    /// On offset drift, drop further content events for the file until the next
+    /// tab snapshot instead of padding the tracked state.
+    pub resync_on_offset_drift: bool,
+    /// Mask file captures that only repeat content shown in an earlier chunk.
+    pub mask_recaptures: bool,
+    /// Show a file unchanged since its capture in an earlier chunk as only
+    /// its first this-many lines instead of re-capturing it in full.
+    pub max_recapture_lines: Option<usize>,
+    /// Root rewrites applied to file paths, commands and terminal output.
+    pub path_mappings: Vec<PathMapping>,
+    /// Relationships to companion files (e.g. tests). After an edit of a
+    /// file is flushed, its tracked companions are shown too, unless the
+    /// conversation already shows their current content.
+    pub companion_files: Vec<CompanionPattern>,
+    /// Files whose tab, content, selection and Vim events are skipped,
+    /// matched against the recorded path.
+    pub ignored_paths: Vec<IgnorePattern>,
+    /// Track at most this many files, forgetting the least recently used
+    /// ones (files with pending edits are kept). A forgotten file is
+    /// captured again when shown. None = unbounded.
+    pub max_tracked_files: Option<usize>,
+    /// Unification of different spellings of one file path, applied before
+    /// `path_mappings`.
+    pub path_canonicalization: PathCanonicalization,
+    /// Rewrite Windows paths (`C:\a\b`) to POSIX form (`/c/a/b`) in file
+    /// paths, commands and terminal output, before `path_mappings`.
+    pub normalize_windows_paths: bool,
+    /// Handling of edits that only change whitespace.
+    pub whitespace_edit_policy: WhitespaceEditPolicy,
+    /// Handling of terminal commands spanning several lines.
+    pub multiline_commands: MultilineCommandPolicy,
+    /// Merge a terminal command into the preceding one (`cmd1 && cmd2`) when
+    /// nothing was shown in between and it ran within this many milliseconds.
+    /// None disables merging.
+    pub merge_commands_window_ms: Option<u64>,
+    /// Attach terminal output that arrives after the next command back to a
+    /// preceding command without output, when its timestamp is earlier than
+    /// the next command's or it ends with a shell-integration "command
+    /// finished" marker (OSC 633;D / 133;D).
+    pub reassociate_late_output: bool,
+    /// Assistant message appended at a `session_end` event, before the
+    /// conversation is finalized. None appends nothing.
+    pub end_of_task_marker: Option<String>,
+    /// Every this many edit commands, record a content hash of each file
+    /// shown in the conversation (see `FileCheckpoint`). None disables it.
+    pub checkpoint_every_edits: Option<usize>,
+    /// Hold events passed to `handle_event` back until events this many
+    /// `reorder_key` units newer arrived, and handle them sorted. None
+    /// handles events as they arrive.
+    pub reorder_window: Option<i64>,
+    pub reorder_key: ReorderKey,
+    /// Also finalize conversations at heuristic task boundaries of events
+    /// handled through `handle_event` (see `TaskBoundaryDetector`), and
+    /// record why each conversation ended.
+    pub task_boundaries: Option<TaskBoundaryConfig>,
+    /// Flush buffered terminal output once it reaches this many bytes,
+    /// instead of waiting for the next non-output event. Later output of the
+    /// same command joins the flushed message. None = unbounded.
+    pub max_terminal_buffer_bytes: Option<usize>,
+    /// Flush buffered terminal output once its first chunk is this many
+    /// milliseconds old, judged by `set_event_time` and `tick`. None disables.
+    pub max_terminal_buffer_age_ms: Option<u64>,
+    /// Files with more lines than this are never captured in full: the first
+    /// viewing emits `wc -l` and all reads are viewport slices. None = no limit.
+    pub large_file_line_threshold: Option<usize>,
+    /// At finalization, drop repeated identical file captures, viewport reads
+    /// superseded by the next read of the same file, and empty outputs.
+    pub compact_conversations: bool,
+    /// Show a multi-line selection as a viewport read of exactly the
+    /// selected lines, instead of only following the cursor.
+    pub emit_selection_ranges: bool,
+    /// Show a multi-line selection as a viewport centered on it, with the
+    /// usual context; takes precedence over `emit_selection_ranges`.
+    pub center_selections: bool,
+    /// Flag the selected lines of a viewport read shown for a multi-line
+    /// selection with `>` in the line number column.
+    pub annotate_selections: bool,
+    /// When a tab snapshot of a file already shown in the conversation
+    /// differs from the tracked content, serialize the difference as an edit
+    /// instead of re-capturing the whole file.
+    pub diff_stale_snapshots: bool,
+    /// Skip the view of a tab event for the file that already has focus,
+    /// unless the terminal had focus in between or the content changed.
+    pub skip_refocus_views: bool,
+    /// Flush the pending edits of other files when an edit arrives for a
+    /// different file, so interleaved edits keep their real order.
+    pub flush_on_cross_file_edit: bool,
+    /// Apply changes of a file that the last terminal command wrote (`echo >>`,
+    /// `sed -i`) silently instead of serializing them again as edits, until
+    /// the editor has focus again. Terminal editors such as `vim` do not
+    /// count: their changes are serialized as edits.
+    pub dedupe_command_writes: bool,
+    /// After a save flushed an edit, read the saved viewport again, as a
+    /// developer checking the result would.
+    pub verify_on_save: bool,
+    /// Commands showing files in the bash persona (default: always `cat -n`).
+    pub view_commands: ViewCommandMix,
+    /// Attach typing and pause timing (from `set_event_time`) to edit and
+    /// command messages.
+    pub record_timing: bool,
+    /// Queue content events of one file arriving within this many
+    /// milliseconds of each other and apply the burst in one pass, when
+    /// another event arrives or on `flush_pending`.
+    pub content_debounce_ms: Option<u64>,
+    /// Counts and samples events that could not be serialized (quiet by default).
+    pub warnings: Arc<WarningAggregator>,
 }
 
 impl Default for ConversationStateManagerConfig {
     fn default() -> Self {
         Self {
             viewport_radius: VIEWPORT_RADIUS,
+            viewport_lines_above: None,
+            viewport_lines_below: None,
+            viewport_margin: 0,
+            viewport_recenter_every: None,
+            viewport_min_movements: 0,
+            viewport_min_shift: 0,
             coalesce_radius: COALESCE_RADIUS,
             max_tokens_per_message: MAX_TOKENS_PER_MESSAGE,
             max_tokens_per_terminal_output: MAX_TOKENS_PER_TERMINAL_OUTPUT,
             max_tokens_per_conversation: None, // No chunking by default (for extension)
             min_conversation_messages: 5,
+            defer_token_counting: false,
+            untracked_file_policy: UntrackedFilePolicy::default(),
+            resync_on_offset_drift: false,
+            mask_recaptures: false,
+            max_recapture_lines: None,
+            path_mappings: Vec::new(),
+            path_canonicalization: PathCanonicalization::default(),
+            companion_files: Vec::new(),
+            ignored_paths: Vec::new(),
+            max_tracked_files: None,
+            whitespace_edit_policy: WhitespaceEditPolicy::default(),
+            multiline_commands: MultilineCommandPolicy::default(),
+            merge_commands_window_ms: None,
+            reassociate_late_output: false,
+            end_of_task_marker: None,
+            checkpoint_every_edits: None,
+            reorder_window: None,
+            reorder_key: ReorderKey::Sequence,
+            task_boundaries: None,
+            max_terminal_buffer_bytes: None,
+            max_terminal_buffer_age_ms: None,
+            large_file_line_threshold: None,
+            compact_conversations: false,
+            emit_selection_ranges: false,
+            center_selections: false,
+            annotate_selections: false,
+            diff_stale_snapshots: false,
+            skip_refocus_views: false,
+            flush_on_cross_file_edit: false,
+            dedupe_command_writes: false,
+            verify_on_save: false,
+            normalize_windows_paths: false,
+            view_commands: ViewCommandMix::default(),
+            record_timing: false,
+            content_debounce_ms: None,
+            warnings: Arc::new(WarningAggregator::default()),
+        }
+    }
+}
+
+/// Counters describing how a conversation was serialized.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConversationMetrics {
+    /// Messages truncated to fit a token budget.
+    pub truncated_messages: usize,
+    /// Viewport reads emitted for tab switches and cursor movements.
+    pub viewport_emissions: usize,
+    /// Viewport moves dropped by `viewport_min_movements` or `viewport_min_shift`.
+    pub suppressed_viewports: usize,
+    /// Content events folded into an already pending edit.
+    pub coalesced_edits: usize,
+    /// Edit commands (`sed`, formatter runs) generated from pending edits.
+    pub edit_commands: usize,
+    /// Terminal outputs truncated to `max_tokens_per_terminal_output`.
+    pub truncated_outputs: usize,
+    /// Whitespace-only edits that were skipped or serialized as a formatter run.
+    pub whitespace_only_edits: usize,
+    /// Terminal commands merged into the preceding command.
+    pub merged_commands: usize,
+    /// Multi-line terminal commands wrapped or split (`multiline_commands`).
+    pub rewritten_commands: usize,
+    /// Late terminal outputs attached to the command they belong to
+    /// (`reassociate_late_output`).
+    pub reassociated_outputs: usize,
+    /// Events that arrived after a newer event (`reorder_window`).
+    pub reordered_events: usize,
+    /// Messages removed by the compaction pass.
+    pub compacted_messages: usize,
+    /// Tokens saved by the compaction pass (0 with deferred token counting).
+    pub compacted_tokens: usize,
+    /// Messages removed by `trim_to_token_budget`.
+    pub trimmed_messages: usize,
+    /// Tracked files forgotten under `max_tracked_files`.
+    pub evicted_files: usize,
+    /// Stale tab snapshots serialized as edits (`diff_stale_snapshots`).
+    pub snapshot_diffs: usize,
+    /// File changes attributed to the last terminal command and not
+    /// serialized again (`dedupe_command_writes`).
+    pub command_write_echoes: usize,
+    /// Viewports read again after a save (`verify_on_save`).
+    pub save_verifications: usize,
+    /// Files renamed in the IDE, serialized as `mv`.
+    pub renamed_files: usize,
+    /// Changes of undo and redo events, merged into the pending edit.
+    pub undo_redo_changes: usize,
+    /// Companion files shown after an edit (`companion_files`).
+    pub companion_views: usize,
+    /// Full captures reusing the rendering and token count of an earlier
+    /// capture of the same content.
+    pub cached_captures: usize,
+    /// Re-captures shortened to `max_recapture_lines`.
+    pub shortened_recaptures: usize,
+}
+
+/// Conversations and events dropped by the state manager, by reason.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct DropStats {
+    /// Conversations with fewer than `min_conversation_messages` messages.
+    pub too_short_conversations: usize,
+    /// Long enough conversations lacking a user or an assistant message.
+    pub missing_role_conversations: usize,
+    /// Messages contained in discarded conversations.
+    pub discarded_messages: usize,
+    /// Content events ignored under `UntrackedFilePolicy::Drop`.
+    pub untracked_edits: usize,
+    /// Content events ignored while a file awaited resync after offset drift.
+    pub resync_edits: usize,
+    /// `vim_command` events that could not be translated.
+    pub unsupported_vim_commands: usize,
+    /// Editor events of files matching `ignored_paths`.
+    pub ignored_path_events: usize,
+    /// Kept conversations removed by dataset sampling or size caps.
+    pub sampled_out_conversations: usize,
+    /// Conversations rejected by pipeline filters, by reason.
+    pub filtered_conversations: BTreeMap<String, usize>,
+}
+
+impl DropStats {
+    pub fn discarded_conversations(&self) -> usize {
+        self.too_short_conversations + self.missing_role_conversations
+    }
+
+    pub fn merge(&mut self, other: &DropStats) {
+        self.too_short_conversations += other.too_short_conversations;
+        self.missing_role_conversations += other.missing_role_conversations;
+        self.discarded_messages += other.discarded_messages;
+        self.untracked_edits += other.untracked_edits;
+        self.resync_edits += other.resync_edits;
+        self.unsupported_vim_commands += other.unsupported_vim_commands;
+        self.ignored_path_events += other.ignored_path_events;
+        self.sampled_out_conversations += other.sampled_out_conversations;
+        for (reason, count) in &other.filtered_conversations {
+            *self.filtered_conversations.entry(reason.clone()).or_default() += count;
         }
     }
 }
 
 /// A finalized conversation with its token count.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct FinalizedConversation {
+    /// Stable id (see `conversation_id`), assigned by `process_session`;
+    /// empty for conversations finalized outside the pipeline.
+    pub id: String,
+    pub messages: Vec<ConversationMessage>,
+    pub token_count: usize,
+    /// Token count of each message, parallel to `messages`.
+    pub message_token_counts: Vec<usize>,
+    pub metrics: ConversationMetrics,
+    /// Languages of the files shown or edited in this conversation, sorted.
+    pub languages: Vec<String>,
+    /// Sequence numbers of the first and last event handled while this
+    /// conversation was current (see `set_event_sequence`).
+    pub first_sequence: Option<i64>,
+    pub last_sequence: Option<i64>,
+    /// Set on augmentation variants (see `PipelineConfig::augmentation`).
+    pub augmentation: Option<AugmentationInfo>,
+    /// File hashes recorded with `checkpoint_every_edits`, in order.
+    pub checkpoints: Vec<FileCheckpoint>,
+    /// Hash of the tracked content of each file shown in the conversation
+    /// when it was finalized, except files with an unflushed edit. Replay
+    /// verification compares the replayed files against it.
+    pub final_state: Vec<FileCheckpoint>,
+    /// Why the conversation ended, with `task_boundaries`; None if it ran
+    /// until the end of the session.
+    pub boundary: Option<TaskBoundary>,
+    /// Labels of `PipelineConfig::classifiers`.
+    pub labels: ConversationLabels,
+}
+
+/// Content hash of a file at some point of a conversation, so that audits
+/// can check intermediate states of a replay, not just the final one.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileCheckpoint {
+    /// Number of messages before the checkpoint.
+    pub message: usize,
+    pub file_path: String,
+    /// `stable_hash` of the file content, as 16 hex digits.
+    pub content_hash: String,
+}
+
+/// Messages `trim_to_token_budget` keeps regardless of the budget.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PinPolicy {
+    /// The latest capture and the latest view of the active file (the file
+    /// shown or edited last), with their outputs.
+    pub active_file: bool,
+    /// The last this many exchanges.
+    pub last_exchanges: usize,
+}
+
+impl Default for PinPolicy {
+    fn default() -> Self {
+        Self {
+            active_file: true,
+            last_exchanges: 1,
+        }
+    }
+}
+
+/// A prompt assembled within a token budget by `get_context_for_model`.
+#[derive(Debug, Clone, Default)]
+pub struct ModelContext {
+    pub system: String,
+    /// Kept history in order, with an `ELISION_MARKER` user message wherever
+    /// messages were left out.
     pub messages: Vec<ConversationMessage>,
+    /// Tokens of the system prompt and all messages.
     pub token_count: usize,
+    pub elided_messages: usize,
+}
+
+/// A content event whose range lies outside the tracked file content,
+/// indicating that our state drifted from the editor's.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OffsetDriftDiagnostic {
+    pub file_path: String,
+    /// Sequence number of the offending event, if known.
+    pub sequence: Option<i64>,
+    pub offset: usize,
+    pub length: usize,
+    /// Length of the tracked content at the time of the event.
+    pub tracked_length: usize,
+}
+
+/// An event held back by `reorder_window`, with its sequence number and time.
+#[derive(Debug, Clone)]
+struct QueuedEvent {
+    sequence: Option<i64>,
+    time_ms: Option<i64>,
+    event: IdeEvent<String>,
+}
+
+/// A terminal output message and the raw output it was made of.
+#[derive(Debug, Clone)]
+struct EmittedOutput {
+    raw: String,
+    value: String,
+    truncated: bool,
+}
+
+/// Where a change applied by `apply_change_event` comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ChangeSource {
+    /// A content event.
+    Typed,
+    /// An undo or redo event, joining the pending edit.
+    History,
+    /// Part of a multi-cursor edit, joining the pending edit.
+    MultiCursor,
+}
+
+/// A content event held back until the file's first snapshot arrives.
+#[derive(Debug, Clone)]
+struct BufferedEdit {
+    offset: usize,
+    length: usize,
+    new_text: String,
+}
+
+/// Whether `content` already shows the effect of `edits`, applied in order:
+/// walking back from the last edit, each edit's text is where it put it.
+fn contains_edits(content: &str, edits: &[BufferedEdit]) -> bool {
+    let mut chars: Vec<char> = content.chars().collect();
+    for edit in edits.iter().rev() {
+        let text: Vec<char> = edit.new_text.replace("\\n", "\n").replace("\\r", "\r").chars().collect();
+        let end = edit.offset.saturating_add(text.len());
+        if end > chars.len() || chars[edit.offset..end] != text[..] {
+            return false;
+        }
+        // The replaced text is unknown: stand in characters no edit matches
+        chars.splice(edit.offset..end, std::iter::repeat_n('\0', edit.length));
+    }
+    true
+}
+
+/// A content event queued under `content_debounce_ms`, with the time and
+/// sequence number it arrived with.
+#[derive(Debug, Clone)]
+struct QueuedEdit {
+    file_path: String,
+    edit: BufferedEdit,
+    time_ms: Option<i64>,
+    sequence: Option<i64>,
+}
+
+/// The most recent message, if it is a terminal command that may absorb the next one.
+#[derive(Debug, Clone)]
+struct LastCommand {
+    command: String,
+    time_ms: Option<i64>,
+    /// Whether the message before it is a command without output.
+    follows_silent_command: bool,
+}
+
+/// What the model last saw of a file in the current conversation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct CaptureRecord {
+    /// Hash of the file content as last shown.
+    content_hash: u64,
+    /// Lines last shown; `None` for a full capture.
+    range: Option<Viewport>,
+    /// Message count right after it was shown.
+    shown_at: usize,
+}
+
+/// Rendered full capture of a file, reused while its content is unchanged.
+#[derive(Debug, Clone)]
+struct CachedCapture {
+    content_hash: u64,
+    rendered: String,
+    /// Token count of `rendered`; `None` until counted.
+    tokens: Option<usize>,
+}
+
+/// Keystroke timing of a pending (coalescing) edit.
+#[derive(Debug, Clone, Copy)]
+struct EditBurst {
+    first_ms: i64,
+    last_ms: i64,
+    keystrokes: usize,
+    max_gap_ms: u64,
+    pause_before_ms: Option<u64>,
+}
+
+/// What the compaction pass needs to know of a message besides its
+/// `MessageKind`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct MessageTag {
+    /// File shown by an action: a capture, viewport read or edit.
+    file_path: Option<String>,
+    /// Whether an observation shows nothing.
+    empty: bool,
+}
+
+impl MessageTag {
+    fn file(file_path: &str) -> Self {
+        Self {
+            file_path: Some(file_path.to_string()),
+            empty: false,
+        }
+    }
+
+    fn observation(empty: bool) -> Self {
+        Self { file_path: None, empty }
+    }
 }
 
 /// Edit region tracking for coalescing nearby edits.
@@ -84,38 +838,150 @@ where
 {
     tokenizer: T,
     config: ConversationStateManagerConfig,
+    path_mapper: PathMapper,
+    path_canonicalizer: PathCanonicalizer,
+    renderer: Box<dyn Renderer>,
+    observers: Vec<Box<dyn ConversationObserver>>,
     // Current conversation being built
     messages: Vec<ConversationMessage>,
     current_tokens: usize,
+    current_message_tokens: Vec<usize>,
+    current_message_tags: Vec<MessageTag>,
+    current_metrics: ConversationMetrics,
+    current_languages: BTreeSet<String>,
+    current_checkpoints: Vec<FileCheckpoint>,
+    /// Edit commands since the last checkpoint.
+    edits_since_checkpoint: usize,
     // Finalized conversations (for chunking mode)
     finalized_conversations: Vec<FinalizedConversation>,
     // File state tracking
     file_states: HashMap<String, String>,
+    /// Last use of each file, for `max_tracked_files`.
+    file_last_used: HashMap<String, u64>,
+    file_clock: u64,
     per_file_viewport: HashMap<String, Option<Viewport>>,
-    files_opened_in_conversation: HashSet<String>,
+    per_file_selection: HashMap<String, Viewport>,
+    /// File with editor focus, from the latest tab or selection event.
+    active_file: Option<String>,
+    /// Whether the terminal had focus since `active_file` last had.
+    terminal_focused: bool,
+    /// Cursor movements since the viewport was last emitted, per file.
+    per_file_movements: HashMap<String, usize>,
+    /// Files shown in the current conversation.
+    capture_history: HashMap<String, CaptureRecord>,
+    files_shown_in_earlier_chunks: HashSet<String>,
+    /// Latest full capture of each file, across conversations.
+    capture_cache: HashMap<String, CachedCapture>,
     terminal_output_buffer: Vec<String>,
+    terminal_buffer_bytes: usize,
+    /// Event time of the oldest buffered output chunk.
+    terminal_buffer_started_ms: Option<i64>,
+    /// Last output message, which later output of the same command joins.
+    last_terminal_output: Option<EmittedOutput>,
     pending_edits_before: HashMap<String, Option<String>>,
     pending_edit_regions: HashMap<String, Option<EditRegion>>,
+    buffered_untracked_edits: HashMap<String, Vec<BufferedEdit>>,
+    /// Content events of the current burst, with `content_debounce_ms`.
+    queued_edits: Vec<QueuedEdit>,
+    /// Events held back by `reorder_window`.
+    reorder_buffer: ReorderBuffer<QueuedEvent>,
+    boundary_detector: Option<TaskBoundaryDetector>,
+    /// Why the current conversation is being finalized.
+    ending_boundary: Option<TaskBoundary>,
+    file_languages: HashMap<String, String>,
+    last_command: Option<LastCommand>,
+    /// Files written by the last terminal command, with `dedupe_command_writes`.
+    command_writes: Vec<String>,
+    current_time_ms: Option<i64>,
+    /// Time of the last earlier event with a timestamp.
+    previous_time_ms: Option<i64>,
+    /// Keystroke timing of each file's pending edit, with `record_timing`.
+    pending_edit_timing: HashMap<String, EditBurst>,
+    // Offset drift tracking
+    current_sequence: Option<i64>,
+    current_first_sequence: Option<i64>,
+    current_last_sequence: Option<i64>,
+    drift_diagnostics: Vec<OffsetDriftDiagnostic>,
+    files_awaiting_resync: HashSet<String>,
+    drop_stats: DropStats,
+    /// Checked before diffing a flushed edit.
+    interrupt: SessionInterrupt,
 }
 
 impl<T> ConversationStateManager<T>
 where
     T: Tokenizer,
 {
-    /// Create a new ConversationStateManager with the given tokenizer.
+    /// Create a new ConversationStateManager with the given tokenizer,
+    /// rendering the bash transcript persona.
     pub fn new(tokenizer: T, config: ConversationStateManagerConfig) -> Self {
+        let renderer = BashRenderer::with_view_commands(config.view_commands.clone());
+        Self::with_renderer(tokenizer, config, Box::new(renderer))
+    }
+
+    /// Create a new ConversationStateManager rendering with a custom persona.
+    pub fn with_renderer(
+        tokenizer: T,
+        config: ConversationStateManagerConfig,
+        renderer: Box<dyn Renderer>,
+    ) -> Self {
+        let reorder_buffer = ReorderBuffer::new(config.reorder_window.unwrap_or(0));
+        let boundary_detector = config.task_boundaries.map(TaskBoundaryDetector::new);
         Self {
             tokenizer,
+            path_mapper: if config.normalize_windows_paths {
+                PathMapper::new(&config.path_mappings).with_windows_normalization()
+            } else {
+                PathMapper::new(&config.path_mappings)
+            },
+            path_canonicalizer: PathCanonicalizer::new(&config.path_canonicalization),
+            renderer,
+            observers: Vec::new(),
             config,
             messages: Vec::new(),
             current_tokens: 0,
+            current_message_tokens: Vec::new(),
+            current_message_tags: Vec::new(),
+            current_metrics: ConversationMetrics::default(),
+            current_languages: BTreeSet::new(),
+            current_checkpoints: Vec::new(),
+            edits_since_checkpoint: 0,
             finalized_conversations: Vec::new(),
             file_states: HashMap::new(),
+            file_last_used: HashMap::new(),
+            file_clock: 0,
             per_file_viewport: HashMap::new(),
-            files_opened_in_conversation: HashSet::new(),
+            per_file_selection: HashMap::new(),
+            active_file: None,
+            terminal_focused: false,
+            per_file_movements: HashMap::new(),
+            capture_history: HashMap::new(),
+            files_shown_in_earlier_chunks: HashSet::new(),
+            capture_cache: HashMap::new(),
             terminal_output_buffer: Vec::new(),
+            terminal_buffer_bytes: 0,
+            terminal_buffer_started_ms: None,
+            last_terminal_output: None,
             pending_edits_before: HashMap::new(),
             pending_edit_regions: HashMap::new(),
+            buffered_untracked_edits: HashMap::new(),
+            queued_edits: Vec::new(),
+            reorder_buffer,
+            boundary_detector,
+            ending_boundary: None,
+            file_languages: HashMap::new(),
+            last_command: None,
+            command_writes: Vec::new(),
+            current_time_ms: None,
+            previous_time_ms: None,
+            pending_edit_timing: HashMap::new(),
+            current_sequence: None,
+            current_first_sequence: None,
+            current_last_sequence: None,
+            drift_diagnostics: Vec::new(),
+            files_awaiting_resync: HashSet::new(),
+            drop_stats: DropStats::default(),
+            interrupt: SessionInterrupt::default(),
         }
     }
 
@@ -123,13 +989,174 @@ where
     pub fn reset(&mut self) {
         self.messages.clear();
         self.current_tokens = 0;
+        self.current_message_tokens.clear();
+        self.current_message_tags.clear();
+        self.current_metrics = ConversationMetrics::default();
+        self.current_languages.clear();
+        self.current_checkpoints.clear();
+        self.edits_since_checkpoint = 0;
         self.finalized_conversations.clear();
         self.file_states.clear();
+        self.path_canonicalizer.clear();
+        self.file_last_used.clear();
+        self.file_clock = 0;
         self.per_file_viewport.clear();
-        self.files_opened_in_conversation.clear();
+        self.per_file_selection.clear();
+        self.active_file = None;
+        self.terminal_focused = false;
+        self.per_file_movements.clear();
+        self.capture_history.clear();
+        self.files_shown_in_earlier_chunks.clear();
+        self.capture_cache.clear();
         self.terminal_output_buffer.clear();
+        self.terminal_buffer_bytes = 0;
+        self.terminal_buffer_started_ms = None;
+        self.last_terminal_output = None;
         self.pending_edits_before.clear();
         self.pending_edit_regions.clear();
+        self.buffered_untracked_edits.clear();
+        self.queued_edits.clear();
+        self.reorder_buffer = ReorderBuffer::new(self.config.reorder_window.unwrap_or(0));
+        self.boundary_detector = self.config.task_boundaries.map(TaskBoundaryDetector::new);
+        self.ending_boundary = None;
+        self.file_languages.clear();
+        self.last_command = None;
+        self.command_writes.clear();
+        self.current_time_ms = None;
+        self.previous_time_ms = None;
+        self.pending_edit_timing.clear();
+        self.current_sequence = None;
+        self.current_first_sequence = None;
+        self.current_last_sequence = None;
+        self.drift_diagnostics.clear();
+        self.files_awaiting_resync.clear();
+        self.drop_stats = DropStats::default();
+    }
+
+    /// Set the sequence number of the event about to be handled.
+    /// Used to attribute diagnostics to the originating event.
+    pub fn set_event_sequence(&mut self, sequence: Option<i64>) {
+        self.current_sequence = sequence;
+        if sequence.is_some() {
+            self.current_first_sequence = self.current_first_sequence.or(sequence);
+            self.current_last_sequence = sequence;
+        }
+    }
+
+    /// Stop flushing edits with `Cancelled` or `TimedOut` once `interrupt`
+    /// fires. Survives `reset`.
+    pub fn set_interrupt(&mut self, interrupt: SessionInterrupt) {
+        self.interrupt = interrupt;
+    }
+
+    /// Register an observer notified of appended messages, flushed edits,
+    /// finalized conversations and truncations. Observers survive `reset`.
+    pub fn add_observer(&mut self, observer: Box<dyn ConversationObserver>) {
+        self.observers.push(observer);
+    }
+
+    /// Set the timestamp (milliseconds) of the event about to be handled.
+    /// Used for time-windowed command merging and terminal buffer aging.
+    pub fn set_event_time(&mut self, time_ms: Option<i64>) {
+        if time_ms.is_some() {
+            self.previous_time_ms = self.current_time_ms.or(self.previous_time_ms);
+        }
+        self.current_time_ms = time_ms;
+        if let Some(time_ms) = time_ms {
+            self.tick(time_ms);
+        }
+    }
+
+    /// Flush buffered terminal output that has exceeded
+    /// `max_terminal_buffer_age_ms` at `time_ms`. Lets the extension flush
+    /// output of long-running commands while no other events arrive.
+    pub fn tick(&mut self, time_ms: i64) {
+        let (Some(max_age), Some(started)) =
+            (self.config.max_terminal_buffer_age_ms, self.terminal_buffer_started_ms)
+        else {
+            return;
+        };
+        if time_ms - started >= max_age as i64 {
+            self.flush_terminal_output_buffer();
+        }
+    }
+
+    /// Record the language of a file (e.g. the CSV `Language` column).
+    /// Conversations showing or editing the file are attributed to it.
+    pub fn set_file_language(&mut self, file_path: &str, language: &str) {
+        if language.is_empty() {
+            return;
+        }
+        let file_path = self.map_file_path(file_path);
+        let file_path = file_path.as_ref();
+        if self.file_languages.get(file_path).map(String::as_str) != Some(language) {
+            self.file_languages.insert(file_path.to_string(), language.to_string());
+        }
+    }
+
+    /// Take all offset drift diagnostics recorded since the last call.
+    pub fn take_drift_diagnostics(&mut self) -> Vec<OffsetDriftDiagnostic> {
+        std::mem::take(&mut self.drift_diagnostics)
+    }
+
+    /// Counts of conversations and events dropped so far.
+    pub fn get_drop_stats(&self) -> DropStats {
+        self.drop_stats.clone()
+    }
+
+    /// The key of a recorded file path in the per-file state.
+    fn map_file_path<'a>(&mut self, file_path: &'a str) -> Cow<'a, str> {
+        match self.path_canonicalizer.canonicalize(file_path) {
+            Cow::Borrowed(path) => self.path_mapper.map_path(path),
+            Cow::Owned(path) => Cow::Owned(self.path_mapper.map_path(&path).into_owned()),
+        }
+    }
+
+    /// Like `map_file_path`, without learning the spelling of a new file.
+    fn lookup_file_path<'a>(&self, file_path: &'a str) -> Cow<'a, str> {
+        match self.path_canonicalizer.canonical(file_path) {
+            Cow::Borrowed(path) => self.path_mapper.map_path(path),
+            Cow::Owned(path) => Cow::Owned(self.path_mapper.map_path(&path).into_owned()),
+        }
+    }
+
+    /// Lines of the current non-empty selection in a file, if any.
+    pub fn get_selection(&self, file_path: &str) -> Option<Viewport> {
+        let file_path = self.lookup_file_path(file_path);
+        self.per_file_selection.get(file_path.as_ref()).copied()
+    }
+
+    /// File with editor focus, i.e. of the latest tab or selection event.
+    pub fn get_active_file(&self) -> Option<&str> {
+        self.active_file.as_deref()
+    }
+
+    /// Whether a change of `file_path` is the echo of the last terminal
+    /// command writing it, with no editor focus since.
+    fn is_command_write(&self, file_path: &str) -> bool {
+        self.terminal_focused
+            && self.command_writes.iter().any(|target| {
+                let target = target.trim_start_matches("./");
+                file_path == target || file_path.strip_suffix(target).is_some_and(|dir| dir.ends_with('/'))
+            })
+    }
+
+    /// Give `file_path` editor focus; returns whether it already had it,
+    /// with no terminal focus in between.
+    fn focus_file(&mut self, file_path: &str) -> bool {
+        let refocus = !self.terminal_focused && self.active_file.as_deref() == Some(file_path);
+        if !refocus {
+            self.active_file = Some(file_path.to_string());
+        }
+        self.terminal_focused = false;
+        refocus
+    }
+
+    /// Files whose content events are being dropped until a fresh tab snapshot arrives.
+    pub fn get_files_awaiting_resync(&self) -> Vec<String> {
+        let mut files: Vec<String> = self.files_awaiting_resync.iter().cloned().collect();
+        files.sort();
+        files
     }
 
     /// Finalize the current conversation and start a new one.
@@ -138,34 +1165,230 @@ where
         if self.messages.is_empty() {
             return;
         }
+        if self.config.compact_conversations {
+            self.compact_current_conversation();
+        }
 
         // Check if conversation meets minimum requirements
         let is_long_enough = self.messages.len() >= self.config.min_conversation_messages;
         let has_user = self.messages.iter().any(|m| m.from == "User");
         let has_assistant = self.messages.iter().any(|m| m.from == "Assistant");
+        let kept = is_long_enough && has_user && has_assistant;
+        notify_all(
+            &mut self.observers,
+            &Notification::ConversationFinalized {
+                messages: self.messages.len(),
+                token_count: self.current_tokens,
+                kept,
+            },
+        );
 
-        if is_long_enough && has_user && has_assistant {
+        if kept {
+            let final_state = self.final_state_checkpoints();
             self.finalized_conversations.push(FinalizedConversation {
+                id: String::new(),
                 messages: std::mem::take(&mut self.messages),
                 token_count: self.current_tokens,
+                message_token_counts: std::mem::take(&mut self.current_message_tokens),
+                metrics: std::mem::take(&mut self.current_metrics),
+                languages: std::mem::take(&mut self.current_languages).into_iter().collect(),
+                first_sequence: self.current_first_sequence,
+                last_sequence: self.current_last_sequence,
+                augmentation: None,
+                checkpoints: std::mem::take(&mut self.current_checkpoints),
+                final_state,
+                boundary: self.ending_boundary.take(),
+                labels: ConversationLabels::new(),
             });
         } else {
+            if !is_long_enough {
+                self.drop_stats.too_short_conversations += 1;
+            } else {
+                self.drop_stats.missing_role_conversations += 1;
+            }
+            self.drop_stats.discarded_messages += self.messages.len();
             self.messages.clear();
         }
 
         self.current_tokens = 0;
-        self.files_opened_in_conversation.clear();
+        self.current_message_tokens.clear();
+        self.current_message_tags.clear();
+        self.current_metrics = ConversationMetrics::default();
+        self.current_languages.clear();
+        self.current_checkpoints.clear();
+        self.ending_boundary = None;
+        if let Some(detector) = self.boundary_detector.as_mut() {
+            detector.conversation_finished();
+        }
+        // The event being handled continues in the next conversation
+        self.current_first_sequence = self.current_sequence;
+        self.current_last_sequence = self.current_sequence;
+        self.last_command = None;
+        self.files_shown_in_earlier_chunks
+            .extend(self.capture_history.drain().map(|(file_path, _)| file_path));
+    }
+
+    /// Drop redundant exchanges from the current conversation: a capture whose
+    /// command and output repeat the file's last capture with no edit of the
+    /// file in between, a viewport read directly followed by another viewport
+    /// read of the same file, and empty outputs that do not answer a command.
+    fn compact_current_conversation(&mut self) {
+        let n = self.messages.len();
+        let mut keep = vec![true; n];
+        let mut last_captures: HashMap<&str, (&String, &String)> = HashMap::new();
+        let has_output = |i: usize| self.is_observation(i);
+
+        for i in 0..n {
+            match self.shown_file(i) {
+                Some((MessageKind::FileCapture, file_path)) if has_output(i + 1) => {
+                    let exchange = (&self.messages[i].value, &self.messages[i + 1].value);
+                    if last_captures.insert(file_path, exchange) == Some(exchange) {
+                        keep[i] = false;
+                        keep[i + 1] = false;
+                    }
+                }
+                Some((MessageKind::EditCommand, file_path)) => {
+                    last_captures.remove(file_path);
+                }
+                // Superseded by a viewport read of the same file
+                Some((MessageKind::Viewport, file_path))
+                    if has_output(i + 1) && self.shown_file(i + 2) == Some((MessageKind::Viewport, file_path)) =>
+                {
+                    keep[i] = false;
+                    keep[i + 1] = false;
+                }
+                None if self.current_message_tags[i].empty
+                    && has_output(i)
+                    && i > 0
+                    && self.messages[i - 1].from != "Assistant" =>
+                {
+                    keep[i] = false
+                }
+                _ => {}
+            }
+        }
+
+        if keep.iter().all(|&k| k) {
+            return;
+        }
+        self.remap_checkpoints(&keep);
+        let messages = std::mem::take(&mut self.messages);
+        let tokens = std::mem::take(&mut self.current_message_tokens);
+        let tags = std::mem::take(&mut self.current_message_tags);
+        for (((message, tokens), tag), keep) in messages.into_iter().zip(tokens).zip(tags).zip(keep) {
+            if keep {
+                self.messages.push(message);
+                self.current_message_tokens.push(tokens);
+                self.current_message_tags.push(tag);
+            } else {
+                self.current_tokens -= tokens;
+                self.current_metrics.compacted_messages += 1;
+                self.current_metrics.compacted_tokens += tokens;
+            }
+        }
+    }
+
+    /// Kind of the action at `i` of the current conversation and the file it
+    /// shows, if it shows one.
+    fn shown_file(&self, i: usize) -> Option<(MessageKind, &str)> {
+        let file_path = self.current_message_tags.get(i)?.file_path.as_deref()?;
+        Some((self.messages[i].kind.unwrap_or(MessageKind::Other), file_path))
+    }
+
+    /// Whether the message at `i` of the current conversation is an observation.
+    fn is_observation(&self, i: usize) -> bool {
+        self.messages.get(i).is_some_and(|message| message.from != "Assistant")
     }
 
     /// Get all finalized conversations with their token counts.
     /// Call this after processing all events.
-    pub fn get_conversations(&mut self) -> Vec<FinalizedConversation> {
+    pub fn get_conversations(&mut self) -> Result<Vec<FinalizedConversation>, SerializerError> {
         // Finalize any remaining conversation
-        self.flush_all_pending_edits();
+        self.flush_reordered_events()?;
+        self.flush_all_pending_edits()?;
+        self.flush_terminal_output_buffer();
+        self.finalize_current_conversation();
+
+        Ok(std::mem::take(&mut self.finalized_conversations))
+    }
+
+    /// Record a use of `file_path` and, with `max_tracked_files`, forget the
+    /// least recently used other files so that it fits.
+    fn touch_file(&mut self, file_path: &str) {
+        self.file_clock += 1;
+        self.file_last_used.insert(file_path.to_string(), self.file_clock);
+        let Some(max_files) = self.config.max_tracked_files else {
+            return;
+        };
+        let others = self.file_states.len() - self.file_states.contains_key(file_path) as usize;
+        let excess = (others + 1).saturating_sub(max_files.max(1));
+        if excess == 0 {
+            return;
+        }
+        let mut candidates: Vec<(u64, String)> = self
+            .file_states
+            .keys()
+            .filter(|f| f.as_str() != file_path && !matches!(self.pending_edits_before.get(*f), Some(Some(_))))
+            .map(|f| (self.file_last_used.get(f).copied().unwrap_or(0), f.clone()))
+            .collect();
+        candidates.sort();
+        for (_, evicted) in candidates.into_iter().take(excess) {
+            self.forget_file(&evicted);
+            self.current_metrics.evicted_files += 1;
+        }
+        let file_states = &self.file_states;
+        self.file_last_used
+            .retain(|f, _| f == file_path || file_states.contains_key(f));
+    }
+
+    /// Drop all state of a file, as if it had never been shown.
+    fn forget_file(&mut self, file_path: &str) {
+        self.file_states.remove(file_path);
+        self.file_last_used.remove(file_path);
+        self.per_file_viewport.remove(file_path);
+        self.per_file_selection.remove(file_path);
+        self.per_file_movements.remove(file_path);
+        self.capture_history.remove(file_path);
+        self.capture_cache.remove(file_path);
+        self.files_awaiting_resync.remove(file_path);
+        self.file_languages.remove(file_path);
+        self.pending_edits_before.remove(file_path);
+        self.pending_edit_regions.remove(file_path);
+        self.pending_edit_timing.remove(file_path);
+        self.files_shown_in_earlier_chunks.remove(file_path);
+    }
+
+    /// Whether `file_path` matches `ignored_paths`, without counting an event.
+    fn matches_ignored_path(&self, file_path: &str) -> bool {
+        self.config.ignored_paths.iter().any(|p| p.matches(file_path))
+    }
+
+    /// Whether events of `file_path` are skipped under `ignored_paths`;
+    /// counts the skipped event.
+    fn is_ignored_path(&mut self, file_path: &str) -> bool {
+        let ignored = self.matches_ignored_path(file_path);
+        if ignored {
+            self.drop_stats.ignored_path_events += 1;
+        }
+        ignored
+    }
+
+    /// Finalize the current conversation now, e.g. when a task is done, and
+    /// start a new one; file states carry over.
+    ///
+    /// Returns the finalized conversation, or `None` if it was empty or too
+    /// short to keep. It is not returned again by `get_conversations`.
+    pub fn force_finalize(&mut self) -> Result<Option<FinalizedConversation>, SerializerError> {
+        self.flush_reordered_events()?;
+        self.flush_all_pending_edits()?;
         self.flush_terminal_output_buffer();
+        let finalized = self.finalized_conversations.len();
         self.finalize_current_conversation();
-        
-        std::mem::take(&mut self.finalized_conversations)
+        if self.finalized_conversations.len() > finalized {
+            Ok(self.finalized_conversations.pop())
+        } else {
+            Ok(None)
+        }
     }
 
     /// Get a copy of all messages.
@@ -173,28 +1396,87 @@ where
         self.messages.clone()
     }
 
-    /// Get the current content of a file.
+    /// Number of messages in the current conversation.
+    pub fn message_count(&self) -> usize {
+        self.messages.len()
+    }
+
+    /// Token count of the current conversation.
+    pub fn token_count(&self) -> usize {
+        self.current_tokens
+    }
+
+    /// Paths of all files whose content is tracked, sorted.
+    pub fn tracked_files(&self) -> Vec<String> {
+        let mut files: Vec<String> = self.file_states.keys().cloned().collect();
+        files.sort();
+        files
+    }
+
+    /// Get the current content of a file, without queued content events.
     pub fn get_file_content(&self, file_path: &str) -> String {
-        self.file_states.get(file_path).cloned().unwrap_or_default()
+        let file_path = self.lookup_file_path(file_path);
+        self.file_states.get(file_path.as_ref()).cloned().unwrap_or_default()
     }
 
     /// Append a message, truncating if it exceeds token limits.
     /// If chunking is enabled and conversation limit would be exceeded,
     /// finalizes current conversation and starts a new one.
-    fn append_message(&mut self, mut message: ConversationMessage) {
-        let mut tokens = self.tokenizer.count_tokens(&message.value);
-        
+    fn append_message(&mut self, message: ConversationMessage) {
+        self.append_tagged_message(message, MessageTag::default());
+    }
+
+    fn append_tagged_message(&mut self, message: ConversationMessage, tag: MessageTag) {
+        self.append_counted_message(message, tag, None);
+    }
+
+    /// Append a message whose untruncated token count may already be known.
+    /// Returns the untruncated count, if it was counted.
+    fn append_counted_message(
+        &mut self,
+        mut message: ConversationMessage,
+        tag: MessageTag,
+        known_tokens: Option<usize>,
+    ) -> Option<usize> {
+        self.last_command = None;
+        message.kind.get_or_insert(MessageKind::Other);
+        if self.config.defer_token_counting && self.config.max_tokens_per_conversation.is_none() {
+            // Token counting happens after finalization
+            self.messages.push(message);
+            self.current_message_tokens.push(0);
+            self.current_message_tags.push(tag);
+            self.notify_appended(0);
+            return None;
+        }
+
+        let counted = known_tokens.unwrap_or_else(|| self.tokenizer.count_tokens(&message.value));
+        let mut tokens = counted;
+        let mut truncated = false;
+
         if tokens > self.config.max_tokens_per_message {
-            message.value = self.tokenizer.truncate_to_max_tokens(
+            notify_all(
+                &mut self.observers,
+                &Notification::Truncated {
+                    role: &message.from,
+                    original_tokens: tokens,
+                    max_tokens: self.config.max_tokens_per_message,
+                },
+            );
+            message.value = truncate_with_marker(
+                &self.tokenizer,
                 &message.value,
                 self.config.max_tokens_per_message,
             );
             tokens = self.config.max_tokens_per_message;
+            truncated = true;
         }
 
         // Check if we need to start a new conversation (chunking mode)
         if let Some(max_tokens) = self.config.max_tokens_per_conversation {
             if self.current_tokens + tokens > max_tokens && !self.messages.is_empty() {
+                if self.boundary_detector.is_some() {
+                    self.ending_boundary = Some(TaskBoundary::TokenLimit);
+                }
                 self.finalize_current_conversation();
                 // After starting a new conversation, we need to re-capture file states
                 // This will happen naturally as files are accessed
@@ -202,438 +1484,3484 @@ where
         }
 
         self.messages.push(message);
+        self.current_message_tokens.push(tokens);
+        self.current_message_tags.push(tag);
         self.current_tokens += tokens;
+        if truncated {
+            self.current_metrics.truncated_messages += 1;
+        }
+        self.notify_appended(tokens);
+        Some(counted)
     }
 
-    /// Capture file contents if not already shown in this conversation.
-    fn maybe_capture_file_contents(&mut self, file_path: &str, content: &str) {
-        if self.files_opened_in_conversation.contains(file_path) {
-            return;
+    fn notify_appended(&mut self, tokens: usize) {
+        if let Some(message) = self.messages.last() {
+            notify_all(
+                &mut self.observers,
+                &Notification::MessageAppended { message, tokens },
+            );
         }
-        let cmd = format!("cat -n {}", file_path);
-        self.append_message(ConversationMessage::assistant(fenced_block(
-            Some("bash"),
-            &clean_text(&cmd),
-        )));
-        let output = line_numbered_output(content, None, None);
-        self.append_message(ConversationMessage::user(format!(
-            "<stdout>\n{}\n</stdout>",
-            output
-        )));
-        self.files_opened_in_conversation.insert(file_path.to_string());
     }
 
-    /// Flush buffered terminal output.
-    pub fn flush_terminal_output_buffer(&mut self) {
-        if self.terminal_output_buffer.is_empty() {
-            return;
-        }
-        let aggregated: String = self.terminal_output_buffer.join("");
-        let out = normalize_terminal_output(&aggregated);
-        let mut cleaned = clean_text(&out);
+    /// Render and append an assistant action.
+    fn append_action(&mut self, action: &Action<'_>, mask: Option<bool>) {
+        let (value, span) = self.renderer.render_action_with_span(action);
+        let tag = match *action {
+            Action::ReadFile { path, .. } | Action::CountLines { path } | Action::EditFile { path, .. } => {
+                MessageTag::file(path)
+            }
+            _ => MessageTag::default(),
+        };
+        let kind = match action {
+            Action::ReadFile { range: None, .. } | Action::CountLines { .. } => MessageKind::FileCapture,
+            Action::ReadFile { range: Some(_), .. } => MessageKind::Viewport,
+            Action::EditFile { .. } => MessageKind::EditCommand,
+            Action::RunCommand { .. } => MessageKind::TerminalCommand,
+            Action::ListDirectory { .. } => MessageKind::DirectoryListing,
+            Action::MoveFile { .. } => MessageKind::TerminalCommand,
+            Action::CheckoutBranch { .. } => MessageKind::GitAction,
+        };
+        let message = ConversationMessage::assistant(value)
+            .with_mask(mask)
+            .with_kind(kind)
+            .with_loss_span(span);
+        self.append_tagged_message(message, tag);
+    }
 
-        let tokens = self.tokenizer.count_tokens(&cleaned);
-        if tokens > self.config.max_tokens_per_terminal_output {
-            let truncated = self.tokenizer.truncate_to_max_tokens(
-                &cleaned,
-                self.config.max_tokens_per_terminal_output,
-            );
-            cleaned = format!("{}\n... [truncated]", truncated);
+    /// Kind of `observation`, answering the last message.
+    fn observation_kind(&self, observation: &Observation<'_>) -> MessageKind {
+        let previous = self.messages.last().and_then(|m| m.kind);
+        match observation {
+            Observation::CommandOutput { .. } if previous == Some(MessageKind::GitAction) => MessageKind::GitAction,
+            Observation::CommandOutput { .. } => MessageKind::TerminalOutput,
+            Observation::DirectoryListing { .. } => MessageKind::DirectoryListing,
+            Observation::FileContent { range: Some(_), .. } if previous == Some(MessageKind::EditCommand) => {
+                MessageKind::EditCommand
+            }
+            Observation::FileContent { range: Some(_), .. } => MessageKind::Viewport,
+            Observation::FileContent { range: None, .. }
+            | Observation::UnknownFileContent
+            | Observation::LineCount { .. } => MessageKind::FileCapture,
         }
+    }
 
-        if !cleaned.trim().is_empty() {
-            self.append_message(ConversationMessage::user(format!(
-                "<stdout>\n{}\n</stdout>",
-                cleaned
-            )));
-        }
-        self.terminal_output_buffer.clear();
+    /// Render and append a user observation.
+    fn emit_observation(&mut self, observation: &Observation<'_>, mask: Option<bool>) {
+        let value = self.renderer.render_observation(observation);
+        let tag = MessageTag::observation(observation.is_empty());
+        let kind = self.observation_kind(observation);
+        self.append_tagged_message(ConversationMessage::user(value).with_mask(mask).with_kind(kind), tag);
     }
 
-    /// Flush pending edits for a specific file.
-    pub fn flush_pending_edit_for_file(&mut self, target_file: &str) {
+    /// Attribute the current conversation to the language of `file_path`, if known.
+    fn note_file_language(&mut self, file_path: &str) {
+        if let Some(language) = self.file_languages.get(file_path) {
+            if !self.current_languages.contains(language) {
+                self.current_languages.insert(language.clone());
+            }
+        }
+    }
+
+    /// Remember that `content` (or its `range`) of a file was just shown.
+    fn record_shown(&mut self, file_path: &str, content: &str, range: Option<Viewport>) {
+        let record = CaptureRecord {
+            content_hash: stable_hash(content.as_bytes()),
+            range,
+            shown_at: self.messages.len(),
+        };
+        self.capture_history.insert(file_path.to_string(), record);
+    }
+
+    /// Whether exactly `content` of the file was shown in this conversation.
+    fn shown_unchanged(&self, file_path: &str, content: &str) -> bool {
+        self.capture_history
+            .get(file_path)
+            .is_some_and(|record| record.content_hash == stable_hash(content.as_bytes()))
+    }
+
+    /// Capture file contents if not already shown in this conversation.
+    fn maybe_capture_file_contents(&mut self, file_path: &str, content: &str) {
+        if self.capture_history.contains_key(file_path) {
+            return;
+        }
+        // Re-captures after a chunk split repeat what the model already saw
+        let mask = (self.config.mask_recaptures
+            && self.files_shown_in_earlier_chunks.contains(file_path))
+        .then_some(true);
+        if !self.shorten_recapture(file_path, content, mask) {
+            self.emit_capture(file_path, content, mask);
+        }
+    }
+
+    /// With `max_recapture_lines`, show a file unchanged since its capture in
+    /// an earlier chunk as only its first lines. Returns whether it did.
+    fn shorten_recapture(&mut self, file_path: &str, content: &str, mask: Option<bool>) -> bool {
+        let Some(max_lines) = self.config.max_recapture_lines else {
+            return false;
+        };
+        let unchanged = self.files_shown_in_earlier_chunks.contains(file_path)
+            && self
+                .capture_cache
+                .get(file_path)
+                .is_some_and(|cached| cached.content_hash == stable_hash(content.as_bytes()));
+        if !unchanged || content.split('\n').count() <= max_lines {
+            return false;
+        }
+        let range = Some(Viewport {
+            start: 1,
+            end: max_lines.max(1),
+        });
+        self.append_action(&Action::ReadFile { path: file_path, range }, mask);
+        self.emit_observation(&Observation::FileContent { content, range, marked: None }, mask);
+        self.record_shown(file_path, content, range);
+        self.current_metrics.shortened_recaptures += 1;
+        true
+    }
+
+    /// Append the full listing of a file, reusing the rendering and token
+    /// count of the file's last capture if the content is the same.
+    fn append_file_content(&mut self, file_path: &str, content: &str, mask: Option<bool>) {
+        let content_hash = stable_hash(content.as_bytes());
+        let observation = Observation::FileContent {
+            content,
+            range: None,
+            marked: None,
+        };
+        let tag = MessageTag::observation(observation.is_empty());
+        let (rendered, known_tokens) = match self.capture_cache.get(file_path) {
+            Some(cached) if cached.content_hash == content_hash => {
+                self.current_metrics.cached_captures += 1;
+                (cached.rendered.clone(), cached.tokens)
+            }
+            _ => (self.renderer.render_observation(&observation), None),
+        };
+        let message = ConversationMessage::user(rendered.clone())
+            .with_mask(mask)
+            .with_kind(MessageKind::FileCapture);
+        let tokens = self.append_counted_message(message, tag, known_tokens).or(known_tokens);
+        self.capture_cache.insert(
+            file_path.to_string(),
+            CachedCapture {
+                content_hash,
+                rendered,
+                tokens,
+            },
+        );
+    }
+
+    fn is_large_file(&self, content: &str) -> bool {
+        self.config
+            .large_file_line_threshold
+            .is_some_and(|threshold| content.split('\n').count() > threshold)
+    }
+
+    /// Emit the full `cat -n` listing of a file, or only its `wc -l` line count
+    /// for large files.
+    fn emit_capture(&mut self, file_path: &str, content: &str, mask: Option<bool>) {
+        if self.is_large_file(content) {
+            self.append_action(&Action::CountLines { path: file_path }, mask);
+            self.emit_observation(
+                &Observation::LineCount {
+                    path: file_path,
+                    lines: content.matches('\n').count(),
+                },
+                mask,
+            );
+        } else {
+            self.append_action(&Action::ReadFile { path: file_path, range: None }, mask);
+            self.append_file_content(file_path, content, mask);
+        }
+        self.record_shown(file_path, content, None);
+    }
+
+    /// Emit a viewport read of `content`, capturing the full file first if needed.
+    fn emit_viewport(&mut self, file_path: &str, content: &str, vp: Viewport) {
+        self.emit_marked_viewport(file_path, content, vp, None);
+    }
+
+    /// `emit_viewport`, flagging the `marked` lines in the output.
+    fn emit_marked_viewport(&mut self, file_path: &str, content: &str, vp: Viewport, marked: Option<Viewport>) {
+        self.maybe_capture_file_contents(file_path, content);
+        let range = Some(vp);
+        // Nothing was appended since the same lines were shown
+        let repeated = self.capture_history.get(file_path).is_some_and(|record| {
+            record.range == range
+                && record.shown_at == self.messages.len()
+                && record.content_hash == stable_hash(content.as_bytes())
+        });
+        if repeated {
+            return;
+        }
+        self.read_viewport(file_path, content, vp, marked);
+    }
+
+    /// Emit a viewport read of `content`, even if it repeats the last one.
+    fn read_viewport(&mut self, file_path: &str, content: &str, vp: Viewport, marked: Option<Viewport>) {
+        let range = Some(vp);
+        self.append_action(&Action::ReadFile { path: file_path, range }, None);
+        self.emit_observation(&Observation::FileContent { content, range, marked }, None);
+        self.record_shown(file_path, content, range);
+        self.current_metrics.viewport_emissions += 1;
+        self.note_file_language(file_path);
+    }
+
+    /// Emit a placeholder capture for a file whose content was never observed,
+    /// and start tracking it as empty.
+    fn capture_unknown_file(&mut self, file_path: &str) {
+        self.flush_terminal_output_buffer();
+        self.append_action(&Action::ReadFile { path: file_path, range: None }, None);
+        self.emit_observation(&Observation::UnknownFileContent, None);
+        self.record_shown(file_path, "", None);
+        self.file_states.insert(file_path.to_string(), String::new());
+        self.note_file_language(file_path);
+    }
+
+    /// Flush buffered terminal output.
+    pub fn flush_terminal_output_buffer(&mut self) {
+        if self.terminal_output_buffer.is_empty() {
+            return;
+        }
+        let mut aggregated: String = self.terminal_output_buffer.join("");
+        // Output flushed early by size or age continues in the same message
+        let continued = self
+            .last_terminal_output
+            .take()
+            .filter(|output| self.messages.last().is_some_and(|m| m.value == output.value));
+        if let Some(output) = &continued {
+            self.pop_message();
+            if output.truncated {
+                self.current_metrics.truncated_messages -= 1;
+                self.current_metrics.truncated_outputs -= 1;
+            }
+            aggregated.insert_str(0, &output.raw);
+        }
+        let out = normalize_terminal_output(&aggregated);
+        let mut cleaned = clean_text(&out);
+
+        let tokens = self.tokenizer.count_tokens(&cleaned);
+        let truncated = tokens > self.config.max_tokens_per_terminal_output;
+        if truncated {
+            notify_all(
+                &mut self.observers,
+                &Notification::Truncated {
+                    role: "User",
+                    original_tokens: tokens,
+                    max_tokens: self.config.max_tokens_per_terminal_output,
+                },
+            );
+            cleaned = truncate_with_marker(
+                &self.tokenizer,
+                &cleaned,
+                self.config.max_tokens_per_terminal_output,
+            );
+        }
+
+        if !cleaned.trim().is_empty() {
+            self.emit_observation(&Observation::CommandOutput { output: &cleaned }, None);
+            if truncated {
+                self.current_metrics.truncated_messages += 1;
+                self.current_metrics.truncated_outputs += 1;
+            }
+            self.last_terminal_output = self.messages.last().map(|message| EmittedOutput {
+                raw: aggregated,
+                value: message.value.clone(),
+                truncated,
+            });
+        }
+        self.terminal_output_buffer.clear();
+        self.terminal_buffer_bytes = 0;
+        self.terminal_buffer_started_ms = None;
+    }
+
+    /// Flush pending edits for a specific file.
+    pub fn flush_pending_edit_for_file(&mut self, target_file: &str) -> Result<(), SerializerError> {
+        let target_file = self.map_file_path(target_file);
+        let target_file = target_file.as_ref();
+        let burst = self.pending_edit_timing.remove(target_file);
         let before_snapshot = match self.pending_edits_before.get(target_file) {
             Some(Some(s)) => s.clone(),
-            _ => return,
+            _ => return Ok(()),
         };
 
         let after_state = self.file_states.get(target_file).cloned().unwrap_or_default();
 
-        if before_snapshot.trim_end_matches('\n') == after_state.trim_end_matches('\n') {
-            self.pending_edits_before.insert(target_file.to_string(), None);
-            self.pending_edit_regions.insert(target_file.to_string(), None);
-            return;
-        }
+        if before_snapshot.trim_end_matches('\n') == after_state.trim_end_matches('\n') {
+            self.pending_edits_before.insert(target_file.to_string(), None);
+            self.pending_edit_regions.insert(target_file.to_string(), None);
+            return Ok(());
+        }
+
+        let changed = compute_changed_block_lines_until(&before_snapshot, &after_state, &self.interrupt)?;
+
+        let mut formatter = None;
+        if self.config.whitespace_edit_policy != WhitespaceEditPolicy::Emit
+            && whitespace_only_hunks(&before_snapshot, &after_state)
+        {
+            // Without the edit, the model's line numbers would go stale
+            let same_lines = before_snapshot.split('\n').count() == after_state.split('\n').count();
+            match self.config.whitespace_edit_policy {
+                WhitespaceEditPolicy::Skip if !same_lines => {}
+                WhitespaceEditPolicy::Skip => {
+                    self.current_metrics.whitespace_only_edits += 1;
+                    self.pending_edits_before.insert(target_file.to_string(), None);
+                    self.pending_edit_regions.insert(target_file.to_string(), None);
+                    return Ok(());
+                }
+                _ => formatter = formatter_for(target_file),
+            }
+        }
+
+        let before_total_lines = before_snapshot.split('\n').count();
+        let lines = changed.replacement_lines.as_slice();
+        let edit = if let Some(formatter) = formatter {
+            self.current_metrics.whitespace_only_edits += 1;
+            FileEdit::Format { formatter }
+        } else if changed.end_before < changed.start_before {
+            // Pure insertion
+            if changed.start_before <= before_total_lines.max(1) {
+                FileEdit::Insert {
+                    before_line: changed.start_before,
+                    lines,
+                }
+            } else {
+                FileEdit::Append { lines }
+            }
+        } else if lines.is_empty() {
+            // Pure deletion
+            FileEdit::Delete {
+                start: changed.start_before,
+                end: changed.end_before,
+            }
+        } else {
+            // Replacement
+            FileEdit::Replace {
+                start: changed.start_before,
+                end: changed.end_before,
+                lines,
+            }
+        };
+
+        let center = (changed.start_after + changed.end_after) / 2;
+        let timing = burst.map(|burst| MessageTiming {
+            typing_ms: Some(burst.last_ms.abs_diff(burst.first_ms)),
+            keystrokes: Some(burst.keystrokes),
+            max_keystroke_gap_ms: Some(burst.max_gap_ms),
+            pause_before_ms: burst.pause_before_ms,
+        });
+        self.emit_file_edit(target_file, &before_snapshot, &after_state, edit, center, timing);
+
+        self.pending_edits_before.insert(target_file.to_string(), None);
+        self.pending_edit_regions.insert(target_file.to_string(), None);
+        self.show_companions(target_file);
+        Ok(())
+    }
+
+    /// Show the tracked companions of an edited file whose current content
+    /// the conversation does not show yet: a capture if the companion was not
+    /// shown at all, otherwise its last viewport.
+    fn show_companions(&mut self, file_path: &str) {
+        let companions: Vec<String> = self
+            .config
+            .companion_files
+            .iter()
+            .filter_map(|pattern| pattern.companion_of(file_path))
+            .collect();
+        for companion in companions {
+            let Some(content) = self.file_states.get(&companion).cloned() else {
+                continue;
+            };
+            let has_pending_edit = self.pending_edits_before.get(&companion).is_some_and(Option::is_some);
+            let shown = self.capture_history.get(&companion).map(|record| record.content_hash);
+            if has_pending_edit || shown == Some(stable_hash(content.as_bytes())) {
+                continue;
+            }
+            self.touch_file(&companion);
+            if shown.is_none() {
+                self.maybe_capture_file_contents(&companion, &content);
+            } else {
+                let total_lines = content.split('\n').count();
+                let vp = self
+                    .per_file_viewport
+                    .get(&companion)
+                    .copied()
+                    .flatten()
+                    .unwrap_or_else(|| self.viewport_around(total_lines, 1));
+                self.per_file_viewport.insert(companion.clone(), Some(vp));
+                self.read_viewport(&companion, &content, vp, None);
+            }
+            self.current_metrics.companion_views += 1;
+        }
+    }
+
+    /// Emit an edit command turning `before` into `after`, followed by a view
+    /// of `after` centered on `center_line`.
+    fn emit_file_edit(
+        &mut self,
+        file_path: &str,
+        before: &str,
+        after: &str,
+        edit: FileEdit<'_>,
+        center_line: usize,
+        timing: Option<MessageTiming>,
+    ) {
+        let total_lines = after.split('\n').count();
+        let vp = self.viewport_around(total_lines, center_line);
+        self.per_file_viewport
+            .insert(file_path.to_string(), Some(vp));
+
+        self.maybe_capture_file_contents(file_path, before);
+
+        let action = Action::EditFile {
+            path: file_path,
+            edit,
+            view: vp,
+        };
+        let (command, span) = self.renderer.render_action_with_span(&action);
+        notify_all(
+            &mut self.observers,
+            &Notification::EditFlushed {
+                file_path,
+                command: &command,
+            },
+        );
+        let message = ConversationMessage::assistant(command)
+            .with_timing(timing)
+            .with_kind(MessageKind::EditCommand)
+            .with_loss_span(span);
+        self.append_tagged_message(message, MessageTag::file(file_path));
+        self.current_metrics.edit_commands += 1;
+        self.emit_observation(
+            &Observation::FileContent {
+                content: after,
+                range: Some(vp),
+                marked: None,
+            },
+            None,
+        );
+        self.record_shown(file_path, after, Some(vp));
+        self.note_file_language(file_path);
+        self.note_checkpoint_edit();
+    }
+
+    /// Count an edit command towards `checkpoint_every_edits` and record the
+    /// hashes of the files shown so far once it is due.
+    fn note_checkpoint_edit(&mut self) {
+        let Some(every) = self.config.checkpoint_every_edits else {
+            return;
+        };
+        self.edits_since_checkpoint += 1;
+        if self.edits_since_checkpoint < every.max(1) {
+            return;
+        }
+        self.edits_since_checkpoint = 0;
+        let mut files: Vec<&String> = self.capture_history.keys().collect();
+        files.sort();
+        for file_path in files {
+            let Some(content) = self.file_states.get(file_path) else {
+                continue;
+            };
+            self.current_checkpoints.push(FileCheckpoint {
+                message: self.messages.len(),
+                file_path: file_path.clone(),
+                content_hash: format!("{:016x}", stable_hash(content.as_bytes())),
+            });
+        }
+    }
+
+    /// Checkpoints of the files shown in the current conversation, at its end.
+    fn final_state_checkpoints(&self) -> Vec<FileCheckpoint> {
+        let mut files: Vec<&String> = self
+            .capture_history
+            .keys()
+            .filter(|file_path| !matches!(self.pending_edits_before.get(*file_path), Some(Some(_))))
+            .collect();
+        files.sort();
+        files
+            .into_iter()
+            .filter_map(|file_path| {
+                let content = self.file_states.get(file_path)?;
+                Some(FileCheckpoint {
+                    message: self.messages.len(),
+                    file_path: file_path.clone(),
+                    content_hash: format!("{:016x}", stable_hash(content.as_bytes())),
+                })
+            })
+            .collect()
+    }
+
+    /// Move checkpoints to their positions after removing the messages not
+    /// in `keep`.
+    fn remap_checkpoints(&mut self, keep: &[bool]) {
+        for checkpoint in &mut self.current_checkpoints {
+            checkpoint.message = keep[..checkpoint.message].iter().filter(|&&k| k).count();
+        }
+    }
+
+    /// Flush all pending edits.
+    pub fn flush_all_pending_edits(&mut self) -> Result<(), SerializerError> {
+        self.flush_pending()?;
+        let files: Vec<String> = self.pending_edits_before.keys().cloned().collect();
+        for file in files {
+            self.flush_pending_edit_for_file(&file)?;
+        }
+        Ok(())
+    }
+
+    /// Handle an IDE event. With `reorder_window`, the event is held back and
+    /// handled once it left the window, after any earlier events arriving
+    /// late (see `ReorderBuffer`).
+    ///
+    /// The `handle_*_event` methods handle an event of one type right away.
+    pub fn handle_event<S: AsRef<str>>(&mut self, event: IdeEvent<S>) -> Result<(), SerializerError> {
+        if self.config.reorder_window.is_none() {
+            return self.dispatch_event(event);
+        }
+        let key = match self.config.reorder_key {
+            ReorderKey::Sequence => self.current_sequence,
+            ReorderKey::Time => self.current_time_ms,
+        };
+        let Some(key) = key else {
+            // Events without a key cannot be ordered: handle everything now
+            self.flush_reordered_events()?;
+            return self.dispatch_event(event);
+        };
+        let queued = QueuedEvent {
+            sequence: self.current_sequence,
+            time_ms: self.current_time_ms,
+            event: event.to_owned_event(),
+        };
+        let reordered = self.reorder_buffer.reordered();
+        let released = self.reorder_buffer.push(key, event.priority(), queued);
+        self.current_metrics.reordered_events += self.reorder_buffer.reordered() - reordered;
+        released.into_iter().try_for_each(|queued| self.dispatch_queued(queued))
+    }
+
+    /// Handle all events held back by `reorder_window`.
+    pub fn flush_reordered_events(&mut self) -> Result<(), SerializerError> {
+        let (sequence, time_ms) = (self.current_sequence, self.current_time_ms);
+        for queued in self.reorder_buffer.drain() {
+            self.dispatch_queued(queued)?;
+        }
+        self.current_sequence = sequence;
+        self.current_time_ms = time_ms;
+        Ok(())
+    }
+
+    /// Handle a held back event with the sequence number and time it arrived with.
+    fn dispatch_queued(&mut self, queued: QueuedEvent) -> Result<(), SerializerError> {
+        self.set_event_sequence(queued.sequence);
+        self.set_event_time(queued.time_ms);
+        self.dispatch_event(queued.event)
+    }
+
+    fn dispatch_event<S: AsRef<str>>(&mut self, event: IdeEvent<S>) -> Result<(), SerializerError> {
+        let time_ms = self.current_time_ms;
+        if let Some(boundary) = self
+            .boundary_detector
+            .as_mut()
+            .and_then(|detector| detector.before_event(event.kind(), time_ms))
+        {
+            self.split_at_boundary(boundary)?;
+        }
+        self.dispatch_to_handler(&event)?;
+        if let Some(detector) = self.boundary_detector.as_mut() {
+            match &event {
+                IdeEvent::TerminalCommand { command } => detector.command(command.as_ref()),
+                IdeEvent::TerminalOutput { output } => detector.output(output.as_ref()),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Finalize the current conversation at a task boundary, unless it is
+    /// still too short to be kept.
+    fn split_at_boundary(&mut self, boundary: TaskBoundary) -> Result<(), SerializerError> {
+        self.flush_all_pending_edits()?;
+        self.flush_terminal_output_buffer();
+        if self.messages.len() < self.config.min_conversation_messages.max(1) {
+            return Ok(());
+        }
+        self.ending_boundary = Some(boundary);
+        self.finalize_current_conversation();
+        Ok(())
+    }
+
+    fn dispatch_to_handler<S: AsRef<str>>(&mut self, event: &IdeEvent<S>) -> Result<(), SerializerError> {
+        match event {
+            IdeEvent::Tab { file_path, content } => {
+                self.handle_tab_event(file_path.as_ref(), content.as_ref().map(AsRef::as_ref))
+            }
+            IdeEvent::Content { file_path, offset, length, text } => {
+                self.handle_content_event(file_path.as_ref(), *offset, *length, text.as_ref())
+            }
+            IdeEvent::Selection { file_path, offset, end_offset } => {
+                self.handle_selection_event(file_path.as_ref(), *offset, *end_offset)
+            }
+            IdeEvent::SelectionLine { file_path, line, column, end } => {
+                self.handle_selection_event_line(file_path.as_ref(), *line, *column, *end)
+            }
+            IdeEvent::VimCommand { file_path, command, cursor_offset } => {
+                self.handle_vim_command_event(file_path.as_ref(), command.as_ref(), *cursor_offset)
+            }
+            IdeEvent::BulkContent { file_path, changes } => {
+                self.handle_bulk_content_event(file_path.as_ref(), changes)
+            }
+            IdeEvent::Undo { file_path, offset, length, text } => {
+                self.handle_undo_event(file_path.as_ref(), *offset, *length, text.as_ref())
+            }
+            IdeEvent::Redo { file_path, offset, length, text } => {
+                self.handle_redo_event(file_path.as_ref(), *offset, *length, text.as_ref())
+            }
+            IdeEvent::FileSave { file_path } => self.handle_file_save_event(file_path.as_ref()),
+            IdeEvent::FileRename { old_path, new_path } => {
+                self.handle_file_rename_event(old_path.as_ref(), new_path.as_ref())
+            }
+            IdeEvent::TerminalCommand { command } => self.handle_terminal_command_event(command.as_ref()),
+            IdeEvent::TerminalOutput { output } => self.handle_terminal_output_event(output.as_ref()),
+            IdeEvent::TerminalFocus => self.handle_terminal_focus_event(),
+            IdeEvent::DirectoryOpen { dir_path, entries } => {
+                let entries: Vec<&str> = entries.iter().map(AsRef::as_ref).collect();
+                self.handle_directory_open_event(dir_path.as_ref(), &entries)
+            }
+            IdeEvent::GitBranchCheckout { branch_info } => {
+                self.handle_git_branch_checkout_event(branch_info.as_ref())
+            }
+            IdeEvent::SessionStart => self.handle_session_start_event(),
+            IdeEvent::SessionEnd => self.handle_session_end_event(),
+        }
+    }
+
+    /// Handle a batch of IDE events in order.
+    pub fn handle_events<S: AsRef<str>>(
+        &mut self,
+        events: impl IntoIterator<Item = IdeEvent<S>>,
+    ) -> Result<(), SerializerError> {
+        events.into_iter().try_for_each(|event| self.handle_event(event))
+    }
+
+    /// Append an action the model actually executed, e.g. in an online
+    /// fine-tuning loop, as an assistant message. `text` is used as is and
+    /// goes through the same truncation and chunking as generated messages.
+    pub fn append_assistant_action(&mut self, text: &str) -> Result<(), SerializerError> {
+        self.flush_reordered_events()?;
+        self.flush_all_pending_edits()?;
+        self.flush_terminal_output_buffer();
+        self.append_message(ConversationMessage::assistant(text));
+        Ok(())
+    }
+
+    /// Append the observed result of an injected action as a user message.
+    pub fn append_observation(&mut self, text: &str) -> Result<(), SerializerError> {
+        self.flush_reordered_events()?;
+        self.flush_all_pending_edits()?;
+        self.flush_terminal_output_buffer();
+        let tag = MessageTag::observation(text.trim().is_empty());
+        self.append_tagged_message(ConversationMessage::user(text), tag);
+        Ok(())
+    }
+
+    /// Handle a tab (file switch) event.
+    pub fn handle_tab_event(
+        &mut self,
+        file_path: &str,
+        text_content: Option<&str>,
+    ) -> Result<(), SerializerError> {
+        if self.is_ignored_path(file_path) {
+            return Ok(());
+        }
+        let file_path = self.map_file_path(file_path);
+        let file_path = file_path.as_ref();
+        self.touch_file(file_path);
+        self.flush_all_pending_edits()?;
+        self.flush_terminal_output_buffer();
+
+        let command_write = self.is_command_write(file_path);
+        let refocus = self.focus_file(file_path);
+        if refocus && self.config.skip_refocus_views {
+            let unchanged = self.file_states.get(file_path).is_some_and(|tracked| {
+                text_content.is_none_or(|text| {
+                    *tracked == strip_bom(text).replace("\\n", "\n").replace("\\r", "\r")
+                })
+            });
+            if unchanged {
+                // Focus came back without leaving the editor: nothing new to show
+                return Ok(());
+            }
+        }
+
+        let stale = text_content.and_then(|text| {
+            let previous = self.file_states.get(file_path)?;
+            (self.config.diff_stale_snapshots
+                && self.capture_history.contains_key(file_path)
+                && !self.files_awaiting_resync.contains(file_path))
+            .then(|| (previous.clone(), strip_bom(text).replace("\\n", "\n").replace("\\r", "\r")))
+        });
+        if let Some((previous, content)) = stale {
+            // Already shown: serialize an external change as an edit, then
+            // treat the event as a plain tab switch
+            if previous != content && command_write {
+                // Already serialized as the command that wrote it
+                self.file_states.insert(file_path.to_string(), content);
+                self.current_metrics.command_write_echoes += 1;
+            } else if previous != content {
+                self.pending_edits_before.insert(file_path.to_string(), Some(previous));
+                self.file_states.insert(file_path.to_string(), content);
+                self.flush_pending_edit_for_file(file_path)?;
+                self.current_metrics.snapshot_diffs += 1;
+            }
+            self.show_current_viewport(file_path);
+            return Ok(());
+        }
+
+        if let Some(text) = text_content {
+            let content = strip_bom(text).replace("\\n", "\n").replace("\\r", "\r");
+            self.file_states.insert(file_path.to_string(), content.clone());
+            self.files_awaiting_resync.remove(file_path);
+
+            if self.shown_unchanged(file_path, &content) {
+                // Already shown as is: refresh the viewport instead of re-capturing
+                self.show_current_viewport(file_path);
+            } else if !self.shorten_recapture(file_path, &content, None) {
+                self.emit_capture(file_path, &content, None);
+            }
+            if self.is_large_file(&content) {
+                // Large files are only ever shown as viewport slices
+                let total_lines = content.split('\n').count();
+                let vp = self
+                    .per_file_viewport
+                    .get(file_path)
+                    .and_then(|v| *v)
+                    .filter(|v| v.end > 0 && v.end <= total_lines)
+                    .unwrap_or_else(|| {
+                        self.viewport_around(total_lines, 1)
+                    });
+                self.per_file_viewport.insert(file_path.to_string(), Some(vp));
+                self.emit_viewport(file_path, &content, vp);
+            }
+            self.note_file_language(file_path);
+
+            if let Some(edits) = self
+                .buffered_untracked_edits
+                .remove(file_path)
+                .filter(|edits| !contains_edits(&content, edits))
+            {
+                for edit in edits {
+                    self.handle_content_event(file_path, edit.offset, edit.length, &edit.new_text)?;
+                }
+            }
+        } else {
+            // File switch without content snapshot: show current viewport only
+            self.show_current_viewport(file_path);
+        }
+        Ok(())
+    }
+
+    /// Emit the file's current viewport (the top of the file if it has none).
+    fn show_current_viewport(&mut self, file_path: &str) {
+        let content = self.file_states.get(file_path).cloned().unwrap_or_default();
+        let total_lines = content.split('\n').count();
+        let vp = self
+            .per_file_viewport
+            .get(file_path)
+            .and_then(|v| *v)
+            .filter(|v| v.end > 0)
+            .unwrap_or_else(|| {
+                let new_vp = self.viewport_around(total_lines, 1);
+                self.per_file_viewport.insert(file_path.to_string(), Some(new_vp));
+                new_vp
+            });
+
+        if vp.end >= vp.start {
+            self.emit_viewport(file_path, &content, vp);
+        }
+    }
+
+    /// Handle a content change event.
+    pub fn handle_content_event(
+        &mut self,
+        file_path: &str,
+        offset: usize,
+        length: usize,
+        new_text: &str,
+    ) -> Result<(), SerializerError> {
+        if self.is_ignored_path(file_path) {
+            return Ok(());
+        }
+        let file_path = self.map_file_path(file_path);
+        let file_path = file_path.as_ref();
+        let Some(debounce_ms) = self.config.content_debounce_ms else {
+            return self.apply_content_event(file_path, offset, length, new_text);
+        };
+        let continues_burst = self.queued_edits.last().is_some_and(|last| {
+            last.file_path == file_path
+                && match (last.time_ms, self.current_time_ms) {
+                    (Some(last), Some(now)) => now - last <= debounce_ms as i64,
+                    _ => true,
+                }
+        });
+        if !continues_burst {
+            self.flush_pending()?;
+        }
+        self.queued_edits.push(QueuedEdit {
+            file_path: file_path.to_string(),
+            edit: BufferedEdit {
+                offset,
+                length,
+                new_text: new_text.to_string(),
+            },
+            time_ms: self.current_time_ms,
+            sequence: self.current_sequence,
+        });
+        Ok(())
+    }
+
+    /// Handle a burst of content events to `file_path` in one call, each
+    /// `(offset, length, new_text)` applied to the content after the ones
+    /// before it, then apply anything still queued under
+    /// `content_debounce_ms`.
+    pub fn handle_content_events<S: AsRef<str>>(
+        &mut self,
+        file_path: &str,
+        changes: &[(usize, usize, S)],
+    ) -> Result<(), SerializerError> {
+        for (offset, length, new_text) in changes {
+            self.handle_event(IdeEvent::Content {
+                file_path,
+                offset: *offset,
+                length: *length,
+                text: new_text.as_ref(),
+            })?;
+        }
+        self.flush_pending()
+    }
+
+    /// Handle a multi-cursor edit: several changes to `file_path` made at
+    /// once, each `(offset, length, new_text)` with offsets into the content
+    /// before any of them. They are applied together, from the highest offset
+    /// down, and join a single pending edit however far apart they are.
+    /// Inserts at the same offset keep their order; changes that overlap are
+    /// rejected with `OverlappingChanges`.
+    pub fn handle_bulk_content_event<S: AsRef<str>>(
+        &mut self,
+        file_path: &str,
+        changes: &[(usize, usize, S)],
+    ) -> Result<(), SerializerError> {
+        self.flush_pending()?;
+        if self.is_ignored_path(file_path) || changes.is_empty() {
+            return Ok(());
+        }
+        let file_path = self.map_file_path(file_path);
+        let file_path = file_path.as_ref();
+        let mut changes: Vec<&(usize, usize, S)> = changes.iter().collect();
+        changes.sort_by_key(|(offset, _, _)| *offset);
+        for pair in changes.windows(2) {
+            let ((offset, length, _), (next_offset, _, _)) = (pair[0], pair[1]);
+            // A replacement is applied after what follows it, so nothing may
+            // start inside it or at its start
+            if offset.saturating_add(*length) > *next_offset || (*length > 0 && offset == next_offset) {
+                return Err(SerializerError::OverlappingChanges {
+                    file_path: file_path.to_string(),
+                    offset: *next_offset,
+                });
+            }
+        }
+        // Stable, so later inserts at an offset are applied first and end up after earlier ones
+        changes.reverse();
+
+        let content = self.file_states.get(file_path).map_or("", String::as_str);
+        let line_of = |offset: usize| {
+            let safe_offset = floor_char_boundary(content, offset.min(content.len()));
+            content[..safe_offset].matches('\n').count() + 1
+        };
+        if changes.iter().all(|(offset, _, _)| self.is_far_from_pending_edit(file_path, line_of(*offset))) {
+            self.flush_pending_edit_for_file(file_path)?;
+        }
+        if self.config.record_timing {
+            self.note_keystroke(file_path);
+        }
+        for (offset, length, new_text) in changes {
+            self.apply_change_event(file_path, *offset, *length, new_text.as_ref(), ChangeSource::MultiCursor)?;
+        }
+        Ok(())
+    }
+
+    /// Handle an undo event: `length` characters at `offset` replaced by
+    /// `new_text`, like a content event. The change always joins the file's
+    /// pending edit, however far from it, so that a burst of undos is
+    /// serialized as its net diff (or nothing, if it undoes the pending edit).
+    pub fn handle_undo_event(
+        &mut self,
+        file_path: &str,
+        offset: usize,
+        length: usize,
+        new_text: &str,
+    ) -> Result<(), SerializerError> {
+        self.handle_history_event(file_path, offset, length, new_text)
+    }
+
+    /// Handle a redo event, coalesced like an undo event.
+    pub fn handle_redo_event(
+        &mut self,
+        file_path: &str,
+        offset: usize,
+        length: usize,
+        new_text: &str,
+    ) -> Result<(), SerializerError> {
+        self.handle_history_event(file_path, offset, length, new_text)
+    }
+
+    fn handle_history_event(
+        &mut self,
+        file_path: &str,
+        offset: usize,
+        length: usize,
+        new_text: &str,
+    ) -> Result<(), SerializerError> {
+        self.flush_pending()?;
+        if self.is_ignored_path(file_path) {
+            return Ok(());
+        }
+        let file_path = self.map_file_path(file_path);
+        self.apply_change_event(file_path.as_ref(), offset, length, new_text, ChangeSource::History)
+    }
+
+    /// Apply the content events queued under `content_debounce_ms`, e.g. from
+    /// a timer in the extension once a burst is over. Other events and
+    /// finalization apply them first.
+    pub fn flush_pending(&mut self) -> Result<(), SerializerError> {
+        if self.queued_edits.is_empty() {
+            return Ok(());
+        }
+        let (time_ms, sequence) = (self.current_time_ms, self.current_sequence);
+        let mut result = Ok(());
+        for queued in std::mem::take(&mut self.queued_edits) {
+            self.current_time_ms = queued.time_ms;
+            self.current_sequence = queued.sequence;
+            let edit = &queued.edit;
+            result = self.apply_content_event(&queued.file_path, edit.offset, edit.length, &edit.new_text);
+            if result.is_err() {
+                break;
+            }
+        }
+        self.current_time_ms = time_ms;
+        self.current_sequence = sequence;
+        result
+    }
+
+    /// Apply a content event to the (mapped) `file_path`.
+    fn apply_content_event(
+        &mut self,
+        file_path: &str,
+        offset: usize,
+        length: usize,
+        new_text: &str,
+    ) -> Result<(), SerializerError> {
+        self.apply_change_event(file_path, offset, length, new_text, ChangeSource::Typed)
+    }
+
+    /// Whether `line` is outside `coalesce_radius` of the file's pending edit.
+    fn is_far_from_pending_edit(&self, file_path: &str, line: usize) -> bool {
+        self.pending_edit_regions
+            .get(file_path)
+            .and_then(|r| *r)
+            .is_some_and(|region| {
+                line < region.start.saturating_sub(self.config.coalesce_radius)
+                    || line > region.end + self.config.coalesce_radius
+            })
+    }
+
+    /// Apply a change to the (mapped) `file_path`.
+    fn apply_change_event(
+        &mut self,
+        file_path: &str,
+        offset: usize,
+        length: usize,
+        new_text: &str,
+        source: ChangeSource,
+    ) -> Result<(), SerializerError> {
+        self.touch_file(file_path);
+        if !self.file_states.contains_key(file_path) {
+            match self.config.untracked_file_policy {
+                UntrackedFilePolicy::AssumeEmpty => {}
+                UntrackedFilePolicy::Drop => {
+                    self.drop_stats.untracked_edits += 1;
+                    return Ok(());
+                }
+                UntrackedFilePolicy::Placeholder => self.capture_unknown_file(file_path),
+                UntrackedFilePolicy::Buffer => {
+                    self.buffered_untracked_edits
+                        .entry(file_path.to_string())
+                        .or_default()
+                        .push(BufferedEdit {
+                            offset,
+                            length,
+                            new_text: new_text.to_string(),
+                        });
+                    return Ok(());
+                }
+            }
+        }
+
+        if self.files_awaiting_resync.contains(file_path) {
+            self.drop_stats.resync_edits += 1;
+            return Ok(());
+        }
+
+        if self.is_command_write(file_path) {
+            // Already serialized as the command that wrote it
+            let before = self.file_states.get(file_path).map_or("", String::as_str);
+            let after = crate::helpers::apply_change(before, offset, length, new_text);
+            self.file_states.insert(file_path.to_string(), after);
+            self.current_metrics.command_write_echoes += 1;
+            return Ok(());
+        }
+
+        self.flush_terminal_output_buffer();
+        if self.config.flush_on_cross_file_edit {
+            let others: Vec<String> = self
+                .pending_edits_before
+                .iter()
+                .filter(|(path, before)| path.as_str() != file_path && before.is_some())
+                .map(|(path, _)| path.clone())
+                .collect();
+            for other in others {
+                self.flush_pending_edit_for_file(&other)?;
+            }
+        }
+
+        let tracked_length = self.file_states.get(file_path).map_or(0, String::len);
+        if offset.saturating_add(length) > tracked_length {
+            self.drift_diagnostics.push(OffsetDriftDiagnostic {
+                file_path: file_path.to_string(),
+                sequence: self.current_sequence,
+                offset,
+                length,
+                tracked_length,
+            });
+            if self.config.resync_on_offset_drift {
+                self.flush_pending_edit_for_file(file_path)?;
+                self.files_awaiting_resync.insert(file_path.to_string());
+                return Ok(());
+            }
+        }
+
+        // Approximate current edit region in line space
+        let before = self.file_states.get(file_path).map_or("", String::as_str);
+        let safe_offset = floor_char_boundary(before, offset.min(before.len()));
+        let safe_end = floor_char_boundary(before, offset.saturating_add(length).min(before.len()));
+        let start_line_current = before[..safe_offset].matches('\n').count() + 1;
+        let deleted_content = &before[safe_offset..safe_end];
+        let lines_added = new_text.matches('\n').count();
+        let lines_deleted = deleted_content.matches('\n').count();
+        let after = crate::helpers::apply_change(before, offset, length, new_text);
+        let region_start = start_line_current;
+        let region_end = start_line_current + lines_added.max(lines_deleted);
+
+        // Flush pending edits if this edit is far from the pending region
+        match source {
+            ChangeSource::Typed => {
+                if self.is_far_from_pending_edit(file_path, region_start) {
+                    self.flush_pending_edit_for_file(file_path)?;
+                }
+                if self.config.record_timing {
+                    self.note_keystroke(file_path);
+                }
+            }
+            ChangeSource::History => {
+                self.current_metrics.undo_redo_changes += 1;
+                if self.config.record_timing {
+                    self.note_keystroke(file_path);
+                }
+            }
+            ChangeSource::MultiCursor => {}
+        }
+        let before = self.file_states.insert(file_path.to_string(), after).unwrap_or_default();
+        if self.pending_edits_before.get(file_path).and_then(|v| v.as_ref()).is_none() {
+            self.pending_edits_before
+                .insert(file_path.to_string(), Some(before));
+        } else {
+            self.current_metrics.coalesced_edits += 1;
+        }
+
+        // Update/initialize region union
+        let current_region = self.pending_edit_regions.get(file_path).and_then(|r| *r);
+        let new_region = if let Some(region) = current_region {
+            EditRegion {
+                start: region.start.min(region_start),
+                end: region.end.max(region_end),
+            }
+        } else {
+            EditRegion {
+                start: region_start,
+                end: region_start.max(region_end),
+            }
+        };
+        self.pending_edit_regions
+            .insert(file_path.to_string(), Some(new_region));
+        Ok(())
+    }
+
+    /// Handle a selection event.
+    ///
+    /// `end_offset` is the other end of a non-empty selection. The selected
+    /// lines are tracked (see `get_selection`) and, with
+    /// `emit_selection_ranges`, a multi-line selection is shown as a viewport
+    /// read of exactly those lines.
+    pub fn handle_selection_event(
+        &mut self,
+        file_path: &str,
+        offset: usize,
+        end_offset: Option<usize>,
+    ) -> Result<(), SerializerError> {
+        self.flush_pending()?;
+        if self.is_ignored_path(file_path) {
+            return Ok(());
+        }
+        let file_path = self.map_file_path(file_path);
+        let file_path = file_path.as_ref();
+        self.touch_file(file_path);
+        let content = self.file_states.get(file_path).cloned().unwrap_or_default();
+        let line_of = |offset: usize| {
+            let safe_offset = floor_char_boundary(&content, offset.min(content.len()));
+            content[..safe_offset].matches('\n').count() + 1
+        };
+
+        let (start, end) = match end_offset {
+            Some(end_offset) => (offset.min(end_offset), offset.max(end_offset)),
+            None => (offset, offset),
+        };
+        let target_line = line_of(start);
+        let selection = (end > start).then(|| {
+            // A selection ending at the start of a line does not include it;
+            // one ending inside a character of the line does
+            let mut end_line = line_of(end);
+            let clamped_end = end.min(content.len());
+            let safe_end = floor_char_boundary(&content, clamped_end);
+            if end_line > target_line && safe_end == clamped_end && content[..safe_end].ends_with('\n') {
+                end_line -= 1;
+            }
+            Viewport {
+                start: target_line,
+                end: end_line,
+            }
+        });
+        self.apply_selection(file_path, &content, target_line, selection);
+        Ok(())
+    }
+
+    /// Handle a selection between two offsets, in either order, e.g. from
+    /// an editor API reporting anchor and active position.
+    pub fn handle_selection_range_event(
+        &mut self,
+        file_path: &str,
+        start_offset: usize,
+        end_offset: usize,
+    ) -> Result<(), SerializerError> {
+        self.handle_selection_event(file_path, start_offset, Some(end_offset))
+    }
+
+    /// Handle a selection event reported as positions (1-based line, 0-based
+    /// column) instead of offsets, e.g. by recorders that do not know the
+    /// document's offset encoding. `end` is the other end of a selection.
+    pub fn handle_selection_event_line(
+        &mut self,
+        file_path: &str,
+        line: usize,
+        column: usize,
+        end: Option<(usize, usize)>,
+    ) -> Result<(), SerializerError> {
+        self.flush_pending()?;
+        if self.is_ignored_path(file_path) {
+            return Ok(());
+        }
+        let file_path = self.map_file_path(file_path);
+        let file_path = file_path.as_ref();
+        self.touch_file(file_path);
+        let content = self.file_states.get(file_path).cloned().unwrap_or_default();
+        let total_lines = content.split('\n').count();
+
+        let (start, end) = match end {
+            Some(end) => ((line, column).min(end), (line, column).max(end)),
+            None => ((line, column), (line, column)),
+        };
+        let target_line = start.0.clamp(1, total_lines);
+        let selection = (end > start).then(|| {
+            // A selection ending at the start of a line does not include it
+            let end_line = if end.1 == 0 && end.0 > start.0 { end.0 - 1 } else { end.0 };
+            Viewport {
+                start: target_line,
+                end: end_line.clamp(target_line, total_lines),
+            }
+        });
+        self.apply_selection(file_path, &content, target_line, selection);
+        Ok(())
+    }
+
+    /// Remember the selection of a file and reveal its cursor line, or the
+    /// selection itself with `emit_selection_ranges`.
+    fn apply_selection(
+        &mut self,
+        file_path: &str,
+        content: &str,
+        target_line: usize,
+        selection: Option<Viewport>,
+    ) {
+        self.focus_file(file_path);
+        match selection {
+            Some(selection) => self.per_file_selection.insert(file_path.to_string(), selection),
+            None => self.per_file_selection.remove(file_path),
+        };
+
+        // During an edit burst (pending edits), suppress viewport emissions
+        if self.pending_edits_before.get(file_path).and_then(|v| v.as_ref()).is_some() {
+            return;
+        }
+
+        self.flush_terminal_output_buffer();
+
+        let show_range = self.config.emit_selection_ranges || self.config.center_selections;
+        match selection.filter(|s| show_range && s.end > s.start) {
+            Some(selection) => {
+                let vp = if self.config.center_selections {
+                    self.viewport_around_range(content.split('\n').count(), selection)
+                } else {
+                    selection
+                };
+                let current_vp = self.per_file_viewport.get(file_path).and_then(|v| *v);
+                if current_vp != Some(vp) {
+                    self.per_file_viewport.insert(file_path.to_string(), Some(vp));
+                    let marked = self.config.annotate_selections.then_some(selection);
+                    self.emit_marked_viewport(file_path, content, vp, marked);
+                }
+            }
+            None => self.reveal_line(file_path, content, target_line),
+        }
+    }
+
+    /// The viewport around `center_line` under the configured radii.
+    fn viewport_around(&self, total_lines: usize, center_line: usize) -> Viewport {
+        let radius = self.config.viewport_radius;
+        compute_viewport(
+            total_lines,
+            center_line,
+            self.config.viewport_lines_above.unwrap_or(radius),
+            self.config.viewport_lines_below.unwrap_or(radius),
+        )
+    }
+
+    /// Viewport centered on the lines of `range`, with at least the usual
+    /// context around its middle.
+    fn viewport_around_range(&self, total_lines: usize, range: Viewport) -> Viewport {
+        let radius = self.config.viewport_radius;
+        let middle = (range.start + range.end) / 2;
+        compute_viewport(
+            total_lines,
+            middle,
+            self.config.viewport_lines_above.unwrap_or(radius).max(middle - range.start),
+            self.config.viewport_lines_below.unwrap_or(radius).max(range.end - middle),
+        )
+    }
+
+    /// Emit a viewport read around `target_line` unless it is already
+    /// visible, outside the margin, and not due for recentering.
+    fn reveal_line(&mut self, file_path: &str, content: &str, target_line: usize) {
+        let total_lines = content.split('\n').count();
+        let current_vp = self.per_file_viewport.get(file_path).and_then(|v| *v);
+        let mut should_emit = false;
+
+        let movements = self.per_file_movements.entry(file_path.to_string()).or_default();
+        *movements += 1;
+        let recenter = self
+            .config
+            .viewport_recenter_every
+            .is_some_and(|every| *movements >= every.max(1));
+        // A margin reaching the cursor line would re-emit on every movement
+        let radius = self.config.viewport_radius;
+        let margin = self
+            .config
+            .viewport_margin
+            .min(self.config.viewport_lines_above.unwrap_or(radius))
+            .min(self.config.viewport_lines_below.unwrap_or(radius));
+
+        let movements = *movements;
+        let vp = if let Some(vp) = current_vp.filter(|v| v.end > 0) {
+            // Edges at the start or end of the file cannot scroll further
+            let near_top = vp.start > 1 && target_line < vp.start + margin;
+            let near_bottom = vp.end < total_lines && target_line + margin > vp.end;
+            let new_vp = self.viewport_around(total_lines, target_line);
+            let moved = target_line < vp.start
+                || target_line > vp.end
+                || near_top
+                || near_bottom
+                || (recenter && new_vp != vp);
+            let jitter = movements < self.config.viewport_min_movements
+                || new_vp.start.abs_diff(vp.start) < self.config.viewport_min_shift;
+            if moved && jitter {
+                self.current_metrics.suppressed_viewports += 1;
+                vp
+            } else if moved {
+                self.per_file_viewport
+                    .insert(file_path.to_string(), Some(new_vp));
+                should_emit = true;
+                new_vp
+            } else {
+                vp
+            }
+        } else {
+            let new_vp = self.viewport_around(total_lines, target_line);
+            self.per_file_viewport
+                .insert(file_path.to_string(), Some(new_vp));
+            should_emit = true;
+            new_vp
+        };
+
+        if should_emit && vp.end >= vp.start {
+            self.per_file_movements.insert(file_path.to_string(), 0);
+            self.emit_viewport(file_path, content, vp);
+        }
+    }
+
+    /// Handle a command typed in a Vim emulation mode.
+    ///
+    /// Line deletions become `sed` deletions and substitutions replace the
+    /// lines they change; both are applied to the tracked file; jumps move the viewport. The recorder
+    /// reports these edits only as `vim_command` events, so the file state
+    /// would drift without them. `cursor_offset` is needed for commands
+    /// relative to the cursor line (`dd`, `:s`); unrecognized commands are
+    /// counted in `DropStats::unsupported_vim_commands`.
+    pub fn handle_vim_command_event(
+        &mut self,
+        file_path: &str,
+        command: &str,
+        cursor_offset: Option<usize>,
+    ) -> Result<(), SerializerError> {
+        self.flush_pending()?;
+        if self.is_ignored_path(file_path) {
+            return Ok(());
+        }
+        let file_path = self.map_file_path(file_path);
+        let file_path = file_path.as_ref();
+        self.touch_file(file_path);
+        let Some(command) = parse_vim_command(command) else {
+            self.drop_stats.unsupported_vim_commands += 1;
+            return Ok(());
+        };
+        if !self.file_states.contains_key(file_path) {
+            self.drop_stats.untracked_edits += 1;
+            return Ok(());
+        }
+        if self.files_awaiting_resync.contains(file_path) {
+            self.drop_stats.resync_edits += 1;
+            return Ok(());
+        }
+
+        self.flush_all_pending_edits()?;
+        self.flush_terminal_output_buffer();
+
+        let before = self.file_states.get(file_path).cloned().unwrap_or_default();
+        let mut lines: Vec<String> = before.split('\n').map(str::to_string).collect();
+        // A trailing newline is not a line of its own for Vim
+        let last_line = lines.len() - usize::from(before.ends_with('\n') && lines.len() > 1);
+        let cursor_line = cursor_offset.map(|offset| {
+            let offset = floor_char_boundary(&before, offset.min(before.len()));
+            before[..offset].matches('\n').count() + 1
+        });
+        let resolve_range = |start: &LineAddress, end: &LineAddress| {
+            let (start, end) = (
+                start.resolve(cursor_line, last_line)?,
+                end.resolve(cursor_line, last_line)?,
+            );
+            Some((start.min(end), start.max(end)))
+        };
+
+        match &command {
+            VimCommand::GotoLine(address) => {
+                let Some(line) = address.resolve(cursor_line, last_line) else {
+                    self.drop_stats.unsupported_vim_commands += 1;
+                    return Ok(());
+                };
+                self.reveal_line(file_path, &before, line);
+            }
+            VimCommand::DeleteLines { start, end } => {
+                let Some((start, end)) = resolve_range(start, end) else {
+                    self.drop_stats.unsupported_vim_commands += 1;
+                    return Ok(());
+                };
+                lines.drain(start - 1..end);
+                let after = lines.join("\n");
+                let timing = self.event_timing();
+                self.emit_file_edit(file_path, &before, &after, FileEdit::Delete { start, end }, start, timing);
+                self.file_states.insert(file_path.to_string(), after);
+            }
+            VimCommand::Substitute { start, end, global, .. } => {
+                let (Some((start, end)), Some((re, regex_replacement))) =
+                    (resolve_range(start, end), command.substitution_regex())
+                else {
+                    self.drop_stats.unsupported_vim_commands += 1;
+                    return Ok(());
+                };
+                let limit = if *global { 0 } else { 1 };
+                let mut changed: Option<(usize, usize)> = None;
+                for (index, line) in lines[start - 1..end].iter_mut().enumerate() {
+                    if let Cow::Owned(replaced) = re.replacen(line, limit, regex_replacement.as_str()) {
+                        let first = changed.map_or(start + index, |(first, _)| first);
+                        changed = Some((first, start + index));
+                        *line = replaced;
+                    }
+                }
+                // Vim reports "Pattern not found" and leaves the buffer untouched
+                let Some((first_changed, last_changed)) = changed else {
+                    return Ok(());
+                };
+                let after = lines.join("\n");
+                // The pattern is in Vim syntax, which sed does not share
+                let edit = FileEdit::Replace {
+                    start: first_changed,
+                    end: last_changed,
+                    lines: &lines[first_changed - 1..last_changed],
+                };
+                let timing = self.event_timing();
+                self.emit_file_edit(file_path, &before, &after, edit, first_changed, timing);
+                self.file_states.insert(file_path.to_string(), after);
+            }
+        }
+        Ok(())
+    }
+
+    /// Handle a terminal command event.
+    pub fn handle_terminal_command_event(&mut self, command: &str) -> Result<(), SerializerError> {
+        self.flush_all_pending_edits()?;
+        self.flush_terminal_output_buffer();
+        self.terminal_focused = true;
+
+        let command = self.path_mapper.map_text(command);
+        let command_str = command.replace("\\n", "\n").replace("\\r", "\r");
+        let cleaned = clean_text(&command_str);
+        let mut commands = normalize_multiline_command(cleaned.clone(), self.config.multiline_commands);
+        if commands.len() > 1 || commands[0] != cleaned {
+            self.current_metrics.rewritten_commands += 1;
+        }
+        if let Some(previous) = self.take_mergeable_command() {
+            commands[0] = format!("{} && {}", previous, commands[0]);
+            self.current_metrics.merged_commands += 1;
+        }
+        let follows_silent_command = self.last_command.is_some();
+        let timing = self.event_timing();
+        let cleaned = commands.pop().expect("at least one command");
+        for command in &commands {
+            self.append_action(&Action::RunCommand { command }, None);
+        }
+        self.append_action(&Action::RunCommand { command: &cleaned }, None);
+        if let Some(message) = self.messages.last_mut() {
+            message.timing = timing;
+        }
+        if self.config.dedupe_command_writes {
+            self.command_writes = commands.iter().chain([&cleaned]).flat_map(|c| command_written_files(c)).collect();
+        }
+        self.last_command = Some(LastCommand {
+            command: cleaned,
+            time_ms: self.current_time_ms,
+            follows_silent_command,
+        });
+        Ok(())
+    }
+
+    /// Milliseconds since the previous timed event.
+    fn pause_before(&self, time_ms: i64) -> Option<u64> {
+        u64::try_from(time_ms - self.previous_time_ms?).ok()
+    }
+
+    /// Timing of a message produced by the current event alone, with
+    /// `record_timing`.
+    fn event_timing(&self) -> Option<MessageTiming> {
+        if !self.config.record_timing {
+            return None;
+        }
+        Some(MessageTiming {
+            pause_before_ms: self.pause_before(self.current_time_ms?),
+            ..Default::default()
+        })
+    }
+
+    /// Add the current event to the keystroke timing of the file's pending edit.
+    fn note_keystroke(&mut self, file_path: &str) {
+        let Some(now) = self.current_time_ms else {
+            return;
+        };
+        match self.pending_edit_timing.get_mut(file_path) {
+            Some(burst) => {
+                burst.max_gap_ms = burst.max_gap_ms.max(now.abs_diff(burst.last_ms));
+                burst.last_ms = now;
+                burst.keystrokes += 1;
+            }
+            None => {
+                let burst = EditBurst {
+                    first_ms: now,
+                    last_ms: now,
+                    keystrokes: 1,
+                    max_gap_ms: 0,
+                    pause_before_ms: self.pause_before(now),
+                };
+                self.pending_edit_timing.insert(file_path.to_string(), burst);
+            }
+        }
+    }
+
+    /// If the last message is a command the current one may be merged into,
+    /// remove it and return its command text.
+    fn take_mergeable_command(&mut self) -> Option<String> {
+        let window = self.config.merge_commands_window_ms?;
+        let last = self.last_command.as_ref()?;
+        let elapsed = self.current_time_ms? - last.time_ms?;
+        if !(0..=window as i64).contains(&elapsed) {
+            return None;
+        }
+        let last = self.last_command.take()?;
+        self.pop_message();
+        Some(last.command)
+    }
+
+    /// Remove the last message of the current conversation.
+    fn pop_message(&mut self) -> Option<(ConversationMessage, MessageTag)> {
+        let message = self.messages.pop()?;
+        notify_all(&mut self.observers, &Notification::MessageRemoved { message: &message });
+        if let Some(tokens) = self.current_message_tokens.pop() {
+            self.current_tokens -= tokens;
+        }
+        let tag = self.current_message_tags.pop().unwrap_or_default();
+        Some((message, tag))
+    }
+
+    /// Split off the part of `output` that belongs to the command before the
+    /// last one, with `reassociate_late_output`, and emit it as that
+    /// command's output. Returns the rest.
+    fn reassociate_late_output(&mut self, output: String) -> String {
+        let Some(last) = self.last_command.as_ref().filter(|last| last.follows_silent_command) else {
+            return output;
+        };
+        let late_len = if self.current_time_ms.zip(last.time_ms).is_some_and(|(now, command)| now < command) {
+            output.len()
+        } else {
+            let first = |markers: [&str; 2]| markers.iter().filter_map(|m| output.find(m)).min();
+            match first(COMMAND_FINISHED_MARKERS) {
+                Some(finished) if first(COMMAND_STARTED_MARKERS).is_none_or(|started| finished < started) => {
+                    finished
+                }
+                _ => 0,
+            }
+        };
+        if output[..late_len].trim().is_empty() {
+            return output;
+        }
+
+        // Move the last command behind the late output
+        let mut last = self.last_command.take();
+        let Some((command, tag)) = self.pop_message() else {
+            return output;
+        };
+        self.terminal_output_buffer.push(output[..late_len].to_string());
+        self.flush_terminal_output_buffer();
+        self.append_tagged_message(command, tag);
+        if let Some(last) = last.as_mut() {
+            last.follows_silent_command = false;
+        }
+        self.last_command = last;
+        self.current_metrics.reassociated_outputs += 1;
+        output[late_len..].to_string()
+    }
+
+    /// Handle a terminal output event.
+    pub fn handle_terminal_output_event(&mut self, output: &str) -> Result<(), SerializerError> {
+        self.flush_pending()?;
+        let output = self.path_mapper.map_text(output);
+        let mut raw_output = output.replace("\\n", "\n").replace("\\r", "\r");
+        if self.config.reassociate_late_output {
+            raw_output = self.reassociate_late_output(raw_output);
+        }
+        if self.terminal_output_buffer.is_empty() {
+            self.terminal_buffer_started_ms = self.current_time_ms;
+        }
+        self.terminal_buffer_bytes += raw_output.len();
+        self.terminal_output_buffer.push(raw_output);
+        self.last_command = None;
+        if self
+            .config
+            .max_terminal_buffer_bytes
+            .is_some_and(|max_bytes| self.terminal_buffer_bytes >= max_bytes)
+        {
+            self.flush_terminal_output_buffer();
+        }
+        Ok(())
+    }
+
+    /// Handle a file save event: flush the file's pending edit where it was
+    /// saved instead of at the next tab or terminal event. With
+    /// `verify_on_save`, the saved viewport is read again.
+    pub fn handle_file_save_event(&mut self, file_path: &str) -> Result<(), SerializerError> {
+        if self.is_ignored_path(file_path) {
+            return Ok(());
+        }
+        self.flush_pending()?;
+        let file_path = self.map_file_path(file_path);
+        let file_path = file_path.as_ref();
+        self.flush_terminal_output_buffer();
+        let messages = self.messages.len();
+        self.flush_pending_edit_for_file(file_path)?;
+        if self.config.verify_on_save && self.messages.len() > messages {
+            let content = self.file_states.get(file_path).cloned().unwrap_or_default();
+            if let Some(vp) = self.per_file_viewport.get(file_path).and_then(|v| *v) {
+                self.read_viewport(file_path, &content, vp, None);
+                self.current_metrics.save_verifications += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// Handle a file rename event: move the tracked state of `old_path` to
+    /// `new_path` and serialize the rename as `mv`, so the renamed file is
+    /// not captured again as a new file.
+    ///
+    /// A file renamed to an ignored path is forgotten, as it is no longer tracked.
+    pub fn handle_file_rename_event(&mut self, old_path: &str, new_path: &str) -> Result<(), SerializerError> {
+        let old_ignored = self.matches_ignored_path(old_path);
+        let new_ignored = self.matches_ignored_path(new_path);
+        if old_ignored || new_ignored {
+            self.drop_stats.ignored_path_events += 1;
+        }
+        if old_ignored {
+            return Ok(());
+        }
+        self.flush_pending()?;
+        let old_path = self.map_file_path(old_path).into_owned();
+        if new_ignored {
+            self.flush_pending_edit_for_file(&old_path)?;
+            self.forget_file(&old_path);
+            self.buffered_untracked_edits.remove(&old_path);
+            if self.active_file.as_deref() == Some(old_path.as_str()) {
+                self.active_file = None;
+            }
+            return Ok(());
+        }
+        let new_path = self.map_file_path(new_path).into_owned();
+        if old_path == new_path {
+            return Ok(());
+        }
+        self.flush_pending_edit_for_file(&old_path)?;
+        self.flush_terminal_output_buffer();
+
+        fn move_entry<V>(map: &mut HashMap<String, V>, from: &str, to: &str) {
+            if let Some(value) = map.remove(from) {
+                map.insert(to.to_string(), value);
+            }
+        }
+        fn move_member(set: &mut HashSet<String>, from: &str, to: &str) {
+            if set.remove(from) {
+                set.insert(to.to_string());
+            }
+        }
+        let (from, to) = (old_path.as_str(), new_path.as_str());
+        move_entry(&mut self.file_states, from, to);
+        move_entry(&mut self.file_last_used, from, to);
+        move_entry(&mut self.per_file_viewport, from, to);
+        move_entry(&mut self.per_file_selection, from, to);
+        move_entry(&mut self.per_file_movements, from, to);
+        move_entry(&mut self.capture_history, from, to);
+        move_entry(&mut self.capture_cache, from, to);
+        move_entry(&mut self.file_languages, from, to);
+        move_entry(&mut self.pending_edits_before, from, to);
+        move_entry(&mut self.pending_edit_regions, from, to);
+        move_entry(&mut self.pending_edit_timing, from, to);
+        move_entry(&mut self.buffered_untracked_edits, from, to);
+        move_member(&mut self.files_shown_in_earlier_chunks, from, to);
+        move_member(&mut self.files_awaiting_resync, from, to);
+        if self.active_file.as_deref() == Some(from) {
+            self.active_file = Some(new_path.clone());
+        }
+
+        self.append_action(&Action::MoveFile { from, to }, None);
+        self.current_metrics.renamed_files += 1;
+        Ok(())
+    }
+
+    /// Handle a terminal focus event.
+    pub fn handle_terminal_focus_event(&mut self) -> Result<(), SerializerError> {
+        self.flush_all_pending_edits()?;
+        self.flush_terminal_output_buffer();
+        // Focus changes don't emit commands/output, but the next tab event
+        // for the active file shows it again
+        self.terminal_focused = true;
+        Ok(())
+    }
+
+    /// Handle a session start event: the recording starts a new task.
+    ///
+    /// Finalizes the current conversation and forgets all file state, so
+    /// files are captured again in the new task.
+    pub fn handle_session_start_event(&mut self) -> Result<(), SerializerError> {
+        self.flush_all_pending_edits()?;
+        self.flush_terminal_output_buffer();
+        self.finalize_current_conversation();
+
+        let files: Vec<String> = self.file_states.keys().cloned().collect();
+        for file in files {
+            self.forget_file(&file);
+        }
+        self.buffered_untracked_edits.clear();
+        self.files_shown_in_earlier_chunks.clear();
+        self.active_file = None;
+        self.terminal_focused = false;
+        Ok(())
+    }
+
+    /// Handle a session end event: the current task is complete.
+    ///
+    /// Appends `end_of_task_marker`, if any, and finalizes the conversation.
+    pub fn handle_session_end_event(&mut self) -> Result<(), SerializerError> {
+        self.flush_all_pending_edits()?;
+        self.flush_terminal_output_buffer();
+        if let Some(marker) = self.config.end_of_task_marker.clone().filter(|_| !self.messages.is_empty()) {
+            self.append_message(ConversationMessage::assistant(marker));
+        }
+        self.finalize_current_conversation();
+        Ok(())
+    }
+
+    /// Handle a directory open event (folder expanded in the explorer).
+    ///
+    /// The recorder only reports entry names, so the listing shows one name per line.
+    pub fn handle_directory_open_event(
+        &mut self,
+        dir_path: &str,
+        entries: &[&str],
+    ) -> Result<(), SerializerError> {
+        self.flush_all_pending_edits()?;
+        self.flush_terminal_output_buffer();
+
+        let dir_path = self.map_file_path(dir_path);
+        self.append_action(&Action::ListDirectory { path: &dir_path }, None);
+        self.emit_observation(&Observation::DirectoryListing { entries }, None);
+        Ok(())
+    }
+
+    /// Handle a git branch checkout event.
+    pub fn handle_git_branch_checkout_event(
+        &mut self,
+        branch_info: &str,
+    ) -> Result<(), SerializerError> {
+        self.flush_all_pending_edits()?;
+        self.flush_terminal_output_buffer();
+
+        let branch_str = branch_info.replace("\\n", "\n").replace("\\r", "\r");
+        let cleaned = clean_text(&branch_str);
+
+        // Extract branch name from "to 'branch_name'" pattern
+        let re = regex::Regex::new(r"to '([^']+)'").unwrap();
+        let branch_name = match re.captures(&cleaned) {
+            Some(caps) => caps.get(1).map(|m| m.as_str().trim().to_string()),
+            None => {
+                self.config.warnings.warn(
+                    "unparsed_branch_checkout",
+                    format!(
+                        "could not extract branch name at sequence {:?} from git checkout message: {}",
+                        self.current_sequence, cleaned
+                    ),
+                );
+                return Ok(());
+            }
+        };
+
+        let branch_name = match branch_name {
+            Some(b) => b,
+            None => return Ok(()),
+        };
+
+        self.append_action(&Action::CheckoutBranch { branch: &branch_name }, None);
+        Ok(())
+    }
+
+    /// Finalize and get conversation ready for model.
+    pub fn finalize_for_model(&mut self) -> Result<Vec<ConversationMessage>, SerializerError> {
+        self.flush_reordered_events()?;
+        self.flush_all_pending_edits()?;
+        self.flush_terminal_output_buffer();
+        if self.config.compact_conversations {
+            self.compact_current_conversation();
+        }
+        Ok(self.get_messages())
+    }
+
+    /// Messages of the current conversation pinned by `pins`.
+    fn pinned_messages(&self, pins: PinPolicy) -> Vec<bool> {
+        let n = self.messages.len();
+        let mut keep = vec![false; n];
+        let active_file = (0..n).rev().find_map(|i| self.shown_file(i)).map(|(_, file_path)| file_path);
+        if let Some(active_file) = active_file.filter(|_| pins.active_file) {
+            let latest_capture = (0..n)
+                .rev()
+                .find(|&i| self.shown_file(i) == Some((MessageKind::FileCapture, active_file)));
+            let latest_view = (0..n)
+                .rev()
+                .find(|&i| {
+                    matches!(
+                        self.shown_file(i),
+                        Some((MessageKind::Viewport | MessageKind::EditCommand, file_path)) if file_path == active_file
+                    )
+                })
+                .filter(|&view| latest_capture.is_none_or(|capture| view > capture));
+            for i in [latest_capture, latest_view].into_iter().flatten() {
+                keep[i] = true;
+                if self.is_observation(i + 1) {
+                    keep[i + 1] = true;
+                }
+            }
+        }
+
+        let mut exchanges = 0;
+        for i in (0..n).rev() {
+            if exchanges == pins.last_exchanges {
+                break;
+            }
+            keep[i] = true;
+            if !self.is_observation(i) {
+                exchanges += 1;
+            }
+        }
+        keep
+    }
+
+    /// Remove the oldest unpinned exchanges (an action and its output) of the
+    /// current conversation until it fits in `budget` tokens, and return the
+    /// removed messages in order.
+    ///
+    /// Once a capture or edit of a file is removed, the file's later edits up
+    /// to its next capture are removed too, since their line numbers refer to
+    /// content the model no longer sees, and the file is captured again when
+    /// next shown. The conversation may still exceed the budget if the pinned
+    /// messages do.
+    pub fn trim_to_token_budget(
+        &mut self,
+        budget: usize,
+        pins: PinPolicy,
+    ) -> Result<Vec<ConversationMessage>, SerializerError> {
+        self.flush_all_pending_edits()?;
+        self.flush_terminal_output_buffer();
+        if self.current_tokens <= budget {
+            return Ok(Vec::new());
+        }
+
+        let pinned = self.pinned_messages(pins);
+        let mut keep = vec![true; pinned.len()];
+        let is_output = |j: usize| self.is_observation(j);
+        let mut excess = self.current_tokens - budget;
+        // Files whose state the model no longer saw since a removed exchange
+        let mut unseen: HashSet<String> = HashSet::new();
+        let mut i = 0;
+        while i < keep.len() {
+            let mut end = i + 1;
+            while end < keep.len() && is_output(end) {
+                end += 1;
+            }
+            let shown = self.shown_file(i);
+            let stale_edit =
+                matches!(shown, Some((MessageKind::EditCommand, file_path)) if unseen.contains(file_path));
+            if (excess > 0 || stale_edit) && !pinned[i..end].iter().any(|&p| p) {
+                keep[i..end].fill(false);
+                let tokens: usize = self.current_message_tokens[i..end].iter().sum();
+                excess = excess.saturating_sub(tokens);
+                if let Some((MessageKind::FileCapture | MessageKind::EditCommand, file_path)) = shown {
+                    unseen.insert(file_path.to_string());
+                }
+            } else if let Some((MessageKind::FileCapture, file_path)) = shown {
+                unseen.remove(file_path);
+            }
+            i = end;
+        }
+
+        self.remap_checkpoints(&keep);
+        let messages = std::mem::take(&mut self.messages);
+        let tokens = std::mem::take(&mut self.current_message_tokens);
+        let tags = std::mem::take(&mut self.current_message_tags);
+        let mut removed = Vec::new();
+        for (((message, tokens), tag), keep) in messages.into_iter().zip(tokens).zip(tags).zip(keep) {
+            if keep {
+                self.messages.push(message);
+                self.current_message_tokens.push(tokens);
+                self.current_message_tags.push(tag);
+            } else {
+                self.current_tokens -= tokens;
+                self.current_metrics.trimmed_messages += 1;
+                removed.push(message);
+            }
+        }
+        for file_path in unseen {
+            self.capture_history.remove(&file_path);
+        }
+        Ok(removed)
+    }
+
+    /// Assemble the best prompt within `max_prompt_tokens`: the system prompt,
+    /// the latest capture and the latest view of the active file (the file
+    /// shown or edited last), then as much recent history as fits.
+    ///
+    /// Pinned messages are kept even if they alone exceed the budget.
+    pub fn get_context_for_model(
+        &mut self,
+        system_prompt: &str,
+        max_prompt_tokens: usize,
+    ) -> Result<ModelContext, SerializerError> {
+        self.flush_all_pending_edits()?;
+        self.flush_terminal_output_buffer();
+
+        let n = self.messages.len();
+        let tokens: Vec<usize> = self
+            .messages
+            .iter()
+            .map(|m| self.tokenizer.count_tokens(&m.value))
+            .collect();
+        let system_tokens = self.tokenizer.count_tokens(system_prompt);
+        let mut keep = vec![system_tokens + tokens.iter().sum::<usize>() <= max_prompt_tokens; n];
+
+        if n > 0 && !keep[0] {
+            keep = self.pinned_messages(PinPolicy {
+                active_file: true,
+                last_exchanges: 0,
+            });
+
+            // Room for up to two elision markers (before and after pinned messages)
+            let mut used = system_tokens + 2 * self.tokenizer.count_tokens(ELISION_MARKER);
+            used += (0..n).filter(|&i| keep[i]).map(|i| tokens[i]).sum::<usize>();
+            for i in (0..n).rev() {
+                if keep[i] {
+                    continue;
+                }
+                if used + tokens[i] > max_prompt_tokens {
+                    break;
+                }
+                used += tokens[i];
+                keep[i] = true;
+            }
+        }
+
+        let mut context = ModelContext {
+            system: system_prompt.to_string(),
+            token_count: system_tokens,
+            ..Default::default()
+        };
+        let mut in_gap = false;
+        for ((message, tokens), keep) in self.messages.iter().zip(tokens).zip(keep) {
+            if !keep {
+                context.elided_messages += 1;
+                in_gap = true;
+                continue;
+            }
+            if in_gap {
+                context.messages.push(ConversationMessage::user(ELISION_MARKER));
+                context.token_count += self.tokenizer.count_tokens(ELISION_MARKER);
+                in_gap = false;
+            }
+            context.messages.push(message.clone());
+            context.token_count += tokens;
+        }
+        if in_gap {
+            context.messages.push(ConversationMessage::user(ELISION_MARKER));
+            context.token_count += self.tokenizer.count_tokens(ELISION_MARKER);
+        }
+        Ok(context)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::budget::CharApproxTokenizer;
+    use crate::validate::validate_structure;
+
+    #[test]
+    fn test_basic_tab_event() {
+        let mut manager =
+            ConversationStateManager::new(CharApproxTokenizer, ConversationStateManagerConfig::default());
+
+        manager
+            .handle_tab_event("/test/file.rs", Some("fn main() {\n    println!(\"hello\");\n}"))
+            .unwrap();
+
+        let messages = manager.finalize_for_model().unwrap();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].from, "Assistant");
+        assert!(messages[0].value.contains("cat -n /test/file.rs"));
+        assert_eq!(messages[1].from, "User");
+        assert!(messages[1].value.contains("<stdout>"));
+    }
+
+    #[test]
+    fn test_content_event() {
+        let mut manager =
+            ConversationStateManager::new(CharApproxTokenizer, ConversationStateManagerConfig::default());
+
+        manager.handle_tab_event("/test/file.rs", Some("line1\nline2\nline3")).unwrap();
+        manager.handle_content_event("/test/file.rs", 6, 5, "modified").unwrap();
+
+        let messages = manager.finalize_for_model().unwrap();
+        // Should have: cat (open file), stdout, sed (edit), stdout
+        assert!(messages.len() >= 4);
+    }
+
+    #[test]
+    fn test_terminal_command() {
+        let mut manager =
+            ConversationStateManager::new(CharApproxTokenizer, ConversationStateManagerConfig::default());
+
+        manager.handle_terminal_command_event("cargo build").unwrap();
+        manager.handle_terminal_output_event("Compiling...\n").unwrap();
+        manager.handle_terminal_output_event("Finished\n").unwrap();
+
+        let messages = manager.finalize_for_model().unwrap();
+        assert_eq!(messages.len(), 2);
+        assert!(messages[0].value.contains("cargo build"));
+        assert!(messages[1].value.contains("Compiling"));
+    }
+
+    #[test]
+    fn test_terminal_output_truncation_marker() {
+        let config = ConversationStateManagerConfig {
+            max_tokens_per_terminal_output: 10,
+            min_conversation_messages: 1,
+            ..Default::default()
+        };
+        let mut manager = ConversationStateManager::new(CharApproxTokenizer, config);
+
+        manager.handle_terminal_command_event("cat big.log").unwrap();
+        manager.handle_terminal_output_event(&"x".repeat(200)).unwrap();
+
+        let conversations = manager.get_conversations().unwrap();
+        let output = &conversations[0].messages[1].value;
+        let body = output.strip_prefix("<stdout>\n").unwrap().strip_suffix("\n</stdout>").unwrap();
+        assert!(body.ends_with(crate::TRUNCATION_MARKER));
+        assert!(CharApproxTokenizer.count_tokens(body) <= 10);
+        assert_eq!(conversations[0].metrics.truncated_messages, 1);
+    }
+
+    #[test]
+    fn test_tab_event_strips_bom() {
+        let mut manager =
+            ConversationStateManager::new(CharApproxTokenizer, ConversationStateManagerConfig::default());
+
+        manager.handle_tab_event("/test/a.cs", Some("\u{feff}abc\ndef")).unwrap();
+        manager.handle_content_event("/test/a.cs", 0, 3, "xyz").unwrap();
+
+        assert_eq!(manager.get_file_content("/test/a.cs"), "xyz\ndef");
+    }
+
+    #[test]
+    fn test_untracked_file_placeholder() {
+        let config = ConversationStateManagerConfig {
+            untracked_file_policy: UntrackedFilePolicy::Placeholder,
+            ..Default::default()
+        };
+        let mut manager = ConversationStateManager::new(CharApproxTokenizer, config);
+
+        manager.handle_content_event("/test/new.rs", 0, 0, "fn x() {}").unwrap();
+
+        let messages = manager.finalize_for_model().unwrap();
+        assert!(messages[0].value.contains("cat -n /test/new.rs"));
+        assert!(messages[1].value.contains("[file content unknown]"));
+        assert!(messages[2].value.contains("sed -i"));
+    }
+
+    #[test]
+    fn test_untracked_file_buffer() {
+        let config = ConversationStateManagerConfig {
+            untracked_file_policy: UntrackedFilePolicy::Buffer,
+            ..Default::default()
+        };
+        let mut manager = ConversationStateManager::new(CharApproxTokenizer, config);
+
+        manager.handle_content_event("/test/a.rs", 0, 0, "// ").unwrap();
+        assert!(manager.finalize_for_model().unwrap().is_empty());
+
+        manager.handle_tab_event("/test/a.rs", Some("line1\nline2")).unwrap();
+        assert_eq!(manager.get_file_content("/test/a.rs"), "// line1\nline2");
+
+        // A snapshot taken after the edits already contains them
+        manager.handle_content_event("/test/b.rs", 0, 0, "// ").unwrap();
+        manager.handle_content_event("/test/b.rs", 8, 1, "X").unwrap();
+        manager.handle_tab_event("/test/b.rs", Some("// line1Xline2")).unwrap();
+        assert_eq!(manager.get_file_content("/test/b.rs"), "// line1Xline2");
+    }
+
+    #[test]
+    fn test_offset_drift_resync() {
+        let config = ConversationStateManagerConfig {
+            resync_on_offset_drift: true,
+            ..Default::default()
+        };
+        let mut manager = ConversationStateManager::new(CharApproxTokenizer, config);
+
+        manager.handle_tab_event("/test/a.rs", Some("short")).unwrap();
+        manager.set_event_sequence(Some(7));
+        manager.handle_content_event("/test/a.rs", 100, 0, "x").unwrap();
+
+        let diagnostics = manager.take_drift_diagnostics();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].sequence, Some(7));
+        assert_eq!(diagnostics[0].tracked_length, 5);
+        assert_eq!(manager.get_files_awaiting_resync(), vec!["/test/a.rs"]);
+
+        // Further edits are dropped until a snapshot arrives
+        manager.handle_content_event("/test/a.rs", 0, 0, "y").unwrap();
+        assert_eq!(manager.get_file_content("/test/a.rs"), "short");
+
+        manager.handle_tab_event("/test/a.rs", Some("resynced")).unwrap();
+        assert!(manager.get_files_awaiting_resync().is_empty());
+        assert_eq!(manager.get_drop_stats().resync_edits, 1);
+    }
+
+    #[test]
+    fn test_drop_stats() {
+        let config = ConversationStateManagerConfig {
+            max_tokens_per_conversation: Some(10),
+            min_conversation_messages: 2,
+            ..Default::default()
+        };
+        let mut manager = ConversationStateManager::new(CharApproxTokenizer, config);
+
+        // Each command fills a whole chunk on its own
+        manager.handle_terminal_command_event(&"a".repeat(40)).unwrap();
+        manager.handle_terminal_command_event("ls").unwrap();
+        manager.handle_terminal_output_event("file").unwrap();
+
+        let conversations = manager.get_conversations().unwrap();
+        assert_eq!(conversations.len(), 1);
+        let drops = manager.get_drop_stats();
+        assert_eq!(drops.too_short_conversations, 1);
+        assert_eq!(drops.missing_role_conversations, 0);
+        assert_eq!(drops.discarded_messages, 1);
+    }
+
+    #[test]
+    fn test_force_finalize() {
+        let config = ConversationStateManagerConfig {
+            min_conversation_messages: 2,
+            ..Default::default()
+        };
+        let mut manager = ConversationStateManager::new(CharApproxTokenizer, config);
+        assert!(manager.force_finalize().unwrap().is_none());
+
+        manager.handle_tab_event("/a.rs", Some("fn a() {}")).unwrap();
+        manager.handle_terminal_command_event("cargo test").unwrap();
+        manager.handle_terminal_output_event("ok").unwrap();
+        let first = manager.force_finalize().unwrap().unwrap();
+        assert_eq!(first.messages.len(), 4);
+        assert_eq!(manager.message_count(), 0);
+
+        // The next conversation shows the file again before editing it
+        manager.handle_content_event("/a.rs", 0, 0, "// ").unwrap();
+        manager.handle_terminal_command_event("ls").unwrap();
+        let conversations = manager.get_conversations().unwrap();
+        assert_eq!(conversations.len(), 1);
+        assert!(conversations[0].messages[0].value.contains("cat -n /a.rs"));
+    }
+
+    #[test]
+    fn test_mask_recaptures() {
+        let config = ConversationStateManagerConfig {
+            max_tokens_per_conversation: Some(60),
+            min_conversation_messages: 1,
+            mask_recaptures: true,
+            ..Default::default()
+        };
+        let mut manager = ConversationStateManager::new(CharApproxTokenizer, config);
+
+        manager.handle_tab_event("/a.rs", Some("fn a() {}")).unwrap();
+        manager.handle_terminal_command_event(&"x".repeat(250)).unwrap();
+        manager.handle_content_event("/a.rs", 0, 0, "// ").unwrap();
+
+        let conversations = manager.get_conversations().unwrap();
+        let last = conversations.last().unwrap();
+        assert!(last.messages[0].value.contains("cat -n /a.rs"));
+        assert_eq!(last.messages[0].mask, Some(true));
+        assert_eq!(last.messages[1].mask, Some(true));
+        assert_eq!(last.messages[2].mask, None);
+    }
+
+    #[test]
+    fn test_capture_cache() {
+        let content = (1..=20).map(|i| format!("line {}\n", i)).collect::<String>();
+        let run = |max_recapture_lines: Option<usize>| {
+            let config = ConversationStateManagerConfig {
+                max_tokens_per_conversation: Some(140),
+                min_conversation_messages: 1,
+                max_recapture_lines,
+                ..Default::default()
+            };
+            let mut manager = ConversationStateManager::new(CharApproxTokenizer, config);
+            manager.handle_tab_event("/a.rs", Some(&content)).unwrap();
+            manager.handle_terminal_command_event(&"x".repeat(100)).unwrap();
+            manager.handle_terminal_output_event(&"y".repeat(200)).unwrap();
+            manager.handle_tab_event("/a.rs", Some(&content)).unwrap();
+            manager.get_conversations().unwrap()
+        };
+
+        let conversations = run(None);
+        let last = conversations.last().unwrap();
+        assert_eq!(last.messages[1].value, "```bash\ncat -n /a.rs\n```\n");
+        assert_eq!(last.messages[2].value, conversations[0].messages[1].value);
+        assert_eq!(last.message_token_counts[2], conversations[0].message_token_counts[1]);
+        assert_eq!(last.metrics.cached_captures, 1);
+
+        let conversations = run(Some(5));
+        let last = conversations.last().unwrap();
+        assert!(last.messages[1].value.contains("sed -n '1,5p'"));
+        assert!(!last.messages[2].value.contains("line 6"));
+        assert_eq!(last.metrics.shortened_recaptures, 1);
+    }
+
+    #[test]
+    fn test_ignored_paths() {
+        let config = ConversationStateManagerConfig {
+            ignored_paths: vec!["node_modules/**".parse().unwrap()],
+            ..Default::default()
+        };
+        let mut manager = ConversationStateManager::new(CharApproxTokenizer, config);
+        manager.handle_tab_event("/p/node_modules/x/index.js", Some("module.exports = 1;")).unwrap();
+        manager.handle_content_event("/p/node_modules/x/index.js", 0, 0, "// ").unwrap();
+        manager.handle_selection_event("/p/node_modules/x/index.js", 3, None).unwrap();
+        manager.handle_tab_event("/p/src/a.js", Some("let a;")).unwrap();
+
+        let messages = manager.finalize_for_model().unwrap();
+        assert!(messages.iter().all(|m| !m.value.contains("node_modules")));
+        assert!(messages[0].value.contains("/p/src/a.js"));
+        assert_eq!(manager.get_drop_stats().ignored_path_events, 3);
+        assert!(manager.tracked_files().iter().all(|f| !f.contains("node_modules")));
+    }
+
+    #[test]
+    fn test_max_tracked_files() {
+        let config = ConversationStateManagerConfig {
+            max_tracked_files: Some(2),
+            ..Default::default()
+        };
+        let mut manager = ConversationStateManager::new(CharApproxTokenizer, config);
+        manager.handle_tab_event("/a.rs", Some("a")).unwrap();
+        manager.handle_tab_event("/b.rs", Some("b")).unwrap();
+        // A pending edit keeps /b.rs tracked although /a.rs was used later
+        manager.handle_content_event("/b.rs", 1, 0, "x").unwrap();
+        manager.handle_selection_event("/a.rs", 0, None).unwrap();
+        manager.handle_tab_event("/c.rs", Some("c")).unwrap();
+        assert_eq!(manager.tracked_files(), vec!["/b.rs", "/c.rs"]);
+        assert_eq!(manager.current_metrics.evicted_files, 1);
+
+        // The forgotten file is captured again
+        manager.handle_tab_event("/a.rs", Some("a")).unwrap();
+        let messages = manager.finalize_for_model().unwrap();
+        assert_eq!(messages.iter().filter(|m| m.value.contains("cat -n /a.rs\n")).count(), 2);
+        assert_eq!(manager.tracked_files(), vec!["/a.rs", "/c.rs"]);
+
+        // Nothing of a forgotten file is left behind
+        let config = ConversationStateManagerConfig {
+            max_tracked_files: Some(1),
+            ..Default::default()
+        };
+        let mut manager = ConversationStateManager::new(CharApproxTokenizer, config);
+        manager.handle_tab_event("/a.rs", Some("a")).unwrap();
+        manager.force_finalize().unwrap();
+        assert!(manager.files_shown_in_earlier_chunks.contains("/a.rs"));
+        manager.handle_tab_event("/b.rs", Some("b")).unwrap();
+        assert_eq!(manager.tracked_files(), vec!["/b.rs"]);
+        assert!(manager.files_shown_in_earlier_chunks.is_empty());
+        assert!(manager.pending_edit_timing.is_empty());
+    }
+
+    #[test]
+    fn test_path_mappings() {
+        let config = ConversationStateManagerConfig {
+            path_mappings: vec!["/home/u/projA=projA/".parse().unwrap()],
+            ..Default::default()
+        };
+        let mut manager = ConversationStateManager::new(CharApproxTokenizer, config);
+
+        manager.handle_tab_event("/home/u/projA/src/a.rs", Some("fn a() {}")).unwrap();
+        manager.handle_content_event("/home/u/projA/src/a.rs", 0, 0, "// ").unwrap();
+        manager.handle_terminal_command_event("cd /home/u/projA && ls").unwrap();
+
+        let messages = manager.finalize_for_model().unwrap();
+        assert!(messages[0].value.contains("cat -n projA/src/a.rs"));
+        assert!(messages[2].value.contains("' projA/src/a.rs && cat -n projA/src/a.rs"));
+        assert!(messages[4].value.contains("cd projA && ls"));
+        assert_eq!(manager.get_file_content("projA/src/a.rs"), "// fn a() {}");
+    }
+
+    #[test]
+    fn test_path_canonicalization() {
+        let config = ConversationStateManagerConfig {
+            path_canonicalization: PathCanonicalization {
+                lexical: true,
+                case_fold: true,
+                symlinks: Vec::new(),
+            },
+            ..Default::default()
+        };
+        let mut manager = ConversationStateManager::new(CharApproxTokenizer, config);
+
+        manager.handle_tab_event("/p/src/Main.rs", Some("fn a() {}")).unwrap();
+        manager.handle_content_event("/p/./SRC/main.rs", 0, 0, "// ").unwrap();
+        manager.handle_tab_event("/p/lib/../src/main.rs", None).unwrap();
+
+        assert_eq!(manager.tracked_files(), vec!["/p/src/Main.rs"]);
+        assert_eq!(manager.get_file_content("/p/src/MAIN.rs"), "// fn a() {}");
+        let messages = manager.finalize_for_model().unwrap();
+        assert!(messages.iter().all(|m| !m.value.contains("SRC") && !m.value.contains("..")));
+    }
+
+    #[test]
+    fn test_companion_files() {
+        let config = ConversationStateManagerConfig {
+            companion_files: vec!["*.rs=*_test.rs".parse().unwrap()],
+            min_conversation_messages: 1,
+            ..Default::default()
+        };
+        let mut manager = ConversationStateManager::new(CharApproxTokenizer, config);
+        manager.handle_tab_event("/a_test.rs", Some("assert!(a());\n")).unwrap();
+        manager.handle_tab_event("/a.rs", Some("fn a() {}\n")).unwrap();
+        // The companion is already shown
+        manager.handle_content_event("/a.rs", 0, 0, "// a\n").unwrap();
+        manager.handle_terminal_command_event("cargo test").unwrap();
+        manager.handle_session_end_event().unwrap();
+        manager.handle_content_event("/a.rs", 0, 0, "// b\n").unwrap();
+        manager.handle_terminal_command_event("ls").unwrap();
+
+        let conversations = manager.get_conversations().unwrap();
+        assert_eq!(conversations[0].metrics.companion_views, 0);
+        assert_eq!(conversations[1].metrics.companion_views, 1);
+        let commands: Vec<&str> = conversations[1]
+            .messages
+            .iter()
+            .filter(|m| m.from == "Assistant")
+            .map(|m| m.value.as_str())
+            .collect();
+        assert!(commands[1].contains("sed -i"));
+        assert!(commands[2].contains("cat -n /a_test.rs"));
+    }
+
+    #[test]
+    fn test_observer_notifications() {
+        use std::sync::{Arc, Mutex};
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let config = ConversationStateManagerConfig {
+            max_tokens_per_message: 5,
+            ..Default::default()
+        };
+        let mut manager = ConversationStateManager::new(CharApproxTokenizer, config);
+        let sink = Arc::clone(&events);
+        manager.add_observer(Box::new(move |n: &Notification<'_>| {
+            let name = match n {
+                Notification::MessageAppended { .. } => "appended",
+                Notification::MessageRemoved { .. } => "removed",
+                Notification::EditFlushed { .. } => "edit",
+                Notification::ConversationFinalized { .. } => "finalized",
+                Notification::Truncated { .. } => "truncated",
+            };
+            sink.lock().unwrap().push(name);
+        }));
+
+        manager.handle_tab_event("/a.rs", Some("fn a() {}")).unwrap();
+        manager.handle_content_event("/a.rs", 0, 0, "// ").unwrap();
+        manager.get_conversations().unwrap();
+
+        assert_eq!(
+            *events.lock().unwrap(),
+            [
+                "truncated", "appended", "truncated", "appended", "edit", "truncated", "appended",
+                "truncated", "appended", "finalized"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_large_file_viewport_only() {
+        let config = ConversationStateManagerConfig {
+            viewport_radius: 2,
+            large_file_line_threshold: Some(10),
+            ..Default::default()
+        };
+        let mut manager = ConversationStateManager::new(CharApproxTokenizer, config);
+        let content: String = (1..=50).map(|i| format!("line{}\n", i)).collect();
+
+        manager.handle_tab_event("/gen.rs", Some(&content)).unwrap();
+        manager.handle_tab_event("/small.rs", Some("a\nb")).unwrap();
+
+        let messages = manager.finalize_for_model().unwrap();
+        assert!(messages[0].value.contains("wc -l /gen.rs"));
+        assert_eq!(messages[1].value, "<stdout>\n50 /gen.rs\n</stdout>");
+        assert!(messages[2].value.contains("cat -n /gen.rs | sed -n '1,3p'"));
+        assert!(!messages[3].value.contains("line4"));
+        assert!(messages[4].value.contains("cat -n /small.rs\n"));
+    }
+
+    #[test]
+    fn test_serialization_metrics() {
+        let config = ConversationStateManagerConfig {
+            max_tokens_per_terminal_output: 2,
+            min_conversation_messages: 1,
+            ..Default::default()
+        };
+        let mut manager = ConversationStateManager::new(CharApproxTokenizer, config);
+        manager.handle_tab_event("/a.rs", Some("fn a() {}\n")).unwrap();
+        manager.handle_content_event("/a.rs", 0, 0, "/").unwrap();
+        manager.handle_content_event("/a.rs", 1, 0, "/").unwrap();
+        manager.handle_content_event("/a.rs", 2, 0, " ").unwrap();
+        manager.handle_terminal_command_event("cargo build").unwrap();
+        manager.handle_terminal_output_event("a long line of compiler output\n").unwrap();
+
+        let conversations = manager.get_conversations().unwrap();
+        let metrics = &conversations[0].metrics;
+        assert_eq!(metrics.coalesced_edits, 2);
+        assert_eq!(metrics.edit_commands, 1);
+        assert_eq!(metrics.truncated_outputs, 1);
+    }
+
+    #[test]
+    fn test_capture_history() {
+        let mut manager =
+            ConversationStateManager::new(CharApproxTokenizer, ConversationStateManagerConfig::default());
+        manager.handle_tab_event("/a.rs", Some("fn a() {}\n")).unwrap();
+        manager.handle_terminal_command_event("ls").unwrap();
+        // Unchanged: viewport refresh only; a tab without content repeats nothing
+        manager.handle_tab_event("/a.rs", Some("fn a() {}\n")).unwrap();
+        manager.handle_tab_event("/a.rs", None).unwrap();
+        // Changed outside the editor: captured again
+        manager.handle_tab_event("/a.rs", Some("fn b() {}\n")).unwrap();
+
+        let messages = manager.finalize_for_model().unwrap();
+        let count = |needle: &str| messages.iter().filter(|m| m.value.contains(needle)).count();
+        assert_eq!(count("cat -n /a.rs\n"), 2);
+        assert_eq!(count("cat -n /a.rs | sed -n"), 1);
+    }
+
+    #[test]
+    fn test_compact_conversations() {
+        let config = ConversationStateManagerConfig {
+            compact_conversations: true,
+            min_conversation_messages: 1,
+            ..Default::default()
+        };
+        let mut manager = ConversationStateManager::new(CharApproxTokenizer, config);
+        let content = (1..=100).map(|i| format!("line {}\n", i)).collect::<String>();
+
+        manager.handle_tab_event("/a.rs", Some(&content)).unwrap();
+        // Superseded by the next viewport read
+        manager.handle_selection_event("/a.rs", content.find("line 50").unwrap(), None).unwrap();
+        manager.handle_selection_event("/a.rs", content.find("line 80").unwrap(), None).unwrap();
+        manager.handle_directory_open_event("/empty", &[]).unwrap();
+        manager.handle_tab_event("/b.rs", Some("fn b() {}")).unwrap();
+        // Shown unchanged before: only the viewport is refreshed
+        manager.handle_tab_event("/b.rs", Some("fn b() {}")).unwrap();
+        // Shown again after an edit and its revert: kept
+        manager.handle_tab_event("/c.rs", Some("c\n")).unwrap();
+        manager.handle_content_event("/c.rs", 0, 1, "x").unwrap();
+        manager.handle_terminal_command_event("git checkout c.rs").unwrap();
+        manager.handle_terminal_output_event("Updated 1 path\n").unwrap();
+        manager.handle_tab_event("/b.rs", Some("fn b() {}")).unwrap();
+        manager.handle_tab_event("/c.rs", Some("c\n")).unwrap();
+
+        let conversations = manager.get_conversations().unwrap();
+        let conv = &conversations[0];
+        let viewports = conv
+            .messages
+            .iter()
+            .filter(|m| m.value.contains("cat -n /a.rs | sed -n"))
+            .count();
+        let captures = conv
+            .messages
+            .iter()
+            .filter(|m| m.value.contains("cat -n /b.rs\n"))
+            .count();
+        assert_eq!(viewports, 1);
+        assert_eq!(captures, 1);
+        let c_captures = conv.messages.iter().filter(|m| m.value.contains("cat -n /c.rs\n")).count();
+        assert_eq!(c_captures, 2);
+        // The only answer to `ls /empty`
+        assert!(validate_structure(&conv.messages).is_empty());
+        assert_eq!(conv.metrics.compacted_messages, 2);
+        assert_eq!(conv.token_count, conv.message_token_counts.iter().sum::<usize>());
+    }
+
+    #[test]
+    fn test_get_context_for_model() {
+        let mut manager =
+            ConversationStateManager::new(CharApproxTokenizer, ConversationStateManagerConfig::default());
+        manager.handle_tab_event("/a.rs", Some("fn a() {}\n")).unwrap();
+        for i in 0..20 {
+            manager.handle_terminal_command_event(&format!("echo {}", i)).unwrap();
+            manager.handle_terminal_output_event(&format!("{}\n", i)).unwrap();
+        }
+
+        let full = manager.get_context_for_model("sys", 10_000).unwrap();
+        assert_eq!(full.elided_messages, 0);
+        assert_eq!(full.messages.len(), 42);
+
+        let context = manager.get_context_for_model("sys", 60).unwrap();
+        assert!(context.elided_messages > 0);
+        assert!(context.token_count <= 60);
+        // The active file's capture is pinned, followed by the elision point
+        assert!(context.messages[0].value.contains("cat -n /a.rs"));
+        assert_eq!(context.messages[2].value, ELISION_MARKER);
+        assert!(context.messages.last().unwrap().value.contains("19"));
+    }
+
+    #[test]
+    fn test_trim_to_token_budget() {
+        let mut manager =
+            ConversationStateManager::new(CharApproxTokenizer, ConversationStateManagerConfig::default());
+        manager.handle_tab_event("/a.rs", Some("fn a() {}\n")).unwrap();
+        for i in 0..20 {
+            manager.handle_terminal_command_event(&format!("echo {}", i)).unwrap();
+            manager.handle_terminal_output_event(&format!("{}\n", i)).unwrap();
+        }
+
+        let pins = PinPolicy {
+            active_file: true,
+            last_exchanges: 2,
+        };
+        assert!(manager.trim_to_token_budget(10_000, pins).unwrap().is_empty());
+        let removed = manager.trim_to_token_budget(60, pins).unwrap();
+        assert!(removed[0].value.contains("echo 0"));
+        assert_eq!(removed.len() % 2, 0);
+        let messages = manager.finalize_for_model().unwrap();
+        assert_eq!(messages.len() + removed.len(), 42);
+        // The active file's capture and the last two exchanges are pinned
+        assert!(messages[0].value.contains("cat -n /a.rs"));
+        assert!(messages[messages.len() - 4].value.contains("echo 18"));
+        assert!(messages.iter().map(|m| m.value.len() / 4).sum::<usize>() <= 60);
+    }
+
+    #[test]
+    fn test_trim_to_token_budget_edits() {
+        let mut manager =
+            ConversationStateManager::new(CharApproxTokenizer, ConversationStateManagerConfig::default());
+        let content: String = (1..=30).map(|i| format!("l{}\n", i)).collect();
+        manager.handle_tab_event("/a.rs", Some(&content)).unwrap();
+        manager.handle_content_event("/a.rs", 0, 2, "x1").unwrap();
+        manager.handle_content_event("/a.rs", content.find("l25").unwrap(), 3, "x25").unwrap();
+        manager.handle_terminal_command_event("ls").unwrap();
+        manager.handle_terminal_output_event("a.rs\n").unwrap();
+
+        // Removing only the capture also removes the edits made on top of it
+        let tokens: usize = manager.get_messages().iter().map(|m| m.value.len() / 4).sum();
+        let pins = PinPolicy {
+            active_file: false,
+            last_exchanges: 1,
+        };
+        let removed = manager.trim_to_token_budget(tokens - 1, pins).unwrap();
+        assert_eq!(removed.len(), 6);
+        assert!(manager.get_messages()[0].value.contains("ls"));
+        // The file is captured again when next shown
+        manager.handle_tab_event("/a.rs", None).unwrap();
+        assert!(manager.get_messages()[2].value.contains("cat -n /a.rs\n"));
+    }
+
+    #[test]
+    fn test_custom_renderer() {
+        use crate::render::{Action, Observation, Renderer};
+
+        struct ToolCallRenderer;
+        impl Renderer for ToolCallRenderer {
+            fn render_action(&self, action: &Action<'_>) -> String {
+                match action {
+                    Action::ReadFile { path, .. } => format!("open_file({})", path),
+                    other => format!("{:?}", other),
+                }
+            }
+            fn render_observation(&self, _observation: &Observation<'_>) -> String {
+                "<observation>".to_string()
+            }
+        }
+
+        let mut manager = ConversationStateManager::with_renderer(
+            CharApproxTokenizer,
+            ConversationStateManagerConfig::default(),
+            Box::new(ToolCallRenderer),
+        );
+        manager.handle_tab_event("/a.rs", Some("fn a() {}")).unwrap();
+
+        let messages = manager.finalize_for_model().unwrap();
+        assert_eq!(messages[0].value, "open_file(/a.rs)");
+        assert_eq!(messages[1].value, "<observation>");
+    }
+
+    #[test]
+    fn test_directory_open_event() {
+        let mut manager =
+            ConversationStateManager::new(CharApproxTokenizer, ConversationStateManagerConfig::default());
+
+        manager.handle_directory_open_event("/proj/src", &["lib.rs", "main.rs"]).unwrap();
+
+        let messages = manager.finalize_for_model().unwrap();
+        assert!(messages[0].value.contains("ls -la /proj/src"));
+        assert_eq!(messages[1].value, "<stdout>\nlib.rs\nmain.rs\n</stdout>");
+    }
+
+    #[test]
+    fn test_vim_command_event() {
+        let config = ConversationStateManagerConfig {
+            min_conversation_messages: 1,
+            ..Default::default()
+        };
+        let mut manager = ConversationStateManager::new(CharApproxTokenizer, config);
+        manager.handle_tab_event("/a.rs", Some("foo 1\nbar\nfoo 2 foo\nbaz\n")).unwrap();
+
+        // Cursor on line 2
+        manager.handle_vim_command_event("/a.rs", "dd", Some(7)).unwrap();
+        assert_eq!(manager.get_file_content("/a.rs"), "foo 1\nfoo 2 foo\nbaz\n");
+        manager.handle_vim_command_event("/a.rs", ":%s/foo \\(\\d\\)/\\1'/g", None).unwrap();
+        assert_eq!(manager.get_file_content("/a.rs"), "1'\n2' foo\nbaz\n");
+        manager.handle_vim_command_event("/a.rs", "G", None).unwrap();
+        manager.handle_vim_command_event("/a.rs", "ciw", Some(0)).unwrap();
+        manager.handle_vim_command_event("/a.rs", "dd", None).unwrap();
+        assert_eq!(manager.get_drop_stats().unsupported_vim_commands, 2);
+
+        let messages = manager.finalize_for_model().unwrap();
+        assert!(messages[2].value.contains("sed -i '2,2d' /a.rs"));
+        assert!(messages[4]
+            .value
+            .contains("sed -i '1,2c\\\n1'\"'\"'\n2'\"'\"' foo' /a.rs"));
+
+        let conversations = manager.get_conversations().unwrap();
+        let outcome = crate::replay::replay_conversation(&conversations[0]);
+        assert!(outcome.checks >= 2 && outcome.passed(), "{:?}", outcome);
+    }
 
-        let changed = compute_changed_block_lines(&before_snapshot, &after_state)
-            .expect("Failed to compute changed block lines");
+    #[test]
+    fn test_selection_ranges() {
+        let content = (1..=30).map(|i| format!("line {}\n", i)).collect::<String>();
+        let offset_of = |line: usize| content.find(&format!("line {}\n", line)).unwrap();
+        for emit_selection_ranges in [false, true] {
+            let config = ConversationStateManagerConfig {
+                viewport_radius: 5,
+                emit_selection_ranges,
+                ..Default::default()
+            };
+            let mut manager = ConversationStateManager::new(CharApproxTokenizer, config);
+            manager.handle_tab_event("/a.rs", Some(&content)).unwrap();
+            manager.handle_selection_event("/a.rs", offset_of(2), None).unwrap();
+            let before = manager.message_count();
 
-        let before_total_lines = before_snapshot.split('\n').count();
-        let sed_cmd: String;
+            // Selecting lines 3..=20, ending at the start of line 21
+            manager.handle_selection_event("/a.rs", offset_of(21), Some(offset_of(3))).unwrap();
+            assert_eq!(manager.get_selection("/a.rs"), Some(Viewport { start: 3, end: 20 }));
 
-        if changed.end_before < changed.start_before {
-            // Pure insertion
-            let escaped_lines: Vec<String> = changed
-                .replacement_lines
-                .iter()
-                .map(|line| escape_single_quotes_for_sed(line))
-                .collect();
-            let sed_payload = escaped_lines.join("\n");
-            if changed.start_before <= before_total_lines.max(1) {
-                sed_cmd = format!(
-                    "sed -i '{}i\\\n{}' {}",
-                    changed.start_before, sed_payload, target_file
-                );
+            let messages = manager.finalize_for_model().unwrap();
+            if emit_selection_ranges {
+                assert_eq!(messages.len(), before + 2);
+                assert!(messages[before].value.contains("sed -n '3,20p'"));
             } else {
-                sed_cmd = format!("sed -i '$a\\\n{}' {}", sed_payload, target_file);
+                assert_eq!(messages.len(), before);
             }
-        } else if changed.replacement_lines.is_empty() {
-            // Pure deletion
-            sed_cmd = format!(
-                "sed -i '{},{}d' {}",
-                changed.start_before, changed.end_before, target_file
-            );
-        } else {
-            // Replacement
-            let escaped_lines: Vec<String> = changed
-                .replacement_lines
-                .iter()
-                .map(|line| escape_single_quotes_for_sed(line))
-                .collect();
-            let sed_payload = escaped_lines.join("\n");
-            sed_cmd = format!(
-                "sed -i '{},{}c\\\n{}' {}",
-                changed.start_before, changed.end_before, sed_payload, target_file
-            );
+
+            manager.handle_selection_event("/a.rs", offset_of(4), Some(offset_of(4))).unwrap();
+            assert_eq!(manager.get_selection("/a.rs"), None);
         }
 
-        let total_lines = after_state.split('\n').count();
-        let center = (changed.start_after + changed.end_after) / 2;
-        let vp = serialize_compute_viewport(total_lines, center, self.config.viewport_radius);
-        self.per_file_viewport
-            .insert(target_file.to_string(), Some(vp));
+        // An end inside a multi-byte character
+        let mut manager = ConversationStateManager::new(CharApproxTokenizer, ConversationStateManagerConfig::default());
+        manager.handle_tab_event("/a.txt", Some("a\né\n")).unwrap();
+        manager.handle_selection_event("/a.txt", 0, Some(3)).unwrap();
+        assert_eq!(manager.get_selection("/a.txt"), Some(Viewport { start: 1, end: 2 }));
+    }
 
-        self.maybe_capture_file_contents(target_file, &before_snapshot);
+    #[test]
+    fn test_content_debounce() {
+        let run = |content_debounce_ms: Option<u64>| {
+            let config = ConversationStateManagerConfig {
+                content_debounce_ms,
+                ..Default::default()
+            };
+            let mut manager = ConversationStateManager::new(CharApproxTokenizer, config);
+            manager.handle_tab_event("/a.rs", Some("fn a() {}\n")).unwrap();
+            for (i, ch) in "xyz".chars().enumerate() {
+                manager.set_event_time(Some(1000 + i as i64 * 10));
+                manager.handle_content_event("/a.rs", i, 0, &ch.to_string()).unwrap();
+            }
+            if content_debounce_ms.is_some() {
+                // Held back until the burst is flushed
+                assert_eq!(manager.get_file_content("/a.rs"), "fn a() {}\n");
+                manager.flush_pending().unwrap();
+            }
+            assert_eq!(manager.get_file_content("/a.rs"), "xyzfn a() {}\n");
+            manager.handle_terminal_command_event("ls").unwrap();
+            manager.finalize_for_model().unwrap()
+        };
+        assert_eq!(run(Some(50)), run(None));
 
-        let chained_cmd = format!(
-            "{} && cat -n {} | sed -n '{},{}p'",
-            sed_cmd, target_file, vp.start, vp.end
-        );
-        self.append_message(ConversationMessage::assistant(fenced_block(
-            Some("bash"),
-            &clean_text(&chained_cmd),
-        )));
+        // A later event applies the queue first
+        let config = ConversationStateManagerConfig {
+            content_debounce_ms: Some(50),
+            ..Default::default()
+        };
+        let mut manager = ConversationStateManager::new(CharApproxTokenizer, config.clone());
+        manager.handle_tab_event("/a.rs", Some("a\n")).unwrap();
+        manager.handle_content_event("/a.rs", 0, 1, "b").unwrap();
+        manager.handle_selection_event("/a.rs", 0, None).unwrap();
+        assert_eq!(manager.get_file_content("/a.rs"), "b\n");
 
-        let viewport_output = line_numbered_output(&after_state, Some(vp.start), Some(vp.end));
-        self.append_message(ConversationMessage::user(format!(
-            "<stdout>\n{}\n</stdout>",
-            viewport_output
-        )));
+        // A burst handed over at once is applied by the single call
+        let mut manager = ConversationStateManager::new(CharApproxTokenizer, config);
+        manager.handle_tab_event("/a.rs", Some("fn a() {}\n")).unwrap();
+        manager
+            .handle_content_events("/a.rs", &[(0, 0, "x"), (1, 0, "y"), (2, 0, "z")])
+            .unwrap();
+        assert_eq!(manager.get_file_content("/a.rs"), "xyzfn a() {}\n");
+        manager.handle_terminal_command_event("ls").unwrap();
+        assert_eq!(manager.finalize_for_model().unwrap(), run(None));
+    }
 
-        self.pending_edits_before.insert(target_file.to_string(), None);
-        self.pending_edit_regions.insert(target_file.to_string(), None);
+    #[test]
+    fn test_selection_event_line() {
+        let content = (1..=30).map(|i| format!("line {}\n", i)).collect::<String>();
+        let config = ConversationStateManagerConfig {
+            viewport_radius: 5,
+            emit_selection_ranges: true,
+            ..Default::default()
+        };
+        let mut manager = ConversationStateManager::new(CharApproxTokenizer, config);
+        manager.handle_tab_event("/a.rs", Some(&content)).unwrap();
+        manager.handle_selection_event_line("/a.rs", 2, 0, None).unwrap();
+        let before = manager.message_count();
+
+        // Backwards selection of lines 3..=20, ending at the start of line 21
+        manager.handle_selection_event_line("/a.rs", 21, 0, Some((3, 2))).unwrap();
+        assert_eq!(manager.get_selection("/a.rs"), Some(Viewport { start: 3, end: 20 }));
+        let messages = manager.finalize_for_model().unwrap();
+        assert!(messages[before].value.contains("sed -n '3,20p'"));
+
+        // Lines past the end are clamped
+        manager.handle_selection_event_line("/a.rs", 99, 4, None).unwrap();
+        assert_eq!(manager.get_selection("/a.rs"), None);
+        let messages = manager.finalize_for_model().unwrap();
+        assert!(messages[messages.len() - 2].value.contains("sed -n '26,31p'"));
     }
 
-    /// Flush all pending edits.
-    pub fn flush_all_pending_edits(&mut self) {
-        let files: Vec<String> = self.pending_edits_before.keys().cloned().collect();
-        for file in files {
-            self.flush_pending_edit_for_file(&file);
-        }
+    #[test]
+    fn test_center_and_annotate_selections() {
+        let content = (1..=40).map(|i| format!("line {}\n", i)).collect::<String>();
+        let config = ConversationStateManagerConfig {
+            viewport_radius: 3,
+            center_selections: true,
+            annotate_selections: true,
+            ..Default::default()
+        };
+        let mut manager = ConversationStateManager::new(CharApproxTokenizer, config);
+        manager.handle_tab_event("/a.rs", Some(&content)).unwrap();
+        let before = manager.message_count();
+
+        // Lines 20..=23
+        let start = content.find("line 20").unwrap();
+        let end = content.find("line 24").unwrap();
+        manager.handle_selection_range_event("/a.rs", end, start).unwrap();
+        let messages = manager.finalize_for_model().unwrap();
+        assert!(messages[before].value.contains("sed -n '18,24p'"));
+        let stdout = &messages[before + 1].value;
+        assert!(stdout.contains("\n    19\tline 19\n>   20\tline 20\n"));
+        assert!(stdout.contains(">   23\tline 23\n    24\tline 24\n"));
+
+        let conversation = FinalizedConversation {
+            messages,
+            ..Default::default()
+        };
+        let outcome = crate::replay_conversation(&conversation);
+        assert_eq!((outcome.checks > 0, outcome.failures), (true, 0));
     }
 
-    /// Handle a tab (file switch) event.
-    pub fn handle_tab_event(&mut self, file_path: &str, text_content: Option<&str>) {
-        self.flush_all_pending_edits();
-        self.flush_terminal_output_buffer();
+    #[test]
+    fn test_handle_event() {
+        let events = [
+            IdeEvent::Tab { file_path: "/a.rs", content: Some("fn a() {}\n") },
+            IdeEvent::Content { file_path: "/a.rs", offset: 0, length: 0, text: "// a\n" },
+            IdeEvent::Selection { file_path: "/a.rs", offset: 0, end_offset: None },
+            IdeEvent::TerminalCommand { command: "cargo build" },
+            IdeEvent::TerminalOutput { output: "ok" },
+            IdeEvent::DirectoryOpen { dir_path: "/src", entries: vec!["a.rs"] },
+        ];
+        let mut typed = ConversationStateManager::new(CharApproxTokenizer, Default::default());
+        typed.handle_events(events).unwrap();
 
-        if let Some(text) = text_content {
-            let content = text.replace("\\n", "\n").replace("\\r", "\r");
-            self.file_states.insert(file_path.to_string(), content.clone());
+        let mut direct = ConversationStateManager::new(CharApproxTokenizer, Default::default());
+        direct.handle_tab_event("/a.rs", Some("fn a() {}\n")).unwrap();
+        direct.handle_content_event("/a.rs", 0, 0, "// a\n").unwrap();
+        direct.handle_selection_event("/a.rs", 0, None).unwrap();
+        direct.handle_terminal_command_event("cargo build").unwrap();
+        direct.handle_terminal_output_event("ok").unwrap();
+        direct.handle_directory_open_event("/src", &["a.rs"]).unwrap();
 
-            let cmd = format!("cat -n {}", file_path);
-            self.append_message(ConversationMessage::assistant(fenced_block(
-                Some("bash"),
-                &clean_text(&cmd),
-            )));
-            let output = line_numbered_output(&content, None, None);
-            self.append_message(ConversationMessage::user(format!(
-                "<stdout>\n{}\n</stdout>",
-                output
-            )));
-            self.files_opened_in_conversation.insert(file_path.to_string());
-        } else {
-            // File switch without content snapshot: show current viewport only
-            let content = self.file_states.get(file_path).cloned().unwrap_or_default();
-            let total_lines = content.split('\n').count();
-            let vp = self
-                .per_file_viewport
-                .get(file_path)
-                .and_then(|v| *v)
-                .filter(|v| v.end > 0)
-                .unwrap_or_else(|| {
-                    let new_vp = serialize_compute_viewport(total_lines, 1, self.config.viewport_radius);
-                    self.per_file_viewport.insert(file_path.to_string(), Some(new_vp));
-                    new_vp
-                });
+        assert_eq!(typed.finalize_for_model().unwrap(), direct.finalize_for_model().unwrap());
+    }
 
-            if vp.end >= vp.start {
-                self.maybe_capture_file_contents(file_path, &content);
-                let cmd = format!("cat -n {} | sed -n '{},{}p'", file_path, vp.start, vp.end);
-                self.append_message(ConversationMessage::assistant(fenced_block(
-                    Some("bash"),
-                    &clean_text(&cmd),
-                )));
-                let viewport_output = line_numbered_output(&content, Some(vp.start), Some(vp.end));
-                self.append_message(ConversationMessage::user(format!(
-                    "<stdout>\n{}\n</stdout>",
-                    viewport_output
-                )));
-            }
-        }
+    #[test]
+    fn test_append_injected_messages() {
+        let config = ConversationStateManagerConfig {
+            max_tokens_per_message: 64,
+            ..Default::default()
+        };
+        let mut manager = ConversationStateManager::new(CharApproxTokenizer, config);
+        let listing = "a.rs\n".repeat(100);
+        manager.handle_tab_event("/a.rs", Some("fn a() {}\n")).unwrap();
+        manager.handle_content_event("/a.rs", 0, 0, "// a\n").unwrap();
+        manager.append_assistant_action("```bash\nls\n```").unwrap();
+        manager.append_observation(&listing).unwrap();
+
+        let messages = manager.finalize_for_model().unwrap();
+        let n = messages.len();
+        // The pending edit is flushed before the injected action
+        assert!(messages[n - 3].value.contains("// a"));
+        assert_eq!(
+            messages[n - 2],
+            ConversationMessage::assistant("```bash\nls\n```").with_kind(MessageKind::Other)
+        );
+        assert_eq!(messages[n - 1].from, "User");
+        assert!(messages[n - 1].value.len() < listing.len());
     }
 
-    /// Handle a content change event.
-    pub fn handle_content_event(
-        &mut self,
-        file_path: &str,
-        offset: usize,
-        length: usize,
-        new_text: &str,
-    ) {
-        self.flush_terminal_output_buffer();
+    #[test]
+    fn test_reorder_window() {
+        let config = ConversationStateManagerConfig {
+            reorder_window: Some(2),
+            ..Default::default()
+        };
+        let mut reordered = ConversationStateManager::new(CharApproxTokenizer, config);
+        // The edit arrives before the switch to its file, with the same sequence
+        let events = [
+            (1, IdeEvent::Content { file_path: "/a.rs", offset: 0, length: 0, text: "// a\n" }),
+            (1, IdeEvent::Tab { file_path: "/a.rs", content: Some("fn a() {}\n") }),
+            (3, IdeEvent::TerminalOutput { output: "ok" }),
+            (2, IdeEvent::TerminalCommand { command: "cargo build" }),
+        ];
+        for (sequence, event) in events {
+            reordered.set_event_sequence(Some(sequence));
+            reordered.handle_event(event).unwrap();
+        }
+        assert_eq!(reordered.current_metrics.reordered_events, 1);
 
-        let before = self.file_states.get(file_path).cloned().unwrap_or_default();
-        let new_text_str = new_text;
+        let mut direct = ConversationStateManager::new(CharApproxTokenizer, Default::default());
+        direct.handle_tab_event("/a.rs", Some("fn a() {}\n")).unwrap();
+        direct.handle_content_event("/a.rs", 0, 0, "// a\n").unwrap();
+        direct.handle_terminal_command_event("cargo build").unwrap();
+        direct.handle_terminal_output_event("ok").unwrap();
 
-        // Approximate current edit region in line space
-        let safe_offset = floor_char_boundary(&before, offset.min(before.len()));
-        let safe_end = floor_char_boundary(&before, (offset + length).min(before.len()));
-        let start_line_current = before[..safe_offset].matches('\n').count() + 1;
-        let deleted_content = &before[safe_offset..safe_end];
-        let lines_added = new_text_str.matches('\n').count();
-        let lines_deleted = deleted_content.matches('\n').count();
-        let region_start = start_line_current;
-        let region_end = start_line_current + lines_added.max(lines_deleted);
+        assert_eq!(reordered.finalize_for_model().unwrap(), direct.finalize_for_model().unwrap());
+    }
 
-        // Flush pending edits if this edit is far from the pending region
-        let current_region = self.pending_edit_regions.get(file_path).and_then(|r| *r);
-        if let Some(region) = current_region {
-            if region_start < region.start.saturating_sub(self.config.coalesce_radius)
-                || region_start > region.end + self.config.coalesce_radius
-            {
-                self.flush_pending_edit_for_file(file_path);
+    #[test]
+    fn test_viewport_follow_modes() {
+        let content = (1..=100).map(|i| format!("line {}\n", i)).collect::<String>();
+        let offset_of = |line: usize| content.find(&format!("line {}\n", line)).unwrap();
+        let viewports = |config: ConversationStateManagerConfig, lines: &[usize]| {
+            let mut manager = ConversationStateManager::new(CharApproxTokenizer, config);
+            manager.handle_tab_event("/a.rs", Some(&content)).unwrap();
+            for &line in lines {
+                manager.handle_selection_event("/a.rs", offset_of(line), None).unwrap();
             }
-        }
+            manager
+                .finalize_for_model()
+                .unwrap()
+                .iter()
+                .filter_map(|m| m.value.split("sed -n '").nth(1))
+                .map(|range| range.split('p').next().unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+        let moves = [50, 53, 56, 58];
 
-        let after = crate::helpers::apply_change(&before, offset, length, new_text);
+        let symmetric = ConversationStateManagerConfig {
+            viewport_radius: 10,
+            ..Default::default()
+        };
+        assert_eq!(viewports(symmetric.clone(), &moves), vec!["40,60"]);
 
-        if self.pending_edits_before.get(file_path).and_then(|v| v.as_ref()).is_none() {
-            self.pending_edits_before
-                .insert(file_path.to_string(), Some(before));
-        }
+        let asymmetric = ConversationStateManagerConfig {
+            viewport_lines_above: Some(2),
+            viewport_lines_below: Some(8),
+            ..symmetric.clone()
+        };
+        assert_eq!(viewports(asymmetric, &[50, 53, 59]), vec!["48,58", "57,67"]);
 
-        // Update/initialize region union
-        let current_region = self.pending_edit_regions.get(file_path).and_then(|r| *r);
-        let new_region = if let Some(region) = current_region {
-            EditRegion {
-                start: region.start.min(region_start),
-                end: region.end.max(region_end),
-            }
-        } else {
-            EditRegion {
-                start: region_start,
-                end: region_start.max(region_end),
-            }
+        let margin = ConversationStateManagerConfig {
+            viewport_margin: 3,
+            ..symmetric.clone()
         };
-        self.pending_edit_regions
-            .insert(file_path.to_string(), Some(new_region));
+        assert_eq!(viewports(margin, &moves), vec!["40,60", "48,68"]);
+
+        let recenter = ConversationStateManagerConfig {
+            viewport_recenter_every: Some(2),
+            ..symmetric.clone()
+        };
+        assert_eq!(viewports(recenter, &moves), vec!["40,60", "46,66"]);
 
-        self.file_states.insert(file_path.to_string(), after);
+        // Scrolling back and forth across the bottom edge
+        let jitter = [50, 61, 59, 62, 75];
+        assert_eq!(viewports(symmetric.clone(), &jitter), vec!["40,60", "51,71", "65,85"]);
+        let min_movements = ConversationStateManagerConfig {
+            viewport_min_movements: 3,
+            ..symmetric.clone()
+        };
+        assert_eq!(viewports(min_movements, &jitter), vec!["40,60", "52,72"]);
+        let min_shift = ConversationStateManagerConfig {
+            viewport_min_shift: 15,
+            ..symmetric
+        };
+        assert_eq!(viewports(min_shift, &jitter), vec!["40,60", "65,85"]);
     }
 
-    /// Handle a selection event.
-    pub fn handle_selection_event(&mut self, file_path: &str, offset: usize) {
-        // During an edit burst (pending edits), suppress viewport emissions
-        if self.pending_edits_before.get(file_path).and_then(|v| v.as_ref()).is_some() {
-            return;
-        }
+    #[test]
+    fn test_terminal_buffer_flush_triggers() {
+        let config = ConversationStateManagerConfig {
+            max_terminal_buffer_bytes: Some(10),
+            max_terminal_buffer_age_ms: Some(5000),
+            ..Default::default()
+        };
+        let mut manager = ConversationStateManager::new(CharApproxTokenizer, config);
+        manager.handle_terminal_command_event("make").unwrap();
 
-        self.flush_terminal_output_buffer();
+        // Size: flushed as soon as 10 bytes are buffered
+        manager.handle_terminal_output_event("12345\n").unwrap();
+        assert_eq!(manager.message_count(), 1);
+        manager.handle_terminal_output_event("6789\n").unwrap();
+        assert_eq!(manager.message_count(), 2);
 
-        let content = self.file_states.get(file_path).cloned().unwrap_or_default();
-        let total_lines = content.split('\n').count();
-        let safe_offset = floor_char_boundary(&content, offset.min(content.len()));
-        let target_line = content[..safe_offset].matches('\n').count() + 1;
+        // Age: by the next event's time, or by an explicit tick
+        manager.set_event_time(Some(1000));
+        manager.handle_terminal_output_event("a\n").unwrap();
+        manager.set_event_time(Some(4000));
+        manager.handle_terminal_output_event("b\n").unwrap();
+        assert_eq!(manager.message_count(), 2);
+        manager.tick(5999);
+        assert!(!manager.get_messages()[1].value.contains("a\nb"));
+        // Each flush joins the command's single output message
+        manager.tick(6000);
+        assert_eq!(manager.message_count(), 2);
+        assert_eq!(manager.get_messages()[1].value, "<stdout>\n12345\n6789\na\nb\n</stdout>");
+    }
 
-        let current_vp = self.per_file_viewport.get(file_path).and_then(|v| *v);
-        let mut should_emit = false;
+    #[test]
+    fn test_skip_refocus_views() {
+        let config = ConversationStateManagerConfig {
+            skip_refocus_views: true,
+            ..Default::default()
+        };
+        let mut manager = ConversationStateManager::new(CharApproxTokenizer, config);
+        assert_eq!(manager.get_active_file(), None);
+        manager.handle_tab_event("/a.rs", Some("fn a() {}\n")).unwrap();
+        manager.handle_tab_event("/b.rs", Some("fn b() {}\n")).unwrap();
+        manager.handle_selection_event("/a.rs", 0, None).unwrap();
+        assert_eq!(manager.get_active_file(), Some("/a.rs"));
+        manager.handle_directory_open_event("/src", &["a.rs", "b.rs"]).unwrap();
+        let count = manager.message_count();
 
-        let vp = if let Some(vp) = current_vp.filter(|v| v.end > 0) {
-            if target_line < vp.start || target_line > vp.end {
-                let new_vp =
-                    serialize_compute_viewport(total_lines, target_line, self.config.viewport_radius);
-                self.per_file_viewport
-                    .insert(file_path.to_string(), Some(new_vp));
-                should_emit = true;
-                new_vp
-            } else {
-                vp
-            }
-        } else {
-            let new_vp =
-                serialize_compute_viewport(total_lines, target_line, self.config.viewport_radius);
-            self.per_file_viewport
-                .insert(file_path.to_string(), Some(new_vp));
-            should_emit = true;
-            new_vp
+        // Refocusing the active file shows nothing new
+        manager.handle_tab_event("/a.rs", Some("fn a() {}\n")).unwrap();
+        assert_eq!(manager.message_count(), count);
+
+        // After a terminal interlude, returning to it shows it again
+        manager.handle_terminal_command_event("ls").unwrap();
+        manager.handle_terminal_output_event("a.rs\nb.rs").unwrap();
+        manager.handle_tab_event("/a.rs", None).unwrap();
+        let messages = manager.finalize_for_model().unwrap();
+        assert!(messages[messages.len() - 2].value.contains("/a.rs"));
+    }
+
+    #[test]
+    fn test_flush_on_cross_file_edit() {
+        let config = ConversationStateManagerConfig {
+            flush_on_cross_file_edit: true,
+            ..Default::default()
         };
+        let mut manager = ConversationStateManager::new(CharApproxTokenizer, config);
+        manager.handle_tab_event("/a.rs", Some("fn a() {}\n")).unwrap();
+        manager.handle_tab_event("/b.rs", Some("fn b() {}\n")).unwrap();
+        let count = manager.message_count();
+        manager.handle_content_event("/a.rs", 0, 0, "// a\n").unwrap();
+        manager.handle_content_event("/b.rs", 0, 0, "// b\n").unwrap();
+        manager.handle_content_event("/a.rs", 0, 0, "// c\n").unwrap();
 
-        if should_emit && vp.end >= vp.start {
-            self.maybe_capture_file_contents(file_path, &content);
-            let cmd = format!("cat -n {} | sed -n '{},{}p'", file_path, vp.start, vp.end);
-            self.append_message(ConversationMessage::assistant(fenced_block(
-                Some("bash"),
-                &clean_text(&cmd),
-            )));
-            let viewport_output = line_numbered_output(&content, Some(vp.start), Some(vp.end));
-            self.append_message(ConversationMessage::user(format!(
-                "<stdout>\n{}\n</stdout>",
-                viewport_output
-            )));
-        }
+        // Each edit is flushed once the other file is edited, in order
+        let messages = manager.finalize_for_model().unwrap();
+        let edited: Vec<bool> = messages[count..]
+            .iter()
+            .step_by(2)
+            .map(|m| m.value.contains("/a.rs"))
+            .collect();
+        assert_eq!(edited, vec![true, false, true]);
     }
 
-    /// Handle a terminal command event.
-    pub fn handle_terminal_command_event(&mut self, command: &str) {
-        self.flush_all_pending_edits();
-        self.flush_terminal_output_buffer();
+    #[test]
+    fn test_task_boundaries() {
+        let config = ConversationStateManagerConfig {
+            task_boundaries: Some(TaskBoundaryConfig {
+                tests_passed: true,
+                git_commit: true,
+                idle_gap_ms: None,
+            }),
+            min_conversation_messages: 1,
+            ..Default::default()
+        };
+        let mut manager = ConversationStateManager::new(CharApproxTokenizer, config);
+        manager
+            .handle_events(vec![
+                IdeEvent::Tab { file_path: "/a.rs", content: Some("fn a() {}\n") },
+                IdeEvent::TerminalCommand { command: "cargo test" },
+                IdeEvent::TerminalOutput { output: "test result: FAILED. 0 passed; 1 failed" },
+                IdeEvent::Content { file_path: "/a.rs", offset: 0, length: 0, text: "// fix\n" },
+                IdeEvent::TerminalCommand { command: "cargo test" },
+                IdeEvent::TerminalOutput { output: "test result: ok. 1 passed" },
+                IdeEvent::TerminalCommand { command: "git commit -am fix" },
+                IdeEvent::TerminalOutput { output: "[main 1a2b3c4] fix" },
+                IdeEvent::Tab { file_path: "/b.rs", content: Some("fn b() {}\n") },
+            ])
+            .unwrap();
 
-        let command_str = command.replace("\\n", "\n").replace("\\r", "\r");
-        self.append_message(ConversationMessage::assistant(fenced_block(
-            Some("bash"),
-            &clean_text(&command_str),
-        )));
+        let conversations = manager.get_conversations().unwrap();
+        let boundaries: Vec<_> = conversations.iter().map(|c| c.boundary).collect();
+        assert_eq!(
+            boundaries,
+            vec![Some(TaskBoundary::TestsPassed), Some(TaskBoundary::GitCommit), None]
+        );
     }
 
-    /// Handle a terminal output event.
-    pub fn handle_terminal_output_event(&mut self, output: &str) {
-        let raw_output = output.replace("\\n", "\n").replace("\\r", "\r");
-        self.terminal_output_buffer.push(raw_output);
+    #[test]
+    fn test_multiline_commands() {
+        let script = "cd build &&\\\n  cmake ..\nmake -j8";
+        let commands = |policy: MultilineCommandPolicy| {
+            let config = ConversationStateManagerConfig {
+                multiline_commands: policy,
+                ..Default::default()
+            };
+            let mut manager = ConversationStateManager::new(CharApproxTokenizer, config);
+            manager.handle_terminal_command_event(script).unwrap();
+            manager.handle_terminal_output_event("done").unwrap();
+            manager
+                .finalize_for_model()
+                .unwrap()
+                .iter()
+                .filter(|m| m.kind == Some(MessageKind::TerminalCommand))
+                .map(|m| m.value.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(commands(MultilineCommandPolicy::Verbatim).len(), 1);
+        assert_eq!(
+            commands(MultilineCommandPolicy::Wrap),
+            vec!["```bash\nbash -lc 'cd build &&\\\n  cmake ..\nmake -j8'\n```\n"]
+        );
+        assert_eq!(
+            commands(MultilineCommandPolicy::Split),
+            vec!["```bash\ncd build && cmake ..\n```\n", "```bash\nmake -j8\n```\n"]
+        );
+        assert_eq!(
+            normalize_multiline_command("cat <<EOF\nx\nEOF".to_string(), MultilineCommandPolicy::Split),
+            vec!["bash -lc 'cat <<EOF\nx\nEOF'"]
+        );
     }
 
-    /// Handle a terminal focus event.
-    pub fn handle_terminal_focus_event(&mut self) {
-        self.flush_all_pending_edits();
-        self.flush_terminal_output_buffer();
-        // No-op for bash transcript; focus changes don't emit commands/output
+    #[test]
+    fn test_unparsed_branch_checkout() {
+        let warnings = Arc::new(WarningAggregator::default());
+        let config = ConversationStateManagerConfig {
+            warnings: warnings.clone(),
+            ..Default::default()
+        };
+        let mut manager = ConversationStateManager::new(CharApproxTokenizer, config);
+        manager.handle_git_branch_checkout_event("Switched to 'main'").unwrap();
+        manager.handle_git_branch_checkout_event("HEAD is now detached").unwrap();
+        manager.handle_git_branch_checkout_event("HEAD is now detached").unwrap();
+        assert_eq!(warnings.counts().get("unparsed_branch_checkout"), Some(&2));
     }
 
-    /// Handle a git branch checkout event.
-    pub fn handle_git_branch_checkout_event(&mut self, branch_info: &str) {
-        self.flush_all_pending_edits();
-        self.flush_terminal_output_buffer();
+    #[test]
+    fn test_message_kinds() {
+        let mut manager = ConversationStateManager::new(CharApproxTokenizer, ConversationStateManagerConfig::default());
+        manager.handle_tab_event("/a.rs", Some("fn a() {}\n")).unwrap();
+        manager.handle_content_event("/a.rs", 0, 0, "// a\n").unwrap();
+        manager.handle_terminal_command_event("cargo build").unwrap();
+        manager.handle_terminal_output_event("ok").unwrap();
+        manager.handle_git_branch_checkout_event("Switched to 'main'").unwrap();
 
-        let branch_str = branch_info.replace("\\n", "\n").replace("\\r", "\r");
-        let cleaned = clean_text(&branch_str);
+        let kinds: Vec<_> = manager.finalize_for_model().unwrap().iter().map(|m| m.kind).collect();
+        assert_eq!(
+            kinds,
+            [
+                MessageKind::FileCapture,
+                MessageKind::FileCapture,
+                MessageKind::EditCommand,
+                MessageKind::EditCommand,
+                MessageKind::TerminalCommand,
+                MessageKind::TerminalOutput,
+                MessageKind::GitAction,
+            ]
+            .map(Some)
+        );
+    }
 
-        // Extract branch name from "to 'branch_name'" pattern
-        let re = regex::Regex::new(r"to '([^']+)'").unwrap();
-        let branch_name = match re.captures(&cleaned) {
-            Some(caps) => caps.get(1).map(|m| m.as_str().trim().to_string()),
-            None => {
-                eprintln!(
-                    "[crowd-pilot] Could not extract branch name from git checkout message: {}",
-                    cleaned
-                );
-                return;
+    #[test]
+    fn test_dedupe_command_writes() {
+        for dedupe_command_writes in [false, true] {
+            let config = ConversationStateManagerConfig {
+                dedupe_command_writes,
+                ..Default::default()
+            };
+            let mut manager = ConversationStateManager::new(CharApproxTokenizer, config);
+            manager.handle_tab_event("/repo/notes.txt", Some("a\n")).unwrap();
+            manager.handle_terminal_command_event("echo b >> notes.txt").unwrap();
+            manager.handle_content_event("/repo/notes.txt", 2, 0, "b\n").unwrap();
+            manager.handle_terminal_command_event("ls").unwrap();
+            // Changes made in a terminal editor are not shown by its command
+            manager.handle_terminal_command_event("vim notes.txt").unwrap();
+            manager.handle_content_event("/repo/notes.txt", 4, 0, "c\n").unwrap();
+            manager.handle_terminal_command_event("ls").unwrap();
+
+            let messages = manager.finalize_for_model().unwrap();
+            assert_eq!(manager.get_file_content("/repo/notes.txt"), "a\nb\nc\n");
+            let edits = messages.iter().filter(|m| m.value.contains("sed -i")).count();
+            assert_eq!(edits, if dedupe_command_writes { 1 } else { 2 });
+        }
+    }
+
+    #[test]
+    fn test_undo_redo_events() {
+        let content: String = (1..=30).map(|i| format!("l{}\n", i)).collect();
+        let far = content.find("l25").unwrap();
+        let config = ConversationStateManagerConfig {
+            min_conversation_messages: 1,
+            ..Default::default()
+        };
+        let mut manager = ConversationStateManager::new(CharApproxTokenizer, config);
+        manager.handle_tab_event("/a.rs", Some(&content)).unwrap();
+        manager.handle_content_event("/a.rs", 0, 0, "x").unwrap();
+        // Undo the typing, then an older change far away, then redo it
+        manager.handle_event(IdeEvent::Undo { file_path: "/a.rs", offset: 0, length: 1, text: "" }).unwrap();
+        manager.handle_undo_event("/a.rs", far, 3, "old").unwrap();
+        manager.handle_redo_event("/a.rs", far, 3, "l25").unwrap();
+        manager.handle_undo_event("/a.rs", far, 3, "old").unwrap();
+        manager.handle_terminal_command_event("ls").unwrap();
+
+        let conversations = manager.get_conversations().unwrap();
+        let edits: Vec<_> = conversations[0]
+            .messages
+            .iter()
+            .filter(|m| m.from == "Assistant" && m.kind == Some(MessageKind::EditCommand))
+            .collect();
+        assert_eq!(edits.len(), 1);
+        assert!(edits[0].value.contains("sed -i '25,25c\\\nold' /a.rs"));
+        assert_eq!(conversations[0].metrics.undo_redo_changes, 4);
+    }
+
+    #[test]
+    fn test_bulk_content_event() {
+        let content: String = (1..=30).map(|i| format!("l{}\n", i)).collect();
+        let (near, far) = (content.find("l2\n").unwrap(), content.find("l28").unwrap());
+        let config = ConversationStateManagerConfig {
+            min_conversation_messages: 1,
+            ..Default::default()
+        };
+        let mut manager = ConversationStateManager::new(CharApproxTokenizer, config);
+        manager.handle_tab_event("/a.rs", Some(&content)).unwrap();
+        // Offsets into the content before the edit, in any order
+        let changes = vec![(near, 2, "x2"), (far, 3, "x28")];
+        manager.handle_event(IdeEvent::BulkContent { file_path: "/a.rs", changes }).unwrap();
+        manager.handle_terminal_command_event("ls").unwrap();
+
+        assert_eq!(manager.get_file_content("/a.rs"), content.replace("l2\n", "x2\n").replace("l28", "x28"));
+        let conversations = manager.get_conversations().unwrap();
+        let edits = conversations[0]
+            .messages
+            .iter()
+            .filter(|m| m.from == "Assistant" && m.kind == Some(MessageKind::EditCommand))
+            .count();
+        assert_eq!(edits, 1);
+
+        // Inserts at one offset keep their order
+        let mut manager = ConversationStateManager::new(CharApproxTokenizer, ConversationStateManagerConfig::default());
+        manager.handle_tab_event("/a.rs", Some("ab\n")).unwrap();
+        // An insert at the start of a replacement must come before it
+        manager
+            .handle_bulk_content_event("/a.rs", &[(1, 0, "x"), (1, 1, "B"), (1, 0, "y")])
+            .unwrap_err();
+        manager.handle_bulk_content_event("/a.rs", &[(1, 0, "x"), (1, 0, "y"), (1, 1, "B")]).unwrap();
+        assert_eq!(manager.get_file_content("/a.rs"), "axyB\n");
+
+        // Overlapping replacements are rejected
+        let result = manager.handle_bulk_content_event("/a.rs", &[(0, 3, "z"), (2, 1, "w")]);
+        assert!(matches!(result, Err(SerializerError::OverlappingChanges { offset: 2, .. })));
+        assert_eq!(manager.get_file_content("/a.rs"), "axyB\n");
+    }
+
+    #[test]
+    fn test_file_save_event() {
+        for verify_on_save in [false, true] {
+            let config = ConversationStateManagerConfig {
+                verify_on_save,
+                ..Default::default()
+            };
+            let mut manager = ConversationStateManager::new(CharApproxTokenizer, config);
+            manager.handle_tab_event("/a.rs", Some("fn a() {}\n")).unwrap();
+            manager.handle_content_event("/a.rs", 0, 0, "// a\n").unwrap();
+            let before_save = manager.get_messages().len();
+            manager.handle_file_save_event("/a.rs").unwrap();
+            // Saving again flushes nothing new
+            manager.handle_file_save_event("/a.rs").unwrap();
+
+            let messages = manager.get_messages();
+            assert!(messages[before_save].value.contains("sed -i"));
+            assert_eq!(messages.len(), before_save + if verify_on_save { 4 } else { 2 });
+            if verify_on_save {
+                assert!(messages[before_save + 2].value.contains("cat -n /a.rs"));
+                assert_eq!(messages[before_save + 3].value, messages[before_save + 1].value);
             }
+        }
+    }
+
+    #[test]
+    fn test_file_rename_event() {
+        let mut manager = ConversationStateManager::new(CharApproxTokenizer, ConversationStateManagerConfig::default());
+        manager.handle_tab_event("/src/old.rs", Some("fn a() {}\n")).unwrap();
+        manager.handle_content_event("/src/old.rs", 0, 0, "// a\n").unwrap();
+        manager.handle_file_rename_event("/src/old.rs", "/src/new.rs").unwrap();
+        manager.handle_tab_event("/src/new.rs", Some("// a\nfn a() {}\n")).unwrap();
+
+        let messages = manager.finalize_for_model().unwrap();
+        let commands: Vec<&str> = messages.iter().filter(|m| m.from == "Assistant").map(|m| m.value.as_str()).collect();
+        assert_eq!(commands.len(), 4);
+        assert!(commands[1].contains("sed -i") && commands[1].contains("/src/old.rs"));
+        assert_eq!(commands[2], "```bash\nmv /src/old.rs /src/new.rs\n```\n");
+        // Known content: a view, not a full capture of a new file
+        assert!(commands[3].contains("cat -n /src/new.rs | sed -n"));
+        assert_eq!(manager.get_file_content("/src/old.rs"), "");
+        assert_eq!(manager.get_file_content("/src/new.rs"), "// a\nfn a() {}\n");
+
+        // Renamed to an ignored path: counted once, the old path forgotten
+        let config = ConversationStateManagerConfig {
+            ignored_paths: vec!["*.bak".parse().unwrap()],
+            ..Default::default()
         };
+        let mut manager = ConversationStateManager::new(CharApproxTokenizer, config);
+        manager.handle_tab_event("/src/a.rs", Some("fn a() {}\n")).unwrap();
+        manager.handle_file_rename_event("/src/a.rs", "/src/a.rs.bak").unwrap();
+        assert_eq!(manager.get_drop_stats().ignored_path_events, 1);
+        assert!(manager.tracked_files().is_empty());
+        manager.handle_file_rename_event("/src/b.bak", "/src/c.bak").unwrap();
+        assert_eq!(manager.get_drop_stats().ignored_path_events, 2);
+    }
 
-        let mut branch_name = match branch_name {
-            Some(b) => b,
-            None => return,
+    #[test]
+    fn test_diff_stale_snapshots() {
+        for diff_stale_snapshots in [false, true] {
+            let config = ConversationStateManagerConfig {
+                diff_stale_snapshots,
+                ..Default::default()
+            };
+            let mut manager = ConversationStateManager::new(CharApproxTokenizer, config);
+            manager.handle_tab_event("/a.rs", Some("fn a() {}\nfn b() {}\n")).unwrap();
+            manager.handle_tab_event("/b.rs", Some("x\n")).unwrap();
+            // Changed on disk while /b.rs was focused
+            manager.handle_tab_event("/a.rs", Some("fn a() {}\nfn c() {}\n")).unwrap();
+
+            let messages = manager.finalize_for_model().unwrap();
+            assert_eq!(manager.get_file_content("/a.rs"), "fn a() {}\nfn c() {}\n");
+            if diff_stale_snapshots {
+                assert!(messages[4].value.contains("sed -i '2,2c\\\nfn c() {}' /a.rs"));
+                let conversations = manager.get_conversations().unwrap();
+                assert_eq!(conversations[0].metrics.snapshot_diffs, 1);
+            } else {
+                assert_eq!(messages[4].value, "```bash\ncat -n /a.rs\n```\n");
+            }
+        }
+    }
+
+    #[test]
+    fn test_merge_commands() {
+        let config = ConversationStateManagerConfig {
+            merge_commands_window_ms: Some(5000),
+            ..Default::default()
         };
+        let mut manager = ConversationStateManager::new(CharApproxTokenizer, config);
 
-        // Safe-quote branch if it contains special characters
-        let special_chars = regex::Regex::new(r"[^A-Za-z0-9._/\\-]").unwrap();
-        if special_chars.is_match(&branch_name) {
-            branch_name = format!("'{}'", branch_name.replace('\'', "'\"'\"'"));
+        for (time, command) in [(0, "cd x"), (1000, "ls"), (2000, "cargo test")] {
+            manager.set_event_time(Some(time));
+            manager.handle_terminal_command_event(command).unwrap();
         }
+        manager.handle_terminal_output_event("ok\n").unwrap();
+        // Output in between prevents merging
+        manager.set_event_time(Some(3000));
+        manager.handle_terminal_command_event("git status").unwrap();
+        // Outside the window
+        manager.set_event_time(Some(60_000));
+        manager.handle_terminal_command_event("git diff").unwrap();
 
-        let cmd = format!("git checkout {}", branch_name);
-        self.append_message(ConversationMessage::assistant(fenced_block(
-            Some("bash"),
-            &clean_text(&cmd),
-        )));
+        let messages = manager.finalize_for_model().unwrap();
+        assert_eq!(messages.len(), 4);
+        assert!(messages[0].value.contains("cd x && ls && cargo test"));
+        assert!(messages[2].value.contains("git status"));
+        assert!(messages[3].value.contains("git diff"));
     }
 
-    /// Finalize and get conversation ready for model.
-    pub fn finalize_for_model(&mut self) -> Vec<ConversationMessage> {
-        self.flush_all_pending_edits();
-        self.flush_terminal_output_buffer();
-        self.get_messages()
+    #[test]
+    fn test_reassociate_late_output() {
+        let config = ConversationStateManagerConfig {
+            reassociate_late_output: true,
+            ..Default::default()
+        };
+        let mut manager = ConversationStateManager::new(CharApproxTokenizer, config);
+
+        // Output of `make` stamped before `ls` ran
+        manager.set_event_time(Some(0));
+        manager.handle_terminal_command_event("make").unwrap();
+        manager.set_event_time(Some(2000));
+        manager.handle_terminal_command_event("ls").unwrap();
+        manager.set_event_time(Some(1000));
+        manager.handle_terminal_output_event("built\n").unwrap();
+        manager.set_event_time(Some(3000));
+        manager.handle_terminal_output_event("a.rs\n").unwrap();
+
+        // Output of `cargo test` followed by its "command finished" marker
+        manager.handle_terminal_command_event("cargo test").unwrap();
+        manager.handle_terminal_command_event("git status").unwrap();
+        manager.handle_terminal_output_event("passed\n\x1b]633;D;0\x07\x1b]633;C\x07clean\n").unwrap();
+
+        let values: Vec<String> = manager.finalize_for_model().unwrap().into_iter().map(|m| m.value).collect();
+        assert_eq!(values.len(), 8);
+        assert!(values[0].contains("make") && values[1].contains("built"));
+        assert!(values[2].contains("ls") && values[3].contains("a.rs"));
+        assert!(values[4].contains("cargo test") && values[5].contains("passed"));
+        assert!(values[6].contains("git status") && values[7].contains("clean"));
+        assert!(!values[7].contains("passed"));
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn test_session_boundaries() {
+        let config = ConversationStateManagerConfig {
+            min_conversation_messages: 1,
+            end_of_task_marker: Some("<task_complete>".to_string()),
+            ..Default::default()
+        };
+        let mut manager = ConversationStateManager::new(CharApproxTokenizer, config);
+        manager.handle_tab_event("/a.rs", Some("fn a() {}\n")).unwrap();
+        manager.handle_session_end_event().unwrap();
+        manager.handle_session_start_event().unwrap();
+        // File state was forgotten: the same snapshot is captured again
+        manager.handle_tab_event("/a.rs", Some("fn a() {}\n")).unwrap();
+        manager.handle_session_start_event().unwrap();
 
-    /// Character-based approximate tokenizer for tests.
-    struct CharApproxTokenizer;
+        let conversations = manager.get_conversations().unwrap();
+        assert_eq!(conversations.len(), 2);
+        assert_eq!(conversations[0].messages.last().unwrap().value, "<task_complete>");
+        assert!(conversations[1].messages[0].value.contains("cat -n /a.rs"));
+        assert_eq!(conversations[1].messages.len(), 2);
+        // Clearing files at a session start is not an eviction
+        assert_eq!(conversations[1].metrics.evicted_files, 0);
+    }
 
-    impl Tokenizer for CharApproxTokenizer {
-        fn count_tokens(&self, text: &str) -> usize {
-            text.len() / 4
+    #[test]
+    fn test_record_timing() {
+        let config = ConversationStateManagerConfig {
+            record_timing: true,
+            ..Default::default()
+        };
+        let mut manager = ConversationStateManager::new(CharApproxTokenizer, config);
+        manager.set_event_time(Some(1000));
+        manager.handle_tab_event("/a.rs", Some("fn a() {}\n")).unwrap();
+        for (time, offset, text) in [(3000, 9, "x"), (3200, 10, "y"), (3700, 11, "z")] {
+            manager.set_event_time(Some(time));
+            manager.handle_content_event("/a.rs", offset, 0, text).unwrap();
         }
+        manager.set_event_time(Some(5000));
+        manager.handle_terminal_command_event("cargo test").unwrap();
 
-        fn truncate_to_max_tokens(&self, text: &str, max_tokens: usize) -> String {
-            text.chars().take(max_tokens * 4).collect()
-        }
+        let messages = manager.finalize_for_model().unwrap();
+        let edit = messages.iter().find(|m| m.value.contains("sed -i")).unwrap();
+        let timing = edit.timing.unwrap();
+        assert_eq!(timing.typing_ms, Some(700));
+        assert_eq!(timing.keystrokes, Some(3));
+        assert_eq!(timing.max_keystroke_gap_ms, Some(500));
+        assert_eq!(timing.pause_before_ms, Some(2000));
+        let command = messages.iter().find(|m| m.value.contains("cargo test")).unwrap();
+        assert_eq!(command.timing.unwrap().pause_before_ms, Some(1300));
     }
 
     #[test]
-    fn test_basic_tab_event() {
-        let mut manager =
-            ConversationStateManager::new(CharApproxTokenizer, ConversationStateManagerConfig::default());
+    fn test_whitespace_edit_policy() {
+        for (policy, expected) in [
+            (WhitespaceEditPolicy::Emit, Some("sed -i")),
+            (WhitespaceEditPolicy::Skip, None),
+            (WhitespaceEditPolicy::Formatter, Some("rustfmt /a.rs && cat -n /a.rs")),
+        ] {
+            let config = ConversationStateManagerConfig {
+                whitespace_edit_policy: policy,
+                ..Default::default()
+            };
+            let mut manager = ConversationStateManager::new(CharApproxTokenizer, config);
+            manager.handle_tab_event("/a.rs", Some("fn a() {\nx();\n}")).unwrap();
+            manager.handle_content_event("/a.rs", 9, 0, "    ").unwrap();
 
-        manager.handle_tab_event("/test/file.rs", Some("fn main() {\n    println!(\"hello\");\n}"));
+            let messages = manager.finalize_for_model().unwrap();
+            match expected {
+                Some(cmd) => assert!(messages[2].value.contains(cmd), "{:?}", policy),
+                None => assert_eq!(messages.len(), 2),
+            }
+            assert_eq!(manager.get_file_content("/a.rs"), "fn a() {\n    x();\n}");
+        }
 
-        let messages = manager.finalize_for_model();
-        assert_eq!(messages.len(), 2);
-        assert_eq!(messages[0].from, "Assistant");
-        assert!(messages[0].value.contains("cat -n /test/file.rs"));
-        assert_eq!(messages[1].from, "User");
-        assert!(messages[1].value.contains("<stdout>"));
+        let config = ConversationStateManagerConfig {
+            whitespace_edit_policy: WhitespaceEditPolicy::Skip,
+            ..Default::default()
+        };
+        for (offset, length, text) in [
+            // Line numbers after the edit change
+            (8, 0, "\n"),
+            // A whitespace hunk next to a real one
+            (0, 14, "fn a() {\n    x();\n} // a"),
+        ] {
+            let mut manager = ConversationStateManager::new(CharApproxTokenizer, config.clone());
+            manager.handle_tab_event("/a.rs", Some("fn a() {\nx();\n}")).unwrap();
+            manager.handle_content_event("/a.rs", offset, length, text).unwrap();
+            let messages = manager.finalize_for_model().unwrap();
+            assert!(messages[2].value.contains("sed -i"), "{:?}", text);
+        }
     }
 
     #[test]
-    fn test_content_event() {
-        let mut manager =
-            ConversationStateManager::new(CharApproxTokenizer, ConversationStateManagerConfig::default());
+    fn test_deferred_token_counting() {
+        let config = ConversationStateManagerConfig {
+            max_tokens_per_message: 1,
+            defer_token_counting: true,
+            min_conversation_messages: 1,
+            ..Default::default()
+        };
+        let mut manager = ConversationStateManager::new(CharApproxTokenizer, config);
 
-        manager.handle_tab_event("/test/file.rs", Some("line1\nline2\nline3"));
-        manager.handle_content_event("/test/file.rs", 6, 5, "modified");
+        manager.handle_tab_event("/test/file.rs", Some("fn main() {}")).unwrap();
 
-        let messages = manager.finalize_for_model();
-        // Should have: cat (open file), stdout, sed (edit), stdout
-        assert!(messages.len() >= 4);
+        let conversations = manager.get_conversations().unwrap();
+        assert_eq!(conversations.len(), 1);
+        // Neither counted nor truncated until the pipeline tokenization stage
+        assert_eq!(conversations[0].token_count, 0);
+        assert!(conversations[0].messages[1].value.contains("fn main() {}"));
     }
 
     #[test]
-    fn test_terminal_command() {
-        let mut manager =
-            ConversationStateManager::new(CharApproxTokenizer, ConversationStateManagerConfig::default());
+    fn test_conversation_languages() {
+        let config = ConversationStateManagerConfig {
+            min_conversation_messages: 1,
+            ..Default::default()
+        };
+        let mut manager = ConversationStateManager::new(CharApproxTokenizer, config);
 
-        manager.handle_terminal_command_event("cargo build");
-        manager.handle_terminal_output_event("Compiling...\n");
-        manager.handle_terminal_output_event("Finished\n");
+        manager.set_file_language("/test/a.rs", "rust");
+        manager.handle_tab_event("/test/a.rs", Some("fn a() {}")).unwrap();
+        manager.set_file_language("/test/b.py", "python");
+        manager.handle_tab_event("/test/b.py", Some("def b(): pass")).unwrap();
+        manager.set_file_language("/test/c.ts", "typescript");
+        manager.handle_terminal_command_event("ls").unwrap();
 
-        let messages = manager.finalize_for_model();
-        assert_eq!(messages.len(), 2);
-        assert!(messages[0].value.contains("cargo build"));
-        assert!(messages[1].value.contains("Compiling"));
+        let conversations = manager.get_conversations().unwrap();
+        assert_eq!(conversations[0].languages, vec!["python", "rust"]);
     }
 }
 
@@ -1,9 +1,15 @@
 //! Diff computation for detecting changed blocks between file versions.
 //!
 //! This module provides a port of Python's difflib.SequenceMatcher for
-//! computing line-based diffs between two strings.
+//! computing line-based diffs between two strings, and message-level diffs
+//! between two serializations of a conversation.
 
 use std::collections::HashMap;
+use std::ops::Range;
+
+use crate::cancel::SessionInterrupt;
+use crate::conversation::ConversationMessage;
+use crate::error::SerializerError;
 
 /// Represents a changed block with line numbers (1-based).
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -22,7 +28,7 @@ pub struct ChangedBlock {
 
 /// Opcode tag indicating the type of operation.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum OpcodeTag {
+pub enum OpcodeTag {
     Replace,
     Delete,
     Insert,
@@ -43,11 +49,15 @@ struct Match {
     n: usize,
 }
 
+/// Lines of `a` scanned by `find_longest_match` between interrupt checks.
+const INTERRUPT_CHECK_LINES: usize = 1024;
+
 /// Port of Python's difflib.SequenceMatcher with autojunk=False.
 struct SequenceMatcher<'a> {
     a: Vec<&'a str>,
     b: Vec<&'a str>,
     b2j: HashMap<&'a str, Vec<usize>>,
+    interrupt: SessionInterrupt,
 }
 
 impl<'a> SequenceMatcher<'a> {
@@ -56,11 +66,28 @@ impl<'a> SequenceMatcher<'a> {
         for (i, &elt) in b.iter().enumerate() {
             b2j.entry(elt).or_default().push(i);
         }
-        Self { a, b, b2j }
+        Self {
+            a,
+            b,
+            b2j,
+            interrupt: SessionInterrupt::default(),
+        }
+    }
+
+    /// Stop matching with `Cancelled` or `TimedOut` once `interrupt` fires.
+    fn with_interrupt(mut self, interrupt: &SessionInterrupt) -> Self {
+        self.interrupt = interrupt.clone();
+        self
     }
 
     /// Find longest matching block in a[alo:ahi] and b[blo:bhi].
-    fn find_longest_match(&self, alo: usize, ahi: usize, blo: usize, bhi: usize) -> Match {
+    fn find_longest_match(
+        &self,
+        alo: usize,
+        ahi: usize,
+        blo: usize,
+        bhi: usize,
+    ) -> Result<Match, SerializerError> {
         let mut besti = alo;
         let mut bestj = blo;
         let mut bestsize = 0;
@@ -68,6 +95,9 @@ impl<'a> SequenceMatcher<'a> {
         let mut j2len: HashMap<usize, usize> = HashMap::new();
 
         for i in alo..ahi {
+            if (i - alo).is_multiple_of(INTERRUPT_CHECK_LINES) {
+                self.interrupt.check()?;
+            }
             let mut newj2len: HashMap<usize, usize> = HashMap::new();
             if let Some(indices) = self.b2j.get(self.a[i]) {
                 for &j in indices {
@@ -104,15 +134,15 @@ impl<'a> SequenceMatcher<'a> {
             bestsize += 1;
         }
 
-        Match {
+        Ok(Match {
             i: besti,
             j: bestj,
             n: bestsize,
-        }
+        })
     }
 
     /// Return list of matching blocks.
-    fn get_matching_blocks(&self) -> Vec<Match> {
+    fn get_matching_blocks(&self) -> Result<Vec<Match>, SerializerError> {
         let la = self.a.len();
         let lb = self.b.len();
 
@@ -120,7 +150,7 @@ impl<'a> SequenceMatcher<'a> {
         let mut matching_blocks = Vec::new();
 
         while let Some((alo, ahi, blo, bhi)) = queue.pop() {
-            let m = self.find_longest_match(alo, ahi, blo, bhi);
+            let m = self.find_longest_match(alo, ahi, blo, bhi)?;
             if m.n > 0 {
                 matching_blocks.push(m);
                 if alo < m.i && blo < m.j {
@@ -176,16 +206,16 @@ impl<'a> SequenceMatcher<'a> {
             n: 0,
         });
 
-        result
+        Ok(result)
     }
 
     /// Return list of opcodes describing how to turn a into b.
-    fn get_opcodes(&self) -> Vec<Opcode> {
+    fn get_opcodes(&self) -> Result<Vec<Opcode>, SerializerError> {
         let mut opcodes = Vec::new();
         let mut i = 0;
         let mut j = 0;
 
-        for m in self.get_matching_blocks() {
+        for m in self.get_matching_blocks()? {
             let mut tag = None;
 
             if i < m.i && j < m.j {
@@ -206,26 +236,39 @@ impl<'a> SequenceMatcher<'a> {
             j = m.j + m.n;
         }
 
-        opcodes
+        Ok(opcodes)
     }
 }
 
 /// Compute the changed block between two strings.
 ///
 /// Returns 1-based line numbers for the changed region and the replacement lines.
-pub fn compute_changed_block_lines(before: &str, after: &str) -> Result<ChangedBlock, &'static str> {
+pub fn compute_changed_block_lines(
+    before: &str,
+    after: &str,
+) -> Result<ChangedBlock, SerializerError> {
+    compute_changed_block_lines_until(before, after, &SessionInterrupt::default())
+}
+
+/// `compute_changed_block_lines` that checks `interrupt` while matching lines
+/// and stops with `Cancelled` or `TimedOut` once it fires.
+pub fn compute_changed_block_lines_until(
+    before: &str,
+    after: &str,
+    interrupt: &SessionInterrupt,
+) -> Result<ChangedBlock, SerializerError> {
     let before_lines: Vec<&str> = before.lines().collect();
     let after_lines: Vec<&str> = after.lines().collect();
 
-    let sm = SequenceMatcher::new(before_lines.clone(), after_lines.clone());
-    let all_opcodes = sm.get_opcodes();
+    let sm = SequenceMatcher::new(before_lines.clone(), after_lines.clone()).with_interrupt(interrupt);
+    let all_opcodes = sm.get_opcodes()?;
     let non_equal: Vec<_> = all_opcodes
         .into_iter()
         .filter(|(tag, _, _, _, _)| *tag != OpcodeTag::Equal)
         .collect();
 
     if non_equal.is_empty() {
-        return Err("Opcode list cannot be empty! Likely a bug in the diff computation.");
+        return Err(SerializerError::EmptyDiff);
     }
 
     let first = non_equal.first().unwrap();
@@ -250,6 +293,53 @@ pub fn compute_changed_block_lines(before: &str, after: &str) -> Result<ChangedB
     })
 }
 
+/// Whether every hunk of the line diff of `before` and `after` only changes
+/// whitespace, i.e. no other text is added, removed or moved between hunks.
+pub fn whitespace_only_hunks(before: &str, after: &str) -> bool {
+    let before_lines: Vec<&str> = before.lines().collect();
+    let after_lines: Vec<&str> = after.lines().collect();
+    let text = |lines: &[&str]| -> String {
+        lines.iter().flat_map(|line| line.chars()).filter(|c| !c.is_whitespace()).collect()
+    };
+    let sm = SequenceMatcher::new(before_lines.clone(), after_lines.clone());
+    // Without an interrupt, matching cannot fail.
+    sm.get_opcodes()
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|(tag, _, _, _, _)| *tag != OpcodeTag::Equal)
+        .all(|(_, i1, i2, j1, j2)| text(&before_lines[i1..i2]) == text(&after_lines[j1..j2]))
+}
+
+/// A run of messages that is equal, deleted, inserted or replaced between two
+/// versions of a conversation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MessageHunk {
+    pub tag: OpcodeTag,
+    pub before: Range<usize>,
+    pub after: Range<usize>,
+}
+
+/// Message-level diff of two conversations; messages are equal if their role
+/// and value are.
+pub fn diff_messages(before: &[ConversationMessage], after: &[ConversationMessage]) -> Vec<MessageHunk> {
+    let key = |m: &ConversationMessage| format!("{}\0{}", m.from, m.value);
+    let before_keys: Vec<String> = before.iter().map(key).collect();
+    let after_keys: Vec<String> = after.iter().map(key).collect();
+    let sm = SequenceMatcher::new(
+        before_keys.iter().map(String::as_str).collect(),
+        after_keys.iter().map(String::as_str).collect(),
+    );
+    sm.get_opcodes()
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(tag, i1, i2, j1, j2)| MessageHunk {
+            tag,
+            before: i1..i2,
+            after: j1..j2,
+        })
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -280,5 +370,35 @@ mod tests {
         assert_eq!(result.start_before, 2);
         assert_eq!(result.end_before, 2);
     }
-}
 
+    #[test]
+    fn test_compute_changed_block_interrupted() {
+        let before: String = (0..5000).map(|i| format!("line{}\n", i)).collect();
+        let after = before.replace("line2500\n", "changed\n");
+        let expired = SessionInterrupt::new(None, Some(std::time::Duration::ZERO));
+        let result = compute_changed_block_lines_until(&before, &after, &expired);
+        assert!(matches!(result, Err(SerializerError::TimedOut(_))));
+        let result = compute_changed_block_lines_until(&before, &after, &SessionInterrupt::default()).unwrap();
+        assert_eq!(result.replacement_lines, vec!["changed"]);
+    }
+
+    #[test]
+    fn test_diff_messages() {
+        let before = vec![
+            ConversationMessage::assistant("cat -n a.rs"),
+            ConversationMessage::user("1 fn a() {}"),
+            ConversationMessage::assistant("ls"),
+        ];
+        let after = vec![
+            ConversationMessage::assistant("cat -n a.rs"),
+            ConversationMessage::user("1 fn a() {}"),
+            ConversationMessage::assistant("ls -la"),
+            ConversationMessage::user("a.rs"),
+        ];
+        let hunks = diff_messages(&before, &after);
+        assert_eq!(hunks.len(), 2);
+        assert_eq!((hunks[0].tag, hunks[0].before.clone()), (OpcodeTag::Equal, 0..2));
+        assert_eq!(hunks[1].tag, OpcodeTag::Replace);
+        assert_eq!((hunks[1].before.clone(), hunks[1].after.clone()), (2..3, 2..4));
+    }
+}
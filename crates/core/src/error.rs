@@ -0,0 +1,65 @@
+//! Error types for the serializer.
+
+use std::path::PathBuf;
+
+use thiserror::Error;
+
+/// Errors produced by the core serializer and pipeline.
+#[derive(Debug, Error)]
+pub enum SerializerError {
+    /// The diff between two differing file versions produced no changed block.
+    #[error("Opcode list cannot be empty! Likely a bug in the diff computation.")]
+    EmptyDiff,
+
+    /// An event is missing a field required by its type.
+    #[error("{event_type} event missing {field}")]
+    MissingField {
+        event_type: String,
+        field: &'static str,
+    },
+
+    /// Changes of a multi-cursor edit overlap, so their result is ambiguous.
+    #[error("overlapping changes to {file_path} at offset {offset}")]
+    OverlappingChanges { file_path: String, offset: usize },
+
+    /// A configuration value could not be parsed.
+    #[error("Invalid configuration: {0}")]
+    InvalidConfig(String),
+
+    /// No session files were found under the given root.
+    #[error("No CSV files found under {0:?}")]
+    NoCsvFiles(PathBuf),
+
+    /// The run was stopped through its `CancellationToken`.
+    #[error("Pipeline run cancelled")]
+    Cancelled,
+
+    /// A session took longer than the session timeout.
+    #[error("Session timed out after {0:?}")]
+    TimedOut(std::time::Duration),
+
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("CSV error: {0}")]
+    Csv(#[from] csv::Error),
+
+    #[error("JSON error: {0}")]
+    Json(#[from] serde_json::Error),
+
+    /// A line of a JSONL dataset is not a valid record.
+    #[error("{path:?} line {line}: {source}")]
+    InvalidRecord {
+        path: PathBuf,
+        line: usize,
+        source: serde_json::Error,
+    },
+
+    /// A record cannot be read with this build or mixed with earlier records.
+    #[error("{path:?} line {line}: {message}")]
+    IncompatibleRecord {
+        path: PathBuf,
+        line: usize,
+        message: String,
+    },
+}
@@ -0,0 +1,219 @@
+//! Typed IDE events, the input of `ConversationStateManager::handle_event`.
+
+/// An IDE event as recorded by the extension, with strings of type `S`
+/// (`&str` when borrowed from a recording, `String` when queued).
+///
+/// Offsets are character offsets into the file content; lines are 1-based
+/// and columns 0-based.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IdeEvent<S> {
+    /// File switch, with a snapshot of the file content if available.
+    Tab {
+        file_path: S,
+        content: Option<S>,
+    },
+    /// `length` characters at `offset` replaced by `text`.
+    Content {
+        file_path: S,
+        offset: usize,
+        length: usize,
+        text: S,
+    },
+    /// Multi-cursor edit: several `(offset, length, text)` changes made at
+    /// once, with offsets into the content before any of them.
+    BulkContent {
+        file_path: S,
+        changes: Vec<(usize, usize, S)>,
+    },
+    /// Cursor move or selection, by offsets.
+    Selection {
+        file_path: S,
+        offset: usize,
+        end_offset: Option<usize>,
+    },
+    /// Cursor move or selection, by `(line, column)` positions.
+    SelectionLine {
+        file_path: S,
+        line: usize,
+        column: usize,
+        end: Option<(usize, usize)>,
+    },
+    /// Change made by undo, otherwise like `Content`.
+    Undo {
+        file_path: S,
+        offset: usize,
+        length: usize,
+        text: S,
+    },
+    /// Change made by redo, otherwise like `Content`.
+    Redo {
+        file_path: S,
+        offset: usize,
+        length: usize,
+        text: S,
+    },
+    /// Explicit save of a file.
+    FileSave {
+        file_path: S,
+    },
+    /// File renamed or moved in the IDE.
+    FileRename {
+        old_path: S,
+        new_path: S,
+    },
+    /// Command typed in a Vim emulation mode.
+    VimCommand {
+        file_path: S,
+        command: S,
+        cursor_offset: Option<usize>,
+    },
+    TerminalCommand {
+        command: S,
+    },
+    TerminalOutput {
+        output: S,
+    },
+    TerminalFocus,
+    /// Folder expanded in the explorer, with its entry names.
+    DirectoryOpen {
+        dir_path: S,
+        entries: Vec<S>,
+    },
+    /// Branch checkout, as the recorder's message (`Switched to 'main'`).
+    GitBranchCheckout {
+        branch_info: S,
+    },
+    /// Explicit start of a recorded task.
+    SessionStart,
+    /// Explicit end of a recorded task.
+    SessionEnd,
+}
+
+impl<S: AsRef<str>> IdeEvent<S> {
+    /// Event type name, as in the CSV `Type` column.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Tab { .. } => "tab",
+            Self::Content { .. } | Self::BulkContent { .. } => "content",
+            Self::Undo { .. } => "undo",
+            Self::Redo { .. } => "redo",
+            Self::Selection { .. } | Self::SelectionLine { .. } => "selection",
+            Self::VimCommand { .. } => "vim_command",
+            Self::FileSave { .. } => "file_save",
+            Self::FileRename { .. } => "file_rename",
+            Self::TerminalCommand { .. } => "terminal_command",
+            Self::TerminalOutput { .. } => "terminal_output",
+            Self::TerminalFocus => "terminal_focus",
+            Self::DirectoryOpen { .. } => "dir_open",
+            Self::GitBranchCheckout { .. } => "git_branch_checkout",
+            Self::SessionStart => "session_start",
+            Self::SessionEnd => "session_end",
+        }
+    }
+
+    /// Rank among events with the same ordering key; lower ranks go first.
+    /// A file switch precedes the selections and edits in that file.
+    pub fn priority(&self) -> u8 {
+        match self {
+            Self::SessionStart => 0,
+            Self::Tab { .. } => 1,
+            Self::Selection { .. } | Self::SelectionLine { .. } => 2,
+            Self::SessionEnd => 4,
+            _ => 3,
+        }
+    }
+
+    /// File the event applies to, if any.
+    pub fn file_path(&self) -> Option<&str> {
+        match self {
+            Self::Tab { file_path, .. }
+            | Self::Content { file_path, .. }
+            | Self::BulkContent { file_path, .. }
+            | Self::Undo { file_path, .. }
+            | Self::Redo { file_path, .. }
+            | Self::Selection { file_path, .. }
+            | Self::SelectionLine { file_path, .. }
+            | Self::VimCommand { file_path, .. }
+            | Self::FileSave { file_path }
+            | Self::FileRename { old_path: file_path, .. } => Some(file_path.as_ref()),
+            Self::DirectoryOpen { dir_path, .. } => Some(dir_path.as_ref()),
+            Self::TerminalCommand { .. }
+            | Self::TerminalOutput { .. }
+            | Self::TerminalFocus
+            | Self::GitBranchCheckout { .. }
+            | Self::SessionStart
+            | Self::SessionEnd => None,
+        }
+    }
+
+    /// A copy owning its strings, e.g. to queue it.
+    pub fn to_owned_event(&self) -> IdeEvent<String> {
+        let own = |s: &S| s.as_ref().to_string();
+        match self {
+            Self::Tab { file_path, content } => IdeEvent::Tab {
+                file_path: own(file_path),
+                content: content.as_ref().map(own),
+            },
+            Self::Content { file_path, offset, length, text } => IdeEvent::Content {
+                file_path: own(file_path),
+                offset: *offset,
+                length: *length,
+                text: own(text),
+            },
+            Self::BulkContent { file_path, changes } => IdeEvent::BulkContent {
+                file_path: own(file_path),
+                changes: changes
+                    .iter()
+                    .map(|(offset, length, text)| (*offset, *length, own(text)))
+                    .collect(),
+            },
+            Self::Undo { file_path, offset, length, text } => IdeEvent::Undo {
+                file_path: own(file_path),
+                offset: *offset,
+                length: *length,
+                text: own(text),
+            },
+            Self::Redo { file_path, offset, length, text } => IdeEvent::Redo {
+                file_path: own(file_path),
+                offset: *offset,
+                length: *length,
+                text: own(text),
+            },
+            Self::Selection { file_path, offset, end_offset } => IdeEvent::Selection {
+                file_path: own(file_path),
+                offset: *offset,
+                end_offset: *end_offset,
+            },
+            Self::SelectionLine { file_path, line, column, end } => IdeEvent::SelectionLine {
+                file_path: own(file_path),
+                line: *line,
+                column: *column,
+                end: *end,
+            },
+            Self::VimCommand { file_path, command, cursor_offset } => IdeEvent::VimCommand {
+                file_path: own(file_path),
+                command: own(command),
+                cursor_offset: *cursor_offset,
+            },
+            Self::FileSave { file_path } => IdeEvent::FileSave {
+                file_path: own(file_path),
+            },
+            Self::FileRename { old_path, new_path } => IdeEvent::FileRename {
+                old_path: own(old_path),
+                new_path: own(new_path),
+            },
+            Self::TerminalCommand { command } => IdeEvent::TerminalCommand { command: own(command) },
+            Self::TerminalOutput { output } => IdeEvent::TerminalOutput { output: own(output) },
+            Self::TerminalFocus => IdeEvent::TerminalFocus,
+            Self::DirectoryOpen { dir_path, entries } => IdeEvent::DirectoryOpen {
+                dir_path: own(dir_path),
+                entries: entries.iter().map(own).collect(),
+            },
+            Self::GitBranchCheckout { branch_info } => IdeEvent::GitBranchCheckout {
+                branch_info: own(branch_info),
+            },
+            Self::SessionStart => IdeEvent::SessionStart,
+            Self::SessionEnd => IdeEvent::SessionEnd,
+        }
+    }
+}
@@ -0,0 +1,138 @@
+//! Pluggable conversation filters for the pipeline.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::conversation::FinalizedConversation;
+use crate::error::SerializerError;
+use crate::pipeline::SessionStats;
+use crate::tags::SessionTags;
+
+/// Where a conversation comes from, for filters that need more than its messages.
+#[derive(Debug, Clone, Copy)]
+pub struct SessionContext<'a> {
+    pub source_path: &'a str,
+    /// Position of the conversation (chunk) within its session.
+    pub chunk_index: usize,
+    /// Ingestion statistics of the whole session.
+    pub stats: &'a SessionStats,
+    /// Tags of the session (consent, license, ...).
+    pub tags: &'a SessionTags,
+}
+
+/// Outcome of a filter.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FilterDecision {
+    Accept,
+    /// Drop the conversation; the reason is tallied in the drop statistics.
+    Reject { reason: String },
+}
+
+/// A stage deciding whether a finalized conversation enters the dataset.
+///
+/// Filters run in parallel across sessions, hence `Send + Sync`.
+pub trait ConversationFilter: Send + Sync {
+    /// Name used in logs and `Debug` output.
+    fn name(&self) -> &str;
+
+    fn accept(&self, conv: &FinalizedConversation, ctx: &SessionContext<'_>) -> FilterDecision;
+}
+
+/// An ordered chain of filters. A conversation is kept only if every filter
+/// accepts it; the first rejection wins.
+#[derive(Clone, Default)]
+pub struct FilterChain {
+    filters: Vec<Arc<dyn ConversationFilter>>,
+}
+
+impl FilterChain {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append a filter to the chain.
+    pub fn with(mut self, filter: impl ConversationFilter + 'static) -> Self {
+        self.filters.push(Arc::new(filter));
+        self
+    }
+
+    pub fn push(&mut self, filter: Arc<dyn ConversationFilter>) {
+        self.filters.push(filter);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.filters.is_empty()
+    }
+
+    pub fn apply(&self, conv: &FinalizedConversation, ctx: &SessionContext<'_>) -> FilterDecision {
+        for filter in &self.filters {
+            let decision = filter.accept(conv, ctx);
+            if decision != FilterDecision::Accept {
+                return decision;
+            }
+        }
+        FilterDecision::Accept
+    }
+}
+
+impl fmt::Debug for FilterChain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.filters.iter().map(|filter| filter.name()))
+            .finish()
+    }
+}
+
+/// Drops conversations whose id (see `conversation_id`) is on a denylist,
+/// e.g. samples flagged by review or contamination checks.
+#[derive(Debug, Clone, Default)]
+pub struct ExcludeIds {
+    ids: HashSet<String>,
+}
+
+impl ExcludeIds {
+    pub fn new(ids: impl IntoIterator<Item = String>) -> Self {
+        Self {
+            ids: ids.into_iter().collect(),
+        }
+    }
+
+    /// Read one id per line; blank lines and `#` comments are skipped, and
+    /// anything after the id on a line is ignored.
+    pub fn from_file(path: &Path) -> Result<Self, SerializerError> {
+        let content = std::fs::read_to_string(path)?;
+        Ok(Self::new(
+            content
+                .lines()
+                .filter_map(|line| line.split_whitespace().next())
+                .filter(|id| !id.starts_with('#'))
+                .map(str::to_string),
+        ))
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+}
+
+impl ConversationFilter for ExcludeIds {
+    fn name(&self) -> &str {
+        "exclude-ids"
+    }
+
+    fn accept(&self, conv: &FinalizedConversation, _ctx: &SessionContext<'_>) -> FilterDecision {
+        if self.ids.contains(&conv.id) {
+            FilterDecision::Reject {
+                reason: "excluded_id".to_string(),
+            }
+        } else {
+            FilterDecision::Accept
+        }
+    }
+}
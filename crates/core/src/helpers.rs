@@ -3,6 +3,9 @@
 use regex::Regex;
 use std::sync::LazyLock;
 
+use crate::error::SerializerError;
+use crate::{Tokenizer, TRUNCATION_MARKER};
+
 // ANSI escape sequence patterns
 static ANSI_CSI_RE: LazyLock<Regex> =
     LazyLock::new(|| Regex::new(r"\x1b\[[0-9;?]*[ -/]*[@-~]").unwrap());
@@ -42,6 +45,98 @@ pub fn floor_char_boundary(s: &str, index: usize) -> usize {
     }
 }
 
+/// Unit in which positions within a text are counted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OffsetEncoding {
+    /// UTF-8 bytes, as Rust string offsets.
+    #[default]
+    Utf8,
+    /// UTF-16 code units, as JavaScript and VS Code offsets.
+    Utf16,
+}
+
+impl OffsetEncoding {
+    fn char_len(self, c: char) -> usize {
+        match self {
+            OffsetEncoding::Utf8 => c.len_utf8(),
+            OffsetEncoding::Utf16 => c.len_utf16(),
+        }
+    }
+}
+
+impl std::str::FromStr for OffsetEncoding {
+    type Err = SerializerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "utf8" | "utf-8" => Ok(Self::Utf8),
+            "utf16" | "utf-16" => Ok(Self::Utf16),
+            other => Err(SerializerError::InvalidConfig(format!(
+                "unknown offset encoding '{}'",
+                other
+            ))),
+        }
+    }
+}
+
+/// Convert an offset into a (1-based line, 0-based column) position, with the
+/// column counted in the same units as the offset.
+///
+/// Offsets past the end clamp to the end; offsets inside a character round
+/// down to its start.
+pub fn offset_to_line_col(content: &str, offset: usize, encoding: OffsetEncoding) -> (usize, usize) {
+    let (mut line, mut column, mut position) = (1, 0, 0);
+    for c in content.chars() {
+        let len = encoding.char_len(c);
+        if position + len > offset {
+            break;
+        }
+        position += len;
+        if c == '\n' {
+            line += 1;
+            column = 0;
+        } else {
+            column += len;
+        }
+    }
+    (line, column)
+}
+
+/// Convert a (1-based line, 0-based column) position into an offset in the
+/// given units; the inverse of `offset_to_line_col`.
+///
+/// Lines past the end clamp to the end of the content, columns past the end
+/// of a line clamp to the end of that line.
+pub fn line_col_to_offset(
+    content: &str,
+    line: usize,
+    column: usize,
+    encoding: OffsetEncoding,
+) -> usize {
+    let (mut current_line, mut current_column, mut position) = (1, 0, 0);
+    for c in content.chars() {
+        if current_line >= line.max(1) {
+            let len = encoding.char_len(c);
+            if c == '\n' || current_column + len > column {
+                break;
+            }
+            current_column += len;
+        } else if c == '\n' {
+            current_line += 1;
+        }
+        position += encoding.char_len(c);
+    }
+    position
+}
+
+/// Strip a leading UTF-8 byte order mark.
+///
+/// Editors report offsets relative to the decoded document, which never
+/// includes the BOM, so snapshots must not include it either.
+pub fn strip_bom(text: &str) -> &str {
+    text.strip_prefix('\u{feff}').unwrap_or(text)
+}
+
 /// Clean text by normalizing line endings and trimming trailing whitespace.
 pub fn clean_text(text: &str) -> String {
     text.replace("\r\n", "\n")
@@ -56,6 +151,18 @@ pub fn fenced_block(language: Option<&str>, content: &str) -> String {
     format!("```{}\n{}\n```\n", lang, content)
 }
 
+/// Truncate text to `max_tokens` including a trailing truncation marker.
+///
+/// The marker's tokens are reserved inside the budget so the result never
+/// exceeds `max_tokens` (as counted by the tokenizer, modulo re-tokenization
+/// effects at the cut point).
+pub fn truncate_with_marker<T: Tokenizer>(tokenizer: &T, text: &str, max_tokens: usize) -> String {
+    let marker_tokens = tokenizer.count_tokens(TRUNCATION_MARKER);
+    let budget = max_tokens.saturating_sub(marker_tokens);
+    let truncated = tokenizer.truncate_to_max_tokens(text, budget);
+    format!("{}{}", truncated, TRUNCATION_MARKER)
+}
+
 /// Apply a text change at the given offset.
 pub fn apply_change(content: &str, offset: usize, length: usize, new_text: &str) -> String {
     let mut base = content.to_string();
@@ -172,24 +279,96 @@ pub struct Viewport {
 
 /// Compute a viewport centered around a given line.
 pub fn serialize_compute_viewport(total_lines: usize, center_line: usize, radius: usize) -> Viewport {
+    compute_viewport(total_lines, center_line, radius, radius)
+}
+
+/// Compute a viewport showing `above` lines before and `below` lines after a given line.
+pub fn compute_viewport(total_lines: usize, center_line: usize, above: usize, below: usize) -> Viewport {
     if total_lines == 0 {
         return Viewport { start: 1, end: 0 };
     }
-    let start = center_line.saturating_sub(radius).max(1);
-    let end = (center_line + radius).min(total_lines);
+    let start = center_line.saturating_sub(above).max(1);
+    let end = (center_line + below).min(total_lines);
     Viewport { start, end }
 }
 
+/// Stable 64-bit FNV-1a hash, identical across platforms, runs and Rust versions.
+pub fn stable_hash(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for &b in bytes {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+/// Map a key to a deterministic pseudo-random value in [0, 1).
+///
+/// The FNV hash is passed through a SplitMix64 finalizer: FNV alone barely
+/// changes its high bits when keys differ only in their last bytes
+/// (`session#1`, `session#2`, ...), which would cluster sampled keys.
+pub fn unit_interval_hash(key: &str) -> f64 {
+    let mut z = stable_hash(key.as_bytes());
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^= z >> 31;
+    (z >> 11) as f64 / (1u64 << 53) as f64
+}
+
 /// Escape single quotes for use in sed commands.
 pub fn escape_single_quotes_for_sed(text: &str) -> String {
     // Close quote, add an escaped single quote, reopen quote: '"'"'
     text.replace('\'', "'\"'\"'")
 }
 
+/// Files a shell command writes to: redirection targets, `tee` arguments
+/// and the file of an in-place `sed`/`perl` edit. Files opened in a terminal
+/// editor are not included: the command does not show what was changed.
+pub fn command_written_files(command: &str) -> Vec<String> {
+    let mut files = Vec::new();
+    for segment in command.split(['\n', ';', '|', '&']) {
+        let words: Vec<&str> = segment
+            .split_whitespace()
+            .map(|word| word.trim_matches(|c| c == '\'' || c == '"'))
+            .collect();
+        let args = || words.iter().skip(1).filter(|word| !word.starts_with('-') && !word.starts_with('>'));
+        let mut redirect = false;
+        for word in &words {
+            if redirect {
+                files.push(word.to_string());
+                redirect = false;
+            } else if let Some(target) = word.strip_prefix(">>").or_else(|| word.strip_prefix('>')) {
+                redirect = target.is_empty();
+                if !target.is_empty() {
+                    files.push(target.to_string());
+                }
+            }
+        }
+        match words.first().copied() {
+            Some("tee") => files.extend(args().map(|word| word.to_string())),
+            Some("sed" | "perl") if words.iter().any(|w| w.starts_with("-i") || *w == "--in-place") => {
+                files.extend(args().next_back().map(|word| word.to_string()));
+            }
+            _ => {}
+        }
+    }
+    files.retain(|file| !file.is_empty() && file != "/dev/null");
+    files
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_command_written_files() {
+        assert_eq!(command_written_files("echo 'x = 1' >> src/config.py"), vec!["src/config.py"]);
+        assert_eq!(command_written_files("cat a.txt > b.txt 2>/dev/null"), vec!["b.txt"]);
+        assert_eq!(command_written_files("sed -i 's/a/b/' main.rs && vim -p lib.rs"), vec!["main.rs"]);
+        assert_eq!(command_written_files("make | tee -a build.log"), vec!["build.log"]);
+        assert!(command_written_files("cargo test -- --nocapture").is_empty());
+    }
+
     #[test]
     fn test_clean_text() {
         assert_eq!(clean_text("hello\r\nworld\r"), "hello\nworld");
@@ -226,6 +405,26 @@ mod tests {
         assert!(output.contains("     3\tline3"));
     }
 
+    #[test]
+    fn test_offset_line_col_conversion() {
+        let content = "ab\n😀x\n\nlast";
+        for (encoding, offset, position) in [
+            (OffsetEncoding::Utf8, 7, (2, 4)),
+            (OffsetEncoding::Utf16, 5, (2, 2)),
+            (OffsetEncoding::Utf16, 4, (2, 0)),
+            (OffsetEncoding::Utf16, 8, (4, 0)),
+            (OffsetEncoding::Utf8, 100, (4, 4)),
+        ] {
+            assert_eq!(offset_to_line_col(content, offset, encoding), position, "{:?}", encoding);
+        }
+
+        assert_eq!(line_col_to_offset(content, 2, 2, OffsetEncoding::Utf16), 5);
+        assert_eq!(line_col_to_offset(content, 2, 4, OffsetEncoding::Utf8), 7);
+        assert_eq!(line_col_to_offset(content, 2, 99, OffsetEncoding::Utf16), 6);
+        assert_eq!(line_col_to_offset(content, 9, 0, OffsetEncoding::Utf16), 12);
+        assert_eq!("utf-16".parse::<OffsetEncoding>().unwrap(), OffsetEncoding::Utf16);
+    }
+
     #[test]
     fn test_viewport() {
         let vp = serialize_compute_viewport(100, 50, 10);
@@ -0,0 +1,210 @@
+//! Conversation labels for label-aware sampling and balancing.
+//!
+//! A `ConversationClassifier` looks at each finalized conversation and
+//! returns labels, e.g. its primary language or a guess of the task type.
+//! The pipeline stores them on the conversation and copies them onto its
+//! records and `index.jsonl` entries. `HeuristicClassifier` is a reference
+//! implementation based on file extensions and command patterns.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+
+use crate::boundary::is_test_command;
+use crate::classify::CommandClass;
+use crate::conversation::FinalizedConversation;
+use crate::filter::SessionContext;
+use crate::stats::command_template;
+
+/// Labels of a conversation, by name.
+pub type ConversationLabels = BTreeMap<String, String>;
+
+/// A stage labelling finalized conversations.
+///
+/// Classifiers run in parallel across sessions, hence `Send + Sync`.
+pub trait ConversationClassifier: Send + Sync {
+    /// Name used in logs and `Debug` output.
+    fn name(&self) -> &str;
+
+    fn classify(&self, conv: &FinalizedConversation, ctx: &SessionContext<'_>) -> ConversationLabels;
+}
+
+/// An ordered list of classifiers. Their labels are merged; a later
+/// classifier overrides an earlier one's label of the same name.
+#[derive(Clone, Default)]
+pub struct ClassifierChain {
+    classifiers: Vec<Arc<dyn ConversationClassifier>>,
+}
+
+impl ClassifierChain {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append a classifier to the chain.
+    pub fn with(mut self, classifier: impl ConversationClassifier + 'static) -> Self {
+        self.classifiers.push(Arc::new(classifier));
+        self
+    }
+
+    pub fn push(&mut self, classifier: Arc<dyn ConversationClassifier>) {
+        self.classifiers.push(classifier);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.classifiers.is_empty()
+    }
+
+    pub fn classify(&self, conv: &FinalizedConversation, ctx: &SessionContext<'_>) -> ConversationLabels {
+        let mut labels = ConversationLabels::new();
+        for classifier in &self.classifiers {
+            labels.extend(classifier.classify(conv, ctx));
+        }
+        labels
+    }
+}
+
+impl fmt::Debug for ClassifierChain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.classifiers.iter().map(|classifier| classifier.name()))
+            .finish()
+    }
+}
+
+/// Language of a file by its extension, as a VS Code language id (like the
+/// CSV `Language` column).
+fn extension_language(path: &str) -> Option<&'static str> {
+    let file_name = path.rsplit('/').next()?;
+    let language = match file_name.rsplit_once('.')?.1 {
+        "rs" => "rust",
+        "py" | "pyi" => "python",
+        "go" => "go",
+        "ts" | "mts" | "cts" => "typescript",
+        "tsx" => "typescriptreact",
+        "js" | "mjs" | "cjs" => "javascript",
+        "jsx" => "javascriptreact",
+        "java" => "java",
+        "kt" | "kts" => "kotlin",
+        "c" | "h" => "c",
+        "cc" | "cpp" | "cxx" | "hpp" | "hh" => "cpp",
+        "cs" => "csharp",
+        "rb" => "ruby",
+        "php" => "php",
+        "swift" => "swift",
+        "scala" => "scala",
+        "sh" | "bash" | "zsh" => "shellscript",
+        "ps1" => "powershell",
+        "sql" => "sql",
+        "html" | "htm" => "html",
+        "css" | "scss" => "css",
+        "json" => "json",
+        "yaml" | "yml" => "yaml",
+        "toml" => "toml",
+        "md" => "markdown",
+        _ => return None,
+    };
+    Some(language)
+}
+
+/// Labels conversations by the files and commands of the assistant:
+///
+/// - `language`: the language of the most mentioned source files, by
+///   extension; unset if no known extension occurs.
+/// - `task`: a guess from the commands run. `debugging` (edits and test
+///   runs), `implementation` (edits without tests), `testing` (tests without
+///   edits), `version_control` (mostly `git`) or `exploration` (anything
+///   else, e.g. reading and searching); unset without commands.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HeuristicClassifier;
+
+impl ConversationClassifier for HeuristicClassifier {
+    fn name(&self) -> &str {
+        "heuristic"
+    }
+
+    fn classify(&self, conv: &FinalizedConversation, _ctx: &SessionContext<'_>) -> ConversationLabels {
+        let mut mentions: BTreeMap<&str, usize> = BTreeMap::new();
+        let (mut commands, mut edits, mut tests, mut git) = (0, 0, 0, 0);
+        for message in &conv.messages {
+            let Some(command) = message.command() else {
+                continue;
+            };
+            for word in command.split_whitespace() {
+                let word = word.trim_matches(|c: char| matches!(c, '\'' | '"' | '(' | ')' | ';' | ','));
+                if let Some(language) = extension_language(word) {
+                    *mentions.entry(language).or_default() += 1;
+                }
+            }
+            commands += 1;
+            if message.command_classes().contains(&CommandClass::Edit) {
+                edits += 1;
+            }
+            if is_test_command(command) {
+                tests += 1;
+            }
+            if command_template(command).starts_with("git") {
+                git += 1;
+            }
+        }
+
+        let mut labels = ConversationLabels::new();
+        // Most mentions; ties go to the alphabetically first language
+        if let Some((language, _)) = mentions.iter().max_by(|a, b| a.1.cmp(b.1).then(b.0.cmp(a.0))) {
+            labels.insert("language".to_string(), language.to_string());
+        }
+        let task = match (edits > 0, tests > 0) {
+            _ if commands == 0 => None,
+            (true, true) => Some("debugging"),
+            (true, false) => Some("implementation"),
+            (false, true) => Some("testing"),
+            (false, false) if git * 2 > commands => Some("version_control"),
+            (false, false) => Some("exploration"),
+        };
+        if let Some(task) = task {
+            labels.insert("task".to_string(), task.to_string());
+        }
+        labels
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conversation::ConversationMessage;
+    use crate::pipeline::SessionStats;
+    use crate::tags::SessionTags;
+
+    #[test]
+    fn test_heuristic_classifier() {
+        let stats = SessionStats::default();
+        let tags = SessionTags::new();
+        let ctx = SessionContext {
+            source_path: "a.csv",
+            chunk_index: 0,
+            stats: &stats,
+            tags: &tags,
+        };
+        let classify = |commands: &[&str]| {
+            let conv = FinalizedConversation {
+                messages: commands
+                    .iter()
+                    .map(|c| ConversationMessage::assistant(format!("```bash\n{}\n```", c)))
+                    .collect(),
+                ..Default::default()
+            };
+            HeuristicClassifier.classify(&conv, &ctx)
+        };
+
+        let labels = classify(&["cat -n src/main.rs", "sed -i '2c\\\nx' src/lib.rs", "cat -n 'setup.py'"]);
+        assert_eq!(labels["language"], "rust");
+        assert_eq!(labels["task"], "implementation");
+        assert_eq!(classify(&["sed -i '1d' a.py", "pytest"])["task"], "debugging");
+        assert_eq!(classify(&["git status", "git log", "ls"])["task"], "version_control");
+        assert_eq!(classify(&["ls", "grep -rn foo ."])["task"], "exploration");
+        assert!(classify(&[]).is_empty());
+
+        let chain = ClassifierChain::new().with(HeuristicClassifier);
+        assert_eq!(format!("{:?}", chain), r#"["heuristic"]"#);
+    }
+}
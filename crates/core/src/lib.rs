@@ -29,21 +29,102 @@ impl<T: Tokenizer + ?Sized> Tokenizer for &T {
     }
 }
 
+mod augment;
+mod blobs;
+mod blur;
+mod boundary;
+mod budget;
+mod cancel;
+mod classify;
+mod contamination;
 mod conversation;
 mod diff;
+mod error;
+mod event;
+mod filter;
 mod helpers;
+mod labels;
+mod observer;
+mod packing;
+mod paths;
 pub mod pipeline;
+mod registry;
+mod reorder;
+mod render;
+mod replay;
+mod runner;
+mod sed;
+mod spans;
+mod stitch;
+mod tags;
+pub mod stats;
+mod validate;
+mod vim;
+mod warnings;
 
-pub use conversation::{ConversationMessage, ConversationStateManager, ConversationStateManagerConfig, FinalizedConversation};
+pub use augment::{load_system_prompts, session_root, AugmentationConfig, AugmentationInfo};
+pub use blobs::{materialize_dataset, materialize_line, BlobStats, BlobStore, BLOBS_DIR};
+pub use blur::TimingBlur;
+pub use boundary::{TaskBoundary, TaskBoundaryConfig, TaskBoundaryDetector};
+pub use budget::{auto_chunk_budget, simulate_budget, BudgetLimits, BudgetSimulation, CharApproxTokenizer};
+pub use classify::{classify_command, CommandClass, DropCommandClasses};
+pub use contamination::{
+    ContaminationConfig, ContaminationFilter, ContaminationMatch, ContaminationReport,
+};
+pub use conversation::{
+    ConversationMessage, ConversationMetrics, ConversationStateManager,
+    ConversationStateManagerConfig, DropStats, FileCheckpoint, FinalizedConversation, MessageKind, MessageTiming,
+    ModelContext,
+    MultilineCommandPolicy, OffsetDriftDiagnostic, PinPolicy, UntrackedFilePolicy, WhitespaceEditPolicy,
+};
 pub use pipeline::{
-    discover_csv_files, process_all_sessions, process_session, write_jsonl_output,
-    NemoMessage, NemoRecord, PipelineConfig, PipelineResult, SessionResult,
+    anonymize_source_path, conversation_id, detect_windows_session, discover_csv_files, process_all_sessions,
+    process_session, process_stitched_session, process_stitched_session_until, read_jsonl_input, sample_sessions, session_id,
+    tokenize_conversations, tokenize_conversations_until,
+    prompt_completion_records, verify_sessions, write_jsonl_output, IndexEntry, RecordVersion,
+    MessageTimingEntry, NemoMessage, NemoRecord, OutputFormat, OutputLayout, PipelineConfig, PipelineResult,
+    PromptCompletionRecord, SessionPlatform, SessionResult, SessionStats, WindowsSessionMode,
+};
+pub use diff::{compute_changed_block_lines, compute_changed_block_lines_until, diff_messages, ChangedBlock, MessageHunk, OpcodeTag};
+pub use error::SerializerError;
+pub use event::IdeEvent;
+pub use filter::{
+    ConversationFilter, ExcludeIds, FilterChain, FilterDecision, SessionContext,
 };
-pub use diff::{compute_changed_block_lines, ChangedBlock};
 pub use helpers::{
-    apply_backspaces, apply_change, clean_text, escape_single_quotes_for_sed, fenced_block,
-    line_numbered_output, normalize_terminal_output, serialize_compute_viewport, Viewport,
+    apply_backspaces, apply_change, clean_text, compute_viewport, escape_single_quotes_for_sed,
+    fenced_block, line_col_to_offset, line_numbered_output, normalize_terminal_output,
+    offset_to_line_col, serialize_compute_viewport, stable_hash, strip_bom, truncate_with_marker,
+    unit_interval_hash, OffsetEncoding, Viewport,
+};
+pub use labels::{ClassifierChain, ConversationClassifier, ConversationLabels, HeuristicClassifier};
+pub use observer::{ConversationObserver, Notification};
+pub use packing::{
+    pack_first_fit_decreasing, PackedSource, PackingConfig, PackingInfo, PackingStats,
+    PackingSystemPrompt,
+};
+pub use paths::{
+    is_windows_path, windows_path_to_posix, CompanionPattern, IgnorePattern, PathCanonicalization, PathCanonicalizer, PathMapper,
+    PathMapping, VENDORED_PATH_PATTERNS,
+};
+pub use registry::{RegistryStats, SessionRegistry};
+pub use render::{
+    Action, BashRenderer, FileEdit, Observation, PowerShellRenderer, Renderer, ViewCommand,
+    ViewCommandMix,
 };
+pub use reorder::{ReorderBuffer, ReorderKey};
+pub use replay::{replay_conversation, ReplayOutcome, VerificationReport};
+pub use cancel::{CancellationToken, SessionInterrupt};
+pub use runner::{Pipeline, PipelineProgress, PipelineRun, SessionSink};
+pub use sed::run_sed;
+pub use spans::loss_spans;
+pub use stats::{DatasetStats, Histogram, LanguageStats, TokenizerComparison};
+pub use stitch::{stitch_sessions, StitchConfig, StitchKey, StitchedSession};
+pub use tags::{
+    load_session_manifest, read_session_tags, RequireTags, SessionTags, TagRequirement,
+};
+pub use validate::{validate_structure, StructureValidation, StructureViolation};
+pub use warnings::{Warning, WarningAggregator, DEFAULT_WARNING_SAMPLES};
 
 /// Default viewport radius (lines above/below cursor to show)
 pub const VIEWPORT_RADIUS: usize = 10;
@@ -57,3 +138,18 @@ pub const MAX_TOKENS_PER_MESSAGE: usize = 2048;
 /// Default maximum tokens per terminal output
 pub const MAX_TOKENS_PER_TERMINAL_OUTPUT: usize = 256;
 
+/// Marker appended to any message or output that was truncated to fit a token budget
+pub const TRUNCATION_MARKER: &str = "\n... [truncated]";
+
+/// Message standing in for history left out of a budgeted model context
+pub const ELISION_MARKER: &str = "[... earlier messages elided ...]";
+
+/// Version of the output record schema; bumped on changes readers must know about
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Version of this serializer crate
+pub const SERIALIZER_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Commit the serializer was built from, if `CROWD_PILOT_GIT_COMMIT` was set at build time
+pub const SERIALIZER_COMMIT: Option<&str> = option_env!("CROWD_PILOT_GIT_COMMIT");
+
@@ -0,0 +1,54 @@
+//! Observer hook for state manager notifications.
+
+use crate::conversation::ConversationMessage;
+
+/// A structured notification emitted by the state manager.
+#[derive(Debug, Clone, Copy)]
+pub enum Notification<'a> {
+    /// A message was appended to the current conversation.
+    MessageAppended {
+        message: &'a ConversationMessage,
+        /// Token count of the message (0 with deferred token counting).
+        tokens: usize,
+    },
+    /// The last message was removed again (e.g. merged into the next command).
+    MessageRemoved { message: &'a ConversationMessage },
+    /// Pending edits to a file were serialized as a command.
+    EditFlushed { file_path: &'a str, command: &'a str },
+    /// The current conversation ended, either kept or discarded.
+    ConversationFinalized {
+        messages: usize,
+        token_count: usize,
+        kept: bool,
+    },
+    /// A message was truncated to fit a token budget.
+    Truncated {
+        role: &'a str,
+        original_tokens: usize,
+        max_tokens: usize,
+    },
+}
+
+/// Receives notifications from a `ConversationStateManager`.
+pub trait ConversationObserver: Send {
+    fn notify(&mut self, notification: &Notification<'_>);
+}
+
+impl<F> ConversationObserver for F
+where
+    F: FnMut(&Notification<'_>) + Send,
+{
+    fn notify(&mut self, notification: &Notification<'_>) {
+        self(notification)
+    }
+}
+
+/// Deliver a notification to every observer.
+pub(crate) fn notify_all(
+    observers: &mut [Box<dyn ConversationObserver>],
+    notification: &Notification<'_>,
+) {
+    for observer in observers {
+        observer.notify(notification);
+    }
+}
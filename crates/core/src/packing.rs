@@ -0,0 +1,152 @@
+//! Packing of short conversations into records of a target token length.
+//!
+//! Unpacked, every short conversation is padded to the trainer's sequence
+//! length. Packing concatenates conversations of the same split into one
+//! record, first-fit decreasing by token count, and records which
+//! conversations each packed record holds.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::conversation::ConversationMetrics;
+use crate::error::SerializerError;
+use crate::labels::ConversationLabels;
+use crate::tags::SessionTags;
+
+/// How the system prompt appears in a packed record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PackingSystemPrompt {
+    /// Only in the record's `system` field.
+    #[default]
+    Once,
+    /// Also repeated, after the separator, at the start of every packed
+    /// conversation but the first.
+    Repeat,
+}
+
+impl PackingSystemPrompt {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Once => "once",
+            Self::Repeat => "repeat",
+        }
+    }
+}
+
+impl std::str::FromStr for PackingSystemPrompt {
+    type Err = SerializerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "once" => Ok(Self::Once),
+            "repeat" => Ok(Self::Repeat),
+            other => Err(SerializerError::InvalidConfig(format!(
+                "unknown packing system prompt mode '{}'",
+                other
+            ))),
+        }
+    }
+}
+
+/// Configuration of the packing stage.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PackingConfig {
+    /// Maximum tokens per packed record. Longer conversations stay unpacked.
+    pub target_tokens: usize,
+    /// Text prepended to the first message of every packed conversation but
+    /// the first (e.g. an end-of-sequence token).
+    pub separator: String,
+    pub system_prompt: PackingSystemPrompt,
+    /// Tokens each conversation boundary adds (separator, repeated system
+    /// prompt), reserved when filling a record.
+    pub boundary_tokens: usize,
+}
+
+impl PackingConfig {
+    /// Text inserted at a conversation boundary.
+    pub fn boundary_text(&self, system_prompt: &str) -> String {
+        match self.system_prompt {
+            PackingSystemPrompt::Once => self.separator.clone(),
+            PackingSystemPrompt::Repeat => format!("{}{}\n\n", self.separator, system_prompt),
+        }
+    }
+}
+
+/// A conversation inside a packed record.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PackedSource {
+    /// Conversation id.
+    pub id: String,
+    pub session: String,
+    pub chunk_index: usize,
+    /// Index of the conversation's first message in the packed record.
+    pub first_message: usize,
+    pub messages: usize,
+    pub tokens: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metrics: Option<ConversationMetrics>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub tags: SessionTags,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: ConversationLabels,
+}
+
+/// Packing metadata of a record.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PackingInfo {
+    /// Tokens of the packed conversations plus boundaries.
+    pub tokens: usize,
+    pub sources: Vec<PackedSource>,
+}
+
+/// Aggregate packing statistics of a run.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PackingStats {
+    pub target_tokens: usize,
+    pub packed_records: usize,
+    pub packed_conversations: usize,
+    /// Records holding a single conversation longer than the target.
+    pub oversized_conversations: usize,
+    /// Packed tokens over `packed_records * target_tokens`.
+    pub fill_ratio: f64,
+}
+
+/// Assign items of the given token counts to bins of at most `target_tokens`,
+/// first-fit decreasing. Each item after the first in a bin costs an extra
+/// `boundary_tokens`. Returns the item indices of each bin in insertion
+/// order; ties keep the input order, so packing is deterministic.
+pub fn pack_first_fit_decreasing(
+    tokens: &[usize],
+    target_tokens: usize,
+    boundary_tokens: usize,
+) -> Vec<Vec<usize>> {
+    let mut order: Vec<usize> = (0..tokens.len()).collect();
+    order.sort_by_key(|&i| std::cmp::Reverse(tokens[i]));
+
+    let mut bins: Vec<(usize, Vec<usize>)> = Vec::new();
+    for i in order {
+        let fit = bins
+            .iter_mut()
+            .find(|(used, _)| *used + boundary_tokens + tokens[i] <= target_tokens);
+        match fit {
+            Some((used, items)) => {
+                *used += boundary_tokens + tokens[i];
+                items.push(i);
+            }
+            None => bins.push((tokens[i], vec![i])),
+        }
+    }
+    bins.into_iter().map(|(_, items)| items).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pack_first_fit_decreasing() {
+        let bins = pack_first_fit_decreasing(&[30, 70, 20, 120, 50, 25], 100, 2);
+        assert_eq!(bins, vec![vec![3], vec![1, 5], vec![4, 0], vec![2]]);
+    }
+}
@@ -0,0 +1,487 @@
+//! Path prefix rewriting for portable transcripts.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+use regex::Regex;
+
+use crate::error::SerializerError;
+
+/// Rewrite rule mapping an absolute root to a portable prefix,
+/// e.g. `/home/u/projA` -> `projA/`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathMapping {
+    pub from: String,
+    pub to: String,
+}
+
+impl std::str::FromStr for PathMapping {
+    type Err = SerializerError;
+
+    /// Parse `FROM=TO`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((from, to)) if !from.trim_end_matches('/').is_empty() => Ok(Self {
+                from: from.to_string(),
+                to: to.to_string(),
+            }),
+            _ => Err(SerializerError::InvalidConfig(format!(
+                "path mapping '{}' is not of the form FROM=TO",
+                s
+            ))),
+        }
+    }
+}
+
+/// Glob of files whose editor events are skipped, e.g. `node_modules/**` or
+/// `*.min.js`.
+///
+/// `*` and `?` match within a path component and `**` across components. A
+/// pattern starting with `/` must match the whole path; others match its
+/// trailing components.
+#[derive(Debug, Clone)]
+pub struct IgnorePattern {
+    glob: String,
+    pattern: Regex,
+}
+
+/// Dependency directories, build outputs, minified bundles and lockfiles.
+pub const VENDORED_PATH_PATTERNS: &[&str] = &[
+    "node_modules/**",
+    "target/**",
+    "dist/**",
+    "vendor/**",
+    ".venv/**",
+    "__pycache__/**",
+    ".git/**",
+    "*.min.js",
+    "*.min.css",
+    "*.lock",
+    "package-lock.json",
+    "pnpm-lock.yaml",
+    "go.sum",
+];
+
+impl IgnorePattern {
+    pub fn as_str(&self) -> &str {
+        &self.glob
+    }
+
+    /// Whether `path` (with `/` or `\` separators) matches.
+    pub fn matches(&self, path: &str) -> bool {
+        self.pattern.is_match(&path.replace('\\', "/"))
+    }
+}
+
+impl std::str::FromStr for IgnorePattern {
+    type Err = SerializerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.trim_matches('/').is_empty() {
+            return Err(SerializerError::InvalidConfig(format!("empty ignore pattern '{}'", s)));
+        }
+        let mut regex = String::from(if s.starts_with('/') { "^/" } else { "^(?:.*/)?" });
+        let mut rest = s.trim_start_matches('/');
+        while let Some(c) = rest.chars().next() {
+            let (piece, len) = if rest.starts_with("**/") {
+                ("(?:.*/)?".to_string(), 3)
+            } else if rest.starts_with("**") {
+                (".*".to_string(), 2)
+            } else if c == '*' {
+                ("[^/]*".to_string(), 1)
+            } else if c == '?' {
+                ("[^/]".to_string(), 1)
+            } else {
+                (regex::escape(&c.to_string()), c.len_utf8())
+            };
+            regex.push_str(&piece);
+            rest = &rest[len..];
+        }
+        regex.push('$');
+        Ok(Self {
+            glob: s.to_string(),
+            pattern: Regex::new(&regex).map_err(|e| {
+                SerializerError::InvalidConfig(format!("ignore pattern '{}': {}", s, e))
+            })?,
+        })
+    }
+}
+
+/// Relationship between a file and its companion (e.g. its tests), as
+/// `PRIMARY=COMPANION` globs with one `*` each: `*.rs=*_test.rs` or
+/// `src/*.ts=test/*.test.ts`.
+///
+/// The `*` of `PRIMARY` matches within a path component. Like an
+/// `IgnorePattern`, a primary glob starting with `/` must match the whole
+/// path and others match its trailing components; the companion is the
+/// path with those components replaced by `COMPANION`, its `*` standing for
+/// the same text.
+#[derive(Debug, Clone)]
+pub struct CompanionPattern {
+    glob: String,
+    primary: Regex,
+    companion: String,
+}
+
+impl CompanionPattern {
+    pub fn as_str(&self) -> &str {
+        &self.glob
+    }
+
+    /// The companion of `path`, if it matches the primary glob and is not
+    /// its own companion.
+    pub fn companion_of(&self, path: &str) -> Option<String> {
+        let captures = self.primary.captures(path)?;
+        let root = captures.get(1).map_or("", |m| m.as_str());
+        let companion = format!("{}{}", root, self.companion.replacen('*', &captures[2], 1));
+        (companion != path).then_some(companion)
+    }
+}
+
+impl std::str::FromStr for CompanionPattern {
+    type Err = SerializerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            SerializerError::InvalidConfig(format!(
+                "companion pattern '{}' is not of the form PRIMARY=COMPANION with one '*' each",
+                s
+            ))
+        };
+        let (primary, companion) = s.split_once('=').ok_or_else(invalid)?;
+        let Some((before, after)) = primary.split_once('*') else {
+            return Err(invalid());
+        };
+        if after.contains('*') || companion.matches('*').count() != 1 {
+            return Err(invalid());
+        }
+        let root = if primary.starts_with('/') { "^()" } else { "^(.*/)?" };
+        let regex = format!(
+            "{}{}([^/]+){}$",
+            root,
+            regex::escape(before),
+            regex::escape(after)
+        );
+        Ok(Self {
+            glob: s.to_string(),
+            primary: Regex::new(&regex).map_err(|_| invalid())?,
+            companion: companion.to_string(),
+        })
+    }
+}
+
+/// Whether `path` is a Windows path: drive-absolute (`C:\`, `C:/`) or UNC
+/// (`\\server\share`).
+pub fn is_windows_path(path: &str) -> bool {
+    let bytes = path.as_bytes();
+    let drive = bytes.len() >= 3
+        && bytes[0].is_ascii_alphabetic()
+        && bytes[1] == b':'
+        && matches!(bytes[2], b'\\' | b'/');
+    drive || path.starts_with("\\\\")
+}
+
+/// POSIX form of a Windows path (`C:\a\b` -> `/c/a/b`, UNC `\\srv\share`
+/// -> `//srv/share`); other paths are returned unchanged.
+pub fn windows_path_to_posix(path: &str) -> Cow<'_, str> {
+    if !is_windows_path(path) {
+        return Cow::Borrowed(path);
+    }
+    let posix = path.replace('\\', "/");
+    Cow::Owned(match posix.split_once(':') {
+        Some((drive, rest)) if drive.len() == 1 => format!("/{}{}", drive.to_lowercase(), rest),
+        _ => posix,
+    })
+}
+
+/// A prepared mapping rule.
+#[derive(Debug, Clone)]
+struct Rule {
+    from: String,
+    /// Replacement without trailing slash.
+    to: String,
+    /// Matches `from` in free text, ending at a path boundary.
+    pattern: Regex,
+}
+
+/// Applies path mappings to file paths and free text (commands, output).
+///
+/// Rules are tried longest `from` first, so nested roots map correctly.
+#[derive(Debug, Clone, Default)]
+pub struct PathMapper {
+    rules: Vec<Rule>,
+    /// Rewrite Windows paths to POSIX form before applying the rules.
+    windows_paths: Option<Regex>,
+}
+
+impl PathMapper {
+    pub fn new(mappings: &[PathMapping]) -> Self {
+        let mut rules: Vec<Rule> = mappings
+            .iter()
+            .map(|m| {
+                let from = m.from.trim_end_matches('/').to_string();
+                let pattern = Regex::new(&format!(r"{}(?:\b|$)", regex::escape(&from)))
+                    .expect("escaped pattern is valid");
+                Rule {
+                    to: m.to.trim_end_matches('/').to_string(),
+                    from,
+                    pattern,
+                }
+            })
+            .collect();
+        rules.sort_by_key(|rule| std::cmp::Reverse(rule.from.len()));
+        Self {
+            rules,
+            windows_paths: None,
+        }
+    }
+
+    /// Also rewrite Windows paths to POSIX form (see `windows_path_to_posix`),
+    /// in paths and free text; rules then match the POSIX form.
+    pub fn with_windows_normalization(mut self) -> Self {
+        self.windows_paths = Some(
+            Regex::new(r#"\b[A-Za-z]:[\\/][^\s"'<>|*?]*|\\\\[^\s"'<>|*?\\]+\\[^\s"'<>|*?]*"#)
+                .expect("windows path pattern is valid"),
+        );
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty() && self.windows_paths.is_none()
+    }
+
+    /// Map a file path using the first rule whose root contains it.
+    pub fn map_path<'a>(&self, path: &'a str) -> Cow<'a, str> {
+        if self.windows_paths.is_some() && is_windows_path(path) {
+            let posix = windows_path_to_posix(path).into_owned();
+            return Cow::Owned(self.map_rules(&posix).into_owned());
+        }
+        self.map_rules(path)
+    }
+
+    fn map_rules<'a>(&self, path: &'a str) -> Cow<'a, str> {
+        for rule in &self.rules {
+            if path == rule.from {
+                return Cow::Owned(rule.to.clone());
+            }
+            if let Some(rest) = path.strip_prefix(&rule.from).and_then(|r| r.strip_prefix('/')) {
+                return Cow::Owned(if rule.to.is_empty() {
+                    rest.to_string()
+                } else {
+                    format!("{}/{}", rule.to, rest)
+                });
+            }
+        }
+        Cow::Borrowed(path)
+    }
+
+    /// Rewrite every occurrence of a mapped root in free text.
+    pub fn map_text<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        let mut text = Cow::Borrowed(text);
+        if let Some(pattern) = &self.windows_paths {
+            if pattern.is_match(&text) {
+                let replaced = pattern
+                    .replace_all(&text, |caps: &regex::Captures<'_>| {
+                        windows_path_to_posix(&caps[0]).into_owned()
+                    })
+                    .into_owned();
+                text = Cow::Owned(replaced);
+            }
+        }
+        for rule in &self.rules {
+            if rule.pattern.is_match(&text) {
+                let replaced = rule.pattern.replace_all(&text, rule.to.as_str()).into_owned();
+                text = Cow::Owned(replaced);
+            }
+        }
+        text
+    }
+}
+
+/// How differently spelled paths of one file are unified, so that the file
+/// keeps a single tracked state.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PathCanonicalization {
+    /// Drop `.` components and repeated slashes, and resolve `..` lexically.
+    pub lexical: bool,
+    /// Treat paths differing only in case as one file, spelled as first seen
+    /// (case-insensitive file systems, e.g. on macOS).
+    pub case_fold: bool,
+    /// Symlinked roots rewritten to their targets (`LINK=TARGET`), before the
+    /// other steps.
+    pub symlinks: Vec<PathMapping>,
+}
+
+impl PathCanonicalization {
+    pub fn is_empty(&self) -> bool {
+        !self.lexical && !self.case_fold && self.symlinks.is_empty()
+    }
+}
+
+/// `path` with `.` components and repeated slashes dropped and `..` resolved
+/// against the preceding component; None if that changes nothing.
+fn normalize_lexically(path: &str) -> Option<String> {
+    let absolute = path.starts_with('/');
+    let mut parts: Vec<&str> = Vec::new();
+    for part in path.split('/') {
+        match part {
+            "" | "." => {}
+            ".." if parts.last().is_some_and(|last| *last != "..") => {
+                parts.pop();
+            }
+            // Nothing is above the root
+            ".." if absolute => {}
+            _ => parts.push(part),
+        }
+    }
+    let mut normalized = parts.join("/");
+    if absolute {
+        normalized.insert(0, '/');
+    } else if normalized.is_empty() {
+        normalized.push('.');
+    }
+    (normalized != path).then_some(normalized)
+}
+
+/// Applies a `PathCanonicalization`, remembering the first spelling of each
+/// path for case folding.
+#[derive(Debug, Clone, Default)]
+pub struct PathCanonicalizer {
+    lexical: bool,
+    case_fold: bool,
+    symlinks: PathMapper,
+    /// First spelling of each lowercased path.
+    spellings: HashMap<String, String>,
+}
+
+impl PathCanonicalizer {
+    pub fn new(config: &PathCanonicalization) -> Self {
+        Self {
+            lexical: config.lexical,
+            case_fold: config.case_fold,
+            symlinks: PathMapper::new(&config.symlinks),
+            spellings: HashMap::new(),
+        }
+    }
+
+    /// Canonical form of `path`, without learning new spellings.
+    pub fn canonical<'a>(&self, path: &'a str) -> Cow<'a, str> {
+        let mut path = self.symlinks.map_path(path);
+        if self.lexical {
+            if let Some(normalized) = normalize_lexically(&path) {
+                path = Cow::Owned(normalized);
+            }
+        }
+        if self.case_fold {
+            if let Some(first) = self.spellings.get(&path.to_lowercase()) {
+                if *first != *path {
+                    path = Cow::Owned(first.clone());
+                }
+            }
+        }
+        path
+    }
+
+    /// Canonical form of `path`, learning its spelling if it is the first.
+    pub fn canonicalize<'a>(&mut self, path: &'a str) -> Cow<'a, str> {
+        let path = self.canonical(path);
+        if self.case_fold {
+            self.spellings.entry(path.to_lowercase()).or_insert_with(|| path.to_string());
+        }
+        path
+    }
+
+    /// Forget the learned spellings.
+    pub fn clear(&mut self) {
+        self.spellings.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_path_mapper() {
+        let mapper = PathMapper::new(&[
+            "/home/u=home/".parse().unwrap(),
+            "/home/u/projA=projA/".parse().unwrap(),
+        ]);
+
+        assert_eq!(mapper.map_path("/home/u/projA/src/main.rs"), "projA/src/main.rs");
+        assert_eq!(mapper.map_path("/home/u/projAB/x.rs"), "home/projAB/x.rs");
+        assert_eq!(mapper.map_path("/etc/hosts"), "/etc/hosts");
+        assert_eq!(
+            mapper.map_text("cd /home/u/projA && ls /home/u/projAB"),
+            "cd projA && ls home/projAB"
+        );
+    }
+
+    #[test]
+    fn test_path_canonicalizer() {
+        assert_eq!(normalize_lexically("/a/./b//c/../d.rs").as_deref(), Some("/a/b/d.rs"));
+        assert_eq!(normalize_lexically("../a/../../b.rs").as_deref(), Some("../../b.rs"));
+        assert_eq!(normalize_lexically("/../a.rs").as_deref(), Some("/a.rs"));
+        assert_eq!(normalize_lexically("src/a.rs"), None);
+
+        let mut canonicalizer = PathCanonicalizer::new(&PathCanonicalization {
+            lexical: true,
+            case_fold: true,
+            symlinks: vec!["/tmp/link=/home/u/proj".parse().unwrap()],
+        });
+        assert_eq!(canonicalizer.canonical("/Home/U/Proj/A.rs"), "/Home/U/Proj/A.rs");
+        assert_eq!(canonicalizer.canonicalize("/home/u/proj/src/A.rs"), "/home/u/proj/src/A.rs");
+        assert_eq!(canonicalizer.canonicalize("/tmp/link/./SRC/a.rs"), "/home/u/proj/src/A.rs");
+        assert_eq!(canonicalizer.canonical("/HOME/u/proj/src/a.rs"), "/home/u/proj/src/A.rs");
+        canonicalizer.clear();
+        assert_eq!(canonicalizer.canonical("/HOME/u/proj/src/a.rs"), "/HOME/u/proj/src/a.rs");
+    }
+
+    #[test]
+    fn test_companion_patterns() {
+        let test: CompanionPattern = "*.rs=*_test.rs".parse().unwrap();
+        assert_eq!(test.companion_of("/p/src/foo.rs").as_deref(), Some("/p/src/foo_test.rs"));
+        let spec: CompanionPattern = "src/*.ts=test/*.test.ts".parse().unwrap();
+        assert_eq!(spec.companion_of("/p/src/a.ts").as_deref(), Some("/p/test/a.test.ts"));
+        assert_eq!(spec.companion_of("/p/lib/a.ts"), None);
+        let absolute: CompanionPattern = "/p/*.c=/p/include/*.h".parse().unwrap();
+        assert_eq!(absolute.companion_of("/p/a.c").as_deref(), Some("/p/include/a.h"));
+        assert_eq!(absolute.companion_of("/q/p/a.c"), None);
+
+        assert!("*.rs".parse::<CompanionPattern>().is_err());
+        assert!("*.rs=tests/a.rs".parse::<CompanionPattern>().is_err());
+    }
+
+    #[test]
+    fn test_ignore_patterns() {
+        let patterns: Vec<IgnorePattern> = VENDORED_PATH_PATTERNS.iter().map(|p| p.parse().unwrap()).collect();
+        let ignored = |path: &str| patterns.iter().any(|p| p.matches(path));
+        assert!(ignored("/home/u/proj/node_modules/react/index.js"));
+        assert!(ignored("/home/u/proj/static/app.min.js"));
+        assert!(ignored("/home/u/proj/Cargo.lock"));
+        assert!(ignored(r"C:\proj\target\debug\build.rs"));
+        assert!(!ignored("/home/u/proj/src/target.rs"));
+        assert!(!ignored("/home/u/proj/src/main.rs"));
+
+        let anchored: IgnorePattern = "/proj/*.rs".parse().unwrap();
+        assert!(anchored.matches("/proj/a.rs"));
+        assert!(!anchored.matches("/other/proj/a.rs"));
+        assert!(!anchored.matches("/proj/src/a.rs"));
+        assert!("".parse::<IgnorePattern>().is_err());
+    }
+
+    #[test]
+    fn test_windows_normalization() {
+        assert_eq!(windows_path_to_posix(r"C:\Users\me\a.rs"), "/c/Users/me/a.rs");
+        assert_eq!(windows_path_to_posix(r"\\srv\share\x"), "//srv/share/x");
+        assert_eq!(windows_path_to_posix("src/a.rs"), "src/a.rs");
+
+        let mapper = PathMapper::new(&["/c/Users/me/proj=proj/".parse().unwrap()])
+            .with_windows_normalization();
+        assert_eq!(mapper.map_path(r"C:\Users\me\proj\src\main.rs"), "proj/src/main.rs");
+        assert_eq!(
+            mapper.map_text(r"cd C:\Users\me\proj; type D:\notes.txt; curl http://x"),
+            "cd proj; type /d/notes.txt; curl http://x"
+        );
+    }
+}
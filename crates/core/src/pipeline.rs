@@ -1,62 +1,708 @@
 //! Pipeline for processing CSV sessions into conversations.
 
-use std::path::Path;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
 
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 use walkdir::WalkDir;
 
-use crate::conversation::{ConversationStateManager, ConversationStateManagerConfig, FinalizedConversation};
-use crate::Tokenizer;
+use crate::augment::{session_root, AugmentationConfig, AugmentationInfo};
+use crate::blobs::{BlobStats, BlobStore};
+use crate::blur::TimingBlur;
+use crate::boundary::{TaskBoundary, TaskBoundaryConfig};
+use crate::budget::{auto_chunk_budget, CharApproxTokenizer};
+use crate::cancel::SessionInterrupt;
+use crate::conversation::{
+    ConversationMessage, ConversationMetrics, ConversationStateManager,
+    ConversationStateManagerConfig, DropStats, FileCheckpoint, FinalizedConversation, MessageKind, MessageTiming,
+    MultilineCommandPolicy, UntrackedFilePolicy, WhitespaceEditPolicy,
+};
+use crate::error::SerializerError;
+use crate::event::IdeEvent;
+use crate::filter::{FilterChain, FilterDecision, SessionContext};
+use crate::helpers::{stable_hash, truncate_with_marker, unit_interval_hash};
+use crate::labels::{ClassifierChain, ConversationLabels};
+use crate::packing::{
+    pack_first_fit_decreasing, PackedSource, PackingConfig, PackingInfo, PackingStats,
+};
+use crate::paths::{is_windows_path, CompanionPattern, IgnorePattern, PathCanonicalization, PathMapping};
+use crate::render::{PowerShellRenderer, ViewCommandMix};
+use crate::reorder::ReorderKey;
+use crate::replay::{replay_conversation, VerificationReport};
+use crate::runner::{Pipeline, PipelineProgress};
+use crate::spans::loss_spans;
+use crate::stats::command_template;
+use crate::stitch::{StitchConfig, StitchedSession};
+use crate::tags::{read_session_tags, SessionTags};
+use crate::validate::{validate_structure, StructureValidation};
+use crate::warnings::WarningAggregator;
+use crate::{Tokenizer, SCHEMA_VERSION, SERIALIZER_COMMIT, SERIALIZER_VERSION};
 
 /// A row from the CSV file.
+///
+/// String fields borrow from the reader's reused `StringRecord` buffer to avoid
+/// per-row allocations.
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "PascalCase")]
-struct CsvRow {
-    #[serde(rename = "Sequence")]
-    _sequence: Option<i64>,
-    #[serde(rename = "Time")]
-    _time: Option<String>,
-    file: String,
+struct CsvRow<'a> {
+    sequence: Option<i64>,
+    #[serde(borrow)]
+    time: Option<&'a str>,
+    file: &'a str,
     range_offset: Option<i64>,
     range_length: Option<i64>,
-    text: Option<String>,
-    #[serde(rename = "Language")]
-    _language: Option<String>,
+    #[serde(borrow)]
+    text: Option<&'a str>,
+    #[serde(borrow)]
+    language: Option<&'a str>,
     #[serde(rename = "Type")]
-    event_type: String,
+    event_type: &'a str,
+    /// Optional position columns of selection events (1-based line, 0-based
+    /// column); preferred over `RangeOffset` when present.
+    line: Option<i64>,
+    column: Option<i64>,
+    end_line: Option<i64>,
+    end_column: Option<i64>,
+}
+
+/// A row as handed to the state manager, written by `PipelineConfig::dump_events_dir`.
+#[derive(Debug, Serialize)]
+struct DumpedEvent<'a> {
+    sequence: Option<i64>,
+    time_ms: Option<i64>,
+    event_type: &'a str,
+    file: &'a str,
+    range_offset: Option<i64>,
+    range_length: Option<i64>,
+    text: Option<&'a str>,
+    language: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    line: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    column: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    end_line: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    end_column: Option<i64>,
+}
+
+impl<'a> From<&CsvRow<'a>> for DumpedEvent<'a> {
+    fn from(row: &CsvRow<'a>) -> Self {
+        Self {
+            sequence: row.sequence,
+            time_ms: row.time.and_then(parse_time_ms),
+            event_type: row.event_type,
+            file: row.file,
+            range_offset: row.range_offset,
+            range_length: row.range_length,
+            text: row.text,
+            language: row.language,
+            line: row.line,
+            column: row.column,
+            end_line: row.end_line,
+            end_column: row.end_column,
+        }
+    }
+}
+
+/// Layout of the JSONL output records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputFormat {
+    /// One NeMo SFT record per conversation.
+    #[default]
+    Conversation,
+    /// One record per assistant message, with the preceding messages as prompt.
+    PromptCompletion,
+}
+
+impl OutputFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Conversation => "conversation",
+            Self::PromptCompletion => "prompt-completion",
+        }
+    }
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = SerializerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "conversation" => Ok(Self::Conversation),
+            "prompt-completion" => Ok(Self::PromptCompletion),
+            other => Err(SerializerError::InvalidConfig(format!(
+                "unknown output format '{}'",
+                other
+            ))),
+        }
+    }
+}
+
+/// File layout written by `write_jsonl_output`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputLayout {
+    /// `training.jsonl` and `validation.jsonl`.
+    #[default]
+    Monolithic,
+    /// `sessions/<hash>.jsonl` per session plus a top-level `index.jsonl`.
+    PerSession,
+}
+
+impl OutputLayout {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Monolithic => "monolithic",
+            Self::PerSession => "per-session",
+        }
+    }
+}
+
+impl std::str::FromStr for OutputLayout {
+    type Err = SerializerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "monolithic" => Ok(Self::Monolithic),
+            "per-session" => Ok(Self::PerSession),
+            other => Err(SerializerError::InvalidConfig(format!(
+                "unknown output layout '{}'",
+                other
+            ))),
+        }
+    }
+}
+
+/// Which sessions count as recorded on Windows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SessionPlatform {
+    /// Per session, from the first file path (`C:\...`, `\\server\...`).
+    #[default]
+    Detect,
+    Posix,
+    Windows,
+}
+
+impl SessionPlatform {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Detect => "detect",
+            Self::Posix => "posix",
+            Self::Windows => "windows",
+        }
+    }
+}
+
+impl std::str::FromStr for SessionPlatform {
+    type Err = SerializerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "detect" => Ok(Self::Detect),
+            "posix" => Ok(Self::Posix),
+            "windows" => Ok(Self::Windows),
+            other => Err(SerializerError::InvalidConfig(format!(
+                "unknown session platform '{}'",
+                other
+            ))),
+        }
+    }
+}
+
+/// Serialization of sessions recorded on Windows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WindowsSessionMode {
+    /// As recorded: Windows paths under the bash persona.
+    #[default]
+    Keep,
+    /// Rewrite Windows paths to POSIX form (`/c/...`), bash persona.
+    PosixPaths,
+    /// Keep Windows paths and use the PowerShell persona.
+    PowerShell,
+}
+
+impl WindowsSessionMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Keep => "keep",
+            Self::PosixPaths => "posix-paths",
+            Self::PowerShell => "powershell",
+        }
+    }
+}
+
+impl std::str::FromStr for WindowsSessionMode {
+    type Err = SerializerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keep" => Ok(Self::Keep),
+            "posix-paths" => Ok(Self::PosixPaths),
+            "powershell" => Ok(Self::PowerShell),
+            other => Err(SerializerError::InvalidConfig(format!(
+                "unknown windows session mode '{}'",
+                other
+            ))),
+        }
+    }
+}
+
+/// Whether the first non-empty `File` value of a session CSV is a Windows path.
+pub fn detect_windows_session(csv_path: &Path) -> Result<bool, SerializerError> {
+    let mut reader = csv::Reader::from_path(csv_path)?;
+    let Some(file_idx) = reader
+        .headers()?
+        .iter()
+        .position(|h| h.trim_start_matches('\u{feff}') == "File")
+    else {
+        return Ok(false);
+    };
+    let mut record = csv::StringRecord::new();
+    while reader.read_record(&mut record)? {
+        match record.get(file_idx).map(str::trim) {
+            Some(file) if !file.is_empty() => return Ok(is_windows_path(file)),
+            _ => {}
+        }
+    }
+    Ok(false)
+}
+
+/// A line of `index.jsonl`: where a conversation's records are and where it came from.
+#[derive(Debug, Serialize)]
+pub struct IndexEntry {
+    /// Session id, the file stem under `sessions/`.
+    pub session: String,
+    pub conversation_id: String,
+    pub source_path: String,
+    /// "train" or "validation".
+    pub split: &'static str,
+    /// Position of the conversation (chunk) within its session.
+    pub chunk_index: usize,
+    /// Line of the conversation's first record in the session file.
+    pub first_record: usize,
+    pub records: usize,
+    pub messages: usize,
+    pub tokens: usize,
+    /// Sequence numbers of the first and last source event of the conversation.
+    pub first_sequence: Option<i64>,
+    pub last_sequence: Option<i64>,
+    /// Why the conversation ended, with `task_boundaries`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub boundary: Option<TaskBoundary>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub tags: SessionTags,
+    /// Labels of `PipelineConfig::classifiers`.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: ConversationLabels,
+    /// Conversation budget of the session, with `auto_chunk_min_tokens`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chunk_budget: Option<usize>,
+}
+
+/// Stable id of a session, derived from its source path.
+pub fn session_id(source_path: &str) -> String {
+    format!("{:016x}", stable_hash(source_path.as_bytes()))
+}
+
+/// Source path of a session with every directory name (user, device, ...)
+/// replaced by a salted hash of it; the file name is kept.
+///
+/// The same directory name maps to the same id across sessions and runs with
+/// the same salt, so sessions can still be grouped by user or device.
+pub fn anonymize_source_path(csv_path: &Path, salt: &str) -> String {
+    let names: Vec<_> = csv_path
+        .components()
+        .filter_map(|c| match c {
+            Component::Normal(name) => Some(name.to_string_lossy()),
+            _ => None,
+        })
+        .collect();
+    let Some((file_name, dirs)) = names.split_last() else {
+        return String::new();
+    };
+    dirs.iter()
+        .map(|dir| format!("{:016x}", stable_hash(format!("{}\0{}", salt, dir).as_bytes())))
+        .chain(std::iter::once(file_name.to_string()))
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Source path of a session as recorded in its outputs and ids: relative to
+/// `PipelineConfig::csv_root` with `/` separators, so the same data yields the
+/// same ids wherever it is stored, and anonymized with `identity_salt`.
+fn session_source_path(csv_path: &Path, config: &PipelineConfig) -> String {
+    let relative = config
+        .csv_root
+        .as_deref()
+        .and_then(|root| csv_path.strip_prefix(root).ok())
+        .unwrap_or(csv_path);
+    match &config.identity_salt {
+        Some(salt) => anonymize_source_path(relative, salt),
+        None if relative.has_root() => relative.to_string_lossy().into_owned(),
+        None => relative
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/"),
+    }
+}
+
+/// Stable id of a conversation: a hash of its session's source path, its
+/// chunk index within the session (before filtering) and its messages.
+///
+/// Rebuilding a dataset from the same sessions and serializer settings yields
+/// the same ids, so eval results and filter lists can reference samples.
+pub fn conversation_id(
+    source_path: &str,
+    chunk_index: usize,
+    messages: &[ConversationMessage],
+) -> String {
+    let mut content = Vec::new();
+    for message in messages {
+        content.extend_from_slice(message.from.as_bytes());
+        content.push(0);
+        content.extend_from_slice(message.value.as_bytes());
+        content.push(0);
+    }
+    let key = format!(
+        "{}\0{}\0{:016x}",
+        source_path,
+        chunk_index,
+        stable_hash(&content)
+    );
+    format!("{:016x}", stable_hash(key.as_bytes()))
 }
 
 /// Configuration for the pipeline.
 #[derive(Debug, Clone)]
 pub struct PipelineConfig {
     pub max_tokens_per_conversation: usize,
+    /// Choose each session's conversation budget between this and
+    /// `max_tokens_per_conversation` from a quick pass with approximate
+    /// token counts (see `auto_chunk_budget`). Ignored with deferred
+    /// tokenization.
+    pub auto_chunk_min_tokens: Option<usize>,
     pub max_tokens_per_message: usize,
     pub min_conversation_messages: usize,
     pub viewport_radius: usize,
+    /// Lines shown above/below the cursor line. None uses `viewport_radius`.
+    pub viewport_lines_above: Option<usize>,
+    pub viewport_lines_below: Option<usize>,
+    /// Re-emit the viewport once the cursor is this close to its edge.
+    pub viewport_margin: usize,
+    /// Recenter the viewport on every k-th cursor movement.
+    pub viewport_recenter_every: Option<usize>,
+    /// Cursor movements required between viewport re-emissions of a file.
+    pub viewport_min_movements: usize,
+    /// Lines a viewport must move by to be re-emitted.
+    pub viewport_min_shift: usize,
     pub coalesce_radius: usize,
     pub val_ratio: f64,
+    /// Count tokens after serialization instead of inside the state manager.
+    /// Disables chunking, so `max_tokens_per_conversation` is ignored.
+    pub deferred_tokenization: bool,
+    /// Number of messages per parallel batch in deferred tokenization.
+    pub tokenization_batch_size: usize,
+    /// Stop processing a session after this long and report it as timed out.
+    pub session_timeout_secs: Option<u64>,
+    /// Noise and rounding for all exported timing values.
+    pub timing_blur: Option<TimingBlur>,
+    /// Handling of content events for files without a prior tab snapshot.
+    pub untracked_file_policy: UntrackedFilePolicy,
+    /// Drop content events after an offset drift until the next tab snapshot.
+    pub resync_on_offset_drift: bool,
+    /// Fraction of conversations to check with replay verification (0.0 disables).
+    pub verify_fraction: f64,
+    /// Check structural invariants of produced conversations and report or
+    /// drop violations.
+    pub structure_validation: StructureValidation,
+    /// Stable-sort rows by their `Sequence` column before processing.
+    /// Some exporters write rows out of order when flushing buffers.
+    pub sort_by_sequence: bool,
+    /// Drop rows that exactly duplicate the preceding row (recorder retries).
+    pub drop_duplicate_rows: bool,
+    /// Event types skipped entirely, for ablation datasets. `selection` and
+    /// `terminal` also name every `selection_*` or `terminal_*` type.
+    pub ignored_event_types: Vec<String>,
+    /// Gaps between events longer than this (in milliseconds) count as idle
+    /// rather than active time.
+    pub idle_gap_ms: u64,
+    /// Record layout written by `write_jsonl_output`.
+    pub output_format: OutputFormat,
+    /// File layout written by `write_jsonl_output`.
+    pub output_layout: OutputLayout,
+    /// Token budget for the context of each prompt/completion pair.
+    pub prompt_context_tokens: usize,
+    /// Roles excluded from the loss, written as the record's `mask`.
+    pub masked_roles: Vec<String>,
+    /// Mask file captures that only repeat content shown in an earlier chunk.
+    pub mask_recaptures: bool,
+    /// Shorten re-captures of files unchanged since an earlier chunk to
+    /// their first this-many lines.
+    pub max_recapture_lines: Option<usize>,
+    /// Root rewrites applied to paths, commands and terminal output.
+    pub path_mappings: Vec<PathMapping>,
+    /// Unification of different spellings of one file path.
+    pub path_canonicalization: PathCanonicalization,
+    /// Companion files shown after edits of their primary file.
+    pub companion_files: Vec<CompanionPattern>,
+    /// Files whose editor events are skipped.
+    pub ignored_paths: Vec<IgnorePattern>,
+    /// Which sessions were recorded on Windows.
+    pub session_platform: SessionPlatform,
+    /// How sessions recorded on Windows are serialized.
+    pub windows_sessions: WindowsSessionMode,
+    /// Handling of edits that only change whitespace.
+    pub whitespace_edit_policy: WhitespaceEditPolicy,
+    /// Handling of terminal commands spanning several lines.
+    pub multiline_commands: MultilineCommandPolicy,
+    /// Merge consecutive terminal commands run within this many milliseconds
+    /// with nothing shown in between. None disables merging.
+    pub merge_commands_window_ms: Option<u64>,
+    /// Attach output arriving after the next command back to the command it
+    /// belongs to, by timestamps and shell-integration markers.
+    pub reassociate_late_output: bool,
+    /// Assistant message appended at `session_end` events.
+    pub end_of_task_marker: Option<String>,
+    /// Record file hashes every this many edits, for audits.
+    pub checkpoint_every_edits: Option<usize>,
+    /// Also split conversations at heuristic task boundaries.
+    pub task_boundaries: Option<TaskBoundaryConfig>,
+    /// Show files with more lines than this only as viewport slices.
+    pub large_file_line_threshold: Option<usize>,
+    /// Forget the least recently used files beyond this many tracked files.
+    pub max_tracked_files: Option<usize>,
+    /// Flush terminal output buffers at this size (bytes) or age (ms).
+    pub max_terminal_buffer_bytes: Option<usize>,
+    pub max_terminal_buffer_age_ms: Option<u64>,
+    /// Drop redundant captures, superseded viewport reads and empty outputs
+    /// from finalized conversations.
+    pub compact_conversations: bool,
+    /// Show multi-line selections as viewport reads of the selected lines.
+    pub emit_selection_ranges: bool,
+    /// Show multi-line selections as viewports centered on them.
+    pub center_selections: bool,
+    /// Flag selected lines in viewports shown for multi-line selections.
+    pub annotate_selections: bool,
+    /// Serialize stale tab snapshots of already shown files as edits.
+    pub diff_stale_snapshots: bool,
+    /// Skip repeated views of the focused file between terminal interludes.
+    pub skip_refocus_views: bool,
+    /// Flush other files' pending edits when the edited file changes.
+    pub flush_on_cross_file_edit: bool,
+    /// Don't serialize file changes written by the last terminal command again.
+    pub dedupe_command_writes: bool,
+    /// Read the saved viewport again after a save flushed an edit.
+    pub verify_on_save: bool,
+    /// Commands showing files, chosen per view by seeded weights.
+    pub view_commands: ViewCommandMix,
+    /// Fraction of conversations to keep, chosen by seeded hash (1.0 keeps all).
+    pub sample_rate: f64,
+    /// Seed of the sampling hash.
+    pub sample_seed: u64,
+    /// Cap on the number of conversations in the dataset.
+    pub max_conversations: Option<usize>,
+    /// Cap on the total tokens of the dataset.
+    pub max_total_tokens: Option<usize>,
+    /// Filters every finalized conversation must pass.
+    pub filters: FilterChain,
+    /// Classifiers labelling the conversations kept by `filters`.
+    pub classifiers: ClassifierChain,
+    /// Session tags by path suffix of the CSV or its directory (see
+    /// `load_session_manifest`); sidecar files override them.
+    pub session_manifest: BTreeMap<String, SessionTags>,
+    /// Process CSV fragments of one session split by recorder restarts as
+    /// one session (see `stitch_sessions`).
+    pub stitch: Option<StitchConfig>,
+    /// Add each conversation's `ConversationMetrics` to its output records.
+    pub emit_metrics: bool,
+    /// Record keystroke and pause timing of edit and command messages and
+    /// add it to the output records.
+    pub emit_timing: bool,
+    /// Write each message's `kind` (see `MessageKind`).
+    pub emit_message_kinds: bool,
+    /// Write the `loss_spans` of assistant messages.
+    pub emit_loss_spans: bool,
+    /// Pack short conversations into records up to a token target.
+    /// Requires the conversation format and monolithic layout.
+    pub packing: Option<PackingConfig>,
+    /// Write each session's normalized event stream (after sorting and
+    /// deduplication, before serialization) to `<dir>/<session id>.jsonl`.
+    pub dump_events_dir: Option<PathBuf>,
+    /// Extra seeded variants of every session's conversations.
+    pub augmentation: Option<AugmentationConfig>,
+    /// Replace directory names in source paths (and the ids derived from
+    /// them) with salted hashes; see `anonymize_source_path`.
+    pub identity_salt: Option<String>,
+    /// Directory the sessions were discovered under. Source paths and ids
+    /// are relative to it; `Pipeline::run` sets it to its `csv_root`.
+    pub csv_root: Option<PathBuf>,
+    /// Counts per-row warnings and keeps capped samples of them, which
+    /// `WarningAggregator::new` also prints.
+    pub warnings: Arc<WarningAggregator>,
+    /// Replace user messages of at least this many bytes with references
+    /// into a content-addressed store in the output directory (see
+    /// `BlobStore`). Requires the conversation format without packing.
+    pub blob_min_bytes: Option<usize>,
 }
 
 impl Default for PipelineConfig {
     fn default() -> Self {
         Self {
             max_tokens_per_conversation: 8192,
+            auto_chunk_min_tokens: None,
             max_tokens_per_message: 2048,
             min_conversation_messages: 5,
             viewport_radius: 10,
+            viewport_lines_above: None,
+            viewport_lines_below: None,
+            viewport_margin: 0,
+            viewport_recenter_every: None,
+            viewport_min_movements: 0,
+            viewport_min_shift: 0,
             coalesce_radius: 5,
             val_ratio: 0.1,
+            deferred_tokenization: false,
+            session_timeout_secs: None,
+            timing_blur: None,
+            tokenization_batch_size: 256,
+            untracked_file_policy: UntrackedFilePolicy::default(),
+            resync_on_offset_drift: false,
+            verify_fraction: 0.0,
+            structure_validation: StructureValidation::default(),
+            sort_by_sequence: true,
+            drop_duplicate_rows: false,
+            ignored_event_types: Vec::new(),
+            idle_gap_ms: 5 * 60 * 1000,
+            output_format: OutputFormat::default(),
+            output_layout: OutputLayout::default(),
+            prompt_context_tokens: 4096,
+            masked_roles: vec!["User".to_string()],
+            mask_recaptures: false,
+            max_recapture_lines: None,
+            path_mappings: Vec::new(),
+            path_canonicalization: PathCanonicalization::default(),
+            companion_files: Vec::new(),
+            ignored_paths: Vec::new(),
+            whitespace_edit_policy: WhitespaceEditPolicy::default(),
+            multiline_commands: MultilineCommandPolicy::default(),
+            merge_commands_window_ms: None,
+            reassociate_late_output: false,
+            end_of_task_marker: None,
+            checkpoint_every_edits: None,
+            task_boundaries: None,
+            large_file_line_threshold: None,
+            max_tracked_files: None,
+            max_terminal_buffer_bytes: None,
+            max_terminal_buffer_age_ms: None,
+            compact_conversations: false,
+            emit_selection_ranges: false,
+            center_selections: false,
+            annotate_selections: false,
+            diff_stale_snapshots: false,
+            skip_refocus_views: false,
+            flush_on_cross_file_edit: false,
+            dedupe_command_writes: false,
+            verify_on_save: false,
+            view_commands: ViewCommandMix::default(),
+            sample_rate: 1.0,
+            sample_seed: 0,
+            max_conversations: None,
+            max_total_tokens: None,
+            filters: FilterChain::default(),
+            classifiers: ClassifierChain::default(),
+            dump_events_dir: None,
+            augmentation: None,
+            identity_salt: None,
+            csv_root: None,
+            warnings: Arc::new(WarningAggregator::default()),
+            blob_min_bytes: None,
+            emit_metrics: false,
+            emit_timing: false,
+            emit_message_kinds: false,
+            emit_loss_spans: false,
+            session_platform: SessionPlatform::default(),
+            windows_sessions: WindowsSessionMode::default(),
+            packing: None,
+            session_manifest: BTreeMap::new(),
+            stitch: None,
         }
     }
 }
 
+/// Per-session ingestion statistics.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SessionStats {
+    /// Recorded on Windows (see `SessionPlatform`).
+    pub windows_session: bool,
+    /// CSV files the session was stitched from.
+    pub fragments: usize,
+    /// Rows whose sequence number was lower than an earlier row's.
+    pub reordered_rows: usize,
+    /// Exact duplicate rows that were dropped.
+    pub duplicate_rows: usize,
+    /// Rows skipped because their event type is ignored.
+    pub ignored_events: usize,
+    /// Number of processed rows per event type.
+    pub event_counts: BTreeMap<String, usize>,
+    /// Earliest and latest parseable `Time` value, in milliseconds.
+    pub start_time_ms: Option<i64>,
+    pub end_time_ms: Option<i64>,
+    /// Sum of gaps between consecutive events no longer than the idle threshold.
+    pub active_time_ms: u64,
+    /// Terminal commands per normalized template (see `stats::command_template`).
+    pub command_counts: BTreeMap<String, usize>,
+    /// Conversations and events dropped by the state manager.
+    pub drops: DropStats,
+    /// Structural invariant violations by kind, with structure validation.
+    pub structure_violations: BTreeMap<String, usize>,
+    /// Conversation budget chosen with `auto_chunk_min_tokens`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chunk_budget: Option<usize>,
+    #[serde(skip)]
+    last_time_ms: Option<i64>,
+}
+
+impl SessionStats {
+    /// Record an event timestamp. Backwards jumps are not counted as activity.
+    fn record_time(&mut self, time_ms: i64, idle_gap_ms: u64) {
+        if let Some(last) = self.last_time_ms {
+            if let Ok(gap) = u64::try_from(time_ms - last) {
+                if gap <= idle_gap_ms {
+                    self.active_time_ms += gap;
+                }
+            }
+        }
+        self.last_time_ms = Some(time_ms);
+        self.start_time_ms = Some(self.start_time_ms.map_or(time_ms, |t| t.min(time_ms)));
+        self.end_time_ms = Some(self.end_time_ms.map_or(time_ms, |t| t.max(time_ms)));
+    }
+
+    /// Wall-clock time between the first and last event.
+    pub fn duration_ms(&self) -> Option<u64> {
+        Some((self.end_time_ms? - self.start_time_ms?) as u64)
+    }
+
+    /// Processed events per wall-clock minute.
+    pub fn events_per_minute(&self) -> Option<f64> {
+        let duration = self.duration_ms().filter(|&d| d > 0)?;
+        let events: usize = self.event_counts.values().sum();
+        Some(events as f64 * 60_000.0 / duration as f64)
+    }
+}
+
 /// Result of processing a single session.
 #[derive(Debug)]
 pub struct SessionResult {
     pub conversations: Vec<FinalizedConversation>,
+    /// See `session_source_path`.
     pub source_path: String,
+    /// Consent, license and similar tags of the session.
+    pub tags: SessionTags,
+    pub stats: SessionStats,
 }
 
 /// Result of processing all sessions.
@@ -68,21 +714,294 @@ pub struct PipelineResult {
     pub val_conversations: usize,
     pub total_messages: usize,
     pub total_tokens: usize,
+    pub truncated_messages: usize,
+    pub reordered_rows: usize,
+    pub duplicate_rows: usize,
+    pub ignored_events: usize,
+    /// JSONL lines written (conversations or prompt/completion pairs).
+    pub total_records: usize,
+    /// Packing statistics, with `PipelineConfig::packing`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub packing: Option<PackingStats>,
+    /// Blob store statistics, with `PipelineConfig::blob_min_bytes`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub blobs: Option<BlobStats>,
+}
+
+/// Schema and serializer version an output record was written with.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RecordVersion {
+    /// 0 for records written before versioning.
+    pub schema_version: u32,
+    /// Crate version, with the build commit if known (`0.1.0+abc123def456`).
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub serializer_version: String,
+}
+
+impl RecordVersion {
+    /// Version of records written by this build.
+    pub fn current() -> Self {
+        let serializer_version = match SERIALIZER_COMMIT {
+            Some(commit) => format!("{}+{}", SERIALIZER_VERSION, &commit[..commit.len().min(12)]),
+            None => SERIALIZER_VERSION.to_string(),
+        };
+        Self {
+            schema_version: SCHEMA_VERSION,
+            serializer_version,
+        }
+    }
+
+    /// Why a record of this version cannot be read by this build or mixed
+    /// with records of `first`, if so.
+    pub fn incompatibility(&self, first: &RecordVersion) -> Option<String> {
+        if self.schema_version > SCHEMA_VERSION {
+            return Some(format!(
+                "schema version {} is newer than this build reads ({})",
+                self.schema_version, SCHEMA_VERSION
+            ));
+        }
+        (self != first).then(|| format!("written by {}, but earlier records by {}", self, first))
+    }
+}
+
+impl std::fmt::Display for RecordVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (self.schema_version, self.serializer_version.as_str()) {
+            (0, _) => write!(f, "an unversioned serializer"),
+            (schema, "") => write!(f, "schema {}", schema),
+            (schema, version) => write!(f, "serializer {} (schema {})", version, schema),
+        }
+    }
 }
 
 /// NeMo conversation record format.
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct NemoRecord {
+    /// Conversation id; for packed records, a hash of the packed ids.
+    /// Empty in datasets written before ids were added.
+    #[serde(default)]
+    pub id: String,
+    #[serde(flatten)]
+    pub version: RecordVersion,
     pub mask: String,
     pub system: String,
     pub conversations: Vec<NemoMessage>,
+    /// Serialization metrics of the conversation, with `emit_metrics`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metrics: Option<ConversationMetrics>,
+    /// Conversations packed into this record, with `PipelineConfig::packing`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub packing: Option<PackingInfo>,
+    /// Session tags; for packed records, those shared by every source.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub tags: SessionTags,
+    /// How an augmentation variant was produced.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub augmentation: Option<AugmentationInfo>,
+    /// Timing of messages, with `emit_timing`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub timing: Vec<MessageTimingEntry>,
+    /// File hashes, with `checkpoint_every_edits`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub checkpoints: Vec<FileCheckpoint>,
+    /// Why the conversation ended, with `task_boundaries`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub boundary: Option<TaskBoundary>,
+    /// Labels of `PipelineConfig::classifiers`; packed records carry them
+    /// per source.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: ConversationLabels,
+}
+
+impl NemoRecord {
+    /// The record's messages, with their mask overrides and timing.
+    pub fn messages(&self) -> Vec<ConversationMessage> {
+        self.conversations
+            .iter()
+            .enumerate()
+            .map(|(i, m)| ConversationMessage {
+                from: m.from.clone(),
+                value: m.value.clone(),
+                mask: m.mask,
+                timing: self.timing.iter().find(|t| t.message == i).map(|t| t.timing),
+                kind: m.kind,
+                loss_span: None,
+            })
+            .collect()
+    }
+}
+
+/// Read a JSONL dataset in the conversation format, e.g. one written by an
+/// earlier version. Blank lines are skipped.
+///
+/// Fails on records of a newer schema and on datasets mixing records of
+/// different serializer versions.
+pub fn read_jsonl_input(path: &Path) -> Result<Vec<NemoRecord>, SerializerError> {
+    let content = std::fs::read_to_string(path)?;
+    let mut records: Vec<NemoRecord> = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record: NemoRecord = serde_json::from_str(line).map_err(|source| SerializerError::InvalidRecord {
+            path: path.to_path_buf(),
+            line: i + 1,
+            source,
+        })?;
+        let first = records.first().map_or(&record.version, |r| &r.version);
+        if let Some(message) = record.version.incompatibility(first) {
+            return Err(SerializerError::IncompatibleRecord {
+                path: path.to_path_buf(),
+                line: i + 1,
+                message,
+            });
+        }
+        records.push(record);
+    }
+    Ok(records)
+}
+
+/// Timing of the message at index `message` of a record.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessageTimingEntry {
+    pub message: usize,
+    #[serde(flatten)]
+    pub timing: MessageTiming,
+}
+
+/// Timing entries of `messages`, numbered from `first_index`.
+fn timing_entries(messages: &[ConversationMessage], first_index: usize) -> Vec<MessageTimingEntry> {
+    messages
+        .iter()
+        .enumerate()
+        .filter_map(|(i, m)| {
+            Some(MessageTimingEntry {
+                message: first_index + i,
+                timing: m.timing?,
+            })
+        })
+        .collect()
 }
 
 /// A message in NeMo format.
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct NemoMessage {
     pub from: String,
     pub value: String,
+    /// Present only where the message overrides its role's mask.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mask: Option<bool>,
+    /// What the message shows, with `emit_message_kinds`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kind: Option<MessageKind>,
+    /// Character ranges of the decision-relevant parts of an assistant
+    /// message, with `emit_loss_spans` (see `loss_spans`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub loss_spans: Option<Vec<(usize, usize)>>,
+}
+
+impl NemoMessage {
+    /// Convert a message, keeping its mask override only if it differs from
+    /// the role policy.
+    pub fn from_message(message: &ConversationMessage, masked_roles: &[String]) -> Self {
+        let role_masked = masked_roles.contains(&message.from);
+        Self {
+            from: message.from.clone(),
+            value: message.value.clone(),
+            mask: message.mask.filter(|&mask| mask != role_masked),
+            kind: None,
+            loss_spans: None,
+        }
+    }
+
+    /// Add the message's kind and loss spans, as far as `config` asks for them.
+    fn annotate(mut self, message: &ConversationMessage, config: &PipelineConfig) -> Self {
+        if config.emit_message_kinds {
+            self.kind = message.kind;
+        }
+        if config.emit_loss_spans && message.from == "Assistant" {
+            self.loss_spans = Some(loss_spans(message));
+        }
+        self
+    }
+}
+
+/// Next-action prediction record: context messages and the assistant reply.
+#[derive(Debug, Serialize)]
+pub struct PromptCompletionRecord {
+    /// Id of the source conversation.
+    pub conversation_id: String,
+    #[serde(flatten)]
+    pub version: RecordVersion,
+    pub system: String,
+    pub prompt: Vec<NemoMessage>,
+    pub completion: String,
+    /// Serialization metrics of the source conversation, with `emit_metrics`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metrics: Option<ConversationMetrics>,
+    /// Tags of the source session.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub tags: SessionTags,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub augmentation: Option<AugmentationInfo>,
+    /// Timing of the completion, with `emit_timing`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub completion_timing: Option<MessageTiming>,
+    /// Labels of the source conversation.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: ConversationLabels,
+}
+
+/// Explode a conversation into one record per assistant message.
+///
+/// The prompt is the longest run of directly preceding messages that fits in
+/// `context_tokens`. Assistant messages without any fitting context, or masked
+/// via their override, are skipped.
+pub fn prompt_completion_records(
+    conversation: &FinalizedConversation,
+    system_prompt: &str,
+    context_tokens: usize,
+    masked_roles: &[String],
+) -> Vec<PromptCompletionRecord> {
+    let messages = &conversation.messages;
+    let token_counts = &conversation.message_token_counts;
+    let mut records = Vec::new();
+
+    for (target, message) in messages.iter().enumerate() {
+        if message.from != "Assistant" || message.mask == Some(true) {
+            continue;
+        }
+        let mut start = target;
+        let mut budget = context_tokens;
+        while start > 0 {
+            let tokens = token_counts.get(start - 1).copied().unwrap_or(0);
+            if tokens > budget {
+                break;
+            }
+            budget -= tokens;
+            start -= 1;
+        }
+        if start == target {
+            continue;
+        }
+        records.push(PromptCompletionRecord {
+            conversation_id: conversation.id.clone(),
+            version: RecordVersion::current(),
+            system: system_prompt.to_string(),
+            prompt: messages[start..target]
+                .iter()
+                .map(|m| NemoMessage::from_message(m, masked_roles))
+                .collect(),
+            completion: message.value.clone(),
+            metrics: None,
+            tags: SessionTags::new(),
+            augmentation: None,
+            completion_timing: message.timing,
+            labels: ConversationLabels::new(),
+        });
+    }
+    records
 }
 
 /// Discover all CSV files in a directory.
@@ -90,7 +1009,7 @@ pub fn discover_csv_files(root: &Path) -> Vec<std::path::PathBuf> {
     let mut paths: Vec<std::path::PathBuf> = WalkDir::new(root)
         .into_iter()
         .filter_map(|e| e.ok())
-        .filter(|e| e.path().extension().map_or(false, |ext| ext == "csv"))
+        .filter(|e| e.path().extension().is_some_and(|ext| ext == "csv"))
         .map(|e| e.path().to_path_buf())
         .collect();
     paths.sort();
@@ -98,204 +1017,1024 @@ pub fn discover_csv_files(root: &Path) -> Vec<std::path::PathBuf> {
 }
 
 /// Process a single CSV session file.
+///
+/// With `config.deferred_tokenization` set, the returned conversations have a
+/// token count of zero; run `tokenize_conversations` on them afterwards.
 pub fn process_session<T>(
     csv_path: &Path,
     tokenizer: &T,
     config: &PipelineConfig,
-) -> Result<Vec<FinalizedConversation>, Box<dyn std::error::Error>>
+) -> Result<SessionResult, SerializerError>
+where
+    T: Tokenizer,
+{
+    process_stitched_session(&StitchedSession::single(csv_path), tokenizer, config)
+}
+
+/// Process the CSV fragments of a session in order, as one session
+/// identified by the first fragment.
+pub fn process_stitched_session<T>(
+    session: &StitchedSession,
+    tokenizer: &T,
+    config: &PipelineConfig,
+) -> Result<SessionResult, SerializerError>
+where
+    T: Tokenizer,
+{
+    process_stitched_session_until(session, tokenizer, config, &SessionInterrupt::default())
+}
+
+/// Conversation budget of a session with `auto_chunk_min_tokens`, from a
+/// quick unsplit pass with approximate token counts and none of the
+/// pipeline's reporting, filtering or labelling.
+fn auto_session_budget(
+    session: &StitchedSession,
+    config: &PipelineConfig,
+    min_tokens: usize,
+    interrupt: &SessionInterrupt,
+) -> Result<usize, SerializerError> {
+    let approx_config = PipelineConfig {
+        max_tokens_per_conversation: usize::MAX,
+        auto_chunk_min_tokens: None,
+        structure_validation: StructureValidation::Off,
+        filters: FilterChain::default(),
+        classifiers: ClassifierChain::default(),
+        timing_blur: None,
+        warnings: Arc::new(WarningAggregator::default()),
+        ..config.clone()
+    };
+    let approx = process_stitched_session_until(session, &CharApproxTokenizer, &approx_config, interrupt)?;
+    let tokens: Vec<Vec<usize>> = approx.conversations.into_iter().map(|c| c.message_token_counts).collect();
+    Ok(auto_chunk_budget(
+        &tokens,
+        min_tokens,
+        config.max_tokens_per_conversation,
+        config.max_tokens_per_message,
+    ))
+}
+
+/// `process_stitched_session`, stopping with `Cancelled` or `TimedOut` when
+/// `interrupt` fires.
+pub fn process_stitched_session_until<T>(
+    session: &StitchedSession,
+    tokenizer: &T,
+    config: &PipelineConfig,
+    interrupt: &SessionInterrupt,
+) -> Result<SessionResult, SerializerError>
 where
     T: Tokenizer,
 {
+    if let Some(min_tokens) = config.auto_chunk_min_tokens.filter(|_| !config.deferred_tokenization) {
+        let budget = auto_session_budget(session, config, min_tokens, interrupt)?;
+        let exact_config = PipelineConfig {
+            max_tokens_per_conversation: budget,
+            auto_chunk_min_tokens: None,
+            ..config.clone()
+        };
+        let mut result = process_stitched_session_until(session, tokenizer, &exact_config, interrupt)?;
+        result.stats.chunk_budget = Some(budget);
+        return Ok(result);
+    }
+
+    let csv_path = session.first();
+    let windows_session = match config.session_platform {
+        SessionPlatform::Detect => detect_windows_session(csv_path)?,
+        SessionPlatform::Posix => false,
+        SessionPlatform::Windows => true,
+    };
+    let windows_mode = if windows_session {
+        config.windows_sessions
+    } else {
+        WindowsSessionMode::Keep
+    };
+
     let manager_config = ConversationStateManagerConfig {
         viewport_radius: config.viewport_radius,
+        viewport_lines_above: config.viewport_lines_above,
+        viewport_lines_below: config.viewport_lines_below,
+        viewport_margin: config.viewport_margin,
+        viewport_recenter_every: config.viewport_recenter_every,
+        viewport_min_movements: config.viewport_min_movements,
+        viewport_min_shift: config.viewport_min_shift,
         coalesce_radius: config.coalesce_radius,
         max_tokens_per_message: config.max_tokens_per_message,
         max_tokens_per_terminal_output: 256,
-        max_tokens_per_conversation: Some(config.max_tokens_per_conversation),
+        max_tokens_per_conversation: if config.deferred_tokenization {
+            None
+        } else {
+            Some(config.max_tokens_per_conversation)
+        },
         min_conversation_messages: config.min_conversation_messages,
+        defer_token_counting: config.deferred_tokenization,
+        untracked_file_policy: config.untracked_file_policy,
+        resync_on_offset_drift: config.resync_on_offset_drift,
+        mask_recaptures: config.mask_recaptures,
+        max_recapture_lines: config.max_recapture_lines,
+        path_mappings: config.path_mappings.clone(),
+        path_canonicalization: config.path_canonicalization.clone(),
+        companion_files: config.companion_files.clone(),
+        ignored_paths: config.ignored_paths.clone(),
+        whitespace_edit_policy: config.whitespace_edit_policy,
+        multiline_commands: config.multiline_commands,
+        merge_commands_window_ms: config.merge_commands_window_ms,
+        reassociate_late_output: config.reassociate_late_output,
+        end_of_task_marker: config.end_of_task_marker.clone(),
+        checkpoint_every_edits: config.checkpoint_every_edits,
+        task_boundaries: config.task_boundaries,
+        large_file_line_threshold: config.large_file_line_threshold,
+        max_tracked_files: config.max_tracked_files,
+        max_terminal_buffer_bytes: config.max_terminal_buffer_bytes,
+        max_terminal_buffer_age_ms: config.max_terminal_buffer_age_ms,
+        compact_conversations: config.compact_conversations,
+        emit_selection_ranges: config.emit_selection_ranges,
+        center_selections: config.center_selections,
+        annotate_selections: config.annotate_selections,
+        diff_stale_snapshots: config.diff_stale_snapshots,
+        skip_refocus_views: config.skip_refocus_views,
+        flush_on_cross_file_edit: config.flush_on_cross_file_edit,
+        dedupe_command_writes: config.dedupe_command_writes,
+        verify_on_save: config.verify_on_save,
+        view_commands: config.view_commands.clone(),
+        record_timing: config.emit_timing,
+        // Debouncing only saves work in the extension's event loop
+        content_debounce_ms: None,
+        warnings: config.warnings.clone(),
+        // Rows are already sorted (see `sort_by_sequence`)
+        reorder_window: None,
+        reorder_key: ReorderKey::Sequence,
+        normalize_windows_paths: windows_mode == WindowsSessionMode::PosixPaths,
     };
 
-    let mut manager = ConversationStateManager::new(tokenizer, manager_config);
+    let mut manager = if windows_mode == WindowsSessionMode::PowerShell {
+        ConversationStateManager::with_renderer(tokenizer, manager_config, Box::new(PowerShellRenderer))
+    } else {
+        ConversationStateManager::new(tokenizer, manager_config)
+    };
+    manager.set_interrupt(interrupt.clone());
 
-    let mut reader = csv::Reader::from_path(csv_path)?;
-    
-    for result in reader.deserialize() {
-        let row: CsvRow = result?;
-        
-        match row.event_type.as_str() {
-            "tab" => {
-                manager.handle_tab_event(&row.file, row.text.as_deref());
-            }
-            "content" => {
-                let offset = row.range_offset.expect("content event missing RangeOffset") as usize;
-                let length = row.range_length.expect("content event missing RangeLength") as usize;
-                let text = row.text.as_deref().unwrap_or("");
-                manager.handle_content_event(&row.file, offset, length, text);
-            }
-            "selection_command" | "selection_mouse" | "selection_keyboard" => {
-                let offset = row.range_offset.expect("selection event missing RangeOffset") as usize;
-                manager.handle_selection_event(&row.file, offset);
-            }
-            "terminal_command" => {
-                let command = row.text.as_deref().unwrap_or_else(|| {
-                    eprintln!("Warning: terminal_command event missing Text in {:?}", csv_path);
-                    ""
-                });
-                manager.handle_terminal_command_event(command);
+    let mut stats = SessionStats {
+        windows_session,
+        fragments: session.fragments.len(),
+        ..Default::default()
+    };
+    let source_path = session_source_path(csv_path, config);
+    let mut event_dump = match &config.dump_events_dir {
+        Some(dir) => {
+            std::fs::create_dir_all(dir)?;
+            let path = dir.join(format!("{}.jsonl", session_id(&source_path)));
+            Some(std::io::BufWriter::new(std::fs::File::create(path)?))
+        }
+        None => None,
+    };
+
+    for (fragment, &time_offset_ms) in session.fragments.iter().zip(&session.time_offsets_ms) {
+        let mut reader = csv::Reader::from_path(fragment)?;
+        // Windows exports may prefix the header row with a BOM
+        let headers: csv::StringRecord = reader
+            .headers()?
+            .iter()
+            .map(|h| h.trim_start_matches('\u{feff}'))
+            .collect();
+        if config.sort_by_sequence || config.drop_duplicate_rows {
+            // Reordering needs the whole session in memory
+            let mut records = reader.records().collect::<Result<Vec<_>, _>>()?;
+            let sequence_idx = headers.iter().position(|h| h == "Sequence");
+            normalize_record_order(&mut records, sequence_idx, config, &mut stats);
+            for record in &records {
+                interrupt.check()?;
+                let row: CsvRow = record.deserialize(Some(&headers))?;
+                if let Some(dump) = event_dump.as_mut() {
+                    writeln!(dump, "{}", serde_json::to_string(&DumpedEvent::from(&row))?)?;
+                }
+                dispatch_row(&mut manager, &row, time_offset_ms, fragment, config, &mut stats)?;
             }
-            "terminal_output" => {
-                let output = row.text.as_deref().unwrap_or_else(|| {
-                    eprintln!("Warning: terminal_output event missing Text in {:?}", csv_path);
-                    ""
-                });
-                manager.handle_terminal_output_event(output);
+        } else {
+            let mut record = csv::StringRecord::new();
+            while reader.read_record(&mut record)? {
+                interrupt.check()?;
+                let row: CsvRow = record.deserialize(Some(&headers))?;
+                if let Some(dump) = event_dump.as_mut() {
+                    writeln!(dump, "{}", serde_json::to_string(&DumpedEvent::from(&row))?)?;
+                }
+                dispatch_row(&mut manager, &row, time_offset_ms, fragment, config, &mut stats)?;
             }
-            "terminal_focus" => {
-                manager.handle_terminal_focus_event();
+        }
+    }
+    if let Some(mut dump) = event_dump {
+        dump.flush()?;
+    }
+
+    let tags = read_session_tags(csv_path, &config.session_manifest)?;
+    let mut conversations = manager.get_conversations()?;
+    stats.drops = manager.get_drop_stats();
+    for (chunk_index, conv) in conversations.iter_mut().enumerate() {
+        conv.id = conversation_id(&source_path, chunk_index, &conv.messages);
+    }
+
+    if config.structure_validation != StructureValidation::Off {
+        conversations.retain(|conv| {
+            let violations = validate_structure(&conv.messages);
+            for (_, violation) in &violations {
+                *stats.structure_violations.entry(violation.as_str().to_string()).or_default() += 1;
             }
-            "git_branch_checkout" => {
-                let branch_info = row.text.as_deref().unwrap_or_else(|| {
-                    eprintln!("Warning: git_branch_checkout event missing Text in {:?}", csv_path);
-                    ""
-                });
-                manager.handle_git_branch_checkout_event(branch_info);
+            if violations.is_empty() || config.structure_validation == StructureValidation::Report {
+                return true;
             }
-            other => {
-                eprintln!("Warning: Unknown event type '{}' in {:?}", other, csv_path);
+            *stats.drops.filtered_conversations.entry("invalid_structure".to_string()).or_default() += 1;
+            false
+        });
+    }
+
+    if !config.filters.is_empty() {
+        let mut chunk_index = 0;
+        conversations.retain(|conv| {
+            let ctx = SessionContext {
+                source_path: &source_path,
+                chunk_index,
+                stats: &stats,
+                tags: &tags,
+            };
+            chunk_index += 1;
+            match config.filters.apply(conv, &ctx) {
+                FilterDecision::Accept => true,
+                FilterDecision::Reject { reason } => {
+                    *stats.drops.filtered_conversations.entry(reason).or_default() += 1;
+                    false
+                }
             }
+        });
+    }
+
+    if !config.classifiers.is_empty() {
+        for (chunk_index, conv) in conversations.iter_mut().enumerate() {
+            let ctx = SessionContext {
+                source_path: &source_path,
+                chunk_index,
+                stats: &stats,
+                tags: &tags,
+            };
+            conv.labels = config.classifiers.classify(conv, &ctx);
         }
     }
 
-    Ok(manager.get_conversations())
+    for drift in manager.take_drift_diagnostics() {
+        config.warnings.warn(
+            "offset_drift",
+            format!(
+                "offset drift in {:?} at sequence {:?}: range {}+{} exceeds tracked length {} of {}",
+                csv_path, drift.sequence, drift.offset, drift.length, drift.tracked_length, drift.file_path
+            ),
+        );
+    }
+    if !stats.structure_violations.is_empty() {
+        let counts: Vec<String> = stats
+            .structure_violations
+            .iter()
+            .map(|(kind, count)| format!("{} {}", count, kind))
+            .collect();
+        config.warnings.warn(
+            "structure_violations",
+            format!("{:?} has structural violations: {}", csv_path, counts.join(", ")),
+        );
+    }
+    if stats.reordered_rows > 0 || stats.duplicate_rows > 0 {
+        config.warnings.warn(
+            "unsorted_rows",
+            format!(
+                "{:?} had {} out-of-order and {} duplicate rows",
+                csv_path, stats.reordered_rows, stats.duplicate_rows
+            ),
+        );
+    }
+
+    let mut session = SessionResult {
+        conversations,
+        source_path,
+        tags,
+        stats,
+    };
+    if let Some(blur) = &config.timing_blur {
+        blur.apply(&mut session);
+    }
+    Ok(session)
 }
 
-/// Process all CSV sessions in a directory in parallel.
-///
-/// Uses rayon for parallel processing. The tokenizer must be `Sync + Send`
-/// to be shared across threads.
-pub fn process_all_sessions<T>(
-    csv_root: &Path,
+/// Re-serialize a session once per augmentation variant and append the
+/// variants' conversations to `session`, so they land in the same split.
+/// Stops with `Cancelled` or `TimedOut` once `interrupt` fires.
+pub(crate) fn append_variants<T>(
+    stitched: &StitchedSession,
     tokenizer: &T,
     config: &PipelineConfig,
-) -> Result<Vec<SessionResult>, Box<dyn std::error::Error>>
+    augmentation: &AugmentationConfig,
+    session: &mut SessionResult,
+    interrupt: &SessionInterrupt,
+) -> Result<(), SerializerError>
 where
-    T: Tokenizer + Sync + Send,
+    T: Tokenizer,
 {
-    let csv_files = discover_csv_files(csv_root);
-
-    if csv_files.is_empty() {
-        return Err(format!("No CSV files found under {:?}", csv_root).into());
+    let root = if augmentation.rename_paths {
+        session_root(stitched.first())?
+    } else {
+        None
+    };
+    for variant in 1..=augmentation.variants {
+        let (info, mapping) = augmentation.plan(
+            &session.source_path,
+            root.as_deref(),
+            config.viewport_lines_above.unwrap_or(config.viewport_radius),
+            config.viewport_lines_below.unwrap_or(config.viewport_radius),
+            variant,
+        );
+        let mut variant_config = PipelineConfig {
+            viewport_lines_above: Some(info.viewport_lines_above),
+            viewport_lines_below: Some(info.viewport_lines_below),
+            dump_events_dir: None,
+            augmentation: None,
+            ..config.clone()
+        };
+        variant_config.path_mappings.splice(0..0, mapping);
+        let variant_session = process_stitched_session_until(stitched, tokenizer, &variant_config, interrupt)?;
+        session.conversations.extend(variant_session.conversations.into_iter().map(|mut conv| {
+            conv.id = format!(
+                "{:016x}",
+                stable_hash(format!("{}#variant{}", conv.id, variant).as_bytes())
+            );
+            conv.augmentation = Some(info.clone());
+            conv
+        }));
     }
+    Ok(())
+}
 
-    let total_files = csv_files.len();
-    let processed_count = AtomicUsize::new(0);
-    let error_count = AtomicUsize::new(0);
-
-    let results: Vec<SessionResult> = csv_files
-        .into_par_iter()
-        .filter_map(|csv_path| {
-            let result = process_session(&csv_path, tokenizer, config);
-            let count = processed_count.fetch_add(1, Ordering::Relaxed) + 1;
-
-            match result {
-                Ok(conversations) => {
-                    if count % 100 == 0 || count == total_files {
-                        eprintln!("Processed {}/{} sessions...", count, total_files);
+/// Sort records by sequence (stable) and/or drop adjacent exact duplicates.
+///
+/// Rows without a parseable sequence keep their position relative to the
+/// preceding row.
+fn normalize_record_order(
+    records: &mut Vec<csv::StringRecord>,
+    sequence_idx: Option<usize>,
+    config: &PipelineConfig,
+    stats: &mut SessionStats,
+) {
+    if let Some(idx) = sequence_idx {
+        let mut last_seq = i64::MIN;
+        let mut max_seq = i64::MIN;
+        let mut keyed: Vec<(i64, csv::StringRecord)> = records
+            .drain(..)
+            .map(|record| {
+                if let Some(seq) = record.get(idx).and_then(|s| s.trim().parse::<i64>().ok()) {
+                    if seq < max_seq {
+                        stats.reordered_rows += 1;
                     }
-                    Some(SessionResult {
-                        conversations,
-                        source_path: csv_path.to_string_lossy().to_string(),
-                    })
+                    max_seq = max_seq.max(seq);
+                    last_seq = seq;
                 }
-                Err(e) => {
-                    error_count.fetch_add(1, Ordering::Relaxed);
-                    eprintln!("Error processing {:?}: {}", csv_path, e);
-                    None
-                }
-            }
-        })
-        .collect();
-
-    let errors = error_count.load(Ordering::Relaxed);
-    if errors > 0 {
-        eprintln!("Warning: {} sessions failed to process", errors);
+                (last_seq, record)
+            })
+            .collect();
+        if config.sort_by_sequence {
+            keyed.sort_by_key(|(seq, _)| *seq);
+        }
+        records.extend(keyed.into_iter().map(|(_, record)| record));
     }
 
-    Ok(results)
+    if config.drop_duplicate_rows {
+        let before = records.len();
+        records.dedup_by(|a, b| a.iter().eq(b.iter()));
+        stats.duplicate_rows = before - records.len();
+    }
 }
 
-/// Write conversations to JSONL files (training and validation).
-pub fn write_jsonl_output(
-    session_results: Vec<SessionResult>,
-    output_dir: &Path,
-    val_ratio: f64,
-    system_prompt: &str,
-) -> Result<PipelineResult, Box<dyn std::error::Error>> {
-    use std::fs::File;
-    use std::io::{BufWriter, Write};
+/// Event types known to `dispatch_row`.
+pub const EVENT_TYPES: &[&str] = &[
+    "tab",
+    "content",
+    "undo",
+    "redo",
+    "selection_command",
+    "selection_mouse",
+    "selection_keyboard",
+    "terminal_command",
+    "terminal_output",
+    "terminal_focus",
+    "dir_open",
+    "vim_command",
+    "file_save",
+    "file_rename",
+    "git_branch_checkout",
+    "session_start",
+    "session_end",
+];
 
-    std::fs::create_dir_all(output_dir)?;
+/// Whether `event_type` is `name` or one of its `<name>_*` types.
+pub(crate) fn event_type_matches(name: &str, event_type: &str) -> bool {
+    event_type
+        .strip_prefix(name)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('_'))
+}
 
-    // Shuffle sessions for train/val split (using simple deterministic shuffle)
-    let mut sessions: Vec<_> = session_results.into_iter().enumerate().collect();
-    // Simple deterministic shuffle based on index
-    sessions.sort_by(|(i, a), (j, b)| {
-        let hash_a = (i * 2654435761) % 1000;
-        let hash_b = (j * 2654435761) % 1000;
-        hash_a.cmp(&hash_b).then_with(|| a.source_path.cmp(&b.source_path))
-    });
+/// Dispatch a single CSV row to the matching state manager handler.
+fn dispatch_row<T>(
+    manager: &mut ConversationStateManager<T>,
+    row: &CsvRow,
+    time_offset_ms: i64,
+    csv_path: &Path,
+    config: &PipelineConfig,
+    stats: &mut SessionStats,
+) -> Result<(), SerializerError>
+where
+    T: Tokenizer,
+{
+    manager.set_event_sequence(row.sequence);
+    let time_ms = row.time.and_then(parse_time_ms).map(|t| t + time_offset_ms);
+    manager.set_event_time(time_ms);
+    if let Some(time_ms) = time_ms {
+        stats.record_time(time_ms, config.idle_gap_ms);
+    }
+    match stats.event_counts.get_mut(row.event_type) {
+        Some(count) => *count += 1,
+        None => {
+            stats.event_counts.insert(row.event_type.to_string(), 1);
+        }
+    }
+    if config.ignored_event_types.iter().any(|name| event_type_matches(name, row.event_type)) {
+        stats.ignored_events += 1;
+        return Ok(());
+    }
+    if let Some(language) = row.language {
+        manager.set_file_language(row.file, language);
+    }
 
-    let total_sessions = sessions.len();
-    let val_count = (total_sessions as f64 * val_ratio).round() as usize;
-    let train_count = total_sessions - val_count;
+    let missing_text = || {
+        config.warnings.warn(
+            "missing_text",
+            format!("{} event missing Text in {:?}", row.event_type, csv_path),
+        );
+        ""
+    };
+    let listing: String;
+    let event = match row.event_type {
+        "tab" => IdeEvent::Tab {
+            file_path: row.file,
+            content: row.text,
+        },
+        "content" => IdeEvent::Content {
+            file_path: row.file,
+            offset: require_field(row.range_offset, row, "RangeOffset")? as usize,
+            length: require_field(row.range_length, row, "RangeLength")? as usize,
+            text: row.text.unwrap_or(""),
+        },
+        "undo" => IdeEvent::Undo {
+            file_path: row.file,
+            offset: require_field(row.range_offset, row, "RangeOffset")? as usize,
+            length: require_field(row.range_length, row, "RangeLength")? as usize,
+            text: row.text.unwrap_or(""),
+        },
+        "redo" => IdeEvent::Redo {
+            file_path: row.file,
+            offset: require_field(row.range_offset, row, "RangeOffset")? as usize,
+            length: require_field(row.range_length, row, "RangeLength")? as usize,
+            text: row.text.unwrap_or(""),
+        },
+        "selection_command" | "selection_mouse" | "selection_keyboard" if row.line.is_some() => {
+            let column = |column: Option<i64>| column.unwrap_or(0).max(0) as usize;
+            IdeEvent::SelectionLine {
+                file_path: row.file,
+                line: row.line.unwrap_or(1).max(1) as usize,
+                column: column(row.column),
+                end: row.end_line.map(|end_line| (end_line.max(1) as usize, column(row.end_column))),
+            }
+        }
+        "selection_command" | "selection_mouse" | "selection_keyboard" => {
+            let offset = require_field(row.range_offset, row, "RangeOffset")? as usize;
+            let end_offset = row
+                .range_length
+                .filter(|&length| length > 0)
+                .map(|length| offset.saturating_add(length as usize));
+            IdeEvent::Selection {
+                file_path: row.file,
+                offset,
+                end_offset,
+            }
+        }
+        "terminal_command" => {
+            let command = row.text.unwrap_or_else(|| missing_text());
+            let template = command_template(command);
+            if !template.is_empty() {
+                *stats.command_counts.entry(template).or_default() += 1;
+            }
+            IdeEvent::TerminalCommand { command }
+        }
+        "terminal_output" => {
+            let output = row.text.unwrap_or_else(|| missing_text());
+            IdeEvent::TerminalOutput { output }
+        }
+        "terminal_focus" => IdeEvent::TerminalFocus,
+        "dir_open" => {
+            // Entries are newline-separated in Text
+            listing = row.text.unwrap_or("").replace("\\n", "\n");
+            IdeEvent::DirectoryOpen {
+                dir_path: row.file,
+                entries: listing.lines().filter(|e| !e.is_empty()).collect(),
+            }
+        }
+        "vim_command" => IdeEvent::VimCommand {
+            file_path: row.file,
+            command: row.text.unwrap_or(""),
+            cursor_offset: row.range_offset.map(|offset| offset as usize),
+        },
+        "file_save" => IdeEvent::FileSave { file_path: row.file },
+        // The new path is in Text
+        "file_rename" => IdeEvent::FileRename {
+            old_path: row.file,
+            new_path: row.text.unwrap_or_else(|| missing_text()),
+        },
+        "git_branch_checkout" => {
+            let branch_info = row.text.unwrap_or_else(|| missing_text());
+            IdeEvent::GitBranchCheckout { branch_info }
+        }
+        "session_start" => IdeEvent::SessionStart,
+        "session_end" => IdeEvent::SessionEnd,
+        other => {
+            config
+                .warnings
+                .warn("unknown_event_type", format!("Unknown event type '{}' in {:?}", other, csv_path));
+            return Ok(());
+        }
+    };
+    manager.handle_event(event)
+}
 
-    let train_path = output_dir.join("training.jsonl");
-    let val_path = output_dir.join("validation.jsonl");
+/// Parse a `Time` value given in milliseconds since the recording started.
+pub(crate) fn parse_time_ms(time: &str) -> Option<i64> {
+    let time = time.trim();
+    time.parse::<i64>()
+        .ok()
+        .or_else(|| time.parse::<f64>().ok().filter(|t| t.is_finite()).map(|t| t as i64))
+}
 
-    let mut train_file = BufWriter::new(File::create(&train_path)?);
-    let mut val_file = BufWriter::new(File::create(&val_path)?);
+/// Unwrap a field required by the row's event type.
+fn require_field<V>(value: Option<V>, row: &CsvRow, field: &'static str) -> Result<V, SerializerError> {
+    value.ok_or_else(|| SerializerError::MissingField {
+        event_type: row.event_type.to_string(),
+        field,
+    })
+}
+
+/// Process all CSV sessions in a directory in parallel.
+///
+/// Uses rayon for parallel processing. The tokenizer must be `Sync + Send`
+/// to be shared across threads.
+pub fn process_all_sessions<T>(
+    csv_root: &Path,
+    tokenizer: &T,
+    config: &PipelineConfig,
+) -> Result<Vec<SessionResult>, SerializerError>
+where
+    T: Tokenizer + Sync + Send,
+{
+    let run = Pipeline::new(tokenizer)
+        .config(config.clone())
+        .on_progress(|progress| match progress {
+            PipelineProgress::Stitched { files, sessions } => {
+                eprintln!("Stitched {} CSV files into {} sessions", files, sessions)
+            }
+            PipelineProgress::Session { processed, total, .. } => {
+                if processed.is_multiple_of(100) || processed == total {
+                    eprintln!("Processed {}/{} sessions...", processed, total);
+                }
+            }
+            PipelineProgress::Tokenizing => eprintln!("Tokenizing finalized conversations..."),
+        })
+        .run(csv_root)?;
+    if run.failed > 0 {
+        eprintln!("Warning: {} sessions failed to process", run.failed);
+    }
+    if run.timed_out > 0 {
+        eprintln!("Warning: {} of them timed out", run.timed_out);
+    }
+    Ok(run.sessions)
+}
+
+/// Count tokens of finalized conversations in parallel batches.
+///
+/// Messages exceeding `max_tokens_per_message` are truncated, matching the
+/// inline behavior of the state manager. Existing token and truncation counts
+/// are overwritten.
+pub fn tokenize_conversations<'a, T, I>(
+    conversations: I,
+    tokenizer: &T,
+    max_tokens_per_message: usize,
+    batch_size: usize,
+) where
+    T: Tokenizer + Sync,
+    I: IntoIterator<Item = &'a mut FinalizedConversation>,
+{
+    tokenize_conversations_until(
+        conversations,
+        tokenizer,
+        max_tokens_per_message,
+        batch_size,
+        &SessionInterrupt::default(),
+    )
+    .expect("the default interrupt never fires");
+}
+
+/// `tokenize_conversations`, checking `interrupt` before each batch. Counts
+/// are left partial when it fires.
+pub fn tokenize_conversations_until<'a, T, I>(
+    conversations: I,
+    tokenizer: &T,
+    max_tokens_per_message: usize,
+    batch_size: usize,
+    interrupt: &SessionInterrupt,
+) -> Result<(), SerializerError>
+where
+    T: Tokenizer + Sync,
+    I: IntoIterator<Item = &'a mut FinalizedConversation>,
+{
+    let mut totals: Vec<(&mut usize, &mut Vec<usize>, &mut usize)> = Vec::new();
+    let mut messages: Vec<(usize, usize, &mut ConversationMessage)> = Vec::new();
+    for (idx, conv) in conversations.into_iter().enumerate() {
+        let FinalizedConversation {
+            messages: conv_messages,
+            token_count,
+            message_token_counts,
+            metrics,
+            ..
+        } = conv;
+        *token_count = 0;
+        metrics.truncated_messages = 0;
+        message_token_counts.clear();
+        message_token_counts.resize(conv_messages.len(), 0);
+        messages.extend(conv_messages.iter_mut().enumerate().map(|(i, m)| (idx, i, m)));
+        totals.push((token_count, message_token_counts, &mut metrics.truncated_messages));
+    }
+
+    let batches: Vec<Vec<(usize, usize, usize, bool)>> = messages
+        .par_chunks_mut(batch_size.max(1))
+        .map(|batch| {
+            interrupt.check()?;
+            Ok(batch
+                .iter_mut()
+                .map(|(idx, msg_idx, message)| {
+                    let tokens = tokenizer.count_tokens(&message.value);
+                    if tokens > max_tokens_per_message {
+                        message.value =
+                            truncate_with_marker(tokenizer, &message.value, max_tokens_per_message);
+                        (*idx, *msg_idx, max_tokens_per_message, true)
+                    } else {
+                        (*idx, *msg_idx, tokens, false)
+                    }
+                })
+                .collect())
+        })
+        .collect::<Result<_, SerializerError>>()?;
+
+    for (idx, msg_idx, tokens, truncated) in batches.into_iter().flatten() {
+        let (token_count, message_token_counts, truncated_messages) = &mut totals[idx];
+        **token_count += tokens;
+        message_token_counts[msg_idx] = tokens;
+        **truncated_messages += truncated as usize;
+    }
+    Ok(())
+}
+
+/// Subsample conversations and apply the dataset size caps of `config`.
+///
+/// Each conversation gets a seeded hash of its source path and chunk index.
+/// Those hashing below `sample_rate` are candidates; candidates are then
+/// accepted in hash order while the caps allow, so the caps cut every session
+/// proportionally rather than dropping the last sessions. Conversation order
+/// within a session is preserved; removals are counted in each session's drops.
+pub fn sample_sessions(
+    session_results: &mut [SessionResult],
+    config: &PipelineConfig,
+) -> Result<(), SerializerError> {
+    if !(config.sample_rate > 0.0 && config.sample_rate <= 1.0) {
+        return Err(SerializerError::InvalidConfig(format!(
+            "sample rate {} is not in (0, 1]",
+            config.sample_rate
+        )));
+    }
+    if config.sample_rate >= 1.0
+        && config.max_conversations.is_none()
+        && config.max_total_tokens.is_none()
+    {
+        return Ok(());
+    }
+
+    let mut candidates: Vec<(f64, usize, usize)> = Vec::new();
+    for (session_idx, session) in session_results.iter().enumerate() {
+        for conv_idx in 0..session.conversations.len() {
+            let key = format!("{}#{}#{}", config.sample_seed, session.source_path, conv_idx);
+            let rank = unit_interval_hash(&key);
+            if rank < config.sample_rate {
+                candidates.push((rank, session_idx, conv_idx));
+            }
+        }
+    }
+    candidates.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    let mut keep: Vec<Vec<bool>> = session_results
+        .iter()
+        .map(|s| vec![false; s.conversations.len()])
+        .collect();
+    let mut conversations = 0;
+    let mut tokens = 0;
+    for (_, session_idx, conv_idx) in candidates {
+        if config.max_conversations.is_some_and(|max| conversations >= max) {
+            break;
+        }
+        let conv_tokens = session_results[session_idx].conversations[conv_idx].token_count;
+        if config.max_total_tokens.is_some_and(|max| tokens + conv_tokens > max) {
+            continue;
+        }
+        keep[session_idx][conv_idx] = true;
+        conversations += 1;
+        tokens += conv_tokens;
+    }
+
+    for (session, keep) in session_results.iter_mut().zip(keep) {
+        let before = session.conversations.len();
+        let mut keep = keep.into_iter();
+        session.conversations.retain(|_| keep.next().unwrap_or(false));
+        session.stats.drops.sampled_out_conversations += before - session.conversations.len();
+    }
+    Ok(())
+}
+
+/// Replay-verify a deterministic sample of the produced conversations, of
+/// `PipelineConfig::verify_fraction`.
+///
+/// A conversation is sampled when the hash of its source path and chunk index
+/// falls below the fraction, so the same sample is chosen across runs.
+pub fn verify_sessions(session_results: &[SessionResult], config: &PipelineConfig) -> VerificationReport {
+    let fraction = config.verify_fraction;
+    let outcomes: Vec<_> = session_results
+        .par_iter()
+        .flat_map_iter(|session| {
+            session
+                .conversations
+                .iter()
+                .enumerate()
+                .filter(move |(idx, _)| {
+                    unit_interval_hash(&format!("{}#{}", session.source_path, idx)) < fraction
+                })
+                .map(|(_, conv)| replay_conversation(conv))
+        })
+        .collect();
+
+    let mut report = VerificationReport::default();
+    for outcome in outcomes {
+        report.record(outcome);
+    }
+    report
+}
+
+/// Write conversations to JSONL files: training and validation, or one file
+/// per session plus an index (see `OutputLayout`).
+pub fn write_jsonl_output(
+    session_results: Vec<SessionResult>,
+    output_dir: &Path,
+    config: &PipelineConfig,
+    system_prompt: &str,
+) -> Result<PipelineResult, SerializerError> {
+    use std::fs::File;
+    use std::io::BufWriter;
+
+    if config.packing.is_some()
+        && (config.output_format != OutputFormat::Conversation
+            || config.output_layout != OutputLayout::Monolithic)
+    {
+        return Err(SerializerError::InvalidConfig(
+            "packing requires the conversation format and monolithic layout".to_string(),
+        ));
+    }
+    if config.blob_min_bytes.is_some()
+        && (config.output_format != OutputFormat::Conversation || config.packing.is_some())
+    {
+        return Err(SerializerError::InvalidConfig(
+            "the blob store requires the conversation format without packing".to_string(),
+        ));
+    }
+    std::fs::create_dir_all(output_dir)?;
+
+    // Shuffle sessions for train/val split (using simple deterministic shuffle)
+    let mut sessions: Vec<_> = session_results.into_iter().enumerate().collect();
+    // Simple deterministic shuffle based on index
+    sessions.sort_by(|(i, a), (j, b)| {
+        let hash_a = (i * 2654435761) % 1000;
+        let hash_b = (j * 2654435761) % 1000;
+        hash_a.cmp(&hash_b).then_with(|| a.source_path.cmp(&b.source_path))
+    });
+
+    let total_sessions = sessions.len();
+    let val_count = (total_sessions as f64 * config.val_ratio).round() as usize;
+    let train_count = total_sessions - val_count;
+
+    let mut split_files = None;
+    let mut index_file = None;
+    let sessions_dir = output_dir.join("sessions");
+    match config.output_layout {
+        OutputLayout::Monolithic => {
+            split_files = Some((
+                BufWriter::new(File::create(output_dir.join("training.jsonl"))?),
+                BufWriter::new(File::create(output_dir.join("validation.jsonl"))?),
+            ));
+        }
+        OutputLayout::PerSession => {
+            std::fs::create_dir_all(&sessions_dir)?;
+            index_file = Some(BufWriter::new(File::create(output_dir.join("index.jsonl"))?));
+        }
+    }
 
     let mut train_conversations = 0;
     let mut val_conversations = 0;
     let mut total_messages = 0;
     let mut total_tokens = 0;
+    let mut truncated_messages = 0;
+    let mut reordered_rows = 0;
+    let mut duplicate_rows = 0;
+    let mut ignored_events = 0;
+    let mut total_records = 0;
+    // Conversations held back for packing, per split
+    let mut pack_train = Vec::new();
+    let mut pack_val = Vec::new();
+    let mut blob_store = config.blob_min_bytes.map(|min_bytes| BlobStore::new(output_dir, min_bytes));
 
     for (idx, (_, session)) in sessions.into_iter().enumerate() {
         let is_validation = idx >= train_count;
-        
-        for conv in session.conversations {
-            let nemo_messages: Vec<NemoMessage> = conv
-                .messages
-                .iter()
-                .map(|m| NemoMessage {
-                    from: m.from.clone(),
-                    value: m.value.clone(),
-                })
-                .collect();
+        reordered_rows += session.stats.reordered_rows;
+        duplicate_rows += session.stats.duplicate_rows;
+        ignored_events += session.stats.ignored_events;
 
-            let record = NemoRecord {
-                mask: "User".to_string(),
-                system: system_prompt.to_string(),
-                conversations: nemo_messages,
+        let session_id = session_id(&session.source_path);
+        let mut session_file = None;
+        if index_file.is_some() && !session.conversations.is_empty() {
+            let path = sessions_dir.join(format!("{}.jsonl", session_id));
+            session_file = Some(BufWriter::new(File::create(path)?));
+        }
+        let mut session_records = 0;
+
+        for (chunk_index, mut conv) in session.conversations.into_iter().enumerate() {
+            if let Some(store) = blob_store.as_mut() {
+                store.externalize(&mut conv.messages)?;
+            }
+            if config.packing.is_some() {
+                if is_validation {
+                    val_conversations += 1;
+                } else {
+                    train_conversations += 1;
+                }
+                total_messages += conv.messages.len();
+                total_tokens += conv.token_count;
+                truncated_messages += conv.metrics.truncated_messages;
+                let pending = if is_validation { &mut pack_val } else { &mut pack_train };
+                pending.push((session_id.clone(), chunk_index, session.tags.clone(), conv));
+                continue;
+            }
+
+            let conv_system_prompt = conv
+                .augmentation
+                .as_ref()
+                .and_then(|a| a.system_prompt.as_deref())
+                .unwrap_or(system_prompt);
+            let json_lines = match config.output_format {
+                OutputFormat::Conversation => {
+                    let nemo_messages: Vec<NemoMessage> = conv
+                        .messages
+                        .iter()
+                        .map(|m| {
+                            NemoMessage::from_message(m, &config.masked_roles)
+                                .annotate(m, config)
+                        })
+                        .collect();
+
+                    let record = NemoRecord {
+                        id: conv.id.clone(),
+                        version: RecordVersion::current(),
+                        mask: config.masked_roles.join(","),
+                        system: conv_system_prompt.to_string(),
+                        conversations: nemo_messages,
+                        metrics: config.emit_metrics.then(|| conv.metrics.clone()),
+                        packing: None,
+                        tags: session.tags.clone(),
+                        augmentation: conv.augmentation.clone(),
+                        timing: timing_entries(&conv.messages, 0),
+                        checkpoints: conv.checkpoints.clone(),
+                        boundary: conv.boundary,
+                        labels: conv.labels.clone(),
+                    };
+                    vec![serde_json::to_string(&record)?]
+                }
+                OutputFormat::PromptCompletion => {
+                    let mut records = prompt_completion_records(
+                        &conv,
+                        conv_system_prompt,
+                        config.prompt_context_tokens,
+                        &config.masked_roles,
+                    );
+                    for record in &mut records {
+                        if config.emit_metrics {
+                            record.metrics = Some(conv.metrics.clone());
+                        }
+                        record.tags = session.tags.clone();
+                        record.augmentation = conv.augmentation.clone();
+                        record.labels = conv.labels.clone();
+                    }
+                    records
+                        .iter()
+                        .map(serde_json::to_string)
+                        .collect::<Result<Vec<_>, _>>()?
+                }
             };
 
-            let json_line = serde_json::to_string(&record)?;
-            
+            let out = match session_file.as_mut() {
+                Some(file) => file,
+                None => {
+                    let (train_file, val_file) =
+                        split_files.as_mut().expect("monolithic layout writes split files");
+                    if is_validation { val_file } else { train_file }
+                }
+            };
+            for json_line in &json_lines {
+                writeln!(out, "{}", json_line)?;
+            }
+            if let Some(index) = index_file.as_mut() {
+                let entry = IndexEntry {
+                    session: session_id.clone(),
+                    conversation_id: conv.id.clone(),
+                    source_path: session.source_path.clone(),
+                    split: if is_validation { "validation" } else { "train" },
+                    chunk_index,
+                    first_record: session_records,
+                    records: json_lines.len(),
+                    messages: conv.messages.len(),
+                    tokens: conv.token_count,
+                    first_sequence: conv.first_sequence,
+                    last_sequence: conv.last_sequence,
+                    boundary: conv.boundary,
+                    tags: session.tags.clone(),
+                    labels: conv.labels.clone(),
+                    chunk_budget: session.stats.chunk_budget,
+                };
+                writeln!(index, "{}", serde_json::to_string(&entry)?)?;
+            }
+            session_records += json_lines.len();
+            total_records += json_lines.len();
             if is_validation {
-                writeln!(val_file, "{}", json_line)?;
                 val_conversations += 1;
             } else {
-                writeln!(train_file, "{}", json_line)?;
                 train_conversations += 1;
             }
 
             total_messages += conv.messages.len();
             total_tokens += conv.token_count;
+            truncated_messages += conv.metrics.truncated_messages;
+        }
+        if let Some(mut file) = session_file {
+            file.flush()?;
+        }
+    }
+
+    let mut packing_stats = None;
+    if let Some(packing) = &config.packing {
+        let (train_file, val_file) =
+            split_files.as_mut().expect("packing writes split files");
+        let mut stats = PackingStats {
+            target_tokens: packing.target_tokens,
+            ..Default::default()
+        };
+        let mut packed_tokens = 0;
+        for (pending, out) in [(pack_train, train_file), (pack_val, val_file)] {
+            for record in pack_records(pending, packing, config, system_prompt) {
+                let info = record.packing.as_ref().expect("packed records carry packing info");
+                stats.packed_records += 1;
+                stats.packed_conversations += info.sources.len();
+                if info.tokens > packing.target_tokens {
+                    stats.oversized_conversations += 1;
+                }
+                packed_tokens += info.tokens;
+                writeln!(out, "{}", serde_json::to_string(&record)?)?;
+            }
+        }
+        total_records = stats.packed_records;
+        if stats.packed_records > 0 && packing.target_tokens > 0 {
+            stats.fill_ratio =
+                packed_tokens as f64 / (stats.packed_records * packing.target_tokens) as f64;
         }
+        packing_stats = Some(stats);
     }
 
-    train_file.flush()?;
-    val_file.flush()?;
+    if let Some((mut train_file, mut val_file)) = split_files {
+        train_file.flush()?;
+        val_file.flush()?;
+    }
+    if let Some(mut index) = index_file {
+        index.flush()?;
+    }
 
     Ok(PipelineResult {
         total_sessions,
@@ -304,28 +2043,116 @@ pub fn write_jsonl_output(
         val_conversations,
         total_messages,
         total_tokens,
+        truncated_messages,
+        reordered_rows,
+        duplicate_rows,
+        ignored_events,
+        total_records,
+        packing: packing_stats,
+        blobs: blob_store.map(|store| store.stats().clone()),
     })
 }
 
+/// Pack conversations of one split into records, first-fit decreasing by
+/// token count. Later conversations in a record start with the boundary text.
+fn pack_records(
+    conversations: Vec<(String, usize, SessionTags, FinalizedConversation)>,
+    packing: &PackingConfig,
+    config: &PipelineConfig,
+    system_prompt: &str,
+) -> Vec<NemoRecord> {
+    let tokens: Vec<usize> = conversations.iter().map(|(_, _, _, c)| c.token_count).collect();
+    let bins = pack_first_fit_decreasing(&tokens, packing.target_tokens, packing.boundary_tokens);
+    let boundary = packing.boundary_text(system_prompt);
+
+    let mut slots: Vec<Option<_>> = conversations.into_iter().map(Some).collect();
+    bins.into_iter()
+        .map(|bin| {
+            let mut messages = Vec::new();
+            let mut sources = Vec::with_capacity(bin.len());
+            let mut timing = Vec::new();
+            let mut checkpoints = Vec::new();
+            let mut record_tokens = 0;
+            for (position, i) in bin.into_iter().enumerate() {
+                let (session, chunk_index, tags, conv) =
+                    slots[i].take().expect("each index packed once");
+                let first_message = messages.len();
+                timing.extend(timing_entries(&conv.messages, first_message));
+                checkpoints.extend(conv.checkpoints.iter().map(|checkpoint| FileCheckpoint {
+                    message: first_message + checkpoint.message,
+                    ..checkpoint.clone()
+                }));
+                messages.extend(
+                    conv.messages
+                        .iter()
+                        .map(|m| {
+                            NemoMessage::from_message(m, &config.masked_roles)
+                                .annotate(m, config)
+                        }),
+                );
+                if position > 0 {
+                    record_tokens += packing.boundary_tokens;
+                    if let Some(first) = messages.get_mut(first_message) {
+                        first.value.insert_str(0, &boundary);
+                        let shift = boundary.chars().count();
+                        for span in first.loss_spans.iter_mut().flatten() {
+                            *span = (span.0 + shift, span.1 + shift);
+                        }
+                    }
+                }
+                record_tokens += conv.token_count;
+                sources.push(PackedSource {
+                    id: conv.id,
+                    session,
+                    chunk_index,
+                    first_message,
+                    messages: conv.messages.len(),
+                    tokens: conv.token_count,
+                    metrics: config.emit_metrics.then_some(conv.metrics),
+                    tags,
+                    labels: conv.labels,
+                });
+            }
+            let id = match sources.as_slice() {
+                [single] => single.id.clone(),
+                _ => {
+                    let ids: Vec<&str> = sources.iter().map(|s| s.id.as_str()).collect();
+                    format!("{:016x}", stable_hash(ids.join(",").as_bytes()))
+                }
+            };
+            let mut tags = sources[0].tags.clone();
+            for source in &sources[1..] {
+                tags.retain(|key, value| source.tags.get(key) == Some(value));
+            }
+            NemoRecord {
+                id,
+                version: RecordVersion::current(),
+                tags,
+                mask: config.masked_roles.join(","),
+                system: system_prompt.to_string(),
+                conversations: messages,
+                metrics: None,
+                augmentation: None,
+                timing,
+                checkpoints,
+                boundary: None,
+                labels: ConversationLabels::new(),
+                packing: Some(PackingInfo {
+                    tokens: record_tokens,
+                    sources,
+                }),
+            }
+        })
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::filter::ConversationFilter;
     use std::io::Write;
     use tempfile::TempDir;
 
-    /// Character-based approximate tokenizer for tests.
-    struct CharApproxTokenizer;
-
-    impl Tokenizer for CharApproxTokenizer {
-        fn count_tokens(&self, text: &str) -> usize {
-            text.len() / 4
-        }
-
-        fn truncate_to_max_tokens(&self, text: &str, max_tokens: usize) -> String {
-            text.chars().take(max_tokens * 4).collect()
-        }
-    }
-
     #[test]
     fn test_discover_csv_files() {
         let temp = TempDir::new().unwrap();
@@ -357,10 +2184,555 @@ mod tests {
         };
 
         let tokenizer = CharApproxTokenizer;
-        let conversations = process_session(&csv_path, &tokenizer, &config).unwrap();
+        let conversations = process_session(&csv_path, &tokenizer, &config).unwrap().conversations;
         
         // Should have at least one conversation with messages
         assert!(!conversations.is_empty() || conversations.iter().any(|c| !c.messages.is_empty()));
     }
-}
 
+    #[test]
+    fn test_process_session_quoted_fields() {
+        let temp = TempDir::new().unwrap();
+        let csv_path = temp.path().join("test.csv");
+
+        let mut file = std::fs::File::create(&csv_path).unwrap();
+        writeln!(file, "Sequence,Time,File,RangeOffset,RangeLength,Text,Language,Type").unwrap();
+        writeln!(file, "1,2024-01-01,/test/a.py,0,0,\"print(\"\"hi\"\")\nx = 1\",python,tab").unwrap();
+
+        let config = PipelineConfig {
+            min_conversation_messages: 2,
+            csv_root: Some(temp.path().to_path_buf()),
+            ..Default::default()
+        };
+
+        let conversations =
+            process_session(&csv_path, &CharApproxTokenizer, &config).unwrap().conversations;
+        assert_eq!(conversations.len(), 1);
+        assert_eq!(
+            conversations[0].id,
+            conversation_id("test.csv", 0, &conversations[0].messages)
+        );
+        let moved = TempDir::new().unwrap();
+        std::fs::create_dir(moved.path().join("nested")).unwrap();
+        let moved_path = moved.path().join("nested").join("test.csv");
+        std::fs::copy(&csv_path, &moved_path).unwrap();
+        let config = PipelineConfig {
+            csv_root: Some(moved.path().join(".").join("nested")),
+            ..config
+        };
+        let moved_conversations =
+            process_session(&moved.path().join("./nested/test.csv"), &CharApproxTokenizer, &config)
+                .unwrap()
+                .conversations;
+        assert_eq!(moved_conversations[0].id, conversations[0].id);
+        assert!(conversations[0].messages[1].value.contains("1\tprint(\"hi\")"));
+        assert!(conversations[0].messages[1].value.contains("2\tx = 1"));
+    }
+
+    #[test]
+    fn test_process_windows_session() {
+        let temp = TempDir::new().unwrap();
+        let csv_path = temp.path().join("test.csv");
+        let mut file = std::fs::File::create(&csv_path).unwrap();
+        writeln!(file, "Sequence,Time,File,RangeOffset,RangeLength,Text,Language,Type").unwrap();
+        writeln!(file, "1,t,C:\\proj\\a.rs,0,0,fn main() {{}},rust,tab").unwrap();
+        writeln!(file, "2,t,C:\\proj\\a.rs,0,0,dir C:\\proj,powershell,terminal_command").unwrap();
+        assert!(detect_windows_session(&csv_path).unwrap());
+
+        let process = |windows_sessions| {
+            let config = PipelineConfig {
+                min_conversation_messages: 2,
+                windows_sessions,
+                ..Default::default()
+            };
+            process_session(&csv_path, &CharApproxTokenizer, &config).unwrap()
+        };
+
+        let session = process(WindowsSessionMode::PosixPaths);
+        assert!(session.stats.windows_session);
+        let messages = &session.conversations[0].messages;
+        assert_eq!(messages[0].value, "```bash\ncat -n /c/proj/a.rs\n```\n");
+        assert!(messages[2].value.contains("dir /c/proj"));
+
+        let session = process(WindowsSessionMode::PowerShell);
+        let messages = &session.conversations[0].messages;
+        assert!(messages[0].value.starts_with("```pwsh\nGet-Content 'C:\\proj\\a.rs'"));
+        assert!(messages[2].value.contains("dir C:\\proj"));
+    }
+
+    #[test]
+    fn test_conversation_id() {
+        let messages = vec![ConversationMessage::assistant("ls"), ConversationMessage::user("a")];
+        let id = conversation_id("s.csv", 0, &messages);
+        assert_eq!(id.len(), 16);
+        assert_eq!(id, conversation_id("s.csv", 0, &messages));
+        assert_ne!(id, conversation_id("s.csv", 1, &messages));
+        assert_ne!(id, conversation_id("t.csv", 0, &messages));
+        assert_ne!(id, conversation_id("s.csv", 0, &messages[..1]));
+    }
+
+    #[test]
+    fn test_anonymize_source_path() {
+        let path = anonymize_source_path(Path::new("/data/alice/laptop/session.csv"), "salt");
+        let parts: Vec<&str> = path.split('/').collect();
+        assert_eq!(parts.len(), 4);
+        assert_eq!(parts[3], "session.csv");
+        assert!(!path.contains("alice") && !path.contains("laptop"));
+        // Same user, same id; another salt, another id
+        let other = anonymize_source_path(Path::new("/data/alice/desktop/other.csv"), "salt");
+        assert_eq!(other.split('/').nth(1), Some(parts[1]));
+        assert_ne!(other.split('/').nth(2), Some(parts[2]));
+        let resalted = anonymize_source_path(Path::new("/data/alice/laptop/session.csv"), "pepper");
+        assert_ne!(resalted.split('/').nth(1), Some(parts[1]));
+    }
+
+    #[test]
+    fn test_process_session_with_bom() {
+        let temp = TempDir::new().unwrap();
+        let csv_path = temp.path().join("test.csv");
+
+        let mut file = std::fs::File::create(&csv_path).unwrap();
+        writeln!(file, "\u{feff}Sequence,Time,File,RangeOffset,RangeLength,Text,Language,Type").unwrap();
+        writeln!(file, "1,2024-01-01,/test/a.cs,0,0,\u{feff}class A {{}},csharp,tab").unwrap();
+
+        let config = PipelineConfig {
+            min_conversation_messages: 2,
+            ..Default::default()
+        };
+
+        let conversations =
+            process_session(&csv_path, &CharApproxTokenizer, &config).unwrap().conversations;
+        assert_eq!(conversations.len(), 1);
+        assert!(conversations[0].messages[1].value.contains("     1\tclass A {}"));
+    }
+
+    #[test]
+    fn test_process_session_reorders_rows() {
+        let temp = TempDir::new().unwrap();
+        let csv_path = temp.path().join("test.csv");
+
+        let mut file = std::fs::File::create(&csv_path).unwrap();
+        writeln!(file, "Sequence,Time,File,RangeOffset,RangeLength,Text,Language,Type").unwrap();
+        writeln!(file, "1,t,/test/a.py,0,0,abc,python,tab").unwrap();
+        writeln!(file, "3,t,/test/a.py,4,0,!,python,content").unwrap();
+        writeln!(file, "2,t,/test/a.py,0,0,x,python,content").unwrap();
+        writeln!(file, "2,t,/test/a.py,0,0,x,python,content").unwrap();
+
+        let config = PipelineConfig {
+            min_conversation_messages: 2,
+            sort_by_sequence: true,
+            drop_duplicate_rows: true,
+            dump_events_dir: Some(temp.path().join("events")),
+            csv_root: Some(temp.path().to_path_buf()),
+            ..Default::default()
+        };
+
+        let session = process_session(&csv_path, &CharApproxTokenizer, &config).unwrap();
+        assert_eq!(session.stats.reordered_rows, 2);
+        assert_eq!(session.stats.duplicate_rows, 1);
+        let last = session.conversations[0].messages.last().unwrap();
+        assert!(last.value.contains("     1\txabc!"));
+        assert_eq!(session.conversations[0].first_sequence, Some(1));
+        assert_eq!(session.conversations[0].last_sequence, Some(3));
+
+        let dump_path = temp
+            .path()
+            .join("events")
+            .join(format!("{}.jsonl", session_id("test.csv")));
+        let dump = std::fs::read_to_string(dump_path).unwrap();
+        let sequences: Vec<i64> = dump
+            .lines()
+            .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap()["sequence"].as_i64())
+            .map(Option::unwrap)
+            .collect();
+        assert_eq!(sequences, [1, 2, 3]);
+    }
+
+    #[test]
+    fn test_process_session_ignored_event_types() {
+        let temp = TempDir::new().unwrap();
+        let csv_path = temp.path().join("test.csv");
+
+        let mut file = std::fs::File::create(&csv_path).unwrap();
+        writeln!(file, "Sequence,Time,File,RangeOffset,RangeLength,Text,Language,Type").unwrap();
+        writeln!(file, "1,t,/test/a.py,0,0,abc,python,tab").unwrap();
+        writeln!(file, "2,t,/test/a.py,0,0,x,python,content").unwrap();
+        writeln!(file, "3,t,/test/a.py,1,0,,python,selection_mouse").unwrap();
+
+        let config = PipelineConfig {
+            min_conversation_messages: 1,
+            ignored_event_types: vec!["content".to_string(), "selection".to_string()],
+            ..Default::default()
+        };
+        let session = process_session(&csv_path, &CharApproxTokenizer, &config).unwrap();
+        assert_eq!(session.stats.ignored_events, 2);
+        assert_eq!(session.stats.event_counts.get("content"), Some(&1));
+        assert!(session.conversations[0].messages.iter().all(|m| !m.value.contains("xabc")));
+
+        assert!(!event_type_matches("select", "selection_mouse"));
+    }
+
+    #[test]
+    fn test_process_session_out_of_range_selection() {
+        let temp = TempDir::new().unwrap();
+        let csv_path = temp.path().join("test.csv");
+
+        let mut file = std::fs::File::create(&csv_path).unwrap();
+        writeln!(file, "Sequence,Time,File,RangeOffset,RangeLength,Text,Language,Type").unwrap();
+        writeln!(file, "1,t,/test/a.py,0,0,abc,python,tab").unwrap();
+        writeln!(file, "2,t,/test/a.py,-1,5,,python,selection_mouse").unwrap();
+
+        let config = PipelineConfig {
+            min_conversation_messages: 1,
+            ..Default::default()
+        };
+        let session = process_session(&csv_path, &CharApproxTokenizer, &config).unwrap();
+        assert_eq!(session.stats.event_counts.get("selection_mouse"), Some(&1));
+    }
+
+    #[test]
+    fn test_process_session_timing() {
+        let temp = TempDir::new().unwrap();
+        let csv_path = temp.path().join("test.csv");
+
+        let mut file = std::fs::File::create(&csv_path).unwrap();
+        writeln!(file, "Sequence,Time,File,RangeOffset,RangeLength,Text,Language,Type").unwrap();
+        writeln!(file, "1,1000,/test/a.py,0,0,abc,python,tab").unwrap();
+        writeln!(file, "2,31000,/test/a.py,0,0,x,python,content").unwrap();
+        // Ten minute pause counts as idle
+        writeln!(file, "3,631000,/test/a.py,0,0,y,python,content").unwrap();
+        writeln!(file, "4,661000,/test/a.py,0,0,z,python,content").unwrap();
+
+        let session = process_session(&csv_path, &CharApproxTokenizer, &PipelineConfig::default())
+            .unwrap();
+        assert_eq!(session.stats.duration_ms(), Some(660_000));
+        assert_eq!(session.stats.active_time_ms, 60_000);
+        assert!((session.stats.events_per_minute().unwrap() - 4.0 / 11.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_process_session_missing_field() {
+        let temp = TempDir::new().unwrap();
+        let csv_path = temp.path().join("test.csv");
+
+        let mut file = std::fs::File::create(&csv_path).unwrap();
+        writeln!(file, "Sequence,Time,File,RangeOffset,RangeLength,Text,Language,Type").unwrap();
+        writeln!(file, "1,2024-01-01,/test/file.rs,,,x,rust,content").unwrap();
+
+        let err = process_session(&csv_path, &CharApproxTokenizer, &PipelineConfig::default())
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            SerializerError::MissingField { field: "RangeOffset", .. }
+        ));
+    }
+
+    #[test]
+    fn test_prompt_completion_records() {
+        let conversation = FinalizedConversation {
+            messages: vec![
+                ConversationMessage::assistant("cat -n a"),
+                ConversationMessage::user("stdout a"),
+                ConversationMessage::assistant("ls"),
+                ConversationMessage::user("stdout ls"),
+                ConversationMessage::assistant("git status"),
+            ],
+            message_token_counts: vec![3, 30, 1, 5, 3],
+            ..Default::default()
+        };
+
+        let records = prompt_completion_records(&conversation, "sys", 10, &[]);
+        // The first assistant message has no context; the second's context does
+        // not fit the budget; the third gets the two preceding messages.
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].completion, "git status");
+        assert_eq!(records[0].prompt.len(), 2);
+        assert_eq!(records[0].prompt[0].value, "ls");
+    }
+
+    #[test]
+    fn test_tokenize_conversations() {
+        let mut conversations = [
+            FinalizedConversation {
+                messages: vec![
+                    ConversationMessage::assistant("a".repeat(40)),
+                    ConversationMessage::user("b".repeat(8)),
+                ],
+                ..Default::default()
+            },
+            FinalizedConversation {
+                messages: vec![ConversationMessage::user("c".repeat(12))],
+                token_count: 99,
+                ..Default::default()
+            },
+        ];
+
+        tokenize_conversations(conversations.iter_mut(), &CharApproxTokenizer, 5, 1);
+
+        // First message truncated to 5 tokens, including the marker
+        assert!(conversations[0].messages[0].value.ends_with(crate::TRUNCATION_MARKER));
+        assert!(CharApproxTokenizer.count_tokens(&conversations[0].messages[0].value) <= 5);
+        assert_eq!(conversations[0].token_count, 5 + 2);
+        assert_eq!(conversations[0].message_token_counts, vec![5, 2]);
+        assert_eq!(conversations[0].metrics.truncated_messages, 1);
+        assert_eq!(conversations[1].token_count, 3);
+    }
+
+    #[test]
+    fn test_write_per_session_layout() {
+        let dir = TempDir::new().unwrap();
+        let conversation = FinalizedConversation {
+            messages: vec![ConversationMessage::user("ls"), ConversationMessage::assistant("cd")],
+            token_count: 1,
+            ..Default::default()
+        };
+        let session_results = vec![SessionResult {
+            conversations: vec![conversation.clone(), conversation],
+            source_path: "a/session.csv".to_string(),
+            tags: SessionTags::from([("consent".to_string(), "research".to_string())]),
+            stats: SessionStats::default(),
+        }];
+        let config = PipelineConfig {
+            output_layout: OutputLayout::PerSession,
+            val_ratio: 0.0,
+            ..Default::default()
+        };
+
+        let result = write_jsonl_output(session_results, dir.path(), &config, "sys").unwrap();
+        assert_eq!(result.total_records, 2);
+        assert!(!dir.path().join("training.jsonl").exists());
+
+        let id = session_id("a/session.csv");
+        let session_file =
+            std::fs::read_to_string(dir.path().join(format!("sessions/{}.jsonl", id))).unwrap();
+        assert_eq!(session_file.lines().count(), 2);
+        let index = std::fs::read_to_string(dir.path().join("index.jsonl")).unwrap();
+        let entries: Vec<serde_json::Value> =
+            index.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1]["session"], id.as_str());
+        assert_eq!(entries[1]["chunk_index"], 1);
+        assert_eq!(entries[1]["first_record"], 1);
+        assert_eq!(entries[1]["split"], "train");
+        assert_eq!(entries[1]["tags"]["consent"], "research");
+    }
+
+    #[test]
+    fn test_write_packed_records() {
+        let dir = TempDir::new().unwrap();
+        let conversation = |tokens: usize| FinalizedConversation {
+            messages: vec![ConversationMessage::user("ls"), ConversationMessage::assistant("cd")],
+            token_count: tokens,
+            ..Default::default()
+        };
+        let session_results = vec![SessionResult {
+            conversations: vec![conversation(60), conversation(30), conversation(150)],
+            source_path: "a/session.csv".to_string(),
+            tags: SessionTags::new(),
+            stats: SessionStats::default(),
+        }];
+        let config = PipelineConfig {
+            val_ratio: 0.0,
+            packing: Some(PackingConfig {
+                target_tokens: 100,
+                separator: "<sep>".to_string(),
+                system_prompt: crate::PackingSystemPrompt::Repeat,
+                boundary_tokens: 5,
+            }),
+            ..Default::default()
+        };
+
+        let result = write_jsonl_output(session_results, dir.path(), &config, "sys").unwrap();
+        assert_eq!(result.total_conversations, 3);
+        assert_eq!(result.total_records, 2);
+        let stats = result.packing.unwrap();
+        assert_eq!(stats.packed_conversations, 3);
+        assert_eq!(stats.oversized_conversations, 1);
+
+        let training = std::fs::read_to_string(dir.path().join("training.jsonl")).unwrap();
+        let records: Vec<serde_json::Value> =
+            training.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+        assert_eq!(records[0]["packing"]["sources"][0]["chunk_index"], 2);
+        let packed = &records[1];
+        assert_eq!(packed["packing"]["tokens"], 95);
+        assert_eq!(packed["packing"]["sources"][1]["chunk_index"], 1);
+        assert_eq!(packed["packing"]["sources"][1]["first_message"], 2);
+        assert_eq!(packed["conversations"][0]["value"], "ls");
+        assert_eq!(packed["conversations"][2]["value"], "<sep>sys\n\nls");
+
+        let per_session = PipelineConfig {
+            output_layout: OutputLayout::PerSession,
+            ..config
+        };
+        assert!(write_jsonl_output(Vec::new(), dir.path(), &per_session, "sys").is_err());
+    }
+
+    #[test]
+    fn test_read_jsonl_input() {
+        let dir = TempDir::new().unwrap();
+        let timing = MessageTiming {
+            typing_ms: Some(1200),
+            ..Default::default()
+        };
+        let messages = vec![
+            ConversationMessage::user("ls").with_mask(Some(false)),
+            ConversationMessage::assistant("cd").with_timing(Some(timing)),
+        ];
+        let session_results = vec![SessionResult {
+            conversations: vec![FinalizedConversation {
+                messages: messages.clone(),
+                ..Default::default()
+            }],
+            source_path: "a/session.csv".to_string(),
+            tags: SessionTags::from([("license".to_string(), "mit".to_string())]),
+            stats: SessionStats::default(),
+        }];
+        let config = PipelineConfig {
+            val_ratio: 0.0,
+            emit_metrics: true,
+            emit_timing: true,
+            ..Default::default()
+        };
+        write_jsonl_output(session_results, dir.path(), &config, "sys").unwrap();
+
+        let records = read_jsonl_input(&dir.path().join("training.jsonl")).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].system, "sys");
+        assert_eq!(records[0].tags["license"], "mit");
+        assert_eq!(records[0].metrics, Some(ConversationMetrics::default()));
+        assert_eq!(records[0].messages(), messages);
+        assert_eq!(records[0].version, RecordVersion::current());
+
+        // Records of earlier versions lack ids and newer metrics
+        let old = dir.path().join("old.jsonl");
+        std::fs::write(
+            &old,
+            "{\"mask\":\"User\",\"system\":\"s\",\"conversations\":[],\"metrics\":{\"edit_commands\":2}}\n\nnot json\n",
+        )
+        .unwrap();
+        let err = read_jsonl_input(&old).unwrap_err();
+        assert!(matches!(err, SerializerError::InvalidRecord { line: 3, .. }));
+        std::fs::write(&old, "{\"mask\":\"User\",\"system\":\"s\",\"conversations\":[],\"metrics\":{\"edit_commands\":2}}\n").unwrap();
+        let records = read_jsonl_input(&old).unwrap();
+        assert_eq!(records[0].id, "");
+        assert_eq!(records[0].version.schema_version, 0);
+        assert_eq!(records[0].metrics.as_ref().unwrap().edit_commands, 2);
+
+        // Appending current records to an old dataset mixes versions
+        let training = std::fs::read_to_string(dir.path().join("training.jsonl")).unwrap();
+        std::fs::write(&old, format!("{{\"mask\":\"User\",\"system\":\"s\",\"conversations\":[]}}\n{}", training)).unwrap();
+        let err = read_jsonl_input(&old).unwrap_err();
+        assert!(matches!(err, SerializerError::IncompatibleRecord { line: 2, .. }));
+    }
+
+    #[test]
+    fn test_sample_sessions() {
+        let conversation = FinalizedConversation {
+            token_count: 10,
+            ..Default::default()
+        };
+        let sessions = || -> Vec<SessionResult> {
+            (0..4)
+                .map(|i| SessionResult {
+                    conversations: vec![conversation.clone(); 25],
+                    source_path: format!("s{}.csv", i),
+                    tags: SessionTags::new(),
+            stats: SessionStats::default(),
+                })
+                .collect()
+        };
+        let count = |results: &[SessionResult]| -> usize {
+            results.iter().map(|s| s.conversations.len()).sum()
+        };
+
+        let mut results = sessions();
+        let config = PipelineConfig {
+            max_conversations: Some(40),
+            max_total_tokens: Some(300),
+            ..Default::default()
+        };
+        sample_sessions(&mut results, &config).unwrap();
+        assert_eq!(count(&results), 30);
+        // Every session contributes
+        assert!(results.iter().all(|s| !s.conversations.is_empty()));
+        assert_eq!(
+            results[0].stats.drops.sampled_out_conversations,
+            25 - results[0].conversations.len()
+        );
+
+        let mut results = sessions();
+        let config = PipelineConfig {
+            sample_rate: 0.5,
+            ..Default::default()
+        };
+        sample_sessions(&mut results, &config).unwrap();
+        let kept = count(&results);
+        assert!((30..=70).contains(&kept));
+
+        // Same seed, same sample
+        let mut again = sessions();
+        sample_sessions(&mut again, &config).unwrap();
+        assert_eq!(count(&again), kept);
+
+        let config = PipelineConfig {
+            sample_rate: 0.0,
+            ..Default::default()
+        };
+        assert!(sample_sessions(&mut sessions(), &config).is_err());
+    }
+
+    #[test]
+    fn test_process_session_filters() {
+        struct NoEcho;
+        impl ConversationFilter for NoEcho {
+            fn name(&self) -> &str {
+                "no-echo"
+            }
+
+            fn accept(&self, conv: &FinalizedConversation, ctx: &SessionContext<'_>) -> FilterDecision {
+                assert!(ctx.source_path.ends_with("test.csv"));
+                if conv.messages.iter().any(|m| m.value.contains("echo")) {
+                    FilterDecision::Reject {
+                        reason: "echo".to_string(),
+                    }
+                } else {
+                    FilterDecision::Accept
+                }
+            }
+        }
+
+        let temp = TempDir::new().unwrap();
+        let csv_path = temp.path().join("test.csv");
+        let mut file = std::fs::File::create(&csv_path).unwrap();
+        writeln!(file, "Sequence,Time,File,RangeOffset,RangeLength,Text,Language,Type").unwrap();
+        writeln!(file, "1,t,/test/file.rs,0,0,fn main() {{}},rust,tab").unwrap();
+        writeln!(file, "2,t,/test/file.rs,0,0,echo hello,bash,terminal_command").unwrap();
+
+        let config = PipelineConfig {
+            min_conversation_messages: 2,
+            filters: FilterChain::new().with(NoEcho),
+            ..Default::default()
+        };
+        assert_eq!(format!("{:?}", config.filters), r#"["no-echo"]"#);
+
+        let session = process_session(&csv_path, &CharApproxTokenizer, &config).unwrap();
+        assert!(session.conversations.is_empty());
+        assert_eq!(session.stats.drops.filtered_conversations.get("echo"), Some(&1));
+
+        let unfiltered = PipelineConfig {
+            min_conversation_messages: 2,
+            ..Default::default()
+        };
+        let session = process_session(&csv_path, &CharApproxTokenizer, &unfiltered).unwrap();
+        let id = session.conversations[0].id.clone();
+        let denylist = temp.path().join("exclude.txt");
+        std::fs::write(&denylist, format!("# flagged in review\n{} contaminated\n", id)).unwrap();
+        let config = PipelineConfig {
+            filters: FilterChain::new().with(crate::ExcludeIds::from_file(&denylist).unwrap()),
+            ..unfiltered
+        };
+        let session = process_session(&csv_path, &CharApproxTokenizer, &config).unwrap();
+        assert!(session.conversations.is_empty());
+        assert_eq!(session.stats.drops.filtered_conversations.get("excluded_id"), Some(&1));
+    }
+}
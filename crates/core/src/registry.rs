@@ -0,0 +1,152 @@
+//! Multiple named state managers sharing one configuration.
+
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+use crate::conversation::{ConversationStateManager, ConversationStateManagerConfig, DropStats};
+use crate::Tokenizer;
+
+/// Aggregate statistics over all sessions of a registry.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct RegistryStats {
+    pub sessions: usize,
+    /// Messages in the sessions' current conversations.
+    pub messages: usize,
+    /// Tokens in the sessions' current conversations.
+    pub tokens: usize,
+    pub drops: DropStats,
+    /// Files tracked by more than one session, with the sessions tracking them.
+    /// Usually a sign of overlapping workspaces.
+    pub shared_files: BTreeMap<String, Vec<String>>,
+}
+
+/// Manages one `ConversationStateManager` per named session (e.g. per
+/// workspace window or remote host). Sessions are created on first use with
+/// the registry's configuration and a clone of its tokenizer.
+pub struct SessionRegistry<T>
+where
+    T: Tokenizer + Clone,
+{
+    tokenizer: T,
+    config: ConversationStateManagerConfig,
+    sessions: BTreeMap<String, ConversationStateManager<T>>,
+}
+
+impl<T> SessionRegistry<T>
+where
+    T: Tokenizer + Clone,
+{
+    pub fn new(tokenizer: T, config: ConversationStateManagerConfig) -> Self {
+        Self {
+            tokenizer,
+            config,
+            sessions: BTreeMap::new(),
+        }
+    }
+
+    /// The configuration shared by all sessions.
+    pub fn config(&self) -> &ConversationStateManagerConfig {
+        &self.config
+    }
+
+    /// Get the manager of a session, creating it if needed.
+    pub fn session(&mut self, name: &str) -> &mut ConversationStateManager<T> {
+        self.sessions
+            .entry(name.to_string())
+            .or_insert_with(|| ConversationStateManager::new(self.tokenizer.clone(), self.config.clone()))
+    }
+
+    /// Get the manager of an existing session.
+    pub fn get(&self, name: &str) -> Option<&ConversationStateManager<T>> {
+        self.sessions.get(name)
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.sessions.contains_key(name)
+    }
+
+    /// Remove a session, returning its manager.
+    pub fn remove(&mut self, name: &str) -> Option<ConversationStateManager<T>> {
+        self.sessions.remove(name)
+    }
+
+    /// Session names, sorted.
+    pub fn session_names(&self) -> Vec<String> {
+        self.sessions.keys().cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.sessions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sessions.is_empty()
+    }
+
+    /// Reset the state of every session, keeping the sessions registered.
+    pub fn reset_all(&mut self) {
+        for manager in self.sessions.values_mut() {
+            manager.reset();
+        }
+    }
+
+    /// Flush aged terminal output in every session (see
+    /// `ConversationStateManager::tick`).
+    pub fn tick_all(&mut self, time_ms: i64) {
+        for manager in self.sessions.values_mut() {
+            manager.tick(time_ms);
+        }
+    }
+
+    /// Statistics aggregated over all sessions.
+    pub fn stats(&self) -> RegistryStats {
+        let mut stats = RegistryStats {
+            sessions: self.sessions.len(),
+            ..Default::default()
+        };
+        let mut file_sessions: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for (name, manager) in &self.sessions {
+            stats.messages += manager.message_count();
+            stats.tokens += manager.token_count();
+            stats.drops.merge(&manager.get_drop_stats());
+            for file in manager.tracked_files() {
+                file_sessions.entry(file).or_default().push(name.clone());
+            }
+        }
+        file_sessions.retain(|_, sessions| sessions.len() > 1);
+        stats.shared_files = file_sessions;
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::budget::CharApproxTokenizer;
+
+    #[test]
+    fn test_session_registry() {
+        let mut registry =
+            SessionRegistry::new(CharApproxTokenizer, ConversationStateManagerConfig::default());
+
+        registry.session("window-1").handle_tab_event("/a.rs", Some("fn a() {}")).unwrap();
+        registry.session("window-2").handle_tab_event("/a.rs", Some("fn a() {}")).unwrap();
+        registry.session("window-2").handle_terminal_command_event("ls").unwrap();
+
+        assert_eq!(registry.session_names(), ["window-1", "window-2"]);
+        let stats = registry.stats();
+        assert_eq!(stats.sessions, 2);
+        assert_eq!(stats.messages, 5);
+        assert_eq!(
+            stats.shared_files.get("/a.rs"),
+            Some(&vec!["window-1".to_string(), "window-2".to_string()])
+        );
+
+        registry.reset_all();
+        assert_eq!(registry.len(), 2);
+        assert_eq!(registry.stats().messages, 0);
+        assert!(registry.remove("window-1").is_some());
+        assert!(!registry.contains("window-1"));
+    }
+}
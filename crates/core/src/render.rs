@@ -0,0 +1,515 @@
+//! Persona-independent actions and observations, and their rendering.
+//!
+//! The state manager describes what the agent does as `Action`s and what it
+//! sees as `Observation`s. A `Renderer` turns them into message text; actions
+//! become assistant messages and observations become user messages.
+//! `BashRenderer` is the default persona (`cat -n`, `sed -i`, `<stdout>`);
+//! `PowerShellRenderer` serves sessions recorded on Windows.
+
+use std::cell::Cell;
+use std::ops::Range;
+
+use crate::error::SerializerError;
+use crate::helpers::{
+    clean_text, escape_single_quotes_for_sed, fenced_block, line_numbered_output,
+    unit_interval_hash, Viewport,
+};
+use crate::sed::{bre_replacement_to_regex, bre_to_regex};
+
+/// A change to a file, in line space of the file before the edit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileEdit<'a> {
+    /// Insert `lines` before line `before_line`.
+    Insert { before_line: usize, lines: &'a [String] },
+    /// Append `lines` at the end of the file.
+    Append { lines: &'a [String] },
+    /// Delete lines `start..=end`.
+    Delete { start: usize, end: usize },
+    /// Replace lines `start..=end` with `lines`.
+    Replace {
+        start: usize,
+        end: usize,
+        lines: &'a [String],
+    },
+    /// Run a code formatter (e.g. `rustfmt`) on the file.
+    Format { formatter: &'a str },
+    /// Substitute `pattern` (a basic regular expression, delimiters escaped)
+    /// with `replacement` within lines `start..=end`.
+    Substitute {
+        start: usize,
+        end: usize,
+        pattern: &'a str,
+        replacement: &'a str,
+        delimiter: char,
+        global: bool,
+        ignore_case: bool,
+    },
+}
+
+/// Something the agent does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action<'a> {
+    /// Read a whole file, or only the lines of `range`.
+    ReadFile {
+        path: &'a str,
+        range: Option<Viewport>,
+    },
+    /// Query a file's line count.
+    CountLines { path: &'a str },
+    /// Edit a file, then read the lines of `view`.
+    EditFile {
+        path: &'a str,
+        edit: FileEdit<'a>,
+        view: Viewport,
+    },
+    /// Run a shell command.
+    RunCommand { command: &'a str },
+    /// List a directory.
+    ListDirectory { path: &'a str },
+    /// Switch to a git branch.
+    CheckoutBranch { branch: &'a str },
+    /// Rename a file.
+    MoveFile { from: &'a str, to: &'a str },
+}
+
+/// Something the agent sees in response to an action.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Observation<'a> {
+    /// File content, whole or restricted to `range`, with the `marked`
+    /// lines flagged (see `annotate_selections`).
+    FileContent {
+        content: &'a str,
+        range: Option<Viewport>,
+        marked: Option<Viewport>,
+    },
+    /// Content of a file that was never observed.
+    UnknownFileContent,
+    /// Number of newline-terminated lines in a file.
+    LineCount { path: &'a str, lines: usize },
+    /// Terminal output, already normalized.
+    CommandOutput { output: &'a str },
+    /// Entry names of a directory.
+    DirectoryListing { entries: &'a [&'a str] },
+}
+
+impl Observation<'_> {
+    /// Whether the observation shows nothing.
+    pub fn is_empty(&self) -> bool {
+        match *self {
+            Observation::FileContent { content, .. } => content.is_empty(),
+            Observation::CommandOutput { output } => output.trim().is_empty(),
+            Observation::DirectoryListing { entries } => entries.is_empty(),
+            Observation::UnknownFileContent | Observation::LineCount { .. } => false,
+        }
+    }
+}
+
+/// `cat -n` style output of `content`, with `>` in the number column of the
+/// `marked` lines.
+fn file_content_output(content: &str, range: Option<Viewport>, marked: Option<Viewport>) -> String {
+    let output = line_numbered_output(content, range.map(|vp| vp.start), range.map(|vp| vp.end));
+    let Some(marked) = marked else {
+        return output;
+    };
+    output
+        .split('\n')
+        .map(|line| {
+            let number = line.split('\t').next().unwrap_or("").trim_start();
+            match (line.strip_prefix(' '), number.parse::<usize>()) {
+                (Some(rest), Ok(n)) if (marked.start..=marked.end).contains(&n) => format!(">{}", rest),
+                _ => line.to_string(),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Renders actions and observations as message text.
+pub trait Renderer: Send {
+    fn render_action(&self, action: &Action<'_>) -> String;
+
+    /// `render_action`, and the byte range of the decision-relevant part of
+    /// the text: the command without its fence and, for edits, without the
+    /// view of the result (see `loss_spans`). `None` if unknown.
+    fn render_action_with_span(&self, action: &Action<'_>) -> (String, Option<Range<usize>>) {
+        (self.render_action(action), None)
+    }
+
+    fn render_observation(&self, observation: &Observation<'_>) -> String;
+}
+
+/// `cmd` in a fence of `language`, and the byte range of `cmd[..decision_end]`
+/// in the result.
+fn fenced_command(language: &str, cmd: &str, decision_end: usize) -> (String, Option<Range<usize>>) {
+    let body = clean_text(cmd);
+    let start = "```".len() + language.len() + 1;
+    let end = start + clean_text(&cmd[..decision_end]).len().min(body.len());
+    (fenced_block(Some(language), &body), Some(start..end))
+}
+
+/// A shell command printing a file with `cat -n` style line numbers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ViewCommand {
+    /// `cat -n FILE`, `cat -n FILE | sed -n 'A,Bp'`
+    Cat,
+    /// `nl -ba FILE`, `nl -ba FILE | sed -n 'A,Bp'`
+    Nl,
+    /// `sed -n '1,$p' FILE | nl -ba`, `sed -n 'A,Bp' FILE | nl -ba -v A`
+    SedNl,
+}
+
+impl ViewCommand {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Cat => "cat",
+            Self::Nl => "nl",
+            Self::SedNl => "sed-nl",
+        }
+    }
+
+    /// Command showing `path`, or only the lines of `range`. Every variant
+    /// prints the same output.
+    pub fn render(&self, path: &str, range: Option<Viewport>) -> String {
+        match (self, range) {
+            (Self::Cat, None) => format!("cat -n {}", path),
+            (Self::Cat, Some(vp)) => format!("cat -n {} | sed -n '{},{}p'", path, vp.start, vp.end),
+            (Self::Nl, None) => format!("nl -ba {}", path),
+            (Self::Nl, Some(vp)) => format!("nl -ba {} | sed -n '{},{}p'", path, vp.start, vp.end),
+            (Self::SedNl, None) => format!("sed -n '1,$p' {} | nl -ba", path),
+            (Self::SedNl, Some(vp)) => format!(
+                "sed -n '{},{}p' {} | nl -ba -v {}",
+                vp.start, vp.end, path, vp.start
+            ),
+        }
+    }
+}
+
+impl std::str::FromStr for ViewCommand {
+    type Err = SerializerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "cat" => Ok(Self::Cat),
+            "nl" => Ok(Self::Nl),
+            "sed-nl" => Ok(Self::SedNl),
+            other => Err(SerializerError::InvalidConfig(format!(
+                "unknown view command '{}' (expected cat, nl or sed-nl)",
+                other
+            ))),
+        }
+    }
+}
+
+/// Weighted, seeded choice of the command used for each file view.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ViewCommandMix {
+    /// Commands with relative weights; empty (or all zero) means `cat -n`.
+    pub weights: Vec<(ViewCommand, u32)>,
+    pub seed: u64,
+}
+
+impl ViewCommandMix {
+    /// The command for the `view`-th view of `path`.
+    fn choose(&self, path: &str, view: u64) -> ViewCommand {
+        let total: u64 = self.weights.iter().map(|&(_, w)| u64::from(w)).sum();
+        if total == 0 {
+            return ViewCommand::Cat;
+        }
+        let key = format!("{}#{}#{}", self.seed, path, view);
+        let mut pick = (unit_interval_hash(&key) * total as f64) as u64;
+        for &(command, weight) in &self.weights {
+            if pick < u64::from(weight) {
+                return command;
+            }
+            pick -= u64::from(weight);
+        }
+        self.weights.last().map_or(ViewCommand::Cat, |&(command, _)| command)
+    }
+}
+
+impl std::str::FromStr for ViewCommandMix {
+    type Err = SerializerError;
+
+    /// Parse `COMMAND=WEIGHT,...` (e.g. `cat=3,nl=1,sed-nl=1`), seed 0.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let weights = s
+            .split(',')
+            .map(|item| {
+                let (command, weight) = item.split_once('=').unwrap_or((item, "1"));
+                let weight = weight.trim().parse().map_err(|_| {
+                    SerializerError::InvalidConfig(format!("invalid view command weight in '{}'", item))
+                })?;
+                Ok((command.trim().parse()?, weight))
+            })
+            .collect::<Result<_, SerializerError>>()?;
+        Ok(Self { weights, seed: 0 })
+    }
+}
+
+/// The bash transcript persona.
+#[derive(Debug, Clone, Default)]
+pub struct BashRenderer {
+    view_commands: ViewCommandMix,
+    /// File views rendered so far, varying the seeded choice.
+    views: Cell<u64>,
+}
+
+impl BashRenderer {
+    /// Show files with commands drawn from `view_commands` instead of
+    /// always `cat -n`.
+    pub fn with_view_commands(view_commands: ViewCommandMix) -> Self {
+        Self {
+            view_commands,
+            views: Cell::new(0),
+        }
+    }
+
+    fn view_command(&self, path: &str, range: Option<Viewport>) -> String {
+        let view = self.views.get();
+        self.views.set(view + 1);
+        self.view_commands.choose(path, view).render(path, range)
+    }
+
+    fn sed_payload(lines: &[String]) -> String {
+        lines
+            .iter()
+            .map(|line| escape_single_quotes_for_sed(line))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    fn edit_command(path: &str, edit: &FileEdit<'_>) -> String {
+        match *edit {
+            FileEdit::Insert { before_line, lines } => {
+                format!("sed -i '{}i\\\n{}' {}", before_line, Self::sed_payload(lines), path)
+            }
+            FileEdit::Append { lines } => {
+                format!("sed -i '$a\\\n{}' {}", Self::sed_payload(lines), path)
+            }
+            FileEdit::Delete { start, end } => format!("sed -i '{},{}d' {}", start, end, path),
+            FileEdit::Replace { start, end, lines } => format!(
+                "sed -i '{},{}c\\\n{}' {}",
+                start,
+                end,
+                Self::sed_payload(lines),
+                path
+            ),
+            FileEdit::Format { formatter } => format!("{} {}", formatter, path),
+            FileEdit::Substitute {
+                start,
+                end,
+                pattern,
+                replacement,
+                delimiter: d,
+                global,
+                ignore_case,
+            } => format!(
+                "sed -i '{},{}s{d}{}{d}{}{d}{}{}' {}",
+                start,
+                end,
+                escape_single_quotes_for_sed(pattern),
+                escape_single_quotes_for_sed(replacement),
+                if global { "g" } else { "" },
+                if ignore_case { "I" } else { "" },
+                path
+            ),
+        }
+    }
+
+    /// Quote a branch name if it contains shell-special characters.
+    fn quote_branch(branch: &str) -> String {
+        let is_plain = branch
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '/' | '\\' | '-'));
+        if is_plain {
+            branch.to_string()
+        } else {
+            format!("'{}'", branch.replace('\'', "'\"'\"'"))
+        }
+    }
+}
+
+impl Renderer for BashRenderer {
+    fn render_action(&self, action: &Action<'_>) -> String {
+        self.render_action_with_span(action).0
+    }
+
+    fn render_action_with_span(&self, action: &Action<'_>) -> (String, Option<Range<usize>>) {
+        let cmd = match *action {
+            Action::ReadFile { path, range } => self.view_command(path, range),
+            Action::CountLines { path } => format!("wc -l {}", path),
+            Action::EditFile { path, edit, view } => {
+                let edit = Self::edit_command(path, &edit);
+                let cmd = format!("{} && {}", edit, self.view_command(path, Some(view)));
+                return fenced_command("bash", &cmd, edit.len());
+            }
+            Action::RunCommand { command } => command.to_string(),
+            Action::ListDirectory { path } => format!("ls -la {}", path),
+            Action::CheckoutBranch { branch } => {
+                format!("git checkout {}", Self::quote_branch(branch))
+            }
+            Action::MoveFile { from, to } => format!("mv {} {}", from, to),
+        };
+        fenced_command("bash", &cmd, cmd.len())
+    }
+
+    fn render_observation(&self, observation: &Observation<'_>) -> String {
+        let output = match *observation {
+            Observation::FileContent { content, range, marked } => file_content_output(content, range, marked),
+            Observation::UnknownFileContent => "[file content unknown]".to_string(),
+            Observation::LineCount { path, lines } => format!("{} {}", lines, path),
+            Observation::CommandOutput { output } => output.to_string(),
+            Observation::DirectoryListing { entries } => clean_text(&entries.join("\n")),
+        };
+        format!("<stdout>\n{}\n</stdout>", output)
+    }
+}
+
+/// The PowerShell transcript persona: `Get-Content` viewers with `cat -n`
+/// style numbering, line edits through a `List[string]`, `pwsh` fences.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PowerShellRenderer;
+
+impl PowerShellRenderer {
+    /// Single-quoted PowerShell string literal.
+    fn quote(text: &str) -> String {
+        format!("'{}'", text.replace('\'', "''"))
+    }
+
+    fn lines_literal(lines: &[String]) -> String {
+        let items: Vec<String> = lines.iter().map(|line| Self::quote(line)).collect();
+        format!("@({})", items.join(", "))
+    }
+
+    /// Numbered listing of a file, or of the lines of `range`.
+    fn view_command(path: &str, range: Option<Viewport>) -> String {
+        let select = match range {
+            Some(vp) => format!(
+                " | Select-Object -Skip {} -First {}",
+                vp.start.saturating_sub(1),
+                (vp.end + 1).saturating_sub(vp.start)
+            ),
+            None => String::new(),
+        };
+        format!(
+            "Get-Content {}{} | ForEach-Object {{ \"{{0,6}}`t{{1}}\" -f $_.ReadCount, $_ }}",
+            Self::quote(path),
+            select
+        )
+    }
+
+    fn edit_command(path: &str, edit: &FileEdit<'_>) -> String {
+        let load = format!(
+            "$f = {}; $l = [Collections.Generic.List[string]](Get-Content $f)",
+            Self::quote(path)
+        );
+        let change = match *edit {
+            FileEdit::Insert { before_line, lines } => format!(
+                "$l.InsertRange({}, [string[]]{})",
+                before_line.saturating_sub(1),
+                Self::lines_literal(lines)
+            ),
+            FileEdit::Append { lines } => {
+                format!("$l.AddRange([string[]]{})", Self::lines_literal(lines))
+            }
+            FileEdit::Delete { start, end } => {
+                format!("$l.RemoveRange({}, {})", start - 1, end + 1 - start)
+            }
+            FileEdit::Replace { start, end, lines } => format!(
+                "$l.RemoveRange({}, {}); $l.InsertRange({}, [string[]]{})",
+                start - 1,
+                end + 1 - start,
+                start - 1,
+                Self::lines_literal(lines)
+            ),
+            FileEdit::Format { formatter } => return format!("{} {}", formatter, Self::quote(path)),
+            FileEdit::Substitute {
+                start,
+                end,
+                pattern,
+                replacement,
+                global,
+                ignore_case,
+                ..
+            } => {
+                let pattern = bre_to_regex(pattern).unwrap_or_else(|| pattern.to_string());
+                let pattern = if ignore_case { format!("(?i){}", pattern) } else { pattern };
+                let replacement =
+                    bre_replacement_to_regex(replacement).unwrap_or_else(|| replacement.to_string());
+                format!(
+                    "$r = [regex]{}; for ($i = {}; $i -lt {}; $i++) {{ $l[$i] = $r.Replace($l[$i], {}{}) }}",
+                    Self::quote(&pattern),
+                    start - 1,
+                    end,
+                    Self::quote(&replacement),
+                    if global { "" } else { ", 1" }
+                )
+            }
+        };
+        format!("{}; {}; Set-Content $f $l", load, change)
+    }
+}
+
+impl Renderer for PowerShellRenderer {
+    fn render_action(&self, action: &Action<'_>) -> String {
+        self.render_action_with_span(action).0
+    }
+
+    fn render_action_with_span(&self, action: &Action<'_>) -> (String, Option<Range<usize>>) {
+        let cmd = match *action {
+            Action::ReadFile { path, range } => Self::view_command(path, range),
+            Action::CountLines { path } => format!("(Get-Content {}).Count", Self::quote(path)),
+            Action::EditFile { path, edit, view } => {
+                let edit = Self::edit_command(path, &edit);
+                let cmd = format!("{}; {}", edit, Self::view_command(path, Some(view)));
+                return fenced_command("pwsh", &cmd, edit.len());
+            }
+            Action::RunCommand { command } => command.to_string(),
+            Action::ListDirectory { path } => {
+                format!("Get-ChildItem -Force -Name {}", Self::quote(path))
+            }
+            Action::CheckoutBranch { branch } => format!("git checkout {}", Self::quote(branch)),
+            Action::MoveFile { from, to } => format!("Move-Item {} {}", Self::quote(from), Self::quote(to)),
+        };
+        fenced_command("pwsh", &cmd, cmd.len())
+    }
+
+    fn render_observation(&self, observation: &Observation<'_>) -> String {
+        match *observation {
+            Observation::LineCount { lines, .. } => format!("<stdout>\n{}\n</stdout>", lines),
+            _ => BashRenderer::default().render_observation(observation),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_powershell_renderer() {
+        let renderer = PowerShellRenderer;
+        let read = renderer.render_action(&Action::ReadFile {
+            path: "C:\\p\\it's.rs",
+            range: Some(Viewport { start: 3, end: 5 }),
+        });
+        assert_eq!(
+            read,
+            "```pwsh\nGet-Content 'C:\\p\\it''s.rs' | Select-Object -Skip 2 -First 3 | ForEach-Object { \"{0,6}`t{1}\" -f $_.ReadCount, $_ }\n```\n"
+        );
+
+        let lines = ["x".to_string()];
+        let edit = renderer.render_action(&Action::EditFile {
+            path: "a.rs",
+            edit: FileEdit::Replace {
+                start: 2,
+                end: 3,
+                lines: &lines,
+            },
+            view: Viewport { start: 1, end: 4 },
+        });
+        assert!(edit.contains(
+            "$f = 'a.rs'; $l = [Collections.Generic.List[string]](Get-Content $f); $l.RemoveRange(1, 2); $l.InsertRange(1, [string[]]@('x')); Set-Content $f $l; Get-Content 'a.rs' | Select-Object -Skip 0 -First 4"
+        ));
+    }
+}
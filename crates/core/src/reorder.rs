@@ -0,0 +1,110 @@
+//! Reordering of slightly out-of-order live events.
+//!
+//! Events reported by different editor APIs can arrive out of order, e.g. an
+//! edit before the switch to its file. `ReorderBuffer` holds events back
+//! until newer ones have arrived and releases them sorted by their ordering
+//! key (sequence number or timestamp), then priority, then arrival.
+
+/// What `reorder_window` is measured in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ReorderKey {
+    /// The event sequence number (`set_event_sequence`).
+    #[default]
+    Sequence,
+    /// The event time in milliseconds (`set_event_time`).
+    Time,
+}
+
+impl std::str::FromStr for ReorderKey {
+    type Err = crate::SerializerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sequence" => Ok(Self::Sequence),
+            "time" => Ok(Self::Time),
+            other => Err(crate::SerializerError::InvalidConfig(format!(
+                "unknown reorder key '{}'",
+                other
+            ))),
+        }
+    }
+}
+
+/// Buffer releasing events once they are `window` keys older than the
+/// newest event.
+#[derive(Debug, Clone)]
+pub struct ReorderBuffer<E> {
+    window: i64,
+    pending: Vec<(i64, u8, u64, E)>,
+    arrivals: u64,
+    newest: Option<i64>,
+    reordered: usize,
+}
+
+impl<E> ReorderBuffer<E> {
+    pub fn new(window: i64) -> Self {
+        Self {
+            window,
+            pending: Vec::new(),
+            arrivals: 0,
+            newest: None,
+            reordered: 0,
+        }
+    }
+
+    /// Add an event and return the events that left the window, in order.
+    pub fn push(&mut self, key: i64, priority: u8, event: E) -> Vec<E> {
+        if self.newest.is_some_and(|newest| key < newest) {
+            self.reordered += 1;
+        }
+        let newest = self.newest.map_or(key, |newest| newest.max(key));
+        self.newest = Some(newest);
+        self.arrivals += 1;
+        self.pending.push((key, priority, self.arrivals, event));
+        self.pending.sort_by_key(|&(key, priority, arrival, _)| (key, priority, arrival));
+
+        let released = self
+            .pending
+            .iter()
+            .take_while(|(key, ..)| *key <= newest.saturating_sub(self.window))
+            .count();
+        self.pending.drain(..released).map(|(.., event)| event).collect()
+    }
+
+    /// Release all held events, in order.
+    pub fn drain(&mut self) -> Vec<E> {
+        self.pending.drain(..).map(|(.., event)| event).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Events that arrived with a key older than an earlier event's.
+    pub fn reordered(&self) -> usize {
+        self.reordered
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reorder_buffer() {
+        let mut buffer = ReorderBuffer::new(3);
+        assert!(buffer.push(1, 3, "content a").is_empty());
+        assert!(buffer.push(1, 1, "tab a").is_empty());
+        assert!(buffer.push(3, 3, "output").is_empty());
+        // Arrived late, but still within the window
+        assert!(buffer.push(2, 3, "command").is_empty());
+        assert_eq!(buffer.push(4, 3, "content b"), vec!["tab a", "content a"]);
+        assert_eq!(buffer.drain(), vec!["command", "output", "content b"]);
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.reordered(), 1);
+    }
+}
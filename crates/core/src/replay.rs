@@ -0,0 +1,354 @@
+//! Replay verification of serialized conversations.
+//!
+//! Re-applies the emitted `sed` edits against the file captures shown earlier
+//! in a conversation and checks that every subsequent viewport read matches the
+//! replayed file state, as do the conversation's file checkpoints and the
+//! files' tracked state at its end.
+
+use std::collections::HashMap;
+use std::iter::Peekable;
+
+use serde::Serialize;
+
+use crate::conversation::{ConversationMessage, FileCheckpoint, FinalizedConversation};
+use crate::helpers::{line_numbered_output, stable_hash};
+use crate::sed::{self, SedCommand};
+
+/// Outcome of replaying a single conversation.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReplayOutcome {
+    /// Viewport outputs compared against replayed state.
+    pub checks: usize,
+    /// Checks where the replayed state did not match.
+    pub failures: usize,
+}
+
+impl ReplayOutcome {
+    pub fn passed(&self) -> bool {
+        self.checks > 0 && self.failures == 0
+    }
+}
+
+/// Aggregate replay verification results for a run.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct VerificationReport {
+    /// Conversations selected for verification.
+    pub sampled_conversations: usize,
+    /// Sampled conversations containing at least one verifiable viewport.
+    pub verified_conversations: usize,
+    /// Verified conversations whose replay matched everywhere.
+    pub passed_conversations: usize,
+    pub total_checks: usize,
+    pub failed_checks: usize,
+    /// `passed_conversations / verified_conversations` (1.0 if none verified).
+    pub fidelity: f64,
+}
+
+impl VerificationReport {
+    pub fn record(&mut self, outcome: ReplayOutcome) {
+        self.sampled_conversations += 1;
+        self.total_checks += outcome.checks;
+        self.failed_checks += outcome.failures;
+        if outcome.checks > 0 {
+            self.verified_conversations += 1;
+            if outcome.failures == 0 {
+                self.passed_conversations += 1;
+            }
+        }
+        self.fidelity = if self.verified_conversations > 0 {
+            self.passed_conversations as f64 / self.verified_conversations as f64
+        } else {
+            1.0
+        };
+    }
+}
+
+/// A serialized assistant action relevant for replay. Views are written
+/// `cat -n FILE | sed -n 'A,Bp'` here, but any `ViewCommand` is accepted.
+enum ReplayAction {
+    /// `cat -n FILE`
+    Capture { file: String },
+    /// `cat -n FILE | sed -n 'A,Bp'`
+    View { file: String, start: usize, end: usize },
+    /// `sed -i 'SCRIPT' FILE && cat -n FILE | sed -n 'A,Bp'`
+    Edit { file: String, script: SedCommand, start: usize, end: usize },
+    /// `CMD ... FILE && cat -n FILE | sed -n 'A,Bp'` for a command we cannot
+    /// replay (e.g. a formatter run).
+    Opaque { file: String },
+    /// `mv FROM TO`
+    Move { from: String, to: String },
+}
+
+fn parse_print_range(script: &str) -> Option<(usize, usize)> {
+    match sed::parse_script(script)? {
+        SedCommand::Print {
+            start: sed::Address::Line(s),
+            end: sed::Address::Line(e),
+        } => Some((s, e)),
+        _ => None,
+    }
+}
+
+/// Parse a view command (see `ViewCommand`) into its file and line range.
+fn parse_view<'a>(words: &[&'a str]) -> Option<(&'a str, Option<(usize, usize)>)> {
+    match *words {
+        ["cat" | "nl", "-n" | "-ba", file] => Some((file, None)),
+        ["sed", "-n", "1,$p", file, "|", "nl", "-ba"] => Some((file, None)),
+        ["cat" | "nl", "-n" | "-ba", file, "|", "sed", "-n", range] => {
+            Some((file, Some(parse_print_range(range)?)))
+        }
+        ["sed", "-n", range, file, "|", "nl", "-ba", "-v", first] => {
+            let (start, end) = parse_print_range(range)?;
+            (first.parse() == Ok(start)).then_some((file, Some((start, end))))
+        }
+        _ => None,
+    }
+}
+
+fn parse_action(message: &ConversationMessage) -> Option<ReplayAction> {
+    if message.from != "Assistant" {
+        return None;
+    }
+    let body = message.value.strip_prefix("```bash\n")?.strip_suffix("\n```\n")?;
+    let words = sed::split_shell_words(body)?;
+    let words: Vec<&str> = words.iter().map(String::as_str).collect();
+
+    if let ["mv", from, to] = words[..] {
+        return Some(ReplayAction::Move {
+            from: from.to_string(),
+            to: to.to_string(),
+        });
+    }
+    let Some(and) = words.iter().position(|&w| w == "&&") else {
+        return match parse_view(&words)? {
+            (file, None) => Some(ReplayAction::Capture { file: file.to_string() }),
+            (file, Some((start, end))) => Some(ReplayAction::View { file: file.to_string(), start, end }),
+        };
+    };
+    let (view_file, Some((start, end))) = parse_view(&words[and + 1..])? else {
+        return None;
+    };
+    match words[..and] {
+        ["sed", "-i", script, file] if file == view_file => Some(ReplayAction::Edit {
+            file: file.to_string(),
+            script: sed::parse_script(script)?,
+            start,
+            end,
+        }),
+        [.., file] if file == view_file => Some(ReplayAction::Opaque { file: file.to_string() }),
+        _ => None,
+    }
+}
+
+/// Extract the body of a `<stdout>` block.
+fn stdout_body(message: &ConversationMessage) -> Option<&str> {
+    if message.from != "User" {
+        return None;
+    }
+    message.value.strip_prefix("<stdout>\n")?.strip_suffix("\n</stdout>")
+}
+
+/// Viewport output without the `>` flags of selected lines (see
+/// `annotate_selections`).
+fn unmark_selection(listing: &str) -> String {
+    listing
+        .split('\n')
+        .map(|line| match line.strip_prefix('>') {
+            Some(rest) if rest.split_once('\t').is_some_and(|(num, _)| num.trim().parse::<usize>().is_ok()) => {
+                format!(" {}", rest)
+            }
+            _ => line.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Recover file lines from full `cat -n` output.
+fn parse_listing(listing: &str) -> Option<Vec<String>> {
+    if listing.is_empty() {
+        return Some(vec![String::new()]);
+    }
+    listing
+        .split('\n')
+        .enumerate()
+        .map(|(idx, line)| {
+            let (num, text) = line.split_once('\t')?;
+            (num.trim().parse::<usize>().ok()? == idx + 1).then(|| text.to_string())
+        })
+        .collect()
+}
+
+/// Check the checkpoints before message `end` against the replayed files.
+fn check_checkpoints(
+    checkpoints: &mut Peekable<std::slice::Iter<'_, FileCheckpoint>>,
+    end: usize,
+    files: &HashMap<String, Option<Vec<String>>>,
+    outcome: &mut ReplayOutcome,
+) {
+    while let Some(checkpoint) = checkpoints.next_if(|checkpoint| checkpoint.message <= end) {
+        if let Some(Some(lines)) = files.get(&checkpoint.file_path) {
+            outcome.checks += 1;
+            if format!("{:016x}", stable_hash(lines.join("\n").as_bytes())) != checkpoint.content_hash {
+                outcome.failures += 1;
+            }
+        }
+    }
+}
+
+/// Replay a conversation, checking each viewport, file checkpoint and the
+/// final state against the replayed state.
+///
+/// Files whose capture cannot be parsed (e.g. truncated) are skipped until
+/// they are captured again.
+pub fn replay_conversation(conversation: &FinalizedConversation) -> ReplayOutcome {
+    let mut outcome = ReplayOutcome::default();
+    let mut files: HashMap<String, Option<Vec<String>>> = HashMap::new();
+    let messages = &conversation.messages;
+    let mut checkpoints = conversation.checkpoints.iter().peekable();
+
+    for (idx, message) in messages.iter().enumerate() {
+        check_checkpoints(&mut checkpoints, idx, &files, &mut outcome);
+        let Some(action) = parse_action(message) else {
+            continue;
+        };
+        let stdout = messages.get(idx + 1).and_then(stdout_body);
+
+        match action {
+            ReplayAction::Capture { file } => {
+                files.insert(file, stdout.and_then(parse_listing));
+            }
+            ReplayAction::View { file, start, end } => {
+                if let Some(Some(lines)) = files.get(&file) {
+                    outcome.checks += 1;
+                    let expected = line_numbered_output(&lines.join("\n"), Some(start), Some(end));
+                    if stdout.map(unmark_selection).as_deref() != Some(expected.as_str()) {
+                        outcome.failures += 1;
+                    }
+                }
+            }
+            ReplayAction::Edit { file, script, start, end } => {
+                if let Some(Some(lines)) = files.get_mut(&file) {
+                    outcome.checks += 1;
+                    let matches = sed::apply(lines, &script).is_some() && {
+                        let expected =
+                            line_numbered_output(&lines.join("\n"), Some(start), Some(end));
+                        stdout == Some(expected.as_str())
+                    };
+                    if !matches {
+                        outcome.failures += 1;
+                        // Stop tracking a file once it diverged
+                        files.insert(file, None);
+                    }
+                }
+            }
+            ReplayAction::Opaque { file } => {
+                files.insert(file, None);
+            }
+            ReplayAction::Move { from, to } => {
+                if let Some(lines) = files.remove(&from) {
+                    files.insert(to, lines);
+                }
+            }
+        }
+    }
+    check_checkpoints(&mut checkpoints, messages.len(), &files, &mut outcome);
+    let mut final_state = conversation.final_state.iter().peekable();
+    check_checkpoints(&mut final_state, messages.len(), &files, &mut outcome);
+
+    outcome
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::budget::CharApproxTokenizer;
+    use crate::conversation::{ConversationStateManager, ConversationStateManagerConfig};
+
+    #[test]
+    fn test_replay_manager_output() {
+        let config = ConversationStateManagerConfig {
+            min_conversation_messages: 1,
+            ..Default::default()
+        };
+        let mut manager = ConversationStateManager::new(CharApproxTokenizer, config);
+        manager.handle_tab_event("/a.rs", Some("one\ntwo\nthree\nfour")).unwrap();
+        manager.handle_content_event("/a.rs", 4, 3, "it's").unwrap();
+        manager.handle_terminal_command_event("ls").unwrap();
+        manager.handle_content_event("/a.rs", 0, 4, "").unwrap();
+        manager.handle_terminal_command_event("ls").unwrap();
+        manager.handle_content_event("/a.rs", 0, 0, "new\n").unwrap();
+
+        let conversations = manager.get_conversations().unwrap();
+        let outcome = replay_conversation(&conversations[0]);
+        // Two edits, a view and the final state
+        assert_eq!(outcome.checks, 4);
+        assert!(outcome.passed());
+    }
+
+    #[test]
+    fn test_replay_checkpoints() {
+        let config = ConversationStateManagerConfig {
+            min_conversation_messages: 1,
+            checkpoint_every_edits: Some(2),
+            ..Default::default()
+        };
+        let mut manager = ConversationStateManager::new(CharApproxTokenizer, config);
+        manager.handle_tab_event("/a.rs", Some("one\ntwo\nthree\n")).unwrap();
+        manager.handle_tab_event("/b.rs", Some("b\n")).unwrap();
+        for edit in ["1", "2", "3", "4"] {
+            manager.handle_content_event("/a.rs", 0, 0, edit).unwrap();
+            manager.handle_terminal_command_event("ls").unwrap();
+        }
+
+        let mut conversations = manager.get_conversations().unwrap();
+        let conversation = &mut conversations[0];
+        // Both files, after the second and the fourth edit, and at the end
+        assert_eq!(conversation.checkpoints.len(), 4);
+        assert_eq!(conversation.final_state.len(), 2);
+        assert_eq!(replay_conversation(conversation), ReplayOutcome { checks: 10, failures: 0 });
+
+        conversation.checkpoints[2].content_hash = "0".repeat(16);
+        assert_eq!(replay_conversation(conversation), ReplayOutcome { checks: 10, failures: 1 });
+        conversation.final_state[0].content_hash = "0".repeat(16);
+        assert_eq!(replay_conversation(conversation), ReplayOutcome { checks: 10, failures: 2 });
+    }
+
+    #[test]
+    fn test_replay_detects_mismatch() {
+        let conversation = FinalizedConversation {
+            messages: vec![
+                ConversationMessage::assistant("```bash\ncat -n /a.rs\n```\n"),
+                ConversationMessage::user("<stdout>\n     1\tone\n     2\ttwo\n</stdout>"),
+                ConversationMessage::assistant("```bash\ncat -n /a.rs | sed -n '1,2p'\n```\n"),
+                ConversationMessage::user("<stdout>\n     1\tone\n     2\tTWO\n</stdout>"),
+            ],
+            ..Default::default()
+        };
+        let outcome = replay_conversation(&conversation);
+        assert_eq!(outcome, ReplayOutcome { checks: 1, failures: 1 });
+    }
+
+    #[test]
+    fn test_replay_view_command_mix() {
+        let config = ConversationStateManagerConfig {
+            min_conversation_messages: 1,
+            view_commands: "cat=1,nl=1,sed-nl=1".parse().unwrap(),
+            ..Default::default()
+        };
+        let mut manager = ConversationStateManager::new(CharApproxTokenizer, config);
+        manager.handle_tab_event("/a.rs", Some("one\ntwo\nthree\nfour")).unwrap();
+        for i in 0..12 {
+            manager.handle_content_event("/a.rs", 0, 0, &format!("{}\n", i)).unwrap();
+            manager.handle_terminal_command_event("ls").unwrap();
+        }
+
+        let conversations = manager.get_conversations().unwrap();
+        let text: String = conversations[0].messages.iter().map(|m| m.value.as_str()).collect();
+        assert!(text.contains("cat -n /a.rs"));
+        assert!(text.contains("nl -ba /a.rs"));
+        assert!(text.contains("| nl -ba -v "));
+        let outcome = replay_conversation(&conversations[0]);
+        assert_eq!(outcome.checks, 13);
+        assert!(outcome.passed());
+    }
+}
@@ -0,0 +1,322 @@
+//! Builder API for embedding the pipeline in other services.
+//!
+//! `process_all_sessions` is what the CLI needs: progress on stderr and every
+//! session in memory. `Pipeline` runs the same processing quietly, reports
+//! progress to a callback, stops when its `CancellationToken` is cancelled
+//! and can hand each session to a `SessionSink` as soon as it is done.
+//! Warnings are returned in `PipelineRun`, and only printed by an aggregator
+//! made with `WarningAggregator::new`.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use rayon::prelude::*;
+
+use crate::cancel::{CancellationToken, SessionInterrupt};
+use crate::error::SerializerError;
+use crate::pipeline::{
+    append_variants, discover_csv_files, event_type_matches, process_stitched_session_until,
+    tokenize_conversations_until, PipelineConfig, SessionResult, EVENT_TYPES,
+};
+use crate::stitch::{stitch_sessions, StitchedSession};
+use crate::warnings::Warning;
+use crate::Tokenizer;
+
+/// A step of a pipeline run, reported to the progress callback.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PipelineProgress {
+    /// CSV files were grouped into sessions (with `PipelineConfig::stitch`).
+    Stitched { files: usize, sessions: usize },
+    /// A session finished; `failed` of the `processed` sessions failed.
+    Session { processed: usize, failed: usize, total: usize },
+    /// Deferred tokenization of the collected sessions started.
+    Tokenizing,
+}
+
+/// Receiver of processed sessions, in completion order. An error stops the
+/// run and is returned by `Pipeline::run`.
+pub trait SessionSink: Send {
+    fn write_session(&mut self, session: SessionResult) -> Result<(), SerializerError>;
+}
+
+impl SessionSink for Vec<SessionResult> {
+    fn write_session(&mut self, session: SessionResult) -> Result<(), SerializerError> {
+        self.push(session);
+        Ok(())
+    }
+}
+
+impl<S: SessionSink + ?Sized> SessionSink for &mut S {
+    fn write_session(&mut self, session: SessionResult) -> Result<(), SerializerError> {
+        (**self).write_session(session)
+    }
+}
+
+/// Outcome of `Pipeline::run`.
+#[derive(Debug, Default)]
+pub struct PipelineRun {
+    /// The sessions in discovery order; empty if a sink received them.
+    pub sessions: Vec<SessionResult>,
+    /// Sessions processed, including failed ones.
+    pub processed: usize,
+    /// Sessions that failed (see `PipelineConfig::warnings`), including
+    /// timed-out ones.
+    pub failed: usize,
+    /// Sessions stopped by `PipelineConfig::session_timeout_secs`.
+    pub timed_out: usize,
+    /// Warnings recorded by `PipelineConfig::warnings`, by category.
+    pub warning_counts: BTreeMap<String, usize>,
+    /// The first warnings of each category.
+    pub warnings: Vec<Warning>,
+}
+
+type ProgressCallback<'a> = Box<dyn Fn(&PipelineProgress) + Send + Sync + 'a>;
+
+/// A configured pipeline run over the sessions under a CSV root.
+pub struct Pipeline<'a, T> {
+    tokenizer: &'a T,
+    config: PipelineConfig,
+    progress: Option<ProgressCallback<'a>>,
+    cancellation: CancellationToken,
+    sink: Option<Mutex<Box<dyn SessionSink + 'a>>>,
+}
+
+impl<'a, T> Pipeline<'a, T>
+where
+    T: Tokenizer + Sync + Send,
+{
+    pub fn new(tokenizer: &'a T) -> Self {
+        Self {
+            tokenizer,
+            config: PipelineConfig::default(),
+            progress: None,
+            cancellation: CancellationToken::new(),
+            sink: None,
+        }
+    }
+
+    pub fn config(mut self, config: PipelineConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Call `progress` after each step; it may be called from worker threads.
+    pub fn on_progress(mut self, progress: impl Fn(&PipelineProgress) + Send + Sync + 'a) -> Self {
+        self.progress = Some(Box::new(progress));
+        self
+    }
+
+    pub fn cancellation(mut self, token: CancellationToken) -> Self {
+        self.cancellation = token;
+        self
+    }
+
+    /// Hand each session to `sink` instead of collecting them. With deferred
+    /// tokenization, sessions are tokenized before they are handed over.
+    pub fn sink(mut self, sink: impl SessionSink + 'a) -> Self {
+        self.sink = Some(Mutex::new(Box::new(sink)));
+        self
+    }
+
+    fn report(&self, progress: PipelineProgress) {
+        if let Some(callback) = &self.progress {
+            callback(&progress);
+        }
+    }
+
+    fn check_cancelled(&self) -> Result<(), SerializerError> {
+        if self.cancellation.is_cancelled() {
+            return Err(SerializerError::Cancelled);
+        }
+        Ok(())
+    }
+
+    /// Process the sessions under `csv_root`.
+    pub fn run(mut self, csv_root: &Path) -> Result<PipelineRun, SerializerError> {
+        self.config.csv_root.get_or_insert_with(|| csv_root.to_path_buf());
+        let config = &self.config;
+        if let Some(unknown) = config
+            .ignored_event_types
+            .iter()
+            .find(|name| !EVENT_TYPES.iter().any(|t| event_type_matches(name, t)))
+        {
+            return Err(SerializerError::InvalidConfig(format!(
+                "unknown event type '{}' (known: {})",
+                unknown,
+                EVENT_TYPES.join(", ")
+            )));
+        }
+        if let Some(blur) = &config.timing_blur {
+            blur.validate()?;
+        }
+        let csv_files = discover_csv_files(csv_root);
+        if csv_files.is_empty() {
+            return Err(SerializerError::NoCsvFiles(csv_root.to_path_buf()));
+        }
+        let sessions = match &config.stitch {
+            Some(stitch) => {
+                let files = csv_files.len();
+                let sessions = stitch_sessions(csv_files, stitch, &config.session_manifest, &config.warnings)?;
+                self.report(PipelineProgress::Stitched {
+                    files,
+                    sessions: sessions.len(),
+                });
+                sessions
+            }
+            None => csv_files.iter().map(|path| StitchedSession::single(path)).collect(),
+        };
+        self.check_cancelled()?;
+
+        let total = sessions.len();
+        let processed_count = AtomicUsize::new(0);
+        let error_count = AtomicUsize::new(0);
+        let timeout_count = AtomicUsize::new(0);
+        let timeout = config.session_timeout_secs.map(Duration::from_secs);
+        let sink = self.sink.take();
+        let sink_error: Mutex<Option<SerializerError>> = Mutex::new(None);
+        let this = &self;
+        let config = &this.config;
+
+        let mut results: Vec<SessionResult> = sessions
+            .into_par_iter()
+            .filter_map(|stitched| {
+                if this.cancellation.is_cancelled() {
+                    return None;
+                }
+                let interrupt = SessionInterrupt::new(Some(this.cancellation.clone()), timeout);
+                let result = process_stitched_session_until(&stitched, this.tokenizer, config, &interrupt)
+                    .and_then(|mut session| {
+                        if let Some(augmentation) = &config.augmentation {
+                            append_variants(&stitched, this.tokenizer, config, augmentation, &mut session, &interrupt)?;
+                        }
+                        if sink.is_some() && config.deferred_tokenization {
+                            tokenize_conversations_until(
+                                session.conversations.iter_mut(),
+                                this.tokenizer,
+                                config.max_tokens_per_message,
+                                config.tokenization_batch_size,
+                                &interrupt,
+                            )?;
+                        }
+                        Ok(session)
+                    });
+                let session = match result {
+                    Ok(session) => Some(session),
+                    Err(SerializerError::Cancelled) => return None,
+                    Err(e) => {
+                        error_count.fetch_add(1, Ordering::Relaxed);
+                        let category = if let SerializerError::TimedOut(_) = e {
+                            timeout_count.fetch_add(1, Ordering::Relaxed);
+                            "session_timeout"
+                        } else {
+                            "session_error"
+                        };
+                        config
+                            .warnings
+                            .warn(category, format!("Error processing {:?}: {}", stitched.first(), e));
+                        None
+                    }
+                };
+                this.report(PipelineProgress::Session {
+                    processed: processed_count.fetch_add(1, Ordering::Relaxed) + 1,
+                    failed: error_count.load(Ordering::Relaxed),
+                    total,
+                });
+                let session = session?;
+                let Some(sink) = &sink else {
+                    return Some(session);
+                };
+                if let Err(e) = sink.lock().unwrap().write_session(session) {
+                    sink_error.lock().unwrap().get_or_insert(e);
+                    this.cancellation.cancel();
+                }
+                None
+            })
+            .collect();
+
+        if let Some(e) = sink_error.into_inner().unwrap() {
+            return Err(e);
+        }
+        self.check_cancelled()?;
+        config.warnings.finish()?;
+
+        if config.deferred_tokenization && sink.is_none() {
+            self.report(PipelineProgress::Tokenizing);
+            tokenize_conversations_until(
+                results.iter_mut().flat_map(|r| r.conversations.iter_mut()),
+                self.tokenizer,
+                config.max_tokens_per_message,
+                config.tokenization_batch_size,
+                &SessionInterrupt::new(Some(self.cancellation.clone()), None),
+            )?;
+        }
+
+        Ok(PipelineRun {
+            sessions: results,
+            processed: processed_count.into_inner(),
+            failed: error_count.into_inner(),
+            timed_out: timeout_count.into_inner(),
+            warning_counts: config.warnings.counts(),
+            warnings: config.warnings.samples(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::budget::CharApproxTokenizer;
+
+    #[test]
+    fn test_pipeline_sink_and_cancellation() {
+        let temp = tempfile::tempdir().unwrap();
+        for name in ["a.csv", "b.csv"] {
+            std::fs::write(
+                temp.path().join(name),
+                "Time,File,RangeOffset,RangeLength,Text,Type\n0,/a.rs,0,0,fn main() {},tab\n",
+            )
+            .unwrap();
+        }
+        let tokenizer = CharApproxTokenizer;
+        let config = PipelineConfig {
+            min_conversation_messages: 1,
+            ..Default::default()
+        };
+
+        let progress = Mutex::new(Vec::new());
+        let mut received = Vec::new();
+        let run = Pipeline::new(&tokenizer)
+            .config(config.clone())
+            .on_progress(|p| progress.lock().unwrap().push(p.clone()))
+            .sink(&mut received)
+            .run(temp.path())
+            .unwrap();
+        assert!(run.sessions.is_empty());
+        assert_eq!((run.processed, run.failed), (2, 0));
+        assert_eq!(received.len(), 2);
+        assert!(progress.into_inner().unwrap().contains(&PipelineProgress::Session {
+            processed: 2,
+            failed: 0,
+            total: 2
+        }));
+
+        // Warnings are returned, not printed
+        std::fs::write(
+            temp.path().join("c.csv"),
+            "Time,File,RangeOffset,RangeLength,Text,Type\n0,/a.rs,0,0,,bogus\n",
+        )
+        .unwrap();
+        let run = Pipeline::new(&tokenizer).config(config.clone()).run(temp.path()).unwrap();
+        assert_eq!(run.warning_counts.get("unknown_event_type"), Some(&1));
+        assert_eq!(run.warnings[0].category, "unknown_event_type");
+        std::fs::remove_file(temp.path().join("c.csv")).unwrap();
+
+        let token = CancellationToken::new();
+        token.cancel();
+        let result = Pipeline::new(&tokenizer).config(config).cancellation(token).run(temp.path());
+        assert!(matches!(result, Err(SerializerError::Cancelled)));
+    }
+}
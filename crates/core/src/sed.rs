@@ -0,0 +1,406 @@
+//! Minimal sed interpreter for the command forms emitted by the serializer.
+//!
+//! Supports `Ni\`, `$a\`, `A,Bc\`, `A,Bd`, `A,Bp` and `A,Bs/RE/REPL/FLAGS`
+//! scripts operating on a file held as a list of lines (split on `\n`, as
+//! `cat -n` shows them).
+//!
+//! Replay verification uses it instead of a system `sed`, so results do not
+//! depend on the platform's sed flavor; `run_sed` exposes it to other tools.
+
+/// Line address in a sed script.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Address {
+    Line(usize),
+    Last,
+}
+
+/// A parsed sed script.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum SedCommand {
+    /// `Ni\` - insert lines before line N.
+    Insert { line: Address, text: Vec<String> },
+    /// `Na\` - append lines after line N.
+    Append { line: Address, text: Vec<String> },
+    /// `A,Bc\` - replace lines A..=B.
+    Change { start: Address, end: Address, text: Vec<String> },
+    /// `A,Bd` - delete lines A..=B.
+    Delete { start: Address, end: Address },
+    /// `A,Bp` - print lines A..=B (used with `-n`).
+    Print { start: Address, end: Address },
+    /// `A,Bs/RE/REPL/FLAGS` - substitute within lines A..=B. `pattern` and
+    /// `replacement` are already translated to `regex` syntax.
+    Substitute {
+        start: Address,
+        end: Address,
+        pattern: String,
+        replacement: String,
+        global: bool,
+    },
+}
+
+fn parse_address(s: &str) -> Option<Address> {
+    if s == "$" {
+        Some(Address::Last)
+    } else {
+        s.parse().ok().map(Address::Line)
+    }
+}
+
+fn resolve(addr: Address, lines: &[String]) -> usize {
+    match addr {
+        Address::Line(n) => n,
+        Address::Last => lines.len(),
+    }
+}
+
+/// Parse a single sed script (the unquoted argument).
+pub(crate) fn parse_script(script: &str) -> Option<SedCommand> {
+    let address_len = script
+        .find(|c: char| !(c.is_ascii_digit() || c == '$' || c == ','))
+        .unwrap_or(script.len());
+    if let Some(rest) = script[address_len..].strip_prefix('s') {
+        return parse_substitution(&script[..address_len], rest);
+    }
+
+    // Text commands: address part, command char, backslash, newline, payload
+    if let Some((head, payload)) = script.split_once("\\\n") {
+        let cmd = head.chars().last()?;
+        let addrs = &head[..head.len() - 1];
+        let text: Vec<String> = payload.split('\n').map(str::to_string).collect();
+        return match cmd {
+            'i' => Some(SedCommand::Insert { line: parse_address(addrs)?, text }),
+            'a' => Some(SedCommand::Append { line: parse_address(addrs)?, text }),
+            'c' => {
+                let (start, end) = parse_range(addrs)?;
+                Some(SedCommand::Change { start, end, text })
+            }
+            _ => None,
+        };
+    }
+
+    let cmd = script.chars().last()?;
+    let (start, end) = parse_range(&script[..script.len() - cmd.len_utf8()])?;
+    match cmd {
+        'd' => Some(SedCommand::Delete { start, end }),
+        'p' => Some(SedCommand::Print { start, end }),
+        _ => None,
+    }
+}
+
+/// Parse the `/RE/REPL/FLAGS` part of an `s` command.
+fn parse_substitution(addrs: &str, rest: &str) -> Option<SedCommand> {
+    let (start, end) = if addrs.is_empty() {
+        (Address::Line(1), Address::Last)
+    } else {
+        parse_range(addrs)?
+    };
+    let delimiter = rest.chars().next()?;
+    let parts = split_on_delimiter(&rest[delimiter.len_utf8()..], delimiter);
+    let [pattern, replacement, flags] = parts.as_slice() else {
+        return None;
+    };
+
+    let mut global = false;
+    let mut ignore_case = false;
+    for flag in flags.chars() {
+        match flag {
+            'g' => global = true,
+            'I' | 'i' => ignore_case = true,
+            _ => return None,
+        }
+    }
+    let pattern = bre_to_regex(pattern)?;
+    Some(SedCommand::Substitute {
+        start,
+        end,
+        pattern: if ignore_case { format!("(?i){}", pattern) } else { pattern },
+        replacement: bre_replacement_to_regex(replacement)?,
+        global,
+    })
+}
+
+/// Split on unescaped `delimiter`, keeping escapes intact.
+pub(crate) fn split_on_delimiter(s: &str, delimiter: char) -> Vec<String> {
+    let mut parts = vec![String::new()];
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            parts.last_mut().unwrap().push(c);
+            if let Some(next) = chars.next() {
+                parts.last_mut().unwrap().push(next);
+            }
+        } else if c == delimiter {
+            parts.push(String::new());
+        } else {
+            parts.last_mut().unwrap().push(c);
+        }
+    }
+    parts
+}
+
+/// Translate a (GNU) basic regular expression into `regex` syntax.
+///
+/// Covers the subset shared with Vim's default "magic" patterns: `.`, `*`,
+/// `^`, `$`, bracket expressions, `\(\)`, `\|`, `\+`, `\?`, `\<\>` and the
+/// `\d\w\s` classes. Returns `None` for anything else (e.g. `\{n,m\}`).
+pub(crate) fn bre_to_regex(pattern: &str) -> Option<String> {
+    let mut out = String::with_capacity(pattern.len());
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next()? {
+                c @ ('(' | ')' | '|' | '+' | '?') => out.push(c),
+                '<' | '>' => out.push_str("\\b"),
+                c @ ('d' | 'w' | 's' | 'D' | 'W' | 'S' | 't') => {
+                    out.push('\\');
+                    out.push(c);
+                }
+                '{' | '}' | '=' => return None,
+                c if !c.is_alphanumeric() => out.push_str(&regex::escape(&c.to_string())),
+                _ => return None,
+            },
+            '.' | '*' | '^' | '$' | '[' | ']' => out.push(c),
+            _ => out.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    Some(out)
+}
+
+/// Translate a sed replacement (`&`, `\1`..`\9`) into `regex` syntax.
+pub(crate) fn bre_replacement_to_regex(replacement: &str) -> Option<String> {
+    let mut out = String::with_capacity(replacement.len());
+    let mut chars = replacement.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next()? {
+                d @ '1'..='9' => out.push_str(&format!("${{{}}}", d)),
+                '$' => out.push_str("$$"),
+                c if !c.is_alphanumeric() => out.push(c),
+                _ => return None,
+            },
+            '&' => out.push_str("${0}"),
+            '$' => out.push_str("$$"),
+            c => out.push(c),
+        }
+    }
+    Some(out)
+}
+
+fn parse_range(s: &str) -> Option<(Address, Address)> {
+    match s.split_once(',') {
+        Some((a, b)) => Some((parse_address(a)?, parse_address(b)?)),
+        None => {
+            let addr = parse_address(s)?;
+            Some((addr, addr))
+        }
+    }
+}
+
+/// Apply an in-place command to `lines`.
+///
+/// Returns `None` when an address falls outside the file, which sed itself
+/// would silently ignore but which indicates a replay mismatch for us.
+pub(crate) fn apply(lines: &mut Vec<String>, cmd: &SedCommand) -> Option<()> {
+    match cmd {
+        SedCommand::Insert { line, text } => {
+            let n = resolve(*line, lines);
+            if n == 0 || n > lines.len().max(1) {
+                return None;
+            }
+            let idx = (n - 1).min(lines.len());
+            lines.splice(idx..idx, text.iter().cloned());
+        }
+        SedCommand::Append { line, text } => {
+            let n = resolve(*line, lines);
+            if n > lines.len() {
+                return None;
+            }
+            lines.splice(n..n, text.iter().cloned());
+        }
+        SedCommand::Change { start, end, text } => {
+            let (s, e) = (resolve(*start, lines), resolve(*end, lines));
+            if s == 0 || s > e || e > lines.len() {
+                return None;
+            }
+            lines.splice(s - 1..e, text.iter().cloned());
+        }
+        SedCommand::Delete { start, end } => {
+            let (s, e) = (resolve(*start, lines), resolve(*end, lines));
+            if s == 0 || s > e || e > lines.len() {
+                return None;
+            }
+            lines.drain(s - 1..e);
+        }
+        SedCommand::Print { .. } => {}
+        SedCommand::Substitute {
+            start,
+            end,
+            pattern,
+            replacement,
+            global,
+        } => {
+            let (s, e) = (resolve(*start, lines), resolve(*end, lines));
+            if s == 0 || s > e || e > lines.len() {
+                return None;
+            }
+            let re = regex::Regex::new(pattern).ok()?;
+            let limit = if *global { 0 } else { 1 };
+            for line in &mut lines[s - 1..e] {
+                if let std::borrow::Cow::Owned(replaced) =
+                    re.replacen(line, limit, replacement.as_str())
+                {
+                    *line = replaced;
+                }
+            }
+        }
+    }
+    Some(())
+}
+
+/// Run a sed script on `content`, as `sed -i SCRIPT` would edit it or, for
+/// `A,Bp` scripts, as `sed -n SCRIPT` would print it.
+///
+/// Returns `None` for unsupported scripts and out-of-range addresses.
+pub fn run_sed(script: &str, content: &str) -> Option<String> {
+    let mut lines: Vec<String> = content.split('\n').map(str::to_string).collect();
+    match parse_script(script)? {
+        SedCommand::Print { start, end } => {
+            let (s, e) = (resolve(start, &lines), resolve(end, &lines));
+            if s == 0 || s > e || e > lines.len() {
+                return None;
+            }
+            Some(lines[s - 1..e].iter().map(|line| format!("{}\n", line)).collect())
+        }
+        command => {
+            apply(&mut lines, &command)?;
+            Some(lines.join("\n"))
+        }
+    }
+}
+
+/// Split a shell command line into words and operators (`|`, `&&`, `||`, `;`).
+///
+/// Handles single quotes, double quotes and backslash escapes, which covers
+/// the quoting produced by `escape_single_quotes_for_sed`.
+pub(crate) fn split_shell_words(command: &str) -> Option<Vec<String>> {
+    let mut words = Vec::new();
+    let mut current = String::new();
+    let mut in_word = false;
+    let mut chars = command.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => {
+                in_word = true;
+                loop {
+                    match chars.next()? {
+                        '\'' => break,
+                        ch => current.push(ch),
+                    }
+                }
+            }
+            '"' => {
+                in_word = true;
+                loop {
+                    match chars.next()? {
+                        '"' => break,
+                        '\\' if matches!(chars.peek(), Some('"' | '\\' | '$' | '`')) => {
+                            current.push(chars.next()?);
+                        }
+                        ch => current.push(ch),
+                    }
+                }
+            }
+            '\\' => {
+                in_word = true;
+                current.push(chars.next()?);
+            }
+            '|' | '&' | ';' => {
+                if in_word {
+                    words.push(std::mem::take(&mut current));
+                    in_word = false;
+                }
+                let mut op = c.to_string();
+                if c != ';' && chars.peek() == Some(&c) {
+                    op.push(chars.next()?);
+                }
+                words.push(op);
+            }
+            c if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut current));
+                    in_word = false;
+                }
+            }
+            c => {
+                in_word = true;
+                current.push(c);
+            }
+        }
+    }
+    if in_word {
+        words.push(current);
+    }
+    Some(words)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(s: &str) -> Vec<String> {
+        s.split('\n').map(str::to_string).collect()
+    }
+
+    #[test]
+    fn test_parse_and_apply() {
+        let mut file = lines("a\nb\nc");
+
+        apply(&mut file, &parse_script("2c\\\nB\nB2").unwrap()).unwrap();
+        assert_eq!(file, lines("a\nB\nB2\nc"));
+
+        apply(&mut file, &parse_script("1i\\\nstart").unwrap()).unwrap();
+        apply(&mut file, &parse_script("$a\\\nend").unwrap()).unwrap();
+        assert_eq!(file, lines("start\na\nB\nB2\nc\nend"));
+
+        apply(&mut file, &parse_script("2,4d").unwrap()).unwrap();
+        assert_eq!(file, lines("start\nc\nend"));
+
+        assert!(apply(&mut file, &parse_script("5,6d").unwrap()).is_none());
+    }
+
+    #[test]
+    fn test_substitute() {
+        let mut file = lines("foo(a) foo\nfoo.bar\nfoo");
+
+        apply(&mut file, &parse_script("1,2s/foo/X/").unwrap()).unwrap();
+        assert_eq!(file, lines("X(a) foo\nX.bar\nfoo"));
+
+        apply(&mut file, &parse_script("s#\\(X\\)(a)#[\\1]\\#&#g").unwrap()).unwrap();
+        assert_eq!(file, lines("[X]#X(a) foo\nX.bar\nfoo"));
+
+        apply(&mut file, &parse_script("2,$s/\\<FOO\\>/$/gI").unwrap()).unwrap();
+        assert_eq!(file, lines("[X]#X(a) foo\nX.bar\n$"));
+
+        assert!(parse_script("1s/a\\{2\\}/b/").is_none());
+        assert!(parse_script("1s/a/b/x").is_none());
+    }
+
+    #[test]
+    fn test_run_sed() {
+        assert_eq!(run_sed("2,3p", "a\nb\nc\nd").as_deref(), Some("b\nc\n"));
+        assert_eq!(run_sed("$a\\\nz", "a\nb").as_deref(), Some("a\nb\nz"));
+        assert_eq!(run_sed("3,4p", "a\nb"), None);
+        assert_eq!(run_sed("1y/a/b/", "a"), None);
+    }
+
+    #[test]
+    fn test_split_shell_words() {
+        let words = split_shell_words("sed -i '1c\\\nit'\"'\"'s' f.rs && cat -n f.rs | sed -n '1,3p'")
+            .unwrap();
+        assert_eq!(
+            words,
+            vec!["sed", "-i", "1c\\\nit's", "f.rs", "&&", "cat", "-n", "f.rs", "|", "sed", "-n", "1,3p"]
+        );
+    }
+}
@@ -0,0 +1,78 @@
+//! Loss spans: the decision-relevant parts of assistant messages.
+//!
+//! An assistant message is mostly boilerplate the model cannot get wrong:
+//! the code fence and, for edits, the view command appended to the edit.
+//! `loss_spans` marks the rest (the command, the `sed` payload) so a trainer
+//! with span-level loss masks can focus the loss on it. The renderer records
+//! where that part is when it builds the message from its `Action`.
+
+use crate::conversation::ConversationMessage;
+use crate::helpers::floor_char_boundary;
+
+/// Character ranges `[start, end)` of the decision-relevant parts of an
+/// assistant message; empty for other roles. Offsets count Unicode scalar
+/// values, like Python string indices.
+///
+/// Uses the message's `loss_span`, cut to the value if it was truncated.
+/// Messages without one (injected text) are trained without their fence.
+pub fn loss_spans(message: &ConversationMessage) -> Vec<(usize, usize)> {
+    if message.from != "Assistant" {
+        return Vec::new();
+    }
+    let value = message.value.as_str();
+    let (mut start, mut end) = (0, value.trim_end().len());
+    if let Some(span) = &message.loss_span {
+        (start, end) = (floor_char_boundary(value, span.start), floor_char_boundary(value, span.end));
+    } else if let Some(fenced) = value.strip_prefix("```") {
+        if let Some((_, body)) = fenced.split_once('\n') {
+            start = value.len() - body.len();
+            end = start + body.trim_end().strip_suffix("```").unwrap_or(body).trim_end().len();
+        }
+    }
+    if start >= end {
+        return Vec::new();
+    }
+    let chars = |byte: usize| value[..byte].chars().count();
+    vec![(chars(start), chars(end))]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helpers::Viewport;
+    use crate::render::{Action, BashRenderer, FileEdit, PowerShellRenderer, Renderer};
+
+    fn trained(message: &ConversationMessage) -> String {
+        let spans = loss_spans(message);
+        message.value.chars().skip(spans[0].0).take(spans[0].1 - spans[0].0).collect()
+    }
+
+    #[test]
+    fn test_loss_spans() {
+        let command = ConversationMessage::assistant("```bash\ncargo test\n```\n");
+        assert_eq!(loss_spans(&command), vec![(8, 18)]);
+
+        let lines = ["let é = a && b;".to_string()];
+        let action = Action::EditFile {
+            path: "a.rs",
+            edit: FileEdit::Replace {
+                start: 2,
+                end: 2,
+                lines: &lines,
+            },
+            view: Viewport { start: 1, end: 5 },
+        };
+        let edit = |renderer: &dyn Renderer| {
+            let (value, span) = renderer.render_action_with_span(&action);
+            ConversationMessage::assistant(value).with_loss_span(span)
+        };
+        assert_eq!(trained(&edit(&BashRenderer::default())), "sed -i '2,2c\\\nlet é = a && b;' a.rs");
+        assert_eq!(
+            trained(&edit(&PowerShellRenderer)),
+            "$f = 'a.rs'; $l = [Collections.Generic.List[string]](Get-Content $f); \
+             $l.RemoveRange(1, 1); $l.InsertRange(1, [string[]]@('let é = a && b;')); Set-Content $f $l"
+        );
+
+        assert!(loss_spans(&ConversationMessage::user("<stdout>\nok\n</stdout>")).is_empty());
+    }
+}
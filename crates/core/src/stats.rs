@@ -0,0 +1,547 @@
+//! Dataset statistics over processed sessions.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::path::Path;
+
+use rayon::prelude::*;
+use serde::Serialize;
+
+use crate::conversation::DropStats;
+use crate::error::SerializerError;
+use crate::pipeline::SessionResult;
+use crate::Tokenizer;
+
+/// Tools whose first non-flag argument is a subcommand worth keeping.
+const SUBCOMMAND_TOOLS: &[&str] = &[
+    "apt", "brew", "cargo", "conda", "docker", "gh", "git", "go", "kubectl", "make", "npm",
+    "pip", "pip3", "pnpm", "poetry", "uv", "yarn",
+];
+
+/// Number of commands listed in the Markdown report.
+const TOP_COMMANDS: usize = 25;
+
+/// Normalize a terminal command to a coarse template for frequency counting.
+///
+/// Uses the first line, skips leading `VAR=value` assignments and `sudo`,
+/// strips the program's directory, and keeps the subcommand for tools like
+/// `git` or `cargo` (`git status`, `cargo test`).
+pub fn command_template(command: &str) -> String {
+    let first_line = command.replace("\\n", "\n");
+    let first_line = first_line.lines().next().unwrap_or("");
+    let mut words = first_line
+        .split_whitespace()
+        .skip_while(|w| *w == "sudo" || (w.contains('=') && !w.starts_with('-')));
+
+    let Some(program) = words.next() else {
+        return String::new();
+    };
+    let program = program.rsplit('/').next().unwrap_or(program);
+    if SUBCOMMAND_TOOLS.contains(&program) {
+        if let Some(sub) = words.find(|w| !w.starts_with('-')) {
+            return format!("{} {}", program, sub);
+        }
+    }
+    program.to_string()
+}
+
+/// Distribution summary with power-of-two buckets.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Histogram {
+    pub count: usize,
+    pub min: usize,
+    pub max: usize,
+    pub mean: f64,
+    pub p50: usize,
+    pub p90: usize,
+    pub p99: usize,
+    /// Bucket upper bound (inclusive) -> number of values. Bounds are powers of two.
+    pub buckets: BTreeMap<usize, usize>,
+}
+
+impl Histogram {
+    pub fn from_values(mut values: Vec<usize>) -> Self {
+        if values.is_empty() {
+            return Self::default();
+        }
+        values.sort_unstable();
+        let percentile = |p: f64| values[((values.len() - 1) as f64 * p).round() as usize];
+
+        let mut buckets = BTreeMap::new();
+        for &v in &values {
+            *buckets.entry(v.next_power_of_two()).or_insert(0) += 1;
+        }
+
+        Self {
+            count: values.len(),
+            min: values[0],
+            max: values[values.len() - 1],
+            mean: values.iter().sum::<usize>() as f64 / values.len() as f64,
+            p50: percentile(0.5),
+            p90: percentile(0.9),
+            p99: percentile(0.99),
+            buckets,
+        }
+    }
+}
+
+/// Per-language totals. A multi-language conversation counts towards each of
+/// its languages, so these do not sum to the dataset totals.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct LanguageStats {
+    pub conversations: usize,
+    pub tokens: usize,
+    /// Fraction of all conversations involving this language.
+    pub conversation_share: f64,
+}
+
+/// Aggregate statistics for a produced dataset.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DatasetStats {
+    pub sessions: usize,
+    pub conversations: usize,
+    pub messages: usize,
+    pub tokens: usize,
+    pub tokens_per_conversation: Histogram,
+    pub messages_per_conversation: Histogram,
+    /// Wall-clock session duration in seconds (sessions with timestamps only).
+    pub session_duration_secs: Histogram,
+    /// Active time per session in seconds, excluding idle gaps.
+    pub session_active_secs: Histogram,
+    /// Events per wall-clock minute, per session.
+    pub session_events_per_minute: Histogram,
+    pub total_duration_secs: u64,
+    pub total_active_secs: u64,
+    /// Input rows per CSV event type.
+    pub event_type_counts: BTreeMap<String, usize>,
+    /// Tokens per message role.
+    pub role_tokens: BTreeMap<String, usize>,
+    /// Fraction of all tokens per message role.
+    pub role_token_share: BTreeMap<String, f64>,
+    /// Tokens per message kind (see `MessageKind`).
+    pub kind_tokens: BTreeMap<String, usize>,
+    pub truncated_messages: usize,
+    /// Fraction of messages that were truncated.
+    pub truncation_rate: f64,
+    pub viewport_emissions: usize,
+    pub coalesced_edits: usize,
+    pub edit_commands: usize,
+    pub truncated_outputs: usize,
+    pub whitespace_only_edits: usize,
+    pub snapshot_diffs: usize,
+    pub compacted_messages: usize,
+    pub compacted_tokens: usize,
+    /// Fraction of pre-compaction tokens removed by the compaction pass.
+    pub compaction_savings: f64,
+    /// Conversations and tokens per file language.
+    pub languages: BTreeMap<String, LanguageStats>,
+    pub terminal_commands: usize,
+    /// Sessions that produced no conversation at all.
+    pub empty_sessions: usize,
+    /// Sessions recorded on Windows (see `SessionPlatform`).
+    pub windows_sessions: usize,
+    /// Dropped conversations and events, by reason.
+    pub drops: DropStats,
+    /// Structural invariant violations by kind, with structure validation.
+    pub structure_violations: BTreeMap<String, usize>,
+    /// Conversation budget per session source path, with
+    /// `auto_chunk_min_tokens`.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub chunk_budgets: BTreeMap<String, usize>,
+    /// Terminal commands per normalized template.
+    pub command_counts: BTreeMap<String, usize>,
+    /// Token counts under a second tokenizer, see `compare_tokenizer`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tokenizer_comparison: Option<TokenizerComparison>,
+}
+
+/// Token counts of the dataset under a second tokenizer, e.g. of another
+/// model family, next to those of the tokenizer it was serialized with.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct TokenizerComparison {
+    /// Name of the second tokenizer.
+    pub tokenizer: String,
+    pub tokens: usize,
+    /// `tokens` divided by the tokens of the serializing tokenizer.
+    pub token_ratio: f64,
+    pub tokens_per_conversation: Histogram,
+    /// Conversations over `max_tokens_per_conversation` when counted with
+    /// the second tokenizer.
+    pub conversations_over_budget: usize,
+    /// Messages over `max_tokens_per_message` when counted with the second
+    /// tokenizer.
+    pub messages_over_budget: usize,
+}
+
+impl DatasetStats {
+    /// Compute statistics over all conversations of the given sessions.
+    pub fn compute(session_results: &[SessionResult]) -> Self {
+        let mut stats = Self {
+            sessions: session_results.len(),
+            ..Default::default()
+        };
+        let mut token_values = Vec::new();
+        let mut message_values = Vec::new();
+        let mut duration_values = Vec::new();
+        let mut active_values = Vec::new();
+        let mut rate_values = Vec::new();
+
+        for session in session_results {
+            for (event_type, count) in &session.stats.event_counts {
+                *stats.event_type_counts.entry(event_type.clone()).or_default() += count;
+            }
+            for (template, count) in &session.stats.command_counts {
+                *stats.command_counts.entry(template.clone()).or_default() += count;
+                stats.terminal_commands += count;
+            }
+            if let Some(duration_ms) = session.stats.duration_ms() {
+                stats.total_duration_secs += duration_ms / 1000;
+                stats.total_active_secs += session.stats.active_time_ms / 1000;
+                duration_values.push((duration_ms / 1000) as usize);
+                active_values.push((session.stats.active_time_ms / 1000) as usize);
+            }
+            if let Some(rate) = session.stats.events_per_minute() {
+                rate_values.push(rate.round() as usize);
+            }
+            stats.drops.merge(&session.stats.drops);
+            for (kind, count) in &session.stats.structure_violations {
+                *stats.structure_violations.entry(kind.clone()).or_default() += count;
+            }
+            if let Some(budget) = session.stats.chunk_budget {
+                stats.chunk_budgets.insert(session.source_path.clone(), budget);
+            }
+            if session.conversations.is_empty() {
+                stats.empty_sessions += 1;
+            }
+            if session.stats.windows_session {
+                stats.windows_sessions += 1;
+            }
+            for conv in &session.conversations {
+                stats.conversations += 1;
+                stats.messages += conv.messages.len();
+                stats.tokens += conv.token_count;
+                stats.truncated_messages += conv.metrics.truncated_messages;
+                stats.viewport_emissions += conv.metrics.viewport_emissions;
+                stats.coalesced_edits += conv.metrics.coalesced_edits;
+                stats.edit_commands += conv.metrics.edit_commands;
+                stats.truncated_outputs += conv.metrics.truncated_outputs;
+                stats.whitespace_only_edits += conv.metrics.whitespace_only_edits;
+                stats.snapshot_diffs += conv.metrics.snapshot_diffs;
+                stats.compacted_messages += conv.metrics.compacted_messages;
+                stats.compacted_tokens += conv.metrics.compacted_tokens;
+                for language in &conv.languages {
+                    let entry = stats.languages.entry(language.clone()).or_default();
+                    entry.conversations += 1;
+                    entry.tokens += conv.token_count;
+                }
+                token_values.push(conv.token_count);
+                message_values.push(conv.messages.len());
+
+                for (message, tokens) in conv.messages.iter().zip(&conv.message_token_counts) {
+                    *stats.role_tokens.entry(message.from.clone()).or_default() += tokens;
+                    if let Some(kind) = message.kind {
+                        *stats.kind_tokens.entry(kind.as_str().to_string()).or_default() += tokens;
+                    }
+                }
+            }
+        }
+
+        stats.tokens_per_conversation = Histogram::from_values(token_values);
+        stats.messages_per_conversation = Histogram::from_values(message_values);
+        stats.session_duration_secs = Histogram::from_values(duration_values);
+        stats.session_active_secs = Histogram::from_values(active_values);
+        stats.session_events_per_minute = Histogram::from_values(rate_values);
+        let role_total: usize = stats.role_tokens.values().sum();
+        if role_total > 0 {
+            stats.role_token_share = stats
+                .role_tokens
+                .iter()
+                .map(|(role, tokens)| (role.clone(), *tokens as f64 / role_total as f64))
+                .collect();
+        }
+        if stats.conversations > 0 {
+            for language in stats.languages.values_mut() {
+                language.conversation_share =
+                    language.conversations as f64 / stats.conversations as f64;
+            }
+        }
+        if stats.compacted_tokens > 0 {
+            stats.compaction_savings =
+                stats.compacted_tokens as f64 / (stats.tokens + stats.compacted_tokens) as f64;
+        }
+        if stats.messages > 0 {
+            stats.truncation_rate = stats.truncated_messages as f64 / stats.messages as f64;
+        }
+        stats
+    }
+
+    /// Count every message again with a second `tokenizer` named `name` and
+    /// record the totals and budget overruns as `tokenizer_comparison`.
+    pub fn compare_tokenizer<T>(
+        &mut self,
+        session_results: &[SessionResult],
+        name: &str,
+        tokenizer: &T,
+        max_tokens_per_conversation: usize,
+        max_tokens_per_message: usize,
+    ) where
+        T: Tokenizer + Sync,
+    {
+        let conversations: Vec<(usize, usize)> = session_results
+            .par_iter()
+            .flat_map_iter(|session| &session.conversations)
+            .map(|conv| {
+                let counts = conv.messages.iter().map(|m| tokenizer.count_tokens(&m.value));
+                counts.fold((0, 0), |(total, over), tokens| {
+                    (total + tokens, over + (tokens > max_tokens_per_message) as usize)
+                })
+            })
+            .collect();
+        let tokens: usize = conversations.iter().map(|(tokens, _)| tokens).sum();
+        self.tokenizer_comparison = Some(TokenizerComparison {
+            tokenizer: name.to_string(),
+            tokens,
+            token_ratio: if self.tokens > 0 { tokens as f64 / self.tokens as f64 } else { 0.0 },
+            tokens_per_conversation: Histogram::from_values(conversations.iter().map(|(t, _)| *t).collect()),
+            conversations_over_budget: conversations
+                .iter()
+                .filter(|(tokens, _)| *tokens > max_tokens_per_conversation)
+                .count(),
+            messages_over_budget: conversations.iter().map(|(_, over)| over).sum(),
+        });
+    }
+
+    /// Command templates sorted by descending frequency, ties by name.
+    pub fn commands_by_frequency(&self) -> Vec<(&str, usize)> {
+        let mut commands: Vec<(&str, usize)> = self
+            .command_counts
+            .iter()
+            .map(|(template, count)| (template.as_str(), *count))
+            .collect();
+        commands.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        commands
+    }
+
+    /// Render a human-readable Markdown report.
+    pub fn to_markdown(&self) -> String {
+        let mut md = String::new();
+        let _ = writeln!(md, "# Dataset statistics\n");
+        let _ = writeln!(md, "| Metric | Value |");
+        let _ = writeln!(md, "|--------|-------|");
+        let _ = writeln!(md, "| Sessions | {} |", self.sessions);
+        let _ = writeln!(md, "| Conversations | {} |", self.conversations);
+        let _ = writeln!(md, "| Messages | {} |", self.messages);
+        let _ = writeln!(md, "| Tokens | {} |", self.tokens);
+        let _ = writeln!(
+            md,
+            "| Truncated messages | {} ({:.2}%) |",
+            self.truncated_messages,
+            self.truncation_rate * 100.0
+        );
+        let _ = writeln!(md, "| Viewport emissions | {} |", self.viewport_emissions);
+        let _ = writeln!(md, "| Edit commands | {} |", self.edit_commands);
+        let _ = writeln!(md, "| Coalesced edits | {} |", self.coalesced_edits);
+        let _ = writeln!(md, "| Truncated terminal outputs | {} |", self.truncated_outputs);
+        let _ = writeln!(md, "| Whitespace-only edits | {} |", self.whitespace_only_edits);
+        let _ = writeln!(md, "| Stale snapshots serialized as edits | {} |", self.snapshot_diffs);
+        let _ = writeln!(
+            md,
+            "| Compacted messages | {} ({} tokens, {:.2}% saved) |",
+            self.compacted_messages,
+            self.compacted_tokens,
+            self.compaction_savings * 100.0
+        );
+        let _ = writeln!(md, "| Total session time | {:.1} h |", self.total_duration_secs as f64 / 3600.0);
+        let _ = writeln!(md, "| Total active time | {:.1} h |", self.total_active_secs as f64 / 3600.0);
+
+        let _ = writeln!(md, "| Empty sessions | {} |", self.empty_sessions);
+        let _ = writeln!(md, "| Windows sessions | {} |", self.windows_sessions);
+
+        let _ = writeln!(md, "\n## Dropped data\n");
+        let _ = writeln!(md, "| Reason | Count |");
+        let _ = writeln!(md, "|--------|-------|");
+        let drops = &self.drops;
+        for (reason, count) in [
+            ("Conversations too short", drops.too_short_conversations),
+            ("Conversations missing a role", drops.missing_role_conversations),
+            ("Messages in discarded conversations", drops.discarded_messages),
+            ("Edits to untracked files", drops.untracked_edits),
+            ("Edits awaiting resync", drops.resync_edits),
+            ("Unsupported Vim commands", drops.unsupported_vim_commands),
+            ("Events of ignored paths", drops.ignored_path_events),
+            ("Conversations sampled out", drops.sampled_out_conversations),
+            ("Truncated messages (kept)", self.truncated_messages),
+        ] {
+            let _ = writeln!(md, "| {} | {} |", reason, count);
+        }
+        for (reason, count) in &drops.filtered_conversations {
+            let _ = writeln!(md, "| Filtered: {} | {} |", reason, count);
+        }
+
+        if !self.structure_violations.is_empty() {
+            let _ = writeln!(md, "\n## Structural violations\n");
+            let _ = writeln!(md, "| Violation | Count |");
+            let _ = writeln!(md, "|-----------|-------|");
+            for (kind, count) in &self.structure_violations {
+                let _ = writeln!(md, "| {} | {} |", kind, count);
+            }
+        }
+
+        for (title, hist) in [
+            ("Tokens per conversation", &self.tokens_per_conversation),
+            ("Messages per conversation", &self.messages_per_conversation),
+            ("Session duration (s)", &self.session_duration_secs),
+            ("Session active time (s)", &self.session_active_secs),
+            ("Session events per minute", &self.session_events_per_minute),
+        ] {
+            let _ = writeln!(md, "\n## {}\n", title);
+            let _ = writeln!(
+                md,
+                "min {} / p50 {} / p90 {} / p99 {} / max {} (mean {:.1})\n",
+                hist.min, hist.p50, hist.p90, hist.p99, hist.max, hist.mean
+            );
+            let _ = writeln!(md, "| <= | Count |");
+            let _ = writeln!(md, "|----|-------|");
+            for (bound, count) in &hist.buckets {
+                let _ = writeln!(md, "| {} | {} |", bound, count);
+            }
+        }
+
+        if let Some(comparison) = &self.tokenizer_comparison {
+            let hist = &comparison.tokens_per_conversation;
+            let _ = writeln!(md, "\n## Tokenizer comparison: {}\n", comparison.tokenizer);
+            let _ = writeln!(md, "| Metric | Value |");
+            let _ = writeln!(md, "|--------|-------|");
+            let _ = writeln!(md, "| Tokens | {} ({:.3}x) |", comparison.tokens, comparison.token_ratio);
+            let _ = writeln!(md, "| Tokens per conversation (p50 / p90 / max) | {} / {} / {} |", hist.p50, hist.p90, hist.max);
+            let _ = writeln!(md, "| Conversations over budget | {} |", comparison.conversations_over_budget);
+            let _ = writeln!(md, "| Messages over budget | {} |", comparison.messages_over_budget);
+        }
+
+        let _ = writeln!(md, "\n## Token share by role\n");
+        let _ = writeln!(md, "| Role | Tokens | Share |");
+        let _ = writeln!(md, "|------|--------|-------|");
+        for (role, tokens) in &self.role_tokens {
+            let share = self.role_token_share.get(role).copied().unwrap_or(0.0);
+            let _ = writeln!(md, "| {} | {} | {:.1}% |", role, tokens, share * 100.0);
+        }
+
+        if !self.kind_tokens.is_empty() {
+            let _ = writeln!(md, "\n## Tokens by message kind\n");
+            let _ = writeln!(md, "| Kind | Tokens |");
+            let _ = writeln!(md, "|------|--------|");
+            for (kind, tokens) in &self.kind_tokens {
+                let _ = writeln!(md, "| {} | {} |", kind, tokens);
+            }
+        }
+
+        let _ = writeln!(md, "\n## Languages\n");
+        let _ = writeln!(md, "| Language | Conversations | Share | Tokens |");
+        let _ = writeln!(md, "|----------|---------------|-------|--------|");
+        for (language, lang_stats) in &self.languages {
+            let _ = writeln!(
+                md,
+                "| {} | {} | {:.1}% | {} |",
+                language,
+                lang_stats.conversations,
+                lang_stats.conversation_share * 100.0,
+                lang_stats.tokens
+            );
+        }
+
+        let _ = writeln!(md, "\n## Top terminal commands\n");
+        let _ = writeln!(md, "| Command | Count | Share |");
+        let _ = writeln!(md, "|---------|-------|-------|");
+        for (template, count) in self.commands_by_frequency().into_iter().take(TOP_COMMANDS) {
+            let share = count as f64 / self.terminal_commands as f64;
+            let _ = writeln!(md, "| `{}` | {} | {:.1}% |", template, count, share * 100.0);
+        }
+
+        let _ = writeln!(md, "\n## Events by type\n");
+        let _ = writeln!(md, "| Type | Count |");
+        let _ = writeln!(md, "|------|-------|");
+        for (event_type, count) in &self.event_type_counts {
+            let _ = writeln!(md, "| {} | {} |", event_type, count);
+        }
+        md
+    }
+
+    /// Write `stats.json`, `stats.md` and the full `commands.csv` frequency
+    /// table into `output_dir`.
+    pub fn write_report(&self, output_dir: &Path) -> Result<(), SerializerError> {
+        std::fs::create_dir_all(output_dir)?;
+        std::fs::write(output_dir.join("stats.json"), serde_json::to_string_pretty(self)?)?;
+        std::fs::write(output_dir.join("stats.md"), self.to_markdown())?;
+
+        let mut writer = csv::Writer::from_path(output_dir.join("commands.csv"))?;
+        writer.write_record(["command", "count"])?;
+        for (template, count) in self.commands_by_frequency() {
+            writer.write_record([template, &count.to_string()])?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conversation::{ConversationMessage, FinalizedConversation};
+
+    #[test]
+    fn test_histogram() {
+        let hist = Histogram::from_values(vec![1, 3, 4, 100]);
+        assert_eq!(hist.count, 4);
+        assert_eq!(hist.min, 1);
+        assert_eq!(hist.max, 100);
+        assert_eq!(hist.buckets.get(&4), Some(&2));
+        assert_eq!(hist.buckets.get(&128), Some(&1));
+    }
+
+    #[test]
+    fn test_command_template() {
+        assert_eq!(command_template("git status"), "git status");
+        assert_eq!(command_template("git --no-pager log -n 5"), "git log");
+        assert_eq!(command_template("RUST_LOG=debug /usr/bin/cargo test --all"), "cargo test");
+        assert_eq!(command_template("sudo ls -la /tmp"), "ls");
+        assert_eq!(command_template("python train.py\\necho done"), "python");
+        assert_eq!(command_template("   "), "");
+    }
+
+    #[test]
+    fn test_compare_tokenizer() {
+        struct WordTokenizer;
+
+        impl Tokenizer for WordTokenizer {
+            fn count_tokens(&self, text: &str) -> usize {
+                text.split_whitespace().count()
+            }
+
+            fn truncate_to_max_tokens(&self, text: &str, _max_tokens: usize) -> String {
+                text.to_string()
+            }
+        }
+
+        let session = SessionResult {
+            conversations: vec![FinalizedConversation {
+                messages: vec![
+                    ConversationMessage::assistant("cat -n a.rs"),
+                    ConversationMessage::user("1 fn main ( ) { }"),
+                ],
+                token_count: 20,
+                ..Default::default()
+            }],
+            source_path: "a.csv".to_string(),
+            tags: Default::default(),
+            stats: Default::default(),
+        };
+        let mut stats = DatasetStats::compute(std::slice::from_ref(&session));
+        stats.compare_tokenizer(&[session], "words", &WordTokenizer, 10, 5);
+
+        let comparison = stats.tokenizer_comparison.unwrap();
+        assert_eq!(comparison.tokens, 10);
+        assert_eq!(comparison.token_ratio, 0.5);
+        assert_eq!(comparison.conversations_over_budget, 0);
+        assert_eq!(comparison.messages_over_budget, 1);
+    }
+}
@@ -0,0 +1,254 @@
+//! Stitching of session fragments split by recorder restarts.
+//!
+//! When the recorder restarts mid-task, one stretch of work ends up in
+//! several CSV files. Fragments with the same key (directory, workspace root
+//! or a session tag) that follow each other within `max_gap_ms` are
+//! processed as one session, in time order.
+//!
+//! `Time` values are relative to the start of each recording. A recording's
+//! absolute start is read from `start_tag` (milliseconds since the epoch);
+//! fragments without it are processed on their own, with a warning, since
+//! file system times do not survive copying the recordings.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use crate::augment::session_root;
+use crate::error::SerializerError;
+use crate::pipeline::parse_time_ms;
+use crate::tags::{read_session_tags, SessionTags};
+use crate::warnings::WarningAggregator;
+
+/// What fragments of one session have in common.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StitchKey {
+    /// The directory containing the CSV.
+    Directory,
+    /// The common root of the files edited in the session.
+    Workspace,
+    /// The value of a session tag, e.g. a user id.
+    Tag(String),
+}
+
+impl std::str::FromStr for StitchKey {
+    type Err = SerializerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "directory" => Ok(Self::Directory),
+            "workspace" => Ok(Self::Workspace),
+            _ => match s.strip_prefix("tag:") {
+                Some(name) if !name.is_empty() => Ok(Self::Tag(name.to_string())),
+                _ => Err(SerializerError::InvalidConfig(format!(
+                    "unknown stitch key '{}' (known: directory, workspace, tag:<name>)",
+                    s
+                ))),
+            },
+        }
+    }
+}
+
+impl std::fmt::Display for StitchKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Directory => write!(f, "directory"),
+            Self::Workspace => write!(f, "workspace"),
+            Self::Tag(name) => write!(f, "tag:{}", name),
+        }
+    }
+}
+
+/// Rules for stitching session fragments.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StitchConfig {
+    /// Fragments are only stitched if all keys match.
+    pub keys: Vec<StitchKey>,
+    /// Longest gap between the end of a fragment and the start of the next.
+    pub max_gap_ms: u64,
+    /// Tag holding the absolute start of a recording, in milliseconds since
+    /// the epoch.
+    pub start_tag: String,
+}
+
+/// CSV files processed as one session.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StitchedSession {
+    pub fragments: Vec<PathBuf>,
+    /// Added to the `Time` values of each fragment, so they continue those
+    /// of the first.
+    pub time_offsets_ms: Vec<i64>,
+}
+
+impl StitchedSession {
+    /// A session of a single CSV file.
+    pub fn single(csv_path: &Path) -> Self {
+        Self {
+            fragments: vec![csv_path.to_path_buf()],
+            time_offsets_ms: vec![0],
+        }
+    }
+
+    /// The first fragment, which identifies the session.
+    pub fn first(&self) -> &Path {
+        &self.fragments[0]
+    }
+}
+
+/// A fragment with its absolute time span.
+struct Fragment {
+    path: PathBuf,
+    /// Absolute start of the recording.
+    origin_ms: i64,
+    first_ms: i64,
+    last_ms: i64,
+}
+
+/// First and last `Time` value of a CSV.
+fn time_span(csv_path: &Path) -> Result<Option<(i64, i64)>, SerializerError> {
+    let mut reader = csv::Reader::from_path(csv_path)?;
+    let Some(time_idx) = reader.headers()?.iter().position(|h| h.trim_start_matches('\u{feff}') == "Time") else {
+        return Ok(None);
+    };
+    let mut span: Option<(i64, i64)> = None;
+    for record in reader.records() {
+        let Some(time) = record?.get(time_idx).and_then(parse_time_ms) else {
+            continue;
+        };
+        span = Some(span.map_or((time, time), |(first, last)| (first.min(time), last.max(time))));
+    }
+    Ok(span)
+}
+
+/// The stitch key of a CSV, or None if one of its parts is unknown.
+fn fragment_key(
+    csv_path: &Path,
+    tags: &SessionTags,
+    config: &StitchConfig,
+) -> Result<Option<Vec<String>>, SerializerError> {
+    let mut key = Vec::with_capacity(config.keys.len());
+    for part in &config.keys {
+        let value = match part {
+            StitchKey::Directory => csv_path.parent().map(|dir| dir.to_string_lossy().into_owned()),
+            StitchKey::Workspace => session_root(csv_path)?,
+            StitchKey::Tag(name) => tags.get(name).cloned(),
+        };
+        match value {
+            Some(value) => key.push(value),
+            None => return Ok(None),
+        }
+    }
+    Ok(Some(key))
+}
+
+/// Absolute start of a recording, from its `start_tag`.
+fn recording_origin(tags: &SessionTags, config: &StitchConfig) -> Option<i64> {
+    tags.get(&config.start_tag).and_then(|value| parse_time_ms(value))
+}
+
+/// Group `csv_files` into sessions. Fragments without a key, timestamps or a
+/// start tag stay sessions of their own; a missing start tag is reported to
+/// `warnings`. Sessions are ordered by their first fragment.
+pub fn stitch_sessions(
+    csv_files: Vec<PathBuf>,
+    config: &StitchConfig,
+    manifest: &BTreeMap<String, SessionTags>,
+    warnings: &WarningAggregator,
+) -> Result<Vec<StitchedSession>, SerializerError> {
+    let mut sessions = Vec::new();
+    let mut groups: BTreeMap<Vec<String>, Vec<Fragment>> = BTreeMap::new();
+    for path in csv_files {
+        let tags = read_session_tags(&path, manifest)?;
+        let Some(key) = fragment_key(&path, &tags, config)? else {
+            sessions.push(StitchedSession::single(&path));
+            continue;
+        };
+        let Some((first_ms, last_ms)) = time_span(&path)? else {
+            sessions.push(StitchedSession::single(&path));
+            continue;
+        };
+        let Some(origin_ms) = recording_origin(&tags, config) else {
+            warnings.warn(
+                "stitch_start_missing",
+                format!("{:?} has no start tag '{}'; not stitched", path, config.start_tag),
+            );
+            sessions.push(StitchedSession::single(&path));
+            continue;
+        };
+        groups.entry(key).or_default().push(Fragment {
+            path,
+            origin_ms,
+            first_ms,
+            last_ms,
+        });
+    }
+
+    for mut fragments in groups.into_values() {
+        fragments.sort_by_key(|f| (f.origin_ms + f.first_ms, f.path.clone()));
+        let mut current: Option<(StitchedSession, i64, i64)> = None;
+        for fragment in fragments {
+            let start_ms = fragment.origin_ms + fragment.first_ms;
+            let end_ms = fragment.origin_ms + fragment.last_ms;
+            match current.as_mut() {
+                Some((session, origin_ms, previous_end_ms))
+                    if (0..=config.max_gap_ms as i64).contains(&(start_ms - *previous_end_ms)) =>
+                {
+                    session.fragments.push(fragment.path);
+                    session.time_offsets_ms.push(fragment.origin_ms - *origin_ms);
+                    *previous_end_ms = end_ms;
+                }
+                _ => {
+                    sessions.extend(current.take().map(|(session, _, _)| session));
+                    current = Some((StitchedSession::single(&fragment.path), fragment.origin_ms, end_ms));
+                }
+            }
+        }
+        sessions.extend(current.map(|(session, _, _)| session));
+    }
+    sessions.sort_by(|a, b| a.first().cmp(b.first()));
+    Ok(sessions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stitch_sessions() {
+        let temp = tempfile::tempdir().unwrap();
+        let write = |name: &str, times: &[i64]| {
+            let path = temp.path().join(name);
+            let rows: String = times.iter().map(|t| format!("{},/a.rs,0,0,,tab\n", t)).collect();
+            std::fs::write(&path, format!("Time,File,RangeOffset,RangeLength,Text,Type\n{}", rows)).unwrap();
+            path
+        };
+        let manifest = BTreeMap::from([
+            ("a.csv".to_string(), BTreeMap::from([("start".to_string(), "1000000".to_string())])),
+            ("b.csv".to_string(), BTreeMap::from([("start".to_string(), "1065000".to_string())])),
+            ("c.csv".to_string(), BTreeMap::from([("start".to_string(), "9000000".to_string())])),
+        ]);
+        // b starts 5s after a ends; c an hour later
+        // d has no start tag
+        let files = vec![
+            write("c.csv", &[0, 10]),
+            write("b.csv", &[0, 2000]),
+            write("a.csv", &[0, 60000]),
+            write("d.csv", &[0, 10]),
+        ];
+        let config = StitchConfig {
+            keys: vec![StitchKey::Directory],
+            max_gap_ms: 60_000,
+            start_tag: "start".to_string(),
+        };
+        let warnings = WarningAggregator::new(0);
+        let sessions = stitch_sessions(files, &config, &manifest, &warnings).unwrap();
+        assert_eq!(sessions.len(), 3);
+        assert_eq!(sessions[0].fragments, vec![temp.path().join("a.csv"), temp.path().join("b.csv")]);
+        assert_eq!(sessions[0].time_offsets_ms, vec![0, 65000]);
+        assert_eq!(sessions[1], StitchedSession::single(&temp.path().join("c.csv")));
+        assert_eq!(sessions[2], StitchedSession::single(&temp.path().join("d.csv")));
+        assert_eq!(warnings.counts().get("stitch_start_missing"), Some(&1));
+
+        assert!("tag:".parse::<StitchKey>().is_err());
+        assert_eq!("tag:user".parse::<StitchKey>().unwrap(), StitchKey::Tag("user".to_string()));
+    }
+}
@@ -0,0 +1,181 @@
+//! Session-level tags (consent, license, user cohort, ...).
+//!
+//! Tags come from a manifest mapping session paths to tags and from a
+//! sidecar next to the CSV: `<stem>.session_meta.json`, or
+//! `session_meta.json` in the CSV's directory. Sidecar values override the
+//! manifest. Tags are copied onto every output record of the session and can
+//! be required with `RequireTags`.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use crate::conversation::FinalizedConversation;
+use crate::error::SerializerError;
+use crate::filter::{ConversationFilter, FilterDecision, SessionContext};
+
+/// Tags of a session, by name.
+pub type SessionTags = BTreeMap<String, String>;
+
+/// Convert a JSON object to tags; non-string values keep their JSON text.
+fn tags_from_json(value: serde_json::Value, origin: &Path) -> Result<SessionTags, SerializerError> {
+    let serde_json::Value::Object(fields) = value else {
+        return Err(SerializerError::InvalidConfig(format!(
+            "session tags in {:?} must be a JSON object",
+            origin
+        )));
+    };
+    Ok(fields
+        .into_iter()
+        .map(|(key, value)| match value {
+            serde_json::Value::String(s) => (key, s),
+            other => (key, other.to_string()),
+        })
+        .collect())
+}
+
+/// Load a manifest: a JSON object from a path suffix of session CSVs or
+/// their directories (`user1/session.csv`, `user1`) to tags.
+pub fn load_session_manifest(path: &Path) -> Result<BTreeMap<String, SessionTags>, SerializerError> {
+    let manifest: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+    let serde_json::Value::Object(sessions) = manifest else {
+        return Err(SerializerError::InvalidConfig(format!(
+            "session manifest {:?} must map session paths to tag objects",
+            path
+        )));
+    };
+    sessions
+        .into_iter()
+        .map(|(session, tags)| Ok((session, tags_from_json(tags, path)?)))
+        .collect()
+}
+
+/// Tags of the session at `csv_path`: matching manifest entries (longest
+/// path last, so more specific entries win), then the sidecar.
+pub fn read_session_tags(
+    csv_path: &Path,
+    manifest: &BTreeMap<String, SessionTags>,
+) -> Result<SessionTags, SerializerError> {
+    let mut matching: Vec<(&String, &SessionTags)> = manifest
+        .iter()
+        .filter(|(session, _)| csv_path.ancestors().any(|p| p.ends_with(session.as_str())))
+        .collect();
+    matching.sort_by_key(|(session, _)| session.len());
+    let mut tags: SessionTags = matching
+        .into_iter()
+        .flat_map(|(_, tags)| tags.clone())
+        .collect();
+
+    let stem = csv_path.file_stem().unwrap_or_default().to_string_lossy();
+    let sidecars = [
+        csv_path.with_file_name(format!("{}.session_meta.json", stem)),
+        csv_path.with_file_name("session_meta.json"),
+    ];
+    if let Some(sidecar) = sidecars.iter().find(|path| path.is_file()) {
+        let value = serde_json::from_str(&std::fs::read_to_string(sidecar)?)?;
+        tags.extend(tags_from_json(value, sidecar)?);
+    }
+    Ok(tags)
+}
+
+/// A `key=value` tag a session must carry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TagRequirement {
+    pub key: String,
+    pub value: String,
+}
+
+impl std::str::FromStr for TagRequirement {
+    type Err = SerializerError;
+
+    /// Parse `KEY=VALUE`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((key, value)) if !key.is_empty() => Ok(Self {
+                key: key.to_string(),
+                value: value.to_string(),
+            }),
+            _ => Err(SerializerError::InvalidConfig(format!(
+                "tag requirement '{}' is not of the form KEY=VALUE",
+                s
+            ))),
+        }
+    }
+}
+
+/// Drops conversations of sessions lacking any of the required tags.
+#[derive(Debug, Clone)]
+pub struct RequireTags {
+    required: Vec<TagRequirement>,
+}
+
+impl RequireTags {
+    pub fn new(required: Vec<TagRequirement>) -> Self {
+        Self { required }
+    }
+}
+
+impl ConversationFilter for RequireTags {
+    fn name(&self) -> &str {
+        "require-tags"
+    }
+
+    fn accept(&self, _conv: &FinalizedConversation, ctx: &SessionContext<'_>) -> FilterDecision {
+        match self
+            .required
+            .iter()
+            .find(|req| ctx.tags.get(&req.key) != Some(&req.value))
+        {
+            Some(missing) => FilterDecision::Reject {
+                reason: format!("missing_tag:{}={}", missing.key, missing.value),
+            },
+            None => FilterDecision::Accept,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_session_tags() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let user_dir = dir.path().join("user1");
+        std::fs::create_dir_all(&user_dir).unwrap();
+        let csv_path = user_dir.join("session.csv");
+        std::fs::write(
+            user_dir.join("session_meta.json"),
+            r#"{"consent": "research", "cohort": 2}"#,
+        )
+        .unwrap();
+        let manifest_path = dir.path().join("manifest.json");
+        std::fs::write(
+            &manifest_path,
+            r#"{"user1": {"license": "cc-by", "consent": "none"}, "user1/session.csv": {"license": "mit"}}"#,
+        )
+        .unwrap();
+
+        let manifest = load_session_manifest(&manifest_path).unwrap();
+        let tags = read_session_tags(&csv_path, &manifest).unwrap();
+        assert_eq!(tags.get("consent").map(String::as_str), Some("research"));
+        assert_eq!(tags.get("license").map(String::as_str), Some("mit"));
+        assert_eq!(tags.get("cohort").map(String::as_str), Some("2"));
+
+        assert!("consent".parse::<TagRequirement>().is_err());
+        let filter = RequireTags::new(vec!["consent=research".parse().unwrap()]);
+        let stats = crate::SessionStats::default();
+        let ctx = SessionContext {
+            source_path: "user1/session.csv",
+            chunk_index: 0,
+            stats: &stats,
+            tags: &tags,
+        };
+        assert_eq!(filter.accept(&Default::default(), &ctx), FilterDecision::Accept);
+        let untagged = SessionTags::new();
+        let ctx = SessionContext {
+            tags: &untagged,
+            ..ctx
+        };
+        assert!(matches!(filter.accept(&Default::default(), &ctx), FilterDecision::Reject { .. }));
+    }
+}
@@ -0,0 +1,139 @@
+//! Structural validation of serialized conversations.
+//!
+//! A safety net for state-machine bugs: checks invariants of the transcript
+//! format itself, independent of the events behind a conversation. Each
+//! command is followed by at most one output, commands do not follow each
+//! other directly, and no fenced block is empty.
+
+use serde::Serialize;
+
+use crate::conversation::ConversationMessage;
+use crate::error::SerializerError;
+
+/// A broken structural invariant.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StructureViolation {
+    /// An assistant message directly follows another one.
+    ConsecutiveAssistant,
+    /// A user message directly follows another one, i.e. a command has more
+    /// than one output.
+    MultipleOutputs,
+    /// An assistant message contains a fenced block without content.
+    EmptyCodeBlock,
+}
+
+impl StructureViolation {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::ConsecutiveAssistant => "consecutive_assistant",
+            Self::MultipleOutputs => "multiple_outputs",
+            Self::EmptyCodeBlock => "empty_code_block",
+        }
+    }
+}
+
+/// What the pipeline does with conversations violating an invariant.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StructureValidation {
+    /// Do not validate.
+    #[default]
+    Off,
+    /// Count violations in the session statistics and warn.
+    Report,
+    /// Also drop violating conversations.
+    Drop,
+}
+
+impl StructureValidation {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Off => "off",
+            Self::Report => "report",
+            Self::Drop => "drop",
+        }
+    }
+}
+
+impl std::str::FromStr for StructureValidation {
+    type Err = SerializerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(Self::Off),
+            "report" => Ok(Self::Report),
+            "drop" => Ok(Self::Drop),
+            other => Err(SerializerError::InvalidConfig(format!(
+                "unknown structure validation '{}'",
+                other
+            ))),
+        }
+    }
+}
+
+/// Whether `value` contains a fenced block with only whitespace inside.
+fn has_empty_code_block(value: &str) -> bool {
+    let mut body: Option<bool> = None;
+    for line in value.lines() {
+        if line.trim_start().starts_with("```") {
+            match body.take() {
+                Some(true) => return true,
+                Some(false) => {}
+                None => body = Some(true),
+            }
+        } else if let Some(empty) = body.as_mut() {
+            *empty &= line.trim().is_empty();
+        }
+    }
+    false
+}
+
+/// Violations of a conversation, with the index of the offending message.
+pub fn validate_structure(messages: &[ConversationMessage]) -> Vec<(usize, StructureViolation)> {
+    let mut violations = Vec::new();
+    for (i, message) in messages.iter().enumerate() {
+        let previous = i.checked_sub(1).map(|p| messages[p].from.as_str());
+        match (message.from.as_str(), previous) {
+            ("Assistant", Some("Assistant")) => {
+                violations.push((i, StructureViolation::ConsecutiveAssistant))
+            }
+            ("User", Some("User")) => violations.push((i, StructureViolation::MultipleOutputs)),
+            _ => {}
+        }
+        if message.from == "Assistant" && has_empty_code_block(&message.value) {
+            violations.push((i, StructureViolation::EmptyCodeBlock));
+        }
+    }
+    violations
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_structure() {
+        let valid = [
+            ConversationMessage::assistant("```bash\nls\n```\n"),
+            ConversationMessage::user("<stdout>\na\n</stdout>"),
+            ConversationMessage::assistant("```bash\ncat -n a\n```\n"),
+        ];
+        assert!(validate_structure(&valid).is_empty());
+
+        let invalid = [
+            ConversationMessage::assistant("```bash\nls\n```\n"),
+            ConversationMessage::user("<stdout>\na\n</stdout>"),
+            ConversationMessage::user("<stdout>\nb\n</stdout>"),
+            ConversationMessage::assistant("```bash\n\n```\n"),
+            ConversationMessage::assistant("```bash\nls\n```\n"),
+        ];
+        assert_eq!(
+            validate_structure(&invalid),
+            vec![
+                (2, StructureViolation::MultipleOutputs),
+                (3, StructureViolation::EmptyCodeBlock),
+                (4, StructureViolation::ConsecutiveAssistant),
+            ]
+        );
+    }
+}
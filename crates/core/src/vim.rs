@@ -0,0 +1,276 @@
+//! Parsing of Vim-emulation commands recorded as `vim_command` events.
+//!
+//! Only commands with a direct serialized equivalent are recognized: line
+//! deletions (`dd`, `3dd`, `:d`, `:5,10d`), substitutions (`:s/a/b/g`,
+//! `:%s/a/b/`, serialized as the lines they change, since Vim patterns are
+//! not sed patterns) and jumps (`gg`, `G`, `42G`, `:42`).
+
+use crate::sed::{bre_replacement_to_regex, bre_to_regex, split_on_delimiter};
+
+/// Base of a line address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum LineBase {
+    /// The cursor line (`.` or implied).
+    Cursor,
+    /// An absolute line number.
+    Line(usize),
+    /// The last line (`$`).
+    Last,
+}
+
+/// A line address with an optional relative offset (`.+2`, `$-1`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct LineAddress {
+    pub base: LineBase,
+    pub offset: i64,
+}
+
+impl LineAddress {
+    const CURSOR: Self = Self::new(LineBase::Cursor, 0);
+
+    const fn new(base: LineBase, offset: i64) -> Self {
+        Self { base, offset }
+    }
+
+    /// Resolve to a 1-based line number, clamped to `1..=last_line`.
+    /// Returns `None` when the address refers to the cursor but it is
+    /// unknown, or when the offset overflows.
+    pub fn resolve(&self, cursor_line: Option<usize>, last_line: usize) -> Option<usize> {
+        let base = match self.base {
+            LineBase::Cursor => cursor_line?,
+            LineBase::Line(n) => n,
+            LineBase::Last => last_line,
+        };
+        let line = i64::try_from(base).ok()?.checked_add(self.offset)?;
+        Some(line.clamp(1, last_line.max(1) as i64) as usize)
+    }
+}
+
+/// A recognized Vim command.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum VimCommand {
+    /// Delete lines `start..=end`.
+    DeleteLines { start: LineAddress, end: LineAddress },
+    /// `:s`; `pattern` and `replacement` are kept in Vim syntax, escaped
+    /// delimiters included (see `substitution_regex`).
+    Substitute {
+        start: LineAddress,
+        end: LineAddress,
+        pattern: String,
+        replacement: String,
+        global: bool,
+        ignore_case: bool,
+    },
+    /// Move the cursor to a line.
+    GotoLine(LineAddress),
+}
+
+impl VimCommand {
+    /// `pattern` and `replacement` of a substitution in `regex` syntax.
+    pub fn substitution_regex(&self) -> Option<(regex::Regex, String)> {
+        let VimCommand::Substitute {
+            pattern,
+            replacement,
+            ignore_case,
+            ..
+        } = self
+        else {
+            return None;
+        };
+        let pattern = bre_to_regex(pattern)?;
+        let pattern = if *ignore_case { format!("(?i){}", pattern) } else { pattern };
+        Some((regex::Regex::new(&pattern).ok()?, bre_replacement_to_regex(replacement)?))
+    }
+}
+
+/// Parse a normal-mode command (`dd`, `3dd`, `gg`, `G`) or an Ex command
+/// (`:...`). Returns `None` for anything unrecognized.
+pub(crate) fn parse_vim_command(command: &str) -> Option<VimCommand> {
+    let command = command.trim();
+    if let Some(ex) = command.strip_prefix(':') {
+        return parse_ex_command(ex.trim_start());
+    }
+
+    let digits = command.len() - command.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+    let count: Option<usize> = command[..digits].parse().ok().filter(|&n| n > 0);
+    match &command[digits..] {
+        "dd" => Some(VimCommand::DeleteLines {
+            start: LineAddress::CURSOR,
+            end: LineAddress::new(LineBase::Cursor, i64::try_from(count.unwrap_or(1)).ok()? - 1),
+        }),
+        "gg" => Some(VimCommand::GotoLine(LineAddress::new(
+            LineBase::Line(count.unwrap_or(1)),
+            0,
+        ))),
+        "G" => Some(VimCommand::GotoLine(LineAddress::new(
+            count.map_or(LineBase::Last, LineBase::Line),
+            0,
+        ))),
+        _ => None,
+    }
+}
+
+fn parse_ex_command(ex: &str) -> Option<VimCommand> {
+    let (range, rest) = parse_ex_range(ex)?;
+    let rest = rest.trim_start();
+    let (start, end) = range.unwrap_or((LineAddress::CURSOR, LineAddress::CURSOR));
+
+    if rest.is_empty() {
+        // `:42` jumps to the last address of the range
+        return range.map(|(_, end)| VimCommand::GotoLine(end));
+    }
+    if matches!(rest, "d" | "de" | "del" | "dele" | "delet" | "delete") {
+        return Some(VimCommand::DeleteLines { start, end });
+    }
+
+    let args = ["substitute", "s"]
+        .iter()
+        .find_map(|name| rest.strip_prefix(name))?;
+    let delimiter = args.chars().next()?;
+    if delimiter.is_alphanumeric() || delimiter.is_whitespace() || matches!(delimiter, '\\' | '"' | '|') {
+        return None;
+    }
+    let mut parts = split_on_delimiter(&args[delimiter.len_utf8()..], delimiter);
+    if parts.len() == 2 {
+        // `:s/a/b` without a trailing delimiter
+        parts.push(String::new());
+    }
+    let [pattern, replacement, flags] = parts.as_slice() else {
+        return None;
+    };
+    // Empty patterns reuse the last search; `~` and `\=` need Vim state
+    if pattern.is_empty() || replacement.contains('~') || replacement.contains("\\=") {
+        return None;
+    }
+
+    let mut global = false;
+    let mut ignore_case = false;
+    for flag in flags.trim_end().chars() {
+        match flag {
+            'g' => global = true,
+            'i' => ignore_case = true,
+            'I' => ignore_case = false,
+            'e' => {}
+            _ => return None,
+        }
+    }
+    let command = VimCommand::Substitute {
+        start,
+        end,
+        pattern: pattern.clone(),
+        replacement: replacement.clone(),
+        global,
+        ignore_case,
+    };
+    command.substitution_regex()?;
+    Some(command)
+}
+
+/// Parse an optional Ex range (`%`, `.`, `$`, `N`, `A,B`, with `+N`/`-N`
+/// offsets) and return it with the remaining command.
+fn parse_ex_range(ex: &str) -> Option<(Option<(LineAddress, LineAddress)>, &str)> {
+    if let Some(rest) = ex.strip_prefix('%') {
+        return Some((
+            Some((
+                LineAddress::new(LineBase::Line(1), 0),
+                LineAddress::new(LineBase::Last, 0),
+            )),
+            rest,
+        ));
+    }
+    let Some((start, rest)) = parse_ex_address(ex) else {
+        return Some((None, ex));
+    };
+    match rest.strip_prefix(',') {
+        Some(rest) => {
+            let (end, rest) = parse_ex_address(rest)?;
+            Some((Some((start, end)), rest))
+        }
+        None => Some((Some((start, start)), rest)),
+    }
+}
+
+fn parse_ex_address(s: &str) -> Option<(LineAddress, &str)> {
+    let (base, mut rest) = if let Some(rest) = s.strip_prefix('.') {
+        (LineBase::Cursor, rest)
+    } else if let Some(rest) = s.strip_prefix('$') {
+        (LineBase::Last, rest)
+    } else {
+        let digits = s.len() - s.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+        if digits > 0 {
+            (LineBase::Line(s[..digits].parse().ok()?), &s[digits..])
+        } else if s.starts_with(['+', '-']) {
+            (LineBase::Cursor, s)
+        } else {
+            return None;
+        }
+    };
+
+    let mut offset = 0i64;
+    while let Some(sign) = rest.chars().next().filter(|c| matches!(c, '+' | '-')) {
+        let tail = &rest[1..];
+        let digits = tail.len() - tail.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+        let amount: i64 = if digits == 0 { 1 } else { tail[..digits].parse().ok()? };
+        offset = if sign == '+' { offset.checked_add(amount)? } else { offset.checked_sub(amount)? };
+        rest = &tail[digits..];
+    }
+    Some((LineAddress::new(base, offset), rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_vim_command() {
+        let cursor = LineAddress::CURSOR;
+        assert_eq!(
+            parse_vim_command("3dd"),
+            Some(VimCommand::DeleteLines {
+                start: cursor,
+                end: LineAddress::new(LineBase::Cursor, 2),
+            })
+        );
+        assert_eq!(
+            parse_vim_command(":.,$-1d"),
+            Some(VimCommand::DeleteLines {
+                start: cursor,
+                end: LineAddress::new(LineBase::Last, -1),
+            })
+        );
+        assert_eq!(
+            parse_vim_command(":42"),
+            Some(VimCommand::GotoLine(LineAddress::new(LineBase::Line(42), 0)))
+        );
+        assert_eq!(
+            parse_vim_command("G"),
+            Some(VimCommand::GotoLine(LineAddress::new(LineBase::Last, 0)))
+        );
+        assert_eq!(
+            parse_vim_command(":%s/foo\\/x/bar/g"),
+            Some(VimCommand::Substitute {
+                start: LineAddress::new(LineBase::Line(1), 0),
+                end: LineAddress::new(LineBase::Last, 0),
+                pattern: "foo\\/x".to_string(),
+                replacement: "bar".to_string(),
+                global: true,
+                ignore_case: false,
+            })
+        );
+        assert!(matches!(
+            parse_vim_command(":s#a#b"),
+            Some(VimCommand::Substitute { global: false, ref pattern, .. }) if pattern == "a"
+        ));
+
+        for unsupported in ["ciw", "yyp", ":w", ":s//x/", ":s/a/~/", ":s/\\v(a)/b/", ":s/a/b/c"] {
+            assert_eq!(parse_vim_command(unsupported), None, "{}", unsupported);
+        }
+
+        let range = LineAddress::new(LineBase::Cursor, 5);
+        assert_eq!(range.resolve(Some(3), 6), Some(6));
+        assert_eq!(range.resolve(None, 6), None);
+        assert_eq!(LineAddress::new(LineBase::Line(usize::MAX), 0).resolve(None, 6), None);
+        assert_eq!(LineAddress::new(LineBase::Line(5), i64::MAX).resolve(None, 6), None);
+        assert_eq!(parse_vim_command(":1+9223372036854775807+1d"), None);
+    }
+}
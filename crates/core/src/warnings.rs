@@ -0,0 +1,176 @@
+//! Rate-limited reporting of per-row warnings.
+//!
+//! A bad data drop can produce a warning for every row. `WarningAggregator`
+//! counts warnings by category and keeps only the first few of each as
+//! samples, which the CLI's aggregator also prints, cut to `MAX_SAMPLE_CHARS`.
+//! Every warning can additionally be written in full to a JSONL report file.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+use crate::error::SerializerError;
+
+/// Warnings printed per category before the rest are only counted.
+pub const DEFAULT_WARNING_SAMPLES: usize = 10;
+
+/// Printed warnings are cut to this many characters.
+const MAX_SAMPLE_CHARS: usize = 300;
+
+/// A sampled warning.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Warning {
+    pub category: &'static str,
+    pub message: String,
+}
+
+#[derive(Debug, Default)]
+struct WarningState {
+    counts: BTreeMap<&'static str, usize>,
+    samples: Vec<Warning>,
+    report: Option<BufWriter<File>>,
+    report_error: Option<std::io::Error>,
+}
+
+/// Thread-safe warning counter, shared by the sessions of a run.
+#[derive(Debug)]
+pub struct WarningAggregator {
+    max_samples: usize,
+    print: bool,
+    state: Mutex<WarningState>,
+}
+
+/// Quiet, for embedding.
+impl Default for WarningAggregator {
+    fn default() -> Self {
+        Self::quiet(DEFAULT_WARNING_SAMPLES)
+    }
+}
+
+impl WarningAggregator {
+    /// Keep and print at most `max_samples` warnings per category.
+    pub fn new(max_samples: usize) -> Self {
+        Self {
+            max_samples,
+            print: true,
+            state: Mutex::new(WarningState::default()),
+        }
+    }
+
+    /// Keep at most `max_samples` warnings per category, without printing.
+    pub fn quiet(max_samples: usize) -> Self {
+        Self {
+            print: false,
+            ..Self::new(max_samples)
+        }
+    }
+
+    /// Also write every warning as a `{"category", "message"}` JSON line to `path`.
+    pub fn with_report(mut self, path: &Path) -> Result<Self, SerializerError> {
+        let state = self.state.get_mut().unwrap_or_else(|e| e.into_inner());
+        state.report = Some(BufWriter::new(File::create(path)?));
+        Ok(self)
+    }
+
+    /// Record a warning of `category`.
+    pub fn warn(&self, category: &'static str, message: impl AsRef<str>) {
+        let message = message.as_ref();
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let count = state.counts.entry(category).or_default();
+        *count += 1;
+        let count = *count;
+        if count <= self.max_samples {
+            state.samples.push(Warning {
+                category,
+                message: message.to_string(),
+            });
+        }
+        if count <= self.max_samples && self.print {
+            match message.char_indices().nth(MAX_SAMPLE_CHARS) {
+                Some((end, _)) => eprintln!("Warning: {}...", &message[..end]),
+                None => eprintln!("Warning: {}", message),
+            }
+        }
+        if count == self.max_samples + 1 && self.print {
+            eprintln!("Warning: further '{}' warnings are only counted", category);
+        }
+
+        let WarningState { report, report_error, .. } = &mut *state;
+        if let Some(writer) = report {
+            let line = serde_json::json!({ "category": category, "message": message });
+            if let Err(e) = writeln!(writer, "{}", line) {
+                *report_error = Some(e);
+                *report = None;
+            }
+        }
+    }
+
+    /// Number of warnings by category.
+    pub fn counts(&self) -> BTreeMap<String, usize> {
+        let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.counts.iter().map(|(category, &count)| (category.to_string(), count)).collect()
+    }
+
+    /// The first warnings of each category, in the order they were recorded.
+    pub fn samples(&self) -> Vec<Warning> {
+        let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.samples.clone()
+    }
+
+    /// Print the totals of categories with suppressed warnings and flush the
+    /// report file.
+    pub fn finish(&self) -> Result<(), SerializerError> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        for (category, &count) in &state.counts {
+            if count > self.max_samples && self.print {
+                eprintln!(
+                    "Warning: {} '{}' warnings ({} not shown)",
+                    count,
+                    category,
+                    count - self.max_samples
+                );
+            }
+        }
+        if let Some(e) = state.report_error.take() {
+            return Err(e.into());
+        }
+        if let Some(writer) = &mut state.report {
+            writer.flush()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_warning_aggregator() {
+        let dir = tempfile::tempdir().unwrap();
+        let report = dir.path().join("warnings.jsonl");
+        let warnings = WarningAggregator::new(2).with_report(&report).unwrap();
+        for i in 0..5 {
+            warnings.warn("unknown_event_type", format!("Unknown event type 'x{}'", i));
+        }
+        warnings.warn("missing_text", "terminal_output event missing Text");
+        warnings.finish().unwrap();
+
+        assert_eq!(
+            warnings.counts(),
+            BTreeMap::from([("missing_text".to_string(), 1), ("unknown_event_type".to_string(), 5)])
+        );
+        let lines: Vec<serde_json::Value> = std::fs::read_to_string(&report)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 6);
+        assert_eq!(lines[4]["message"], "Unknown event type 'x4'");
+        let samples = warnings.samples();
+        assert_eq!(samples.len(), 3);
+        assert_eq!(samples[2].message, "terminal_output event missing Text");
+    }
+}
@@ -4,12 +4,16 @@
 //! since accurate tokenization is not required for runtime inference.
 
 use napi::bindgen_prelude::*;
+use napi::threadsafe_function::{ErrorStrategy, ThreadsafeFunction, ThreadsafeFunctionCallMode};
+use napi::JsFunction;
 use napi_derive::napi;
 use std::sync::Mutex;
 
 use crowd_pilot_serializer_core::{
     ConversationMessage as CoreMessage, ConversationStateManager as CoreManager,
-    ConversationStateManagerConfig, Tokenizer,
+    ConversationStateManagerConfig, FinalizedConversation as CoreFinalizedConversation, IdeEvent,
+    ModelContext as CoreModelContext, Notification, OffsetEncoding, PathCanonicalization, OffsetDriftDiagnostic as CoreDriftDiagnostic,
+    RegistryStats as CoreRegistryStats, SerializerError, SessionRegistry as CoreRegistry, CharApproxTokenizer,
 };
 
 /// A message in the conversation.
@@ -17,13 +21,86 @@ use crowd_pilot_serializer_core::{
 pub struct ConversationMessage {
     pub from: String,
     pub value: String,
+    /// Classes of the assistant's command ("read", "edit", "build", "network",
+    /// "destructive"), for gating what the model is shown.
+    pub command_classes: Vec<String>,
+    /// What the message shows ("file_capture", "viewport", "edit_command",
+    /// "terminal_command", "terminal_output", "git_action", ...).
+    pub kind: Option<String>,
 }
 
 impl From<CoreMessage> for ConversationMessage {
     fn from(msg: CoreMessage) -> Self {
+        let command_classes = msg
+            .command_classes()
+            .iter()
+            .map(|class| class.as_str().to_string())
+            .collect();
         Self {
+            kind: msg.kind.map(|kind| kind.as_str().to_string()),
             from: msg.from,
             value: msg.value,
+            command_classes,
+        }
+    }
+}
+
+/// A prompt assembled within a token budget.
+#[napi(object)]
+pub struct ModelContext {
+    pub system: String,
+    /// Kept history, with an elision marker message wherever messages were left out.
+    pub messages: Vec<ConversationMessage>,
+    pub token_count: u32,
+    pub elided_messages: u32,
+}
+
+impl From<CoreModelContext> for ModelContext {
+    fn from(context: CoreModelContext) -> Self {
+        Self {
+            system: context.system,
+            messages: context.messages.into_iter().map(Into::into).collect(),
+            token_count: context.token_count as u32,
+            elided_messages: context.elided_messages as u32,
+        }
+    }
+}
+
+/// One change of a multi-cursor edit: `length` characters at `offset`
+/// replaced by `text`.
+#[napi(object)]
+pub struct ContentChange {
+    pub offset: u32,
+    pub length: u32,
+    pub text: String,
+}
+
+impl ContentChange {
+    fn to_core(&self) -> (usize, usize, &str) {
+        (self.offset as usize, self.length as usize, self.text.as_str())
+    }
+}
+
+/// A conversation split off with `startNewConversation`.
+#[napi(object)]
+pub struct FinalizedConversation {
+    pub messages: Vec<ConversationMessage>,
+    pub token_count: u32,
+    /// Languages of the files shown or edited in the conversation, sorted.
+    pub languages: Vec<String>,
+    /// Sequence numbers of the first and last event of the conversation.
+    pub first_sequence: Option<i64>,
+    pub last_sequence: Option<i64>,
+}
+
+impl From<CoreFinalizedConversation> for FinalizedConversation {
+    fn from(conversation: CoreFinalizedConversation) -> Self {
+        Self {
+            messages: conversation.messages.into_iter().map(Into::into).collect(),
+            token_count: conversation.token_count as u32,
+            languages: conversation.languages,
+            first_sequence: conversation.first_sequence,
+            last_sequence: conversation.last_sequence,
         }
     }
 }
@@ -34,28 +111,391 @@ impl From<CoreMessage> for ConversationMessage {
 pub struct ConversationStateManagerOptions {
     /// Viewport radius (lines above/below cursor to show).
     pub viewport_radius: Option<u32>,
+    /// Lines shown above the cursor (defaults to `viewportRadius`).
+    pub viewport_lines_above: Option<u32>,
+    /// Lines shown below the cursor (defaults to `viewportRadius`).
+    pub viewport_lines_below: Option<u32>,
+    /// Re-emit the viewport once the cursor is this close to its edge.
+    pub viewport_margin: Option<u32>,
+    /// Recenter the viewport on every k-th cursor movement.
+    pub viewport_recenter_every: Option<u32>,
+    /// Cursor movements required between viewport re-emissions of a file.
+    pub viewport_min_movements: Option<u32>,
+    /// Lines a viewport must move by to be re-emitted.
+    pub viewport_min_shift: Option<u32>,
     /// Coalesce radius for grouping nearby edits.
     pub coalesce_radius: Option<u32>,
     /// Maximum tokens per message.
     pub max_tokens_per_message: Option<u32>,
     /// Maximum tokens per terminal output.
     pub max_tokens_per_terminal_output: Option<u32>,
+    /// Handling of edits to never-captured files:
+    /// "assume-empty" (default), "drop", "placeholder", or "buffer".
+    pub untracked_file_policy: Option<String>,
+    /// Drop edits after an out-of-range offset until the file's next tab snapshot.
+    pub resync_on_offset_drift: Option<bool>,
+    /// Root rewrites of the form "FROM=TO", e.g. "/home/u/projA=projA/".
+    pub path_mappings: Option<Vec<String>>,
+    /// Treat "a/./b.rs", "a//b.rs" and "a/x/../b.rs" as the same file as "a/b.rs".
+    pub normalize_paths: Option<bool>,
+    /// Treat paths differing only in case as the same file, spelled as first seen.
+    pub case_insensitive_paths: Option<bool>,
+    /// Symlinked roots of the form "LINK=TARGET"; paths under LINK are treated as under TARGET.
+    pub symlinks: Option<Vec<String>>,
+    /// Companion relationships "PRIMARY=COMPANION", e.g. "*.rs=*_test.rs": after
+    /// an edit, the file's companion is shown too if it is tracked.
+    pub companion_files: Option<Vec<String>>,
+    /// Globs of files whose editor events are skipped, e.g. "node_modules/**".
+    pub ignored_paths: Option<Vec<String>>,
+    /// Whitespace-only edits: "emit" (default), "skip", or "formatter".
+    pub whitespace_edit_policy: Option<String>,
+    /// Multi-line terminal commands: "verbatim" (default), "wrap"
+    /// (`bash -lc '...'`), or "split" into one turn per line.
+    pub multiline_commands: Option<String>,
+    /// Merge consecutive terminal commands run within this many milliseconds
+    /// (requires `setEventTime`).
+    pub merge_commands_window_ms: Option<u32>,
+    /// Hold events back until events this many `reorderBy` units newer
+    /// arrived, and handle them sorted (requires `setEventSequence` or
+    /// `setEventTime`). Call `flushReorderedEvents` to handle held events.
+    pub reorder_window: Option<i64>,
+    /// Ordering key of `reorderWindow`: "sequence" (default) or "time".
+    pub reorder_by: Option<String>,
+    /// Attach terminal output that arrives after the next command back to a
+    /// preceding command without output (by `setEventTime` timestamps and
+    /// shell-integration markers).
+    pub reassociate_late_output: Option<bool>,
+    /// Assistant message appended by `handleSessionEndEvent`.
+    pub end_of_task_marker: Option<String>,
+    /// Flush buffered terminal output once it reaches this many bytes.
+    pub max_terminal_buffer_bytes: Option<u32>,
+    /// Flush buffered terminal output once it is this many milliseconds old
+    /// (requires `setEventTime` or `tick`).
+    pub max_terminal_buffer_age_ms: Option<u32>,
+    /// Show files with more lines than this only as viewport slices.
+    pub large_file_line_threshold: Option<u32>,
+    /// Track at most this many files, forgetting the least recently used ones
+    /// (bounds memory in long sessions; forgotten files are captured again).
+    pub max_tracked_files: Option<u32>,
+    /// Drop repeated captures, superseded viewport reads and empty outputs
+    /// in `finalizeForModel`.
+    pub compact_conversations: Option<bool>,
+    /// Show multi-line selections as a read of exactly the selected lines.
+    pub emit_selection_ranges: Option<bool>,
+    /// Show multi-line selections as a viewport centered on the selected lines.
+    pub center_selections: Option<bool>,
+    /// Flag the selected lines with ">" in viewports shown for multi-line selections.
+    pub annotate_selections: Option<bool>,
+    /// Serialize a tab snapshot that differs from the tracked content of an
+    /// already shown file as an edit instead of a full re-capture.
+    pub diff_stale_snapshots: Option<bool>,
+    /// Skip the view of a tab event for the file that already has focus,
+    /// unless the terminal had focus in between or the content changed.
+    pub skip_refocus_views: Option<bool>,
+    /// Flush the pending edits of other files when an edit arrives for a
+    /// different file, keeping interleaved edits in their real order.
+    pub flush_on_cross_file_edit: Option<bool>,
+    /// Apply file changes written by the last terminal command silently
+    /// instead of serializing them again as edits.
+    pub dedupe_command_writes: Option<bool>,
+    /// Read the saved viewport again after a save flushed an edit.
+    pub verify_on_save: Option<bool>,
+    /// Rewrite Windows paths (`C:\a\b`) to POSIX form (`/c/a/b`) in file
+    /// paths, commands and terminal output.
+    pub normalize_windows_paths: Option<bool>,
+    /// Queue content events of one file arriving within this many
+    /// milliseconds of each other and apply each burst in one pass (on the
+    /// next other event or `flushPending`).
+    pub content_debounce_ms: Option<u32>,
+}
+
+/// A 1-based, inclusive line range.
+#[napi(object)]
+pub struct LineRange {
+    pub start: u32,
+    pub end: u32,
 }
 
-/// Character-based approximate tokenizer (~4 chars per token).
-/// Used for the VS Code extension runtime where exact tokenization is not required.
-struct CharApproxTokenizer;
+/// A content event whose range exceeded the tracked file content.
+#[napi(object)]
+pub struct OffsetDriftDiagnostic {
+    pub file_path: String,
+    pub sequence: Option<i64>,
+    pub offset: u32,
+    pub length: u32,
+    pub tracked_length: u32,
+}
 
-impl Tokenizer for CharApproxTokenizer {
-    fn count_tokens(&self, text: &str) -> usize {
-        text.len() / 4
+impl From<CoreDriftDiagnostic> for OffsetDriftDiagnostic {
+    fn from(diag: CoreDriftDiagnostic) -> Self {
+        Self {
+            file_path: diag.file_path,
+            sequence: diag.sequence,
+            offset: diag.offset as u32,
+            length: diag.length as u32,
+            tracked_length: diag.tracked_length as u32,
+        }
+    }
+}
+
+/// Files tracked by more than one session of a registry.
+#[napi(object)]
+pub struct SharedFile {
+    pub file_path: String,
+    pub sessions: Vec<String>,
+}
+
+/// Aggregate statistics over all sessions of a `SessionRegistry`.
+#[napi(object)]
+pub struct RegistryStats {
+    pub sessions: u32,
+    /// Messages in the sessions' current conversations.
+    pub messages: u32,
+    /// Tokens in the sessions' current conversations.
+    pub tokens: u32,
+    pub discarded_conversations: u32,
+    pub untracked_edits: u32,
+    pub resync_edits: u32,
+    pub shared_files: Vec<SharedFile>,
+}
+
+impl From<CoreRegistryStats> for RegistryStats {
+    fn from(stats: CoreRegistryStats) -> Self {
+        Self {
+            sessions: stats.sessions as u32,
+            messages: stats.messages as u32,
+            tokens: stats.tokens as u32,
+            discarded_conversations: stats.drops.discarded_conversations() as u32,
+            untracked_edits: stats.drops.untracked_edits as u32,
+            resync_edits: stats.drops.resync_edits as u32,
+            shared_files: stats
+                .shared_files
+                .into_iter()
+                .map(|(file_path, sessions)| SharedFile {
+                    file_path,
+                    sessions,
+                })
+                .collect(),
+        }
     }
+}
+
+/// A state manager notification delivered to observers.
+#[napi(object)]
+pub struct ManagerNotification {
+    /// "message_appended", "message_removed", "edit_flushed",
+    /// "conversation_finalized" or "truncated".
+    pub kind: String,
+    /// Message role (appended/removed/truncated messages).
+    pub role: Option<String>,
+    /// Message text (appended/removed) or serialized edit command (edit_flushed).
+    pub value: Option<String>,
+    pub file_path: Option<String>,
+    /// Message tokens, conversation tokens, or tokens before truncation.
+    pub tokens: Option<u32>,
+    /// Token budget a truncated message was cut to.
+    pub max_tokens: Option<u32>,
+    /// Message count of a finalized conversation.
+    pub messages: Option<u32>,
+    /// Whether a finalized conversation was kept.
+    pub kept: Option<bool>,
+}
 
-    fn truncate_to_max_tokens(&self, text: &str, max_tokens: usize) -> String {
-        text.chars().take(max_tokens * 4).collect()
+impl From<&Notification<'_>> for ManagerNotification {
+    fn from(notification: &Notification<'_>) -> Self {
+        let mut out = Self {
+            kind: String::new(),
+            role: None,
+            value: None,
+            file_path: None,
+            tokens: None,
+            max_tokens: None,
+            messages: None,
+            kept: None,
+        };
+        match *notification {
+            Notification::MessageAppended { message, tokens } => {
+                out.kind = "message_appended".to_string();
+                out.role = Some(message.from.clone());
+                out.value = Some(message.value.clone());
+                out.tokens = Some(tokens as u32);
+            }
+            Notification::MessageRemoved { message } => {
+                out.kind = "message_removed".to_string();
+                out.role = Some(message.from.clone());
+                out.value = Some(message.value.clone());
+            }
+            Notification::EditFlushed { file_path, command } => {
+                out.kind = "edit_flushed".to_string();
+                out.file_path = Some(file_path.to_string());
+                out.value = Some(command.to_string());
+            }
+            Notification::ConversationFinalized {
+                messages,
+                token_count,
+                kept,
+            } => {
+                out.kind = "conversation_finalized".to_string();
+                out.messages = Some(messages as u32);
+                out.tokens = Some(token_count as u32);
+                out.kept = Some(kept);
+            }
+            Notification::Truncated {
+                role,
+                original_tokens,
+                max_tokens,
+            } => {
+                out.kind = "truncated".to_string();
+                out.role = Some(role.to_string());
+                out.tokens = Some(original_tokens as u32);
+                out.max_tokens = Some(max_tokens as u32);
+            }
+        }
+        out
     }
 }
 
+/// Convert a core serializer error into a JavaScript error.
+fn to_napi_error(err: SerializerError) -> Error {
+    Error::from_reason(err.to_string())
+}
+
+/// Build a core configuration from JS options, using core defaults for
+/// unspecified values.
+fn config_from_options(
+    options: Option<ConversationStateManagerOptions>,
+) -> Result<ConversationStateManagerConfig> {
+    let defaults = ConversationStateManagerConfig::default();
+    
+    let config = match options {
+        Some(opts) => ConversationStateManagerConfig {
+            viewport_radius: opts.viewport_radius.map(|v| v as usize).unwrap_or(defaults.viewport_radius),
+            viewport_lines_above: opts
+                .viewport_lines_above
+                .map(|v| v as usize)
+                .or(defaults.viewport_lines_above),
+            viewport_lines_below: opts
+                .viewport_lines_below
+                .map(|v| v as usize)
+                .or(defaults.viewport_lines_below),
+            viewport_margin: opts.viewport_margin.map(|v| v as usize).unwrap_or(defaults.viewport_margin),
+            viewport_recenter_every: opts
+                .viewport_recenter_every
+                .map(|v| v as usize)
+                .or(defaults.viewport_recenter_every),
+            viewport_min_movements: opts
+                .viewport_min_movements
+                .map(|v| v as usize)
+                .unwrap_or(defaults.viewport_min_movements),
+            viewport_min_shift: opts.viewport_min_shift.map(|v| v as usize).unwrap_or(defaults.viewport_min_shift),
+            coalesce_radius: opts.coalesce_radius.map(|v| v as usize).unwrap_or(defaults.coalesce_radius),
+            max_tokens_per_message: opts.max_tokens_per_message.map(|v| v as usize).unwrap_or(defaults.max_tokens_per_message),
+            max_tokens_per_terminal_output: opts.max_tokens_per_terminal_output.map(|v| v as usize).unwrap_or(defaults.max_tokens_per_terminal_output),
+            untracked_file_policy: match opts.untracked_file_policy {
+                Some(policy) => policy.parse().map_err(to_napi_error)?,
+                None => defaults.untracked_file_policy,
+            },
+            resync_on_offset_drift: opts.resync_on_offset_drift.unwrap_or(defaults.resync_on_offset_drift),
+            path_mappings: match opts.path_mappings {
+                Some(mappings) => mappings
+                    .iter()
+                    .map(|m| m.parse().map_err(to_napi_error))
+                    .collect::<Result<_>>()?,
+                None => defaults.path_mappings,
+            },
+            path_canonicalization: PathCanonicalization {
+                lexical: opts.normalize_paths.unwrap_or(defaults.path_canonicalization.lexical),
+                case_fold: opts
+                    .case_insensitive_paths
+                    .unwrap_or(defaults.path_canonicalization.case_fold),
+                symlinks: match opts.symlinks {
+                    Some(symlinks) => symlinks
+                        .iter()
+                        .map(|m| m.parse().map_err(to_napi_error))
+                        .collect::<Result<_>>()?,
+                    None => defaults.path_canonicalization.symlinks,
+                },
+            },
+            companion_files: match opts.companion_files {
+                Some(patterns) => patterns
+                    .iter()
+                    .map(|p| p.parse().map_err(to_napi_error))
+                    .collect::<Result<_>>()?,
+                None => defaults.companion_files,
+            },
+            ignored_paths: match opts.ignored_paths {
+                Some(patterns) => patterns
+                    .iter()
+                    .map(|p| p.parse().map_err(to_napi_error))
+                    .collect::<Result<_>>()?,
+                None => defaults.ignored_paths,
+            },
+            whitespace_edit_policy: match opts.whitespace_edit_policy {
+                Some(policy) => policy.parse().map_err(to_napi_error)?,
+                None => defaults.whitespace_edit_policy,
+            },
+            multiline_commands: match opts.multiline_commands {
+                Some(policy) => policy.parse().map_err(to_napi_error)?,
+                None => defaults.multiline_commands,
+            },
+            merge_commands_window_ms: opts
+                .merge_commands_window_ms
+                .map(u64::from)
+                .or(defaults.merge_commands_window_ms),
+            reorder_window: opts.reorder_window.or(defaults.reorder_window),
+            reorder_key: match opts.reorder_by {
+                Some(key) => key.parse().map_err(to_napi_error)?,
+                None => defaults.reorder_key,
+            },
+            reassociate_late_output: opts.reassociate_late_output.unwrap_or(defaults.reassociate_late_output),
+            end_of_task_marker: opts.end_of_task_marker.or(defaults.end_of_task_marker),
+            max_terminal_buffer_bytes: opts
+                .max_terminal_buffer_bytes
+                .map(|v| v as usize)
+                .or(defaults.max_terminal_buffer_bytes),
+            max_terminal_buffer_age_ms: opts
+                .max_terminal_buffer_age_ms
+                .map(u64::from)
+                .or(defaults.max_terminal_buffer_age_ms),
+            large_file_line_threshold: opts
+                .large_file_line_threshold
+                .map(|v| v as usize)
+                .or(defaults.large_file_line_threshold),
+            max_tracked_files: opts
+                .max_tracked_files
+                .map(|v| v as usize)
+                .or(defaults.max_tracked_files),
+            compact_conversations: opts.compact_conversations.unwrap_or(defaults.compact_conversations),
+            emit_selection_ranges: opts.emit_selection_ranges.unwrap_or(defaults.emit_selection_ranges),
+            center_selections: opts.center_selections.unwrap_or(defaults.center_selections),
+            annotate_selections: opts.annotate_selections.unwrap_or(defaults.annotate_selections),
+            diff_stale_snapshots: opts.diff_stale_snapshots.unwrap_or(defaults.diff_stale_snapshots),
+            skip_refocus_views: opts.skip_refocus_views.unwrap_or(defaults.skip_refocus_views),
+            flush_on_cross_file_edit: opts
+                .flush_on_cross_file_edit
+                .unwrap_or(defaults.flush_on_cross_file_edit),
+            dedupe_command_writes: opts.dedupe_command_writes.unwrap_or(defaults.dedupe_command_writes),
+            verify_on_save: opts.verify_on_save.unwrap_or(defaults.verify_on_save),
+            normalize_windows_paths: opts
+                .normalize_windows_paths
+                .unwrap_or(defaults.normalize_windows_paths),
+            content_debounce_ms: opts
+                .content_debounce_ms
+                .map(u64::from)
+                .or(defaults.content_debounce_ms),
+            // Extension-specific: no chunking (single ongoing conversation)
+            max_tokens_per_conversation: None,
+            ..defaults
+        },
+        None => ConversationStateManagerConfig {
+            // Extension-specific: no chunking
+            max_tokens_per_conversation: None,
+            ..defaults
+        },
+    };
+    Ok(config)
+}
+
 /// Manages conversation state for serializing IDE events.
 ///
 /// Uses character-based token approximation for the VS Code extension runtime.
@@ -65,6 +505,14 @@ pub struct ConversationStateManager {
     inner: Mutex<CoreManager<CharApproxTokenizer>>,
 }
 
+impl ConversationStateManager {
+    /// Handle an event, through the reordering window if configured.
+    fn handle(&self, event: IdeEvent<String>) -> Result<()> {
+        let mut inner = self.inner.lock().map_err(|_| Error::from_reason("Lock poisoned"))?;
+        inner.handle_event(event).map_err(to_napi_error)
+    }
+}
+
 #[napi]
 impl ConversationStateManager {
     /// Create a new ConversationStateManager with default character-based token approximation.
@@ -72,25 +520,7 @@ impl ConversationStateManager {
     /// @param options - Optional configuration options.
     #[napi(constructor)]
     pub fn new(options: Option<ConversationStateManagerOptions>) -> Result<Self> {
-        let defaults = ConversationStateManagerConfig::default();
-        
-        let config = match options {
-            Some(opts) => ConversationStateManagerConfig {
-                viewport_radius: opts.viewport_radius.map(|v| v as usize).unwrap_or(defaults.viewport_radius),
-                coalesce_radius: opts.coalesce_radius.map(|v| v as usize).unwrap_or(defaults.coalesce_radius),
-                max_tokens_per_message: opts.max_tokens_per_message.map(|v| v as usize).unwrap_or(defaults.max_tokens_per_message),
-                max_tokens_per_terminal_output: opts.max_tokens_per_terminal_output.map(|v| v as usize).unwrap_or(defaults.max_tokens_per_terminal_output),
-                // Extension-specific: no chunking (single ongoing conversation)
-                max_tokens_per_conversation: None,
-                min_conversation_messages: defaults.min_conversation_messages,
-            },
-            None => ConversationStateManagerConfig {
-                // Extension-specific: no chunking
-                max_tokens_per_conversation: None,
-                ..defaults
-            },
-        };
-
+        let config = config_from_options(options)?;
         Ok(Self {
             inner: Mutex::new(CoreManager::new(CharApproxTokenizer, config)),
         })
@@ -118,15 +548,97 @@ impl ConversationStateManager {
         Ok(inner.get_file_content(&file_path))
     }
 
+    /// Register a callback invoked with each notification. Callbacks run
+    /// asynchronously on the event loop and do not keep the process alive.
+    ///
+    /// @param callback - Called with a `ManagerNotification`.
+    #[napi]
+    pub fn add_observer(&self, env: Env, callback: JsFunction) -> Result<()> {
+        let mut tsfn: ThreadsafeFunction<ManagerNotification, ErrorStrategy::Fatal> = callback
+            .create_threadsafe_function(0, |ctx| Ok(vec![ctx.value]))?;
+        tsfn.unref(&env)?;
+
+        let mut inner = self.inner.lock().map_err(|_| Error::from_reason("Lock poisoned"))?;
+        inner.add_observer(Box::new(move |notification: &Notification<'_>| {
+            tsfn.call(notification.into(), ThreadsafeFunctionCallMode::NonBlocking);
+        }));
+        Ok(())
+    }
+
+    /// Set the timestamp (milliseconds) of the next event, used for command merging.
+    #[napi]
+    pub fn set_event_time(&self, time_ms: Option<i64>) -> Result<()> {
+        let mut inner = self.inner.lock().map_err(|_| Error::from_reason("Lock poisoned"))?;
+        inner.set_event_time(time_ms);
+        Ok(())
+    }
+
+    /// Flush terminal output older than `maxTerminalBufferAgeMs` at `timeMs`.
+    /// Call periodically while a command keeps producing output.
+    #[napi]
+    pub fn tick(&self, time_ms: i64) -> Result<()> {
+        let mut inner = self.inner.lock().map_err(|_| Error::from_reason("Lock poisoned"))?;
+        inner.tick(time_ms);
+        Ok(())
+    }
+
+    /// Apply content events queued under `contentDebounceMs`. Call from a
+    /// timer once a burst of edits is over.
+    #[napi]
+    pub fn flush_pending(&self) -> Result<()> {
+        let mut inner = self.inner.lock().map_err(|_| Error::from_reason("Lock poisoned"))?;
+        inner.flush_pending().map_err(to_napi_error)
+    }
+
+    /// Set the sequence number of the next event, used in drift diagnostics.
+    #[napi]
+    pub fn set_event_sequence(&self, sequence: Option<i64>) -> Result<()> {
+        let mut inner = self.inner.lock().map_err(|_| Error::from_reason("Lock poisoned"))?;
+        inner.set_event_sequence(sequence);
+        Ok(())
+    }
+
+    /// Take all offset drift diagnostics recorded since the last call.
+    #[napi]
+    pub fn take_drift_diagnostics(&self) -> Result<Vec<OffsetDriftDiagnostic>> {
+        let mut inner = self.inner.lock().map_err(|_| Error::from_reason("Lock poisoned"))?;
+        Ok(inner.take_drift_diagnostics().into_iter().map(Into::into).collect())
+    }
+
+    /// Lines of the current non-empty selection in a file, if any.
+    #[napi]
+    pub fn get_selection(&self, file_path: String) -> Result<Option<LineRange>> {
+        let inner = self.inner.lock().map_err(|_| Error::from_reason("Lock poisoned"))?;
+        Ok(inner.get_selection(&file_path).map(|vp| LineRange {
+            start: vp.start as u32,
+            end: vp.end as u32,
+        }))
+    }
+
+    /// File with editor focus, i.e. of the latest tab or selection event.
+    #[napi]
+    pub fn get_active_file(&self) -> Result<Option<String>> {
+        let inner = self.inner.lock().map_err(|_| Error::from_reason("Lock poisoned"))?;
+        Ok(inner.get_active_file().map(str::to_string))
+    }
+
+    /// Files awaiting a fresh tab snapshot (with content) after an offset drift.
+    #[napi]
+    pub fn get_files_awaiting_resync(&self) -> Result<Vec<String>> {
+        let inner = self.inner.lock().map_err(|_| Error::from_reason("Lock poisoned"))?;
+        Ok(inner.get_files_awaiting_resync())
+    }
+
     /// Handle a tab (file switch) event.
     ///
     /// @param filePath - The path to the file.
     /// @param textContent - The file contents, or null if switching to an already-open file.
     #[napi]
     pub fn handle_tab_event(&self, file_path: String, text_content: Option<String>) -> Result<()> {
-        let mut inner = self.inner.lock().map_err(|_| Error::from_reason("Lock poisoned"))?;
-        inner.handle_tab_event(&file_path, text_content.as_deref());
-        Ok(())
+        self.handle(IdeEvent::Tab {
+            file_path,
+            content: text_content,
+        })
     }
 
     /// Handle a content change event.
@@ -143,20 +655,138 @@ impl ConversationStateManager {
         length: u32,
         new_text: String,
     ) -> Result<()> {
+        self.handle(IdeEvent::Content {
+            file_path,
+            offset: offset as usize,
+            length: length as usize,
+            text: new_text,
+        })
+    }
+
+    /// Handle a burst of content events (a large paste, replace-all) in one
+    /// call: each change applies to the content after the ones before it.
+    /// Any events queued under `contentDebounceMs` are applied as well.
+    #[napi]
+    pub fn handle_content_events(&self, file_path: String, changes: Vec<ContentChange>) -> Result<()> {
+        let changes: Vec<_> = changes.iter().map(ContentChange::to_core).collect();
         let mut inner = self.inner.lock().map_err(|_| Error::from_reason("Lock poisoned"))?;
-        inner.handle_content_event(&file_path, offset as usize, length as usize, &new_text);
-        Ok(())
+        inner.handle_content_events(&file_path, &changes).map_err(to_napi_error)
+    }
+
+    /// Handle a multi-cursor edit: changes made at once, with offsets into
+    /// the content before any of them. They are serialized as one edit.
+    #[napi]
+    pub fn handle_bulk_content_event(&self, file_path: String, changes: Vec<ContentChange>) -> Result<()> {
+        self.handle(IdeEvent::BulkContent {
+            file_path,
+            changes: changes
+                .into_iter()
+                .map(|change| (change.offset as usize, change.length as usize, change.text))
+                .collect(),
+        })
+    }
+
+    /// Handle a change made by undo (Ctrl+Z). A burst of undos is
+    /// serialized as its net diff.
+    #[napi]
+    pub fn handle_undo_event(&self, file_path: String, offset: u32, length: u32, new_text: String) -> Result<()> {
+        self.handle(IdeEvent::Undo {
+            file_path,
+            offset: offset as usize,
+            length: length as usize,
+            text: new_text,
+        })
+    }
+
+    /// Handle a change made by redo, coalesced like undo.
+    #[napi]
+    pub fn handle_redo_event(&self, file_path: String, offset: u32, length: u32, new_text: String) -> Result<()> {
+        self.handle(IdeEvent::Redo {
+            file_path,
+            offset: offset as usize,
+            length: length as usize,
+            text: new_text,
+        })
     }
 
     /// Handle a selection event.
     ///
     /// @param filePath - The path to the file.
     /// @param offset - The character offset of the selection start.
+    /// @param endOffset - The character offset of the selection end, for a
+    /// non-empty selection.
     #[napi]
-    pub fn handle_selection_event(&self, file_path: String, offset: u32) -> Result<()> {
-        let mut inner = self.inner.lock().map_err(|_| Error::from_reason("Lock poisoned"))?;
-        inner.handle_selection_event(&file_path, offset as usize);
-        Ok(())
+    pub fn handle_selection_event(
+        &self,
+        file_path: String,
+        offset: u32,
+        end_offset: Option<u32>,
+    ) -> Result<()> {
+        self.handle(IdeEvent::Selection {
+            file_path,
+            offset: offset as usize,
+            end_offset: end_offset.map(|o| o as usize),
+        })
+    }
+
+    /// Handle a selection between two offsets, in either order.
+    ///
+    /// @param filePath - The path to the file.
+    /// @param startOffset - The character offset of one end of the selection.
+    /// @param endOffset - The character offset of the other end.
+    #[napi]
+    pub fn handle_selection_range_event(&self, file_path: String, start_offset: u32, end_offset: u32) -> Result<()> {
+        self.handle(IdeEvent::Selection {
+            file_path,
+            offset: start_offset as usize,
+            end_offset: Some(end_offset as usize),
+        })
+    }
+
+    /// Handle a selection event given as line and column, without offset
+    /// arithmetic on the tracked content.
+    ///
+    /// @param filePath - The path to the file.
+    /// @param line - The 1-based line of the selection start.
+    /// @param column - The 0-based column of the selection start.
+    /// @param endLine - The 1-based line of the selection end, for a
+    /// non-empty selection.
+    /// @param endColumn - The 0-based column of the selection end.
+    #[napi]
+    pub fn handle_selection_event_line(
+        &self,
+        file_path: String,
+        line: u32,
+        column: u32,
+        end_line: Option<u32>,
+        end_column: Option<u32>,
+    ) -> Result<()> {
+        self.handle(IdeEvent::SelectionLine {
+            file_path,
+            line: line as usize,
+            column: column as usize,
+            end: end_line.map(|l| (l as usize, end_column.unwrap_or(0) as usize)),
+        })
+    }
+
+    /// Handle a command typed in a Vim emulation mode (`dd`, `:%s/a/b/g`, `:42`).
+    ///
+    /// @param filePath - The path to the file.
+    /// @param command - The Vim command.
+    /// @param cursorOffset - The character offset of the cursor, needed for
+    /// commands relative to the cursor line.
+    #[napi]
+    pub fn handle_vim_command_event(
+        &self,
+        file_path: String,
+        command: String,
+        cursor_offset: Option<u32>,
+    ) -> Result<()> {
+        self.handle(IdeEvent::VimCommand {
+            file_path,
+            command,
+            cursor_offset: cursor_offset.map(|o| o as usize),
+        })
     }
 
     /// Handle a terminal command event.
@@ -164,9 +794,7 @@ impl ConversationStateManager {
     /// @param command - The command that was executed.
     #[napi]
     pub fn handle_terminal_command_event(&self, command: String) -> Result<()> {
-        let mut inner = self.inner.lock().map_err(|_| Error::from_reason("Lock poisoned"))?;
-        inner.handle_terminal_command_event(&command);
-        Ok(())
+        self.handle(IdeEvent::TerminalCommand { command })
     }
 
     /// Handle a terminal output event.
@@ -174,17 +802,54 @@ impl ConversationStateManager {
     /// @param output - The terminal output.
     #[napi]
     pub fn handle_terminal_output_event(&self, output: String) -> Result<()> {
-        let mut inner = self.inner.lock().map_err(|_| Error::from_reason("Lock poisoned"))?;
-        inner.handle_terminal_output_event(&output);
-        Ok(())
+        self.handle(IdeEvent::TerminalOutput { output })
+    }
+
+    /// Handle a file save event: flush the pending edit of the file.
+    ///
+    /// @param filePath - The saved file.
+    #[napi]
+    pub fn handle_file_save_event(&self, file_path: String) -> Result<()> {
+        self.handle(IdeEvent::FileSave { file_path })
+    }
+
+    /// Handle a file rename event: move the file's tracked state and
+    /// serialize the rename as `mv`.
+    ///
+    /// @param oldPath - The path before the rename.
+    /// @param newPath - The path after the rename.
+    #[napi]
+    pub fn handle_file_rename_event(&self, old_path: String, new_path: String) -> Result<()> {
+        self.handle(IdeEvent::FileRename { old_path, new_path })
     }
 
     /// Handle a terminal focus event.
     #[napi]
     pub fn handle_terminal_focus_event(&self) -> Result<()> {
-        let mut inner = self.inner.lock().map_err(|_| Error::from_reason("Lock poisoned"))?;
-        inner.handle_terminal_focus_event();
-        Ok(())
+        self.handle(IdeEvent::<String>::TerminalFocus)
+    }
+
+    /// Handle a session start event: finalize the conversation and forget
+    /// all file state.
+    #[napi]
+    pub fn handle_session_start_event(&self) -> Result<()> {
+        self.handle(IdeEvent::<String>::SessionStart)
+    }
+
+    /// Handle a session end event: append the end-of-task marker, if
+    /// configured, and finalize the conversation.
+    #[napi]
+    pub fn handle_session_end_event(&self) -> Result<()> {
+        self.handle(IdeEvent::<String>::SessionEnd)
+    }
+
+    /// Handle a directory open event.
+    ///
+    /// @param dirPath - The directory that was opened.
+    /// @param entries - Names of the directory's entries.
+    #[napi]
+    pub fn handle_directory_open_event(&self, dir_path: String, entries: Vec<String>) -> Result<()> {
+        self.handle(IdeEvent::DirectoryOpen { dir_path, entries })
     }
 
     /// Handle a git branch checkout event.
@@ -192,16 +857,471 @@ impl ConversationStateManager {
     /// @param branchInfo - The git checkout message containing the branch name.
     #[napi]
     pub fn handle_git_branch_checkout_event(&self, branch_info: String) -> Result<()> {
+        self.handle(IdeEvent::GitBranchCheckout { branch_info })
+    }
+
+    /// Handle all events held back by the `reorderWindow` option.
+    #[napi]
+    pub fn flush_reordered_events(&self) -> Result<()> {
         let mut inner = self.inner.lock().map_err(|_| Error::from_reason("Lock poisoned"))?;
-        inner.handle_git_branch_checkout_event(&branch_info);
-        Ok(())
+        inner.flush_reordered_events().map_err(to_napi_error)
+    }
+
+    /// Append an action the model actually executed (online fine-tuning),
+    /// truncated like generated messages.
+    ///
+    /// @param text - The rendered action, e.g. a fenced bash block.
+    #[napi]
+    pub fn append_assistant_action(&self, text: String) -> Result<()> {
+        let mut inner = self.inner.lock().map_err(|_| Error::from_reason("Lock poisoned"))?;
+        inner.append_assistant_action(&text).map_err(to_napi_error)
+    }
+
+    /// Append the observed result of an injected action as a user message.
+    ///
+    /// @param text - The observation, e.g. the command output.
+    #[napi]
+    pub fn append_observation(&self, text: String) -> Result<()> {
+        let mut inner = self.inner.lock().map_err(|_| Error::from_reason("Lock poisoned"))?;
+        inner.append_observation(&text).map_err(to_napi_error)
     }
 
     /// Finalize and get conversation ready for model.
     #[napi]
     pub fn finalize_for_model(&self) -> Result<Vec<ConversationMessage>> {
         let mut inner = self.inner.lock().map_err(|_| Error::from_reason("Lock poisoned"))?;
-        Ok(inner.finalize_for_model().into_iter().map(Into::into).collect())
+        let messages = inner.finalize_for_model().map_err(to_napi_error)?;
+        Ok(messages.into_iter().map(Into::into).collect())
+    }
+
+    /// Split the transcript here (task completed, new session): finalize the
+    /// current conversation and start a new one. Returns the finalized
+    /// conversation, or null if it was empty or too short to keep.
+    #[napi]
+    pub fn start_new_conversation(&self) -> Result<Option<FinalizedConversation>> {
+        let mut inner = self.inner.lock().map_err(|_| Error::from_reason("Lock poisoned"))?;
+        let conversation = inner.force_finalize().map_err(to_napi_error)?;
+        Ok(conversation.map(Into::into))
+    }
+
+    /// Assemble a prompt within a token budget: the system prompt, the latest
+    /// capture and view of the active file, then as much recent history as fits.
+    ///
+    /// @param systemPrompt - The system prompt, always included.
+    /// @param maxPromptTokens - Token budget for the whole prompt.
+    #[napi]
+    pub fn get_context_for_model(&self, system_prompt: String, max_prompt_tokens: u32) -> Result<ModelContext> {
+        let mut inner = self.inner.lock().map_err(|_| Error::from_reason("Lock poisoned"))?;
+        let context = inner
+            .get_context_for_model(&system_prompt, max_prompt_tokens as usize)
+            .map_err(to_napi_error)?;
+        Ok(context.into())
+    }
+}
+
+/// Manages one state manager per named session (workspace window, remote host)
+/// with a shared configuration. Sessions are created on first use.
+#[napi]
+pub struct SessionRegistry {
+    inner: Mutex<CoreRegistry<CharApproxTokenizer>>,
+}
+
+impl SessionRegistry {
+    /// Handle an event in a session, through the reordering window if configured.
+    fn handle(&self, session: &str, event: IdeEvent<String>) -> Result<()> {
+        let mut inner = self.inner.lock().map_err(|_| Error::from_reason("Lock poisoned"))?;
+        inner.session(session).handle_event(event).map_err(to_napi_error)
+    }
+}
+
+#[napi]
+impl SessionRegistry {
+    /// Create a registry whose sessions all use the given options.
+    ///
+    /// @param options - Optional configuration options shared by all sessions.
+    #[napi(constructor)]
+    pub fn new(options: Option<ConversationStateManagerOptions>) -> Result<Self> {
+        let config = config_from_options(options)?;
+        Ok(Self {
+            inner: Mutex::new(CoreRegistry::new(CharApproxTokenizer, config)),
+        })
+    }
+
+    /// Names of all sessions, sorted.
+    #[napi]
+    pub fn session_names(&self) -> Result<Vec<String>> {
+        let inner = self.inner.lock().map_err(|_| Error::from_reason("Lock poisoned"))?;
+        Ok(inner.session_names())
+    }
+
+    /// Whether a session exists.
+    #[napi]
+    pub fn has_session(&self, session: String) -> Result<bool> {
+        let inner = self.inner.lock().map_err(|_| Error::from_reason("Lock poisoned"))?;
+        Ok(inner.contains(&session))
+    }
+
+    /// Remove a session. Returns whether it existed.
+    #[napi]
+    pub fn remove_session(&self, session: String) -> Result<bool> {
+        let mut inner = self.inner.lock().map_err(|_| Error::from_reason("Lock poisoned"))?;
+        Ok(inner.remove(&session).is_some())
+    }
+
+    /// Reset the state of one session.
+    #[napi]
+    pub fn reset(&self, session: String) -> Result<()> {
+        let mut inner = self.inner.lock().map_err(|_| Error::from_reason("Lock poisoned"))?;
+        inner.session(&session).reset();
+        Ok(())
+    }
+
+    /// Reset the state of every session.
+    #[napi]
+    pub fn reset_all(&self) -> Result<()> {
+        let mut inner = self.inner.lock().map_err(|_| Error::from_reason("Lock poisoned"))?;
+        inner.reset_all();
+        Ok(())
+    }
+
+    /// Statistics aggregated over all sessions.
+    #[napi]
+    pub fn get_stats(&self) -> Result<RegistryStats> {
+        let inner = self.inner.lock().map_err(|_| Error::from_reason("Lock poisoned"))?;
+        Ok(inner.stats().into())
+    }
+
+    /// Get a copy of all messages of a session.
+    #[napi]
+    pub fn get_messages(&self, session: String) -> Result<Vec<ConversationMessage>> {
+        let inner = self.inner.lock().map_err(|_| Error::from_reason("Lock poisoned"))?;
+        Ok(inner
+            .get(&session)
+            .map(|manager| manager.get_messages().into_iter().map(Into::into).collect())
+            .unwrap_or_default())
+    }
+
+    /// Flush aged terminal output in every session.
+    #[napi]
+    pub fn tick(&self, time_ms: i64) -> Result<()> {
+        let mut inner = self.inner.lock().map_err(|_| Error::from_reason("Lock poisoned"))?;
+        inner.tick_all(time_ms);
+        Ok(())
+    }
+
+    /// Apply a session's queued content events.
+    #[napi]
+    pub fn flush_pending(&self, session: String) -> Result<()> {
+        let mut inner = self.inner.lock().map_err(|_| Error::from_reason("Lock poisoned"))?;
+        inner.session(&session).flush_pending().map_err(to_napi_error)
+    }
+
+    /// Set the timestamp (milliseconds) of a session's next event.
+    #[napi]
+    pub fn set_event_time(&self, session: String, time_ms: Option<i64>) -> Result<()> {
+        let mut inner = self.inner.lock().map_err(|_| Error::from_reason("Lock poisoned"))?;
+        inner.session(&session).set_event_time(time_ms);
+        Ok(())
+    }
+
+    /// Set the sequence number of a session's next event.
+    #[napi]
+    pub fn set_event_sequence(&self, session: String, sequence: Option<i64>) -> Result<()> {
+        let mut inner = self.inner.lock().map_err(|_| Error::from_reason("Lock poisoned"))?;
+        inner.session(&session).set_event_sequence(sequence);
+        Ok(())
+    }
+
+    /// Handle a tab (file switch) event in a session.
+    #[napi]
+    pub fn handle_tab_event(
+        &self,
+        session: String,
+        file_path: String,
+        text_content: Option<String>,
+    ) -> Result<()> {
+        self.handle(
+            &session,
+            IdeEvent::Tab {
+                file_path,
+                content: text_content,
+            },
+        )
+    }
+
+    /// Handle a content change event in a session.
+    #[napi]
+    pub fn handle_content_event(
+        &self,
+        session: String,
+        file_path: String,
+        offset: u32,
+        length: u32,
+        new_text: String,
+    ) -> Result<()> {
+        self.handle(
+            &session,
+            IdeEvent::Content {
+                file_path,
+                offset: offset as usize,
+                length: length as usize,
+                text: new_text,
+            },
+        )
+    }
+
+    /// Handle a burst of content events in a session in one call.
+    #[napi]
+    pub fn handle_content_events(
+        &self,
+        session: String,
+        file_path: String,
+        changes: Vec<ContentChange>,
+    ) -> Result<()> {
+        let changes: Vec<_> = changes.iter().map(ContentChange::to_core).collect();
+        let mut inner = self.inner.lock().map_err(|_| Error::from_reason("Lock poisoned"))?;
+        inner
+            .session(&session)
+            .handle_content_events(&file_path, &changes)
+            .map_err(to_napi_error)
+    }
+
+    /// Handle a multi-cursor edit in a session.
+    #[napi]
+    pub fn handle_bulk_content_event(
+        &self,
+        session: String,
+        file_path: String,
+        changes: Vec<ContentChange>,
+    ) -> Result<()> {
+        self.handle(
+            &session,
+            IdeEvent::BulkContent {
+                file_path,
+                changes: changes
+                    .into_iter()
+                    .map(|change| (change.offset as usize, change.length as usize, change.text))
+                    .collect(),
+            },
+        )
+    }
+
+    /// Handle an undo event in a session.
+    #[napi]
+    pub fn handle_undo_event(
+        &self,
+        session: String,
+        file_path: String,
+        offset: u32,
+        length: u32,
+        new_text: String,
+    ) -> Result<()> {
+        self.handle(
+            &session,
+            IdeEvent::Undo {
+                file_path,
+                offset: offset as usize,
+                length: length as usize,
+                text: new_text,
+            },
+        )
+    }
+
+    /// Handle a redo event in a session.
+    #[napi]
+    pub fn handle_redo_event(
+        &self,
+        session: String,
+        file_path: String,
+        offset: u32,
+        length: u32,
+        new_text: String,
+    ) -> Result<()> {
+        self.handle(
+            &session,
+            IdeEvent::Redo {
+                file_path,
+                offset: offset as usize,
+                length: length as usize,
+                text: new_text,
+            },
+        )
+    }
+
+    /// Handle a selection event in a session.
+    #[napi]
+    pub fn handle_selection_event(
+        &self,
+        session: String,
+        file_path: String,
+        offset: u32,
+        end_offset: Option<u32>,
+    ) -> Result<()> {
+        self.handle(
+            &session,
+            IdeEvent::Selection {
+                file_path,
+                offset: offset as usize,
+                end_offset: end_offset.map(|o| o as usize),
+            },
+        )
+    }
+
+    /// Handle a selection between two offsets in a session.
+    #[napi]
+    pub fn handle_selection_range_event(
+        &self,
+        session: String,
+        file_path: String,
+        start_offset: u32,
+        end_offset: u32,
+    ) -> Result<()> {
+        self.handle(
+            &session,
+            IdeEvent::Selection {
+                file_path,
+                offset: start_offset as usize,
+                end_offset: Some(end_offset as usize),
+            },
+        )
+    }
+
+    /// Handle a line-based selection event in a session.
+    #[napi]
+    pub fn handle_selection_event_line(
+        &self,
+        session: String,
+        file_path: String,
+        line: u32,
+        column: u32,
+        end_line: Option<u32>,
+        end_column: Option<u32>,
+    ) -> Result<()> {
+        self.handle(
+            &session,
+            IdeEvent::SelectionLine {
+                file_path,
+                line: line as usize,
+                column: column as usize,
+                end: end_line.map(|l| (l as usize, end_column.unwrap_or(0) as usize)),
+            },
+        )
+    }
+
+    /// Handle a Vim emulation command in a session.
+    #[napi]
+    pub fn handle_vim_command_event(
+        &self,
+        session: String,
+        file_path: String,
+        command: String,
+        cursor_offset: Option<u32>,
+    ) -> Result<()> {
+        self.handle(
+            &session,
+            IdeEvent::VimCommand {
+                file_path,
+                command,
+                cursor_offset: cursor_offset.map(|o| o as usize),
+            },
+        )
+    }
+
+    /// Handle a terminal command event in a session.
+    #[napi]
+    pub fn handle_terminal_command_event(&self, session: String, command: String) -> Result<()> {
+        self.handle(&session, IdeEvent::TerminalCommand { command })
+    }
+
+    /// Handle a terminal output event in a session.
+    #[napi]
+    pub fn handle_terminal_output_event(&self, session: String, output: String) -> Result<()> {
+        self.handle(&session, IdeEvent::TerminalOutput { output })
+    }
+
+    /// Handle a file save event in a session.
+    #[napi]
+    pub fn handle_file_save_event(&self, session: String, file_path: String) -> Result<()> {
+        self.handle(&session, IdeEvent::FileSave { file_path })
+    }
+
+    /// Handle a file rename event in a session.
+    #[napi]
+    pub fn handle_file_rename_event(&self, session: String, old_path: String, new_path: String) -> Result<()> {
+        self.handle(&session, IdeEvent::FileRename { old_path, new_path })
+    }
+
+    /// Handle a terminal focus event in a session.
+    #[napi]
+    pub fn handle_terminal_focus_event(&self, session: String) -> Result<()> {
+        self.handle(&session, IdeEvent::<String>::TerminalFocus)
+    }
+
+    /// Handle a session start event in a session.
+    #[napi]
+    pub fn handle_session_start_event(&self, session: String) -> Result<()> {
+        self.handle(&session, IdeEvent::<String>::SessionStart)
+    }
+
+    /// Handle a session end event in a session.
+    #[napi]
+    pub fn handle_session_end_event(&self, session: String) -> Result<()> {
+        self.handle(&session, IdeEvent::<String>::SessionEnd)
+    }
+
+    /// Append an action the model actually executed in a session.
+    #[napi]
+    pub fn append_assistant_action(&self, session: String, text: String) -> Result<()> {
+        let mut inner = self.inner.lock().map_err(|_| Error::from_reason("Lock poisoned"))?;
+        inner.session(&session).append_assistant_action(&text).map_err(to_napi_error)
+    }
+
+    /// Append the observed result of an injected action in a session.
+    #[napi]
+    pub fn append_observation(&self, session: String, text: String) -> Result<()> {
+        let mut inner = self.inner.lock().map_err(|_| Error::from_reason("Lock poisoned"))?;
+        inner.session(&session).append_observation(&text).map_err(to_napi_error)
+    }
+
+    /// Handle a directory open event in a session.
+    #[napi]
+    pub fn handle_directory_open_event(
+        &self,
+        session: String,
+        dir_path: String,
+        entries: Vec<String>,
+    ) -> Result<()> {
+        self.handle(&session, IdeEvent::DirectoryOpen { dir_path, entries })
+    }
+
+    /// Handle a git branch checkout event in a session.
+    #[napi]
+    pub fn handle_git_branch_checkout_event(&self, session: String, branch_info: String) -> Result<()> {
+        self.handle(&session, IdeEvent::GitBranchCheckout { branch_info })
+    }
+
+    /// Handle all events of a session held back by the `reorderWindow` option.
+    #[napi]
+    pub fn flush_reordered_events(&self, session: String) -> Result<()> {
+        let mut inner = self.inner.lock().map_err(|_| Error::from_reason("Lock poisoned"))?;
+        inner.session(&session).flush_reordered_events().map_err(to_napi_error)
+    }
+
+    /// Finalize and get a session's conversation ready for model.
+    #[napi]
+    pub fn finalize_for_model(&self, session: String) -> Result<Vec<ConversationMessage>> {
+        let mut inner = self.inner.lock().map_err(|_| Error::from_reason("Lock poisoned"))?;
+        let messages = inner.session(&session).finalize_for_model().map_err(to_napi_error)?;
+        Ok(messages.into_iter().map(Into::into).collect())
+    }
+
+    /// Split a session's transcript here and return the finalized
+    /// conversation, or null if it was empty or too short to keep.
+    #[napi]
+    pub fn start_new_conversation(&self, session: String) -> Result<Option<FinalizedConversation>> {
+        let mut inner = self.inner.lock().map_err(|_| Error::from_reason("Lock poisoned"))?;
+        let conversation = inner.session(&session).force_finalize().map_err(to_napi_error)?;
+        Ok(conversation.map(Into::into))
     }
 }
 
@@ -224,6 +1344,16 @@ pub fn fenced_block(language: Option<String>, content: String) -> String {
     crowd_pilot_serializer_core::fenced_block(language.as_deref(), &content)
 }
 
+/// Helper function: classify a shell command ("read", "edit", "build",
+/// "network", "destructive").
+#[napi]
+pub fn classify_command(command: String) -> Vec<String> {
+    crowd_pilot_serializer_core::classify_command(&command)
+        .iter()
+        .map(|class| class.as_str().to_string())
+        .collect()
+}
+
 /// Helper function: normalize terminal output.
 #[napi]
 pub fn normalize_terminal_output(raw: String) -> String {
@@ -243,3 +1373,52 @@ pub fn line_numbered_output(
         end_line.map(|v| v as usize),
     )
 }
+
+/// A position in a text: 1-based line, 0-based column.
+#[napi(object)]
+pub struct LineColumn {
+    pub line: u32,
+    pub column: u32,
+}
+
+fn parse_offset_encoding(encoding: Option<String>) -> Result<OffsetEncoding> {
+    match encoding {
+        Some(encoding) => encoding.parse().map_err(to_napi_error),
+        // JavaScript string offsets count UTF-16 code units
+        None => Ok(OffsetEncoding::Utf16),
+    }
+}
+
+/// Helper function: convert an offset into a line/column position.
+///
+/// @param encoding - "utf16" (default, JavaScript offsets) or "utf8".
+#[napi]
+pub fn offset_to_line_col(content: String, offset: u32, encoding: Option<String>) -> Result<LineColumn> {
+    let (line, column) = crowd_pilot_serializer_core::offset_to_line_col(
+        &content,
+        offset as usize,
+        parse_offset_encoding(encoding)?,
+    );
+    Ok(LineColumn {
+        line: line as u32,
+        column: column as u32,
+    })
+}
+
+/// Helper function: convert a line/column position into an offset.
+///
+/// @param encoding - "utf16" (default, JavaScript offsets) or "utf8".
+#[napi]
+pub fn line_col_to_offset(
+    content: String,
+    line: u32,
+    column: u32,
+    encoding: Option<String>,
+) -> Result<u32> {
+    Ok(crowd_pilot_serializer_core::line_col_to_offset(
+        &content,
+        line as usize,
+        column as usize,
+        parse_offset_encoding(encoding)?,
+    ) as u32)
+}